@@ -0,0 +1,16 @@
+#!/usr/bin/env sh
+
+# Re-renders every golden image scene and overwrites the references in
+# code/showcase/golden/references. The scenes only match on lavapipe,
+# so they're ignored by a plain `cargo test`, and need a display to
+# open their windows on. Something like
+#
+#   VK_ICD_FILENAMES=/usr/share/vulkan/icd.d/lvp_icd.x86_64.json xvfb-run ./bless-golden.sh
+#
+# works on Linux. Run `cargo test --test golden -- --ignored` the same
+# way to check them.
+
+# abort on errors
+set -e
+
+GOLDEN_BLESS=1 cargo test --workspace --test golden -- --ignored "$@"
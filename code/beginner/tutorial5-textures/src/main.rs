@@ -255,7 +255,12 @@ impl State {
             .get_current_frame()
             .expect("Timeout getting texture")
             .output;
+        self.render_to(&frame.view);
+    }
 
+    // Draws into any view, not just the swap chain's. The golden image
+    // tests use this to draw into a texture they can read back.
+    fn render_to(&mut self, view: &wgpu::TextureView) {
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
@@ -265,7 +270,7 @@ impl State {
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                    attachment: &frame.view,
+                    attachment: view,
                     resolve_target: None,
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Clear(wgpu::Color {
@@ -181,6 +181,16 @@ impl Instance {
     }
 }
 
+impl framework::Posable for Instance {
+    fn set_position(&mut self, position: cgmath::Vector3<f32>) {
+        self.position = position;
+    }
+
+    fn set_rotation(&mut self, rotation: cgmath::Quaternion<f32>) {
+        self.rotation = rotation;
+    }
+}
+
 #[derive(Copy, Clone)]
 struct InstanceRaw {
     #[allow(dead_code)]
@@ -216,8 +226,8 @@ struct State {
     uniform_buffer: wgpu::Buffer,
     uniform_bind_group: wgpu::BindGroup,
     instances: Vec<Instance>,
-    #[allow(dead_code)]
     instance_buffer: wgpu::Buffer,
+    instance_animator: framework::InstanceAnimator,
     depth_texture: texture::Texture,
     size: winit::dpi::PhysicalSize<u32>,
     light: Light,
@@ -391,9 +401,21 @@ impl State {
         let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Instance Buffer"),
             contents: bytemuck::cast_slice(&instance_data),
-            usage: wgpu::BufferUsage::STORAGE,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
         });
 
+        // Spin every instance about the axis it's tilted around
+        let mut instance_animator = framework::InstanceAnimator::new();
+        for (i, instance) in instances.iter().enumerate() {
+            let axis = if instance.position.is_zero() {
+                cgmath::Vector3::unit_z()
+            } else {
+                instance.position.normalize()
+            };
+            let spin = framework::Track::spin(axis, instance.rotation, 4.0);
+            instance_animator.add(i, framework::TransformTrack::new().rotation(spin));
+        }
+
         let uniform_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[
@@ -540,6 +562,7 @@ impl State {
             uniforms,
             instances,
             instance_buffer,
+            instance_animator,
             depth_texture,
             size,
             light,
@@ -579,6 +602,15 @@ impl State {
                 * old_position;
         self.queue
             .write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&[self.light]));
+
+        // Like the light, the instances move a fixed step each frame
+        self.instance_animator.update_raw(
+            1.0 / 60.0,
+            &self.queue,
+            &self.instance_buffer,
+            &mut self.instances,
+            Instance::to_raw,
+        );
     }
 
     fn render(&mut self) {
@@ -587,7 +619,12 @@ impl State {
             .get_current_frame()
             .expect("Timeout getting texture")
             .output;
+        self.render_to(&frame.view);
+    }
 
+    // Draws into any view, not just the swap chain's. The golden image
+    // tests use this to draw into a texture they can read back.
+    fn render_to(&mut self, view: &wgpu::TextureView) {
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
@@ -597,7 +634,7 @@ impl State {
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                    attachment: &frame.view,
+                    attachment: view,
                     resolve_target: None,
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Clear(wgpu::Color {
@@ -0,0 +1,17 @@
+//! Draws this tutorial's scene and compares it to its reference image.
+//! See the `golden` crate for how to run and bless it.
+
+#![allow(dead_code, clippy::all)]
+
+include!("../src/main.rs");
+
+#[test]
+#[ignore = "needs lavapipe, see the golden crate"]
+fn renders_like_the_reference() {
+    golden::check(env!("CARGO_PKG_NAME"), |window| {
+        let mut state = futures::executor::block_on(State::new(window));
+        let target = golden::GoldenTarget::for_swap_chain(&state.device, &state.sc_desc);
+        state.render_to(target.view());
+        target.read(&state.device, &state.queue)
+    });
+}
@@ -0,0 +1,66 @@
+#version 450
+
+// A variant of world_space.frag that mixes in reflections from an
+// environment cube map. The cube map uses the same layout as
+// framework::EnvironmentBinding.
+
+layout(location=0) in vec2 v_tex_coords;
+layout(location=1) in vec3 v_position;
+layout(location=2) in mat3 v_tangent_matrix;
+
+layout(location=0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_diffuse;
+layout(set = 0, binding = 1) uniform sampler s_diffuse;
+layout(set = 0, binding = 2) uniform texture2D t_normal;
+layout(set = 0, binding = 3) uniform sampler s_normal;
+
+layout(set=1, binding=0) 
+uniform Uniforms {
+    vec3 u_view_position;
+    mat4 u_view_proj; // unused
+};
+
+layout(set = 2, binding = 0) uniform Light {
+    vec3 light_position;
+    vec3 light_color;
+};
+
+layout(set = 3, binding = 0) uniform textureCube t_environment;
+layout(set = 3, binding = 1) uniform sampler s_environment;
+
+// Samples the environment in the direction the view vector bounces
+// off the surface. Both vectors need to be in world space and point
+// away from the surface.
+vec3 sample_environment(vec3 normal, vec3 view_dir) {
+    vec3 reflect_dir = reflect(-view_dir, normal);
+    return texture(samplerCube(t_environment, s_environment), reflect_dir).rgb;
+}
+
+void main() {
+    vec4 object_color = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords);
+    vec4 object_normal = texture(sampler2D(t_normal, s_normal), v_tex_coords);
+
+    float ambient_strength = 0.1;
+    vec3 ambient_color = light_color * ambient_strength;
+
+    vec3 normal = normalize(v_tangent_matrix * (object_normal.rgb * 2.0 - 1.0));
+    vec3 light_dir = normalize(light_position - v_position);
+    
+    float diffuse_strength = max(dot(normal, light_dir), 0.0);
+    vec3 diffuse_color = light_color * diffuse_strength;
+
+    vec3 view_dir = normalize(u_view_position - v_position);
+    vec3 half_dir = normalize(view_dir + light_dir);
+    float specular_strength = pow(max(dot(normal, half_dir), 0.0), 32);
+    vec3 specular_color = specular_strength * light_color;
+
+    // Surfaces reflect more of their surroundings at grazing angles
+    float reflectivity = 0.25;
+    float fresnel = reflectivity + (1.0 - reflectivity) * pow(1.0 - max(dot(normal, view_dir), 0.0), 5.0);
+    vec3 environment_color = sample_environment(normal, view_dir);
+
+    vec3 lit = (ambient_color + diffuse_color + specular_color) * object_color.xyz;
+    vec3 result = mix(lit, environment_color, fresnel * reflectivity);
+    f_color = vec4(result, object_color.a);
+}
@@ -23,6 +23,45 @@ pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
 
 const NUM_INSTANCES_PER_ROW: u32 = 10;
 
+/**
+ * Builds the faces of a simple sky for the cubes to reflect: blue
+ * overhead, fading to white at the horizon and brown below it.
+ */
+fn sky_faces(size: u32) -> Vec<image::DynamicImage> {
+    let sky = cgmath::vec3(0.3, 0.5, 0.9);
+    let horizon = cgmath::vec3(1.0, 1.0, 1.0);
+    let ground = cgmath::vec3(0.4, 0.3, 0.2);
+    (0..framework::CUBE_FACES)
+        .map(|face| {
+            image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(size, size, |x, y| {
+                let u = (x as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                let v = (y as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                let dir: cgmath::Vector3<f32> = match face {
+                    0 => (1.0, -v, -u),
+                    1 => (-1.0, -v, u),
+                    2 => (u, 1.0, v),
+                    3 => (u, -1.0, -v),
+                    4 => (u, -v, 1.0),
+                    _ => (-u, -v, -1.0),
+                }
+                .into();
+                let up = dir.normalize().y;
+                let color = if up > 0.0 {
+                    horizon.lerp(sky, up)
+                } else {
+                    horizon.lerp(ground, (-up * 4.0).min(1.0))
+                };
+                image::Rgba([
+                    (color.x * 255.0) as u8,
+                    (color.y * 255.0) as u8,
+                    (color.z * 255.0) as u8,
+                    255,
+                ])
+            }))
+        })
+        .collect()
+}
+
 struct Camera {
     eye: cgmath::Point3<f32>,
     target: cgmath::Point3<f32>,
@@ -178,6 +217,16 @@ impl Instance {
     }
 }
 
+impl framework::Posable for Instance {
+    fn set_position(&mut self, position: cgmath::Vector3<f32>) {
+        self.position = position;
+    }
+
+    fn set_rotation(&mut self, rotation: cgmath::Quaternion<f32>) {
+        self.rotation = rotation;
+    }
+}
+
 #[derive(Copy, Clone)]
 struct InstanceRaw {
     #[allow(dead_code)]
@@ -213,8 +262,8 @@ struct State {
     uniform_buffer: wgpu::Buffer,
     uniform_bind_group: wgpu::BindGroup,
     instances: Vec<Instance>,
-    #[allow(dead_code)]
     instance_buffer: wgpu::Buffer,
+    instance_animator: framework::InstanceAnimator,
     depth_texture: texture::Texture,
     size: winit::dpi::PhysicalSize<u32>,
     light: Light,
@@ -223,6 +272,9 @@ struct State {
     light_render_pipeline: wgpu::RenderPipeline,
     #[allow(dead_code)]
     debug_material: model::Material,
+    #[allow(dead_code)]
+    environment: framework::Texture<'static>,
+    environment_binding: framework::EnvironmentBinding,
 }
 
 fn create_render_pipeline(
@@ -407,9 +459,21 @@ impl State {
         let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Instance Buffer"),
             contents: bytemuck::cast_slice(&instance_data),
-            usage: wgpu::BufferUsage::STORAGE,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
         });
 
+        // Spin every instance about the axis it's tilted around
+        let mut instance_animator = framework::InstanceAnimator::new();
+        for (i, instance) in instances.iter().enumerate() {
+            let axis = if instance.position.is_zero() {
+                cgmath::Vector3::unit_z()
+            } else {
+                instance.position.normalize()
+            };
+            let spin = framework::Track::spin(axis, instance.rotation, 4.0);
+            instance_animator.add(i, framework::TransformTrack::new().rotation(spin));
+        }
+
         let uniform_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[
@@ -502,6 +566,10 @@ impl State {
         let depth_texture =
             texture::Texture::create_depth_texture(&device, &sc_desc, "depth_texture");
 
+        let environment =
+            framework::Texture::from_cube_faces(&device, &queue, &sky_faces(64)).unwrap();
+        let environment_binding = framework::EnvironmentBinding::new(&device, &environment);
+
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
@@ -509,6 +577,7 @@ impl State {
                     &texture_bind_group_layout,
                     &uniform_bind_group_layout,
                     &light_bind_group_layout,
+                    &environment_binding.layout,
                 ],
                 push_constant_ranges: &[],
             });
@@ -586,6 +655,7 @@ impl State {
             uniforms,
             instances,
             instance_buffer,
+            instance_animator,
             depth_texture,
             size,
             light,
@@ -594,6 +664,8 @@ impl State {
             light_render_pipeline,
             #[allow(dead_code)]
             debug_material,
+            environment,
+            environment_binding,
         }
     }
 
@@ -627,6 +699,15 @@ impl State {
                 * old_position;
         self.queue
             .write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&[self.light]));
+
+        // Like the light, the instances move a fixed step each frame
+        self.instance_animator.update_raw(
+            1.0 / 60.0,
+            &self.queue,
+            &self.instance_buffer,
+            &mut self.instances,
+            Instance::to_raw,
+        );
     }
 
     fn render(&mut self) {
@@ -635,7 +716,12 @@ impl State {
             .get_current_frame()
             .expect("Timeout getting texture")
             .output;
+        self.render_to(&frame.view);
+    }
 
+    // Draws into any view, not just the swap chain's. The golden image
+    // tests use this to draw into a texture they can read back.
+    fn render_to(&mut self, view: &wgpu::TextureView) {
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
@@ -645,7 +731,7 @@ impl State {
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                    attachment: &frame.view,
+                    attachment: view,
                     resolve_target: None,
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Clear(wgpu::Color {
@@ -675,6 +761,7 @@ impl State {
             );
 
             render_pass.set_pipeline(&self.render_pipeline);
+            render_pass.set_bind_group(3, &self.environment_binding.bind_group, &[]);
             render_pass.draw_model_instanced(
                 &self.obj_model,
                 0..self.instances.len() as u32,
@@ -4,6 +4,7 @@ layout(location=0) in vec2 v_tex_coords;
 layout(location=1) in vec3 v_position; // UPDATED!
 layout(location=2) in vec3 v_light_position; // NEW!
 layout(location=3) in vec3 v_view_position; // NEW!
+layout(location=4) in mat3 v_tangent_to_world;
 
 layout(location=0) out vec4 f_color;
 
@@ -17,6 +18,19 @@ layout(set = 2, binding = 0) uniform Light {
     vec3 light_color;
 };
 
+// The same environment binding as environment.frag. The cube map
+// uses the layout of framework::EnvironmentBinding.
+layout(set = 3, binding = 0) uniform textureCube t_environment;
+layout(set = 3, binding = 1) uniform sampler s_environment;
+
+// Samples the environment in the direction the view vector bounces
+// off the surface. Both vectors need to be in world space and point
+// away from the surface.
+vec3 sample_environment(vec3 normal, vec3 view_dir) {
+    vec3 reflect_dir = reflect(-view_dir, normal);
+    return texture(samplerCube(t_environment, s_environment), reflect_dir).rgb;
+}
+
 void main() {
     vec4 object_color = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords);
     vec4 object_normal = texture(sampler2D(t_normal, s_normal), v_tex_coords);
@@ -35,6 +49,15 @@ void main() {
     float specular_strength = pow(max(dot(normal, half_dir), 0.0), 32);
     vec3 specular_color = specular_strength * light_color;
 
-    vec3 result = (ambient_color + diffuse_color + specular_color) * object_color.xyz;
+    // Surfaces reflect more of their surroundings at grazing angles
+    float reflectivity = 0.25;
+    float fresnel = reflectivity + (1.0 - reflectivity) * pow(1.0 - max(dot(normal, view_dir), 0.0), 5.0);
+    vec3 environment_color = sample_environment(
+        normalize(v_tangent_to_world * normal),
+        normalize(v_tangent_to_world * view_dir)
+    );
+
+    vec3 lit = (ambient_color + diffuse_color + specular_color) * object_color.xyz;
+    vec3 result = mix(lit, environment_color, fresnel * reflectivity);
     f_color = vec4(result, object_color.a);
 }
\ No newline at end of file
@@ -10,6 +10,7 @@ layout(location=0) out vec2 v_tex_coords;
 layout(location=1) out vec3 v_position; // UPDATED!
 layout(location=2) out vec3 v_light_position; // NEW!
 layout(location=3) out vec3 v_view_position; // NEW!
+layout(location=4) out mat3 v_tangent_to_world;
 
 layout(set=1, binding=0) 
 uniform Uniforms {
@@ -53,5 +54,9 @@ void main() {
     v_light_position = tangent_matrix * light_position;
     v_view_position = tangent_matrix * u_view_position;
 
+    // The environment is sampled in world space, so the fragment
+    // shader needs a way back out of tangent space
+    v_tangent_to_world = transpose(tangent_matrix);
+
     gl_Position = u_view_proj * model_space;
 }
\ No newline at end of file
@@ -179,6 +179,16 @@ impl Instance {
     }
 }
 
+impl framework::Posable for Instance {
+    fn set_position(&mut self, position: cgmath::Vector3<f32>) {
+        self.position = position;
+    }
+
+    fn set_rotation(&mut self, rotation: cgmath::Quaternion<f32>) {
+        self.rotation = rotation;
+    }
+}
+
 #[derive(Copy, Clone)]
 struct InstanceRaw {
     #[allow(dead_code)]
@@ -214,8 +224,8 @@ struct State {
     uniform_buffer: wgpu::Buffer,
     uniform_bind_group: wgpu::BindGroup,
     instances: Vec<Instance>,
-    #[allow(dead_code)]
     instance_buffer: wgpu::Buffer,
+    instance_animator: framework::InstanceAnimator,
     depth_texture: texture::Texture,
     size: winit::dpi::PhysicalSize<u32>,
     light: Light,
@@ -352,6 +362,18 @@ impl State {
             wgpu::BufferUsage::STORAGE_READ | wgpu::BufferUsage::COPY_DST,
         );
 
+        // Spin every instance about the axis it's tilted around
+        let mut instance_animator = framework::InstanceAnimator::new();
+        for (i, instance) in instances.iter().enumerate() {
+            let axis = if instance.position.is_zero() {
+                cgmath::Vector3::unit_z()
+            } else {
+                instance.position.normalize()
+            };
+            let spin = framework::Track::spin(axis, instance.rotation, 4.0);
+            instance_animator.add(i, framework::TransformTrack::new().rotation(spin));
+        }
+
         let uniform_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 bindings: &[
@@ -513,6 +535,7 @@ impl State {
             uniforms,
             instances,
             instance_buffer,
+            instance_animator,
             depth_texture,
             size,
             light,
@@ -578,6 +601,28 @@ impl State {
             std::mem::size_of::<Light>() as wgpu::BufferAddress,
         );
 
+        // Spin the instances. This crate is on an older wgpu than the
+        // framework, so the ones that moved get copied over by hand.
+        self.instance_animator.advance(dt.as_secs_f32());
+        if let Some(changed) = self.instance_animator.apply(&mut self.instances) {
+            let offset = changed.start * std::mem::size_of::<InstanceRaw>();
+            let instance_data = self.instances[changed]
+                .iter()
+                .map(Instance::to_raw)
+                .collect::<Vec<_>>();
+            let staging_buffer = self.device.create_buffer_with_data(
+                bytemuck::cast_slice(&instance_data),
+                wgpu::BufferUsage::COPY_SRC,
+            );
+            encoder.copy_buffer_to_buffer(
+                &staging_buffer,
+                0,
+                &self.instance_buffer,
+                offset as wgpu::BufferAddress,
+                (instance_data.len() * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            );
+        }
+
         self.queue.submit(&[encoder.finish()]);
     }
 
@@ -0,0 +1,198 @@
+use anyhow::*;
+use framework::{
+    dispatch, Buffer, ComputePipelineBuilder, StorageBinding, StorageEntry, ToRaw,
+    DEFAULT_WORKGROUP_SIZE,
+};
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+
+/// A `u32` that can live in a [framework::Buffer]
+#[derive(Debug, Copy, Clone)]
+pub struct Value(pub u32);
+
+impl ToRaw for Value {
+    type Output = u32;
+    fn to_raw(&self) -> Self::Output {
+        self.0
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct Step {
+    offset: u32,
+    count: u32,
+    // Uniform buffers are at least 16 bytes on some backends
+    _padding: [u32; 2],
+}
+
+unsafe impl bytemuck::Pod for Step {}
+unsafe impl bytemuck::Zeroable for Step {}
+
+/// The inclusive prefix sum the GPU version has to match. Sums wrap
+/// like `uint` addition does in the shader.
+pub fn prefix_sum_cpu(values: &[u32]) -> Vec<u32> {
+    values
+        .iter()
+        .scan(0u32, |sum, &v| {
+            *sum = sum.wrapping_add(v);
+            Some(*sum)
+        })
+        .collect()
+}
+
+/// The offsets each step of the scan adds from: 1, 2, 4, ... up to
+/// `count`
+pub fn step_offsets(count: u32) -> Vec<u32> {
+    std::iter::successors(Some(1u32), |offset| offset.checked_mul(2))
+        .take_while(|&offset| offset < count)
+        .collect()
+}
+
+/**
+ * Computes inclusive prefix sums on the GPU with a Hillis-Steele scan.
+ * Each step reads one storage buffer and writes the other, so it takes
+ * `log2(n)` dispatches. That's more work than a work-efficient scan,
+ * but every step is one line of GLSL.
+ */
+pub struct PrefixSum {
+    pipeline: wgpu::ComputePipeline,
+    storage: StorageBinding,
+    step_layout: wgpu::BindGroupLayout,
+}
+
+impl PrefixSum {
+    pub fn new(device: &wgpu::Device) -> Result<Self> {
+        // Only used for its layout, the real buffers are bound in run
+        let placeholder = Buffer::storage(device, vec![Value(0)]);
+        let storage = StorageBinding::new(
+            device,
+            &[
+                StorageEntry::read_only(&placeholder),
+                StorageEntry::read_write(&placeholder),
+            ],
+        );
+        let step_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::COMPUTE,
+                ty: wgpu::BindingType::UniformBuffer {
+                    dynamic: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("PrefixSum::step_layout"),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("PrefixSum::layout"),
+            bind_group_layouts: &[&storage.layout, &step_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = ComputePipelineBuilder::new()
+            .label("PrefixSum::pipeline")
+            .layout(&layout)
+            .compute_shader(wgpu::include_spirv!("prefix_sum.comp.spv"))
+            .build(device)?;
+        Ok(Self {
+            pipeline,
+            storage,
+            step_layout,
+        })
+    }
+
+    pub async fn run(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        values: &[u32],
+    ) -> Result<Vec<u32>> {
+        if values.is_empty() {
+            return Ok(Vec::new());
+        }
+        let count = values.len() as u32;
+        let buffers = [
+            Buffer::storage(device, values.iter().copied().map(Value).collect()),
+            Buffer::storage(device, vec![Value(0); values.len()]),
+        ];
+        // Reading from 0 and writing to 1, then the other way around
+        let storage_groups = [
+            self.storage.create_bind_group(
+                device,
+                &[
+                    StorageEntry::read_only(&buffers[0]),
+                    StorageEntry::read_write(&buffers[1]),
+                ],
+            ),
+            self.storage.create_bind_group(
+                device,
+                &[
+                    StorageEntry::read_only(&buffers[1]),
+                    StorageEntry::read_write(&buffers[0]),
+                ],
+            ),
+        ];
+
+        let offsets = step_offsets(count);
+        let steps = offsets
+            .iter()
+            .map(|&offset| {
+                let buffer = device.create_buffer_init(&BufferInitDescriptor {
+                    label: Some("PrefixSum::step"),
+                    contents: bytemuck::cast_slice(&[Step {
+                        offset,
+                        count,
+                        _padding: [0; 2],
+                    }]),
+                    usage: wgpu::BufferUsage::UNIFORM,
+                });
+                let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("PrefixSum::step_bind_group"),
+                    layout: &self.step_layout,
+                    entries: &[wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::Buffer(buffer.slice(..)),
+                    }],
+                });
+                (buffer, bind_group)
+            })
+            .collect::<Vec<_>>();
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("PrefixSum::encoder"),
+        });
+        for (i, (_, step_group)) in steps.iter().enumerate() {
+            dispatch(
+                &mut encoder,
+                &self.pipeline,
+                &[&storage_groups[i % 2], step_group],
+                count,
+                DEFAULT_WORKGROUP_SIZE,
+            );
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+
+        // Every step flips which buffer holds the latest sums
+        buffers[offsets.len() % 2].read_back(device, queue).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_prefix_sum() {
+        assert_eq!(prefix_sum_cpu(&[]), Vec::<u32>::new());
+        assert_eq!(prefix_sum_cpu(&[3, 1, 4, 1, 5]), vec![3, 4, 8, 9, 14]);
+        assert_eq!(prefix_sum_cpu(&[u32::MAX, 2]), vec![u32::MAX, 1]);
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(step_offsets(1), Vec::<u32>::new());
+        assert_eq!(step_offsets(2), vec![1]);
+        assert_eq!(step_offsets(5), vec![1, 2, 4]);
+        assert_eq!(step_offsets(8), vec![1, 2, 4]);
+        assert_eq!(step_offsets(9), vec![1, 2, 4, 8]);
+    }
+}
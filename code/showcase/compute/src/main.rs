@@ -0,0 +1,37 @@
+use anyhow::*;
+use compute::{prefix_sum_cpu, PrefixSum};
+use framework::Headless;
+use rand::Rng;
+use std::time::Instant;
+
+async fn run() -> Result<()> {
+    let headless = Headless::any_adapter().await?;
+    println!("Running on {}", headless.info.name);
+
+    let mut rng = rand::thread_rng();
+    let values = (0..1_000_000)
+        .map(|_| rng.gen_range(0, 16))
+        .collect::<Vec<u32>>();
+    let prefix_sum = PrefixSum::new(&headless.device)?;
+
+    let start = Instant::now();
+    let gpu = prefix_sum
+        .run(&headless.device, &headless.queue, &values)
+        .await?;
+    println!("GPU: {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let cpu = prefix_sum_cpu(&values);
+    println!("CPU: {:?}", start.elapsed());
+
+    match gpu.iter().zip(&cpu).position(|(a, b)| a != b) {
+        Some(i) => bail!("Mismatch at {}: GPU {} vs CPU {}", i, gpu[i], cpu[i]),
+        None => println!("All {} sums match", values.len()),
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    futures::executor::block_on(run())
+}
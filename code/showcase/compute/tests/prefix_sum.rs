@@ -0,0 +1,32 @@
+use compute::{prefix_sum_cpu, PrefixSum};
+use framework::Headless;
+use futures::executor::block_on;
+use rand::Rng;
+
+/// Checks the GPU scan against the CPU one for `count` random values
+fn check(count: usize) {
+    let headless = block_on(Headless::any_adapter()).unwrap();
+    let mut rng = rand::thread_rng();
+    let values = (0..count).map(|_| rng.gen::<u32>()).collect::<Vec<_>>();
+    let prefix_sum = PrefixSum::new(&headless.device).unwrap();
+    let gpu = block_on(prefix_sum.run(&headless.device, &headless.queue, &values)).unwrap();
+    assert_eq!(gpu, prefix_sum_cpu(&values), "on {}", headless.info.name);
+}
+
+#[test]
+#[ignore = "needs a GPU or a software adapter"]
+fn single_value() {
+    check(1);
+}
+
+#[test]
+#[ignore = "needs a GPU or a software adapter"]
+fn partial_workgroup() {
+    check(100);
+}
+
+#[test]
+#[ignore = "needs a GPU or a software adapter"]
+fn many_workgroups() {
+    check(100_000);
+}
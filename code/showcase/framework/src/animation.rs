@@ -0,0 +1,608 @@
+use cgmath::*;
+use std::mem;
+use std::ops::{Add, Mul, Range, Sub};
+
+use crate::buffer::Buffer;
+use crate::instance::{Instance, InstanceRaw};
+use crate::light::Light;
+
+/// Values that can be keyframed
+pub trait Animatable:
+    Copy + Add<Output = Self> + Sub<Output = Self> + Mul<f32, Output = Self>
+{
+    fn lerp(self, other: Self, t: f32) -> Self {
+        self * (1.0 - t) + other * t
+    }
+
+    /// Cleans up after cubic interpolation, which can leave values
+    /// that aren't valid on their own, like rotations that aren't unit
+    /// length
+    fn finish(self) -> Self {
+        self
+    }
+
+    /// `self`, or an equivalent value closer to `other`. Cubic
+    /// interpolation aligns neighbouring keys with this before mixing
+    /// them.
+    fn align(self, _other: Self) -> Self {
+        self
+    }
+}
+
+impl Animatable for f32 {}
+impl Animatable for Vector2<f32> {}
+impl Animatable for Vector3<f32> {}
+impl Animatable for Vector4<f32> {}
+
+impl Animatable for Quaternion<f32> {
+    /// Normalized lerp, taking the short way around
+    fn lerp(self, other: Self, t: f32) -> Self {
+        let other = other.align(self);
+        (self * (1.0 - t) + other * t).normalize()
+    }
+
+    fn finish(self) -> Self {
+        self.normalize()
+    }
+
+    /// `q` and `-q` are the same rotation, this picks whichever is in
+    /// the same hemisphere as `other`
+    fn align(self, other: Self) -> Self {
+        if self.dot(other) < 0.0 {
+            -self
+        } else {
+            self
+        }
+    }
+}
+
+/// How values between keyframes are worked out
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Holds each key until the next one
+    Step,
+    Linear,
+    /// Hermite curves. glTF channels store an in tangent, the value and
+    /// an out tangent for every key. [Track]s work out Catmull-Rom
+    /// tangents from the neighbouring keys instead.
+    CubicSpline,
+}
+
+/// How a track's time is mapped into its keys once it runs past the
+/// last one
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PlayMode {
+    /// Holds the last key
+    Once,
+    Loop,
+    /// Plays forwards, then backwards, then forwards again
+    PingPong,
+}
+
+impl PlayMode {
+    /// Maps `time` into `0..=duration`
+    pub fn wrap(self, time: f32, duration: f32) -> f32 {
+        if duration <= 0.0 {
+            return 0.0;
+        }
+        match self {
+            PlayMode::Once => time.max(0.0).min(duration),
+            PlayMode::Loop => time.rem_euclid(duration),
+            PlayMode::PingPong => {
+                let t = time.rem_euclid(duration * 2.0);
+                if t > duration {
+                    duration * 2.0 - t
+                } else {
+                    t
+                }
+            }
+        }
+    }
+}
+
+/// The keys either side of `time` and how far between them it is.
+/// Times outside the keys hold the first or last one. `times` can't
+/// be empty.
+pub(crate) fn segment(times: &[f32], time: f32) -> (usize, usize, f32) {
+    let last = times.len() - 1;
+    let next = match times.iter().position(|&t| t > time) {
+        Some(0) => return (0, 0, 0.0),
+        Some(next) => next,
+        None => return (last, last, 0.0),
+    };
+    let (t0, t1) = (times[next - 1], times[next]);
+    (next - 1, next, (time - t0) / (t1 - t0))
+}
+
+/// A cubic Hermite curve from `p0` to `p1`. The tangents are scaled to
+/// the length of the segment.
+pub(crate) fn hermite<T: Animatable>(p0: T, m0: T, p1: T, m1: T, t: f32) -> T {
+    let (t2, t3) = (t * t, t * t * t);
+    (p0 * (2.0 * t3 - 3.0 * t2 + 1.0)
+        + m0 * (t3 - 2.0 * t2 + t)
+        + p1 * (-2.0 * t3 + 3.0 * t2)
+        + m1 * (t3 - t2))
+        .finish()
+}
+
+/**
+ * Keyframes for one value, starting at time 0. Build one with
+ * [Track::key]:
+ *
+ * ```ignore
+ * let bob = Track::new(Interpolation::CubicSpline, PlayMode::PingPong)
+ *     .key(0.0, 0.0)
+ *     .key(1.0, 0.5);
+ * ```
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track<T> {
+    pub interpolation: Interpolation,
+    pub mode: PlayMode,
+    times: Vec<f32>,
+    values: Vec<T>,
+}
+
+impl<T: Animatable> Track<T> {
+    pub fn new(interpolation: Interpolation, mode: PlayMode) -> Self {
+        Self {
+            interpolation,
+            mode,
+            times: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    /// Adds a key, replacing any key already at `time`. Keys can be
+    /// added in any order.
+    pub fn key(mut self, time: f32, value: T) -> Self {
+        match self.times.iter().position(|&t| t >= time) {
+            Some(i) if self.times[i] == time => self.values[i] = value,
+            Some(i) => {
+                self.times.insert(i, time);
+                self.values.insert(i, value);
+            }
+            None => {
+                self.times.push(time);
+                self.values.push(value);
+            }
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.times.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty()
+    }
+
+    /// When the last key is
+    pub fn duration(&self) -> f32 {
+        self.times.last().copied().unwrap_or(0.0)
+    }
+
+    /// The value at `time`, after [Track::mode] has been applied.
+    /// Empty tracks have no value.
+    pub fn sample(&self, time: f32) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let time = self.mode.wrap(time, self.duration());
+        let (a, b, t) = segment(&self.times, time);
+        Some(match self.interpolation {
+            Interpolation::Step => self.values[a],
+            Interpolation::Linear => self.values[a].lerp(self.values[b], t),
+            Interpolation::CubicSpline if a == b => self.values[a],
+            Interpolation::CubicSpline => {
+                let dt = self.times[b] - self.times[a];
+                let p0 = self.values[a];
+                let p1 = self.values[b].align(p0);
+                hermite(
+                    p0,
+                    self.tangent(a, p0) * dt,
+                    p1,
+                    self.tangent(b, p1) * dt,
+                    t,
+                )
+            }
+        })
+    }
+
+    /// The Catmull-Rom tangent at key `i`, one sided at the ends. `at`
+    /// is the key's value as the curve sees it, and its neighbours are
+    /// aligned with it first.
+    fn tangent(&self, i: usize, at: T) -> T {
+        let before = i.saturating_sub(1);
+        let after = (i + 1).min(self.len() - 1);
+        let dt = self.times[after] - self.times[before];
+        if dt <= 0.0 {
+            return at * 0.0;
+        }
+        (self.values[after].align(at) - self.values[before].align(at)) * (1.0 / dt)
+    }
+}
+
+impl Track<Quaternion<f32>> {
+    /// A looping turn about `axis`, starting from `from` and taking
+    /// `period` seconds to go all the way round
+    pub fn spin(axis: Vector3<f32>, from: Quaternion<f32>, period: f32) -> Self {
+        let steps = 8;
+        (0..=steps).fold(
+            Self::new(Interpolation::Linear, PlayMode::Loop),
+            |track, step| {
+                let turn = step as f32 / steps as f32;
+                let rotation = Quaternion::from_axis_angle(axis, Deg(360.0 * turn));
+                track.key(period * turn, rotation * from)
+            },
+        )
+    }
+}
+
+/// Instance types an [InstanceAnimator] can pose, so demos with their
+/// own instance structs can animate them too. Types without a scale
+/// can leave [Posable::set_scale] alone.
+pub trait Posable {
+    fn set_position(&mut self, position: Vector3<f32>);
+    fn set_rotation(&mut self, rotation: Quaternion<f32>);
+    fn set_scale(&mut self, _scale: Vector3<f32>) {}
+}
+
+impl Posable for Instance {
+    fn set_position(&mut self, position: Vector3<f32>) {
+        self.position = position;
+    }
+
+    fn set_rotation(&mut self, rotation: Quaternion<f32>) {
+        self.rotation = rotation;
+    }
+
+    fn set_scale(&mut self, scale: Vector3<f32>) {
+        self.scale = scale;
+    }
+}
+
+/// Tracks for the parts of an instance. Parts without a track are
+/// left alone.
+#[derive(Debug, Clone)]
+pub struct TransformTrack {
+    pub position: Option<Track<Vector3<f32>>>,
+    pub rotation: Option<Track<Quaternion<f32>>>,
+    pub scale: Option<Track<Vector3<f32>>>,
+}
+
+impl TransformTrack {
+    pub fn new() -> Self {
+        Self {
+            position: None,
+            rotation: None,
+            scale: None,
+        }
+    }
+
+    pub fn position(mut self, track: Track<Vector3<f32>>) -> Self {
+        self.position = Some(track);
+        self
+    }
+
+    pub fn rotation(mut self, track: Track<Quaternion<f32>>) -> Self {
+        self.rotation = Some(track);
+        self
+    }
+
+    pub fn scale(mut self, track: Track<Vector3<f32>>) -> Self {
+        self.scale = Some(track);
+        self
+    }
+
+    pub fn apply(&self, time: f32, instance: &mut impl Posable) {
+        if let Some(position) = self.position.as_ref().and_then(|t| t.sample(time)) {
+            instance.set_position(position);
+        }
+        if let Some(rotation) = self.rotation.as_ref().and_then(|t| t.sample(time)) {
+            instance.set_rotation(rotation);
+        }
+        if let Some(scale) = self.scale.as_ref().and_then(|t| t.sample(time)) {
+            instance.set_scale(scale);
+        }
+    }
+}
+
+impl Default for TransformTrack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tracks for a [Light]. Parts without a track keep their current value.
+#[derive(Debug, Clone, Default)]
+pub struct LightTrack {
+    pub position: Option<Track<Vector3<f32>>>,
+    pub color: Option<Track<Vector3<f32>>>,
+}
+
+impl LightTrack {
+    /// Moves `light` to where the tracks have it at `time`
+    pub fn apply(&self, time: f32, queue: &wgpu::Queue, light: &mut Light) {
+        let position = self.position.as_ref().and_then(|t| t.sample(time));
+        let color = self.color.as_ref().and_then(|t| t.sample(time));
+        if position.is_none() && color.is_none() {
+            return;
+        }
+        let position = position.unwrap_or_else(|| light.position());
+        let color = color.unwrap_or_else(|| light.color());
+        light.set(queue, position, color);
+    }
+}
+
+/**
+ * Plays [TransformTrack]s on instances and keeps their buffer up to
+ * date. Call [InstanceAnimator::update] once a frame.
+ */
+#[derive(Debug, Clone)]
+pub struct InstanceAnimator {
+    pub time: f32,
+    pub speed: f32,
+    pub paused: bool,
+    tracks: Vec<(usize, TransformTrack)>,
+}
+
+impl InstanceAnimator {
+    pub fn new() -> Self {
+        Self {
+            time: 0.0,
+            speed: 1.0,
+            paused: false,
+            tracks: Vec::new(),
+        }
+    }
+
+    /// Animates the instance at `index`. Each instance should only get
+    /// one track, as later tracks overwrite earlier ones.
+    pub fn add(&mut self, index: usize, track: TransformTrack) -> &mut Self {
+        self.tracks.push((index, track));
+        self
+    }
+
+    pub fn advance(&mut self, dt: f32) {
+        if !self.paused {
+            self.time += dt * self.speed;
+        }
+    }
+
+    /// Poses the animated instances and returns which of them changed,
+    /// or `None` if there aren't any
+    pub fn apply<I: Posable>(&self, instances: &mut [I]) -> Option<Range<usize>> {
+        let mut changed: Option<Range<usize>> = None;
+        for (index, track) in &self.tracks {
+            let instance = match instances.get_mut(*index) {
+                Some(instance) => instance,
+                None => continue,
+            };
+            track.apply(self.time, instance);
+            changed = Some(match changed {
+                Some(range) => range.start.min(*index)..range.end.max(index + 1),
+                None => *index..index + 1,
+            });
+        }
+        changed
+    }
+
+    /// Advances, poses and uploads just the instances that moved.
+    /// `buffer` needs [wgpu::BufferUsage::COPY_DST].
+    pub fn update(
+        &mut self,
+        dt: f32,
+        queue: &wgpu::Queue,
+        buffer: &mut Buffer<Instance, InstanceRaw>,
+    ) {
+        self.advance(dt);
+        if let Some(range) = self.apply(&mut buffer.data) {
+            buffer.update_range(queue, range);
+        }
+    }
+
+    /**
+     * Like [InstanceAnimator::update], for instances that are uploaded
+     * to a plain `buffer` of whatever `to_raw` turns them into. The
+     * tutorials keep their instances like this.
+     */
+    pub fn update_raw<I: Posable, R: bytemuck::Pod>(
+        &mut self,
+        dt: f32,
+        queue: &wgpu::Queue,
+        buffer: &wgpu::Buffer,
+        instances: &mut [I],
+        to_raw: impl Fn(&I) -> R,
+    ) {
+        self.advance(dt);
+        if let Some(range) = self.apply(instances) {
+            let offset = (range.start * mem::size_of::<R>()) as wgpu::BufferAddress;
+            let raw = instances[range].iter().map(to_raw).collect::<Vec<_>>();
+            queue.write_buffer(buffer, offset, bytemuck::cast_slice(&raw));
+        }
+    }
+}
+
+impl Default for InstanceAnimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linear() -> Track<f32> {
+        Track::new(Interpolation::Linear, PlayMode::Once)
+            .key(0.0, 0.0)
+            .key(1.0, 10.0)
+            .key(3.0, 20.0)
+    }
+
+    #[test]
+    fn empty_and_single_key_tracks() {
+        let empty = Track::<f32>::new(Interpolation::Linear, PlayMode::Loop);
+        assert_eq!(empty.sample(1.0), None);
+        assert_eq!(empty.duration(), 0.0);
+
+        for &interpolation in &[
+            Interpolation::Step,
+            Interpolation::Linear,
+            Interpolation::CubicSpline,
+        ] {
+            for &mode in &[PlayMode::Once, PlayMode::Loop, PlayMode::PingPong] {
+                let single = Track::new(interpolation, mode).key(2.0, 5.0);
+                assert_eq!(single.sample(-1.0), Some(5.0));
+                assert_eq!(single.sample(2.0), Some(5.0));
+                assert_eq!(single.sample(100.0), Some(5.0));
+            }
+        }
+    }
+
+    #[test]
+    fn keys_stay_sorted() {
+        let track = Track::new(Interpolation::Step, PlayMode::Once)
+            .key(2.0, 2.0)
+            .key(0.0, 0.0)
+            .key(1.0, 1.0)
+            .key(1.0, 7.0);
+        assert_eq!(track.len(), 3);
+        assert_eq!(track.sample(1.5), Some(7.0));
+        assert_eq!(track.duration(), 2.0);
+    }
+
+    #[test]
+    fn step_and_linear() {
+        let track = linear();
+        assert_eq!(track.sample(0.5), Some(5.0));
+        assert_eq!(track.sample(1.0), Some(10.0));
+        assert_eq!(track.sample(2.0), Some(15.0));
+        assert_eq!(track.sample(-5.0), Some(0.0));
+        assert_eq!(track.sample(5.0), Some(20.0));
+
+        let step = Track {
+            interpolation: Interpolation::Step,
+            ..linear()
+        };
+        assert_eq!(step.sample(0.999), Some(0.0));
+        // Landing exactly on a key jumps to it
+        assert_eq!(step.sample(1.0), Some(10.0));
+        assert_eq!(step.sample(2.999), Some(10.0));
+        assert_eq!(step.sample(3.0), Some(20.0));
+    }
+
+    #[test]
+    fn cubic() {
+        let cubic = Track {
+            interpolation: Interpolation::CubicSpline,
+            ..linear()
+        };
+        // Passes through every key
+        assert_eq!(cubic.sample(0.0), Some(0.0));
+        assert!((cubic.sample(1.0).unwrap() - 10.0).abs() < 1e-5);
+        assert!((cubic.sample(3.0).unwrap() - 20.0).abs() < 1e-5);
+
+        // Evenly spaced keys on a line stay on the line
+        let straight = Track::new(Interpolation::CubicSpline, PlayMode::Once)
+            .key(0.0, Vector3::new(0.0, 0.0, 0.0))
+            .key(1.0, Vector3::new(1.0, 2.0, 0.0))
+            .key(2.0, Vector3::new(2.0, 4.0, 0.0));
+        let p = straight.sample(1.25).unwrap();
+        assert!((p - Vector3::new(1.25, 2.5, 0.0)).magnitude() < 1e-5);
+
+        // Rotations come out unit length
+        let spin = Track::new(Interpolation::CubicSpline, PlayMode::Loop)
+            .key(0.0, Quaternion::one())
+            .key(1.0, Quaternion::from_angle_y(Deg(90.0)))
+            .key(2.0, Quaternion::from_angle_y(Deg(180.0)));
+        let q = spin.sample(0.3).unwrap();
+        assert!((q.magnitude() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn rotations_take_the_short_way() {
+        let track = Track::new(Interpolation::Linear, PlayMode::Once)
+            .key(0.0, Quaternion::from_angle_y(Deg(-20.0)))
+            .key(1.0, -Quaternion::from_angle_y(Deg(20.0)));
+        let halfway = track.sample(0.5).unwrap();
+        assert!((halfway.s.abs() - 1.0).abs() < 1e-5);
+
+        // Keys stored with flipped signs still turn a steady 10 degrees
+        // a second
+        let cubic = Track::new(Interpolation::CubicSpline, PlayMode::Once)
+            .key(0.0, Quaternion::from_angle_y(Deg(0.0)))
+            .key(1.0, -Quaternion::from_angle_y(Deg(10.0)))
+            .key(2.0, Quaternion::from_angle_y(Deg(20.0)))
+            .key(3.0, -Quaternion::from_angle_y(Deg(30.0)));
+        for &(time, degrees) in &[(0.5, 5.0), (1.5, 15.0), (2.5, 25.0)] {
+            let q = cubic.sample(time).unwrap();
+            let expected = Quaternion::from_angle_y(Deg(degrees));
+            assert!(q.dot(expected).abs() > 0.9999, "{:?} at {}", q, time);
+        }
+    }
+
+    #[test]
+    fn play_modes() {
+        assert_eq!(PlayMode::Once.wrap(-1.0, 2.0), 0.0);
+        assert_eq!(PlayMode::Once.wrap(3.0, 2.0), 2.0);
+        assert_eq!(PlayMode::Loop.wrap(2.5, 2.0), 0.5);
+        assert_eq!(PlayMode::Loop.wrap(-0.5, 2.0), 1.5);
+        assert_eq!(PlayMode::Loop.wrap(2.0, 2.0), 0.0);
+        assert_eq!(PlayMode::PingPong.wrap(1.5, 2.0), 1.5);
+        assert_eq!(PlayMode::PingPong.wrap(2.5, 2.0), 1.5);
+        assert_eq!(PlayMode::PingPong.wrap(4.5, 2.0), 0.5);
+        assert_eq!(PlayMode::PingPong.wrap(-0.5, 2.0), 0.5);
+        for &mode in &[PlayMode::Once, PlayMode::Loop, PlayMode::PingPong] {
+            assert_eq!(mode.wrap(5.0, 0.0), 0.0);
+        }
+
+        let bounce = Track {
+            mode: PlayMode::PingPong,
+            ..linear()
+        };
+        assert_eq!(bounce.sample(4.0), Some(15.0));
+        assert_eq!(bounce.sample(6.0), Some(0.0));
+    }
+
+    #[test]
+    fn animator_only_touches_animated_instances() {
+        let still = Instance::new(Vector3::zero(), Quaternion::one());
+        let mut instances = vec![still; 5];
+        let mut animator = InstanceAnimator::new();
+        let slide = Track::new(Interpolation::Linear, PlayMode::Loop)
+            .key(0.0, Vector3::zero())
+            .key(2.0, Vector3::new(4.0, 0.0, 0.0));
+        animator
+            .add(1, TransformTrack::new().position(slide.clone()))
+            .add(3, TransformTrack::new().position(slide))
+            // Out of range instances are skipped
+            .add(10, TransformTrack::new());
+
+        animator.advance(0.5);
+        assert_eq!(animator.apply(&mut instances), Some(1..4));
+        assert_eq!(instances[1].position, Vector3::new(1.0, 0.0, 0.0));
+        assert_eq!(instances[3].position, Vector3::new(1.0, 0.0, 0.0));
+        assert_eq!(instances[2].position, still.position);
+        assert_eq!(instances[1].rotation, still.rotation);
+
+        animator.paused = true;
+        animator.advance(1.0);
+        assert_eq!(animator.time, 0.5);
+        assert_eq!(InstanceAnimator::new().apply(&mut instances), None);
+    }
+
+    #[test]
+    fn spin_turns_all_the_way_round() {
+        let from = Quaternion::from_angle_x(Deg(45.0));
+        let spin = Track::spin(Vector3::unit_y(), from, 4.0);
+        assert_eq!(spin.duration(), 4.0);
+        for &(time, degrees) in &[(0.0, 0.0), (1.0, 90.0), (2.0, 180.0), (5.0, 90.0)] {
+            let expected = Quaternion::from_angle_y(Deg(degrees)) * from;
+            let q = spin.sample(time).unwrap();
+            assert!(q.dot(expected).abs() > 0.9999, "{:?} at {}", q, time);
+        }
+    }
+}
@@ -0,0 +1,20 @@
+#version 450
+
+layout(location=0) in vec2 v_ndc;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_input;
+layout(set=0, binding=1) uniform sampler s_input;
+layout(set=0, binding=2)
+uniform Params {
+    float u_intensity;
+};
+layout(set=0, binding=3) uniform texture2D t_bloom;
+
+void main() {
+    vec2 uv = vec2(v_ndc.x, -v_ndc.y) * 0.5 + 0.5;
+    vec4 scene = texture(sampler2D(t_input, s_input), uv);
+    vec3 bloom = texture(sampler2D(t_bloom, s_input), uv).rgb;
+    f_color = vec4(scene.rgb + bloom * u_intensity, scene.a);
+}
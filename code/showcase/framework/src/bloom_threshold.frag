@@ -0,0 +1,27 @@
+#version 450
+
+layout(location=0) in vec2 v_ndc;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_input;
+layout(set=0, binding=1) uniform sampler s_input;
+layout(set=0, binding=2)
+uniform Params {
+    float u_threshold;
+    // How far below the threshold the cutoff starts fading in
+    float u_knee;
+};
+
+void main() {
+    vec2 uv = vec2(v_ndc.x, -v_ndc.y) * 0.5 + 0.5;
+    vec3 color = texture(sampler2D(t_input, s_input), uv).rgb;
+
+    // Soft threshold so bright spots don't pop in and out
+    float brightness = max(color.r, max(color.g, color.b));
+    float soft = clamp(brightness - u_threshold + u_knee, 0.0, 2.0 * u_knee);
+    soft = soft * soft / (4.0 * u_knee + 0.00001);
+    float contribution = max(soft, brightness - u_threshold) / max(brightness, 0.00001);
+
+    f_color = vec4(color * contribution, 1.0);
+}
@@ -0,0 +1,31 @@
+#version 450
+
+layout(location=0) in vec2 v_ndc;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_input;
+layout(set=0, binding=1) uniform sampler s_input;
+layout(set=0, binding=2)
+uniform Params {
+    // (1, 0) for horizontal and (0, 1) for vertical
+    vec2 u_direction;
+};
+
+// 9 tap gaussian folded into 5 taps using linear filtering
+const float OFFSETS[3] = float[](0.0, 1.3846153846, 3.2307692308);
+const float WEIGHTS[3] = float[](0.2270270270, 0.3162162162, 0.0702702703);
+
+void main() {
+    vec2 uv = vec2(v_ndc.x, -v_ndc.y) * 0.5 + 0.5;
+    vec2 texel = u_direction / vec2(textureSize(sampler2D(t_input, s_input), 0));
+
+    vec3 color = texture(sampler2D(t_input, s_input), uv).rgb * WEIGHTS[0];
+    for (int i = 1; i < 3; i++) {
+        vec2 offset = texel * OFFSETS[i];
+        color += texture(sampler2D(t_input, s_input), uv + offset).rgb * WEIGHTS[i];
+        color += texture(sampler2D(t_input, s_input), uv - offset).rgb * WEIGHTS[i];
+    }
+
+    f_color = vec4(color, 1.0);
+}
@@ -56,7 +56,9 @@ impl<U: ToRaw<Output = R>, R: Copy + bytemuck::Pod + bytemuck::Zeroable> Buffer<
     }
 
     pub fn storage(device: &wgpu::Device, data: Vec<U>) -> Self {
-        let usage = wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST;
+        // COPY_SRC so results can be read back with [crate::read_buffer]
+        let usage =
+            wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::COPY_SRC;
         Self::with_usage(device, data, usage)
     }
 
@@ -80,6 +82,33 @@ impl<U: ToRaw<Output = R>, R: Copy + bytemuck::Pod + bytemuck::Zeroable> Buffer<
         Self::from_parts(data, raw_buffer, usage)
     }
 
+    /// For instances and other per vertex data that changes. The
+    /// buffer can be updated with [Buffer::update].
+    pub fn vertex(device: &wgpu::Device, data: Vec<U>) -> Self {
+        let usage = wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST;
+        Self::with_usage(device, data, usage)
+    }
+
+    /// Uploads everything in [Buffer::data]. The buffer needs
+    /// [wgpu::BufferUsage::COPY_DST].
+    pub fn update(&mut self, queue: &wgpu::Queue) {
+        self.update_range(queue, 0..self.data.len());
+    }
+
+    /// Uploads just `range` of [Buffer::data]
+    pub fn update_range(&mut self, queue: &wgpu::Queue, range: std::ops::Range<usize>) {
+        let raw = &mut self.raw_buffer.data;
+        for (out, datum) in raw[range.clone()].iter_mut().zip(&self.data[range.clone()]) {
+            *out = datum.to_raw();
+        }
+        let offset = (range.start * mem::size_of::<R>()) as wgpu::BufferAddress;
+        queue.write_buffer(
+            &self.raw_buffer.buffer,
+            offset,
+            bytemuck::cast_slice(&raw[range]),
+        );
+    }
+
     pub fn from_parts(data: Vec<U>, raw_buffer: RawBuffer<R>, usage: wgpu::BufferUsage) -> Self {
         Self {
             data,
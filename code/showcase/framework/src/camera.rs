@@ -29,8 +29,8 @@ pub fn camera_setup<V: Into<Point3<f32>>, Y: Into<Rad<f32>>, P: Into<Rad<f32>>>(
 #[derive(Debug)]
 pub struct Camera {
     pub position: Point3<f32>,
-    yaw: Rad<f32>,
-    pitch: Rad<f32>,
+    pub yaw: Rad<f32>,
+    pub pitch: Rad<f32>,
 }
 
 impl Camera {
@@ -57,9 +57,9 @@ impl Camera {
 
 pub struct Projection {
     aspect: f32,
-    fovy: Rad<f32>,
-    znear: f32,
-    zfar: f32,
+    pub fovy: Rad<f32>,
+    pub znear: f32,
+    pub zfar: f32,
 }
 
 impl Projection {
@@ -81,6 +81,57 @@ impl Projection {
     }
 }
 
+/**
+ * The six planes of a view volume, pointing inwards. Build it from the
+ * same matrix that goes into [crate::Uniforms] to cull things that
+ * can't be seen.
+ */
+#[derive(Debug, Copy, Clone)]
+pub struct Frustum {
+    /// Left, right, bottom, top, near and far. xyz is the normal and w
+    /// the distance, so a point is inside when `dot(plane, p.extend(1))`
+    /// is positive for every plane.
+    pub planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    pub fn from_matrix(view_proj: Matrix4<f32>) -> Self {
+        let row = |i: usize| view_proj.row(i);
+        let normalize = |p: Vector4<f32>| p / p.truncate().magnitude();
+        Self {
+            planes: [
+                normalize(row(3) + row(0)),
+                normalize(row(3) - row(0)),
+                normalize(row(3) + row(1)),
+                normalize(row(3) - row(1)),
+                // wgpu's clip space is 0 to 1 in z
+                normalize(row(2)),
+                normalize(row(3) - row(2)),
+            ],
+        }
+    }
+
+    pub fn contains_sphere(&self, center: Point3<f32>, radius: f32) -> bool {
+        self.planes
+            .iter()
+            .all(|p| p.truncate().dot(center.to_vec()) + p.w >= -radius)
+    }
+
+    /// Whether any part of the box might be inside. Boxes near the
+    /// frustum's corners can pass without being visible.
+    pub fn intersects_aabb(&self, min: Point3<f32>, max: Point3<f32>) -> bool {
+        self.planes.iter().all(|p| {
+            // The corner furthest along the plane's normal
+            let corner = Vector3::new(
+                if p.x >= 0.0 { max.x } else { min.x },
+                if p.y >= 0.0 { max.y } else { min.y },
+                if p.z >= 0.0 { max.z } else { min.z },
+            );
+            p.truncate().dot(corner) + p.w >= 0.0
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct CameraController {
     amount_left: f32,
@@ -202,3 +253,31 @@ impl CameraController {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frustum_culling() {
+        let camera = Camera::new((0.0, 0.0, 0.0), Deg(0.0), Deg(0.0));
+        let projection = Projection::new(100, 100, Deg(90.0), 0.1, 100.0);
+        let frustum = Frustum::from_matrix(projection.calc_matrix() * camera.calc_matrix());
+
+        // A yaw of zero looks down +x
+        assert!(frustum.contains_sphere(Point3::new(10.0, 0.0, 0.0), 1.0));
+        assert!(!frustum.contains_sphere(Point3::new(-10.0, 0.0, 0.0), 1.0));
+        assert!(!frustum.contains_sphere(Point3::new(200.0, 0.0, 0.0), 1.0));
+        // Just outside the 45 degree side plane, but close enough to touch
+        assert!(frustum.contains_sphere(Point3::new(10.0, 0.0, 11.0), 1.0));
+        assert!(!frustum.contains_sphere(Point3::new(10.0, 0.0, 12.0), 1.0));
+
+        let aabb = |x: f32, z: f32| {
+            frustum.intersects_aabb(Point3::new(x, -1.0, z), Point3::new(x + 2.0, 1.0, z + 2.0))
+        };
+        assert!(aabb(5.0, -1.0));
+        assert!(!aabb(-5.0, -1.0));
+        assert!(aabb(5.0, 6.0));
+        assert!(!aabb(5.0, 8.0));
+    }
+}
@@ -0,0 +1,428 @@
+use anyhow::*;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::postprocess::FullscreenPass;
+use crate::texture::Texture;
+use crate::Display;
+
+/// The largest side a supersampled screenshot can have
+pub const MAX_CAPTURE_SIZE: u32 = 8192;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ScreenshotNaming {
+    /// Milliseconds since the unix epoch
+    Timestamp,
+    /// How many frames [Display::end_frame] has seen
+    FrameNumber,
+}
+
+/**
+ * Where [Display] saves screenshots to and what it calls them. Files
+ * end up in `directory` as `{prefix}-{timestamp or frame}.png`.
+ */
+pub struct Screenshots {
+    pub directory: PathBuf,
+    pub prefix: String,
+    pub naming: ScreenshotNaming,
+    requested: Option<CaptureRequest>,
+    captured: Option<image::RgbaImage>,
+    blit: Option<FullscreenPass>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct CaptureRequest {
+    scale: u32,
+    /// Keep the frame for [Display::take_captured_frame] instead of
+    /// saving it
+    in_memory: bool,
+}
+
+impl Default for Screenshots {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("screenshots"),
+            prefix: "screenshot".to_string(),
+            naming: ScreenshotNaming::Timestamp,
+            requested: None,
+            captured: None,
+            blit: None,
+        }
+    }
+}
+
+impl Screenshots {
+    pub fn file_name(&self, frame_number: u64) -> PathBuf {
+        let suffix = match self.naming {
+            ScreenshotNaming::Timestamp => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or(0)
+                .to_string(),
+            ScreenshotNaming::FrameNumber => format!("{:06}", frame_number),
+        };
+        self.directory
+            .join(format!("{}-{}.png", self.prefix, suffix))
+    }
+
+    /// Whether the next [Display::begin_frame] will be captured
+    pub fn is_pending(&self) -> bool {
+        self.requested.is_some()
+    }
+}
+
+/**
+ * A frame from [Display::begin_frame]. Draw into [Frame::view] rather
+ * than the swap chain's view directly so that the frame can be
+ * captured, then hand it back with [Display::end_frame].
+ */
+pub struct Frame {
+    pub output: wgpu::SwapChainFrame,
+    capture: Option<Texture<'static>>,
+    scale: u32,
+    width: u32,
+    height: u32,
+    in_memory: bool,
+}
+
+impl Frame {
+    pub fn view(&self) -> &wgpu::TextureView {
+        match &self.capture {
+            Some(capture) => &capture.view,
+            None => &self.output.output.view,
+        }
+    }
+
+    /// How many times bigger than the window this frame is. This is
+    /// only ever more than 1 for supersampled screenshots, in which
+    /// case [Display::color_attachment] and [Display::depth_attachment]
+    /// are temporarily resized to match.
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    /// The size every attachment drawn alongside [Frame::view] needs.
+    /// Only the [Display]'s own targets follow it, so demos with their
+    /// own depth buffers or offscreen targets, such as
+    /// [crate::WeightedBlendedOit], have to resize them when this
+    /// changes.
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+}
+
+impl Display {
+    /// Saves the next frame drawn between [Display::begin_frame] and
+    /// [Display::end_frame] as a PNG
+    pub fn capture_next_frame(&mut self) {
+        self.capture_next_frame_supersampled(1);
+    }
+
+    /// Like [Display::capture_next_frame] but renders the frame at
+    /// `scale` times the window's resolution. The size is clamped to
+    /// [MAX_CAPTURE_SIZE]. Demos that draw with attachments of their
+    /// own have to size them to [Frame::size] for this to work.
+    pub fn capture_next_frame_supersampled(&mut self, scale: u32) {
+        let largest = self.sc_desc.width.max(self.sc_desc.height).max(1);
+        let scale = scale.max(1).min((MAX_CAPTURE_SIZE / largest).max(1));
+        self.screenshots.requested = Some(CaptureRequest {
+            scale,
+            in_memory: false,
+        });
+    }
+
+    /// Captures the next frame like [Display::capture_next_frame] but
+    /// keeps it around for [Display::take_captured_frame] rather than
+    /// writing it to disk
+    pub fn capture_next_frame_in_memory(&mut self) {
+        self.screenshots.requested = Some(CaptureRequest {
+            scale: 1,
+            in_memory: true,
+        });
+    }
+
+    /// The frame grabbed by [Display::capture_next_frame_in_memory],
+    /// if [Display::end_frame] has seen it yet
+    pub fn take_captured_frame(&mut self) -> Option<image::RgbaImage> {
+        self.screenshots.captured.take()
+    }
+
+    /// How many times bigger than the window the next frame from
+    /// [Display::begin_frame] will be, 1 unless a supersampled
+    /// screenshot is pending
+    pub fn capture_scale(&self) -> u32 {
+        self.screenshots.requested.map_or(1, |r| r.scale)
+    }
+
+    /// How many frames have been through [Display::end_frame]
+    pub fn frame_number(&self) -> u64 {
+        self.frame_number
+    }
+
+    pub fn begin_frame(&mut self) -> Result<Frame> {
+        let output = self.swap_chain.get_current_frame()?;
+        let request = match self.screenshots.requested.take() {
+            Some(request) => request,
+            None => {
+                return Ok(Frame {
+                    output,
+                    capture: None,
+                    scale: 1,
+                    width: self.sc_desc.width,
+                    height: self.sc_desc.height,
+                    in_memory: false,
+                })
+            }
+        };
+        let scale = request.scale;
+
+        // The swap chain can only be rendered to, so we draw into a
+        // texture we can copy from and blit that to the screen after.
+        let mut desc = self.sc_desc.clone();
+        desc.width *= scale;
+        desc.height *= scale;
+        if scale != 1 {
+            self.create_targets(&desc);
+        }
+        let capture = Texture::from_descriptor(
+            &self.device,
+            wgpu::TextureDescriptor {
+                label: Some("Display::capture"),
+                size: wgpu::Extent3d {
+                    width: desc.width,
+                    height: desc.height,
+                    depth: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: desc.format,
+                usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT
+                    | wgpu::TextureUsage::SAMPLED
+                    | wgpu::TextureUsage::COPY_SRC,
+            },
+        );
+
+        Ok(Frame {
+            output,
+            capture: Some(capture),
+            scale,
+            width: desc.width,
+            height: desc.height,
+            in_memory: request.in_memory,
+        })
+    }
+
+    /// Presents `frame`, saving it first if it was captured. Returns
+    /// where the screenshot was saved to. Frames captured with
+    /// [Display::capture_next_frame_in_memory] aren't saved and are
+    /// left for [Display::take_captured_frame] instead.
+    pub fn end_frame(&mut self, mut frame: Frame) -> Result<Option<PathBuf>> {
+        self.frame_number += 1;
+        if let Some(post_process) = &mut self.post_process {
+            let mut encoder = self
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Display::post_process"),
+                });
+            post_process.present(&self.device, &self.queue, &mut encoder, frame.view());
+            self.queue.submit(std::iter::once(encoder.finish()));
+        }
+        #[cfg(feature = "text")]
+        self.draw_text(&frame)?;
+        let result = match frame.capture.take() {
+            Some(capture) => self.save_capture(&frame, capture),
+            None => Ok(None),
+        };
+        // Presenting happens when the swap chain frame is dropped, so
+        // [crate::run] holds onto it to draw the GUI on top
+        if self.hold_frames {
+            self.held_frame = Some(frame.output);
+        }
+        result
+    }
+
+    fn save_capture(
+        &mut self,
+        frame: &Frame,
+        capture: Texture<'static>,
+    ) -> Result<Option<PathBuf>> {
+        if frame.scale != 1 {
+            self.recreate_targets();
+        }
+
+        if self.screenshots.blit.is_none() {
+            self.screenshots.blit = Some(FullscreenPass::new(
+                &self.device,
+                "Display::capture_blit",
+                wgpu::include_spirv!("blit.frag.spv"),
+                self.sc_desc.format,
+                false,
+                false,
+            )?);
+        }
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Display::end_frame"),
+            });
+        if let Some(blit) = &mut self.screenshots.blit {
+            blit.draw(
+                &self.device,
+                &mut encoder,
+                &capture.view,
+                None,
+                None,
+                &frame.output.output.view,
+            );
+            // Every capture gets a new texture
+            blit.invalidate();
+        }
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        let image = read_image(&self.device, &self.queue, &capture)?;
+        if frame.in_memory {
+            self.screenshots.captured = Some(image);
+            return Ok(None);
+        }
+
+        let path = self.screenshots.file_name(self.frame_number);
+        std::fs::create_dir_all(&self.screenshots.directory)?;
+        image.save(&path)?;
+        log::info!("Saved screenshot to {:?}", path);
+        Ok(Some(path))
+    }
+}
+
+/// Rows copied out of a texture have to be a multiple of
+/// [wgpu::COPY_BYTES_PER_ROW_ALIGNMENT] long
+pub fn padded_bytes_per_row(unpadded: u32) -> u32 {
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    unpadded.div_ceil(align) * align
+}
+
+/// Copies the first mip level of `texture` into memory with the row
+/// padding removed. This blocks until the GPU is done.
+pub fn read_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    size: wgpu::Extent3d,
+    bytes_per_pixel: u32,
+) -> Result<Vec<u8>> {
+    let unpadded = size.width * bytes_per_pixel;
+    let padded = padded_bytes_per_row(unpadded);
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("read_texture::buffer"),
+        size: (padded * size.height) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+        mapped_at_creation: false,
+    });
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("read_texture::encoder"),
+    });
+    encoder.copy_texture_to_buffer(
+        wgpu::TextureCopyView {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        wgpu::BufferCopyView {
+            buffer: &buffer,
+            layout: wgpu::TextureDataLayout {
+                offset: 0,
+                bytes_per_row: padded,
+                rows_per_image: size.height,
+            },
+        },
+        wgpu::Extent3d {
+            width: size.width,
+            height: size.height,
+            depth: 1,
+        },
+    );
+    queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = buffer.slice(..);
+    let request = slice.map_async(wgpu::MapMode::Read);
+    device.poll(wgpu::Maintain::Wait);
+    futures::executor::block_on(request)?;
+    let data = unpad_rows(&slice.get_mapped_range(), padded, unpadded);
+    buffer.unmap();
+    Ok(data)
+}
+
+fn unpad_rows(data: &[u8], padded: u32, unpadded: u32) -> Vec<u8> {
+    data.chunks(padded as usize)
+        .flat_map(|row| &row[..unpadded as usize])
+        .copied()
+        .collect()
+}
+
+/// Reads back an 8 bit RGBA or BGRA texture, such as a copy of a
+/// frame, as an RGBA image
+pub(crate) fn read_image(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &Texture,
+) -> Result<image::RgbaImage> {
+    let size = texture.desc.size;
+    let mut pixels = read_texture(device, queue, &texture.texture, size, 4)?;
+    match texture.desc.format {
+        wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb => {
+            bgra_to_rgba(&mut pixels)
+        }
+        wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Rgba8UnormSrgb => {}
+        format => bail!("Can't read a {:?} texture back as an image", format),
+    }
+    image::RgbaImage::from_raw(size.width, size.height, pixels)
+        .context("Texture was the wrong size")
+}
+
+fn bgra_to_rgba(pixels: &mut [u8]) {
+    for pixel in pixels.chunks_exact_mut(4) {
+        pixel.swap(0, 2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_are_unpadded() {
+        assert_eq!(padded_bytes_per_row(4), 256);
+        assert_eq!(padded_bytes_per_row(256), 256);
+        assert_eq!(padded_bytes_per_row(260), 512);
+
+        let padded = padded_bytes_per_row(8);
+        let mut data = vec![0u8; padded as usize * 2];
+        data[..8].copy_from_slice(&[1; 8]);
+        data[padded as usize..padded as usize + 8].copy_from_slice(&[2; 8]);
+        let rows = unpad_rows(&data, padded, 8);
+        assert_eq!(rows, [[1u8; 8], [2u8; 8]].concat());
+    }
+
+    #[test]
+    fn file_names() {
+        let mut screenshots = Screenshots {
+            naming: ScreenshotNaming::FrameNumber,
+            ..Default::default()
+        };
+        assert_eq!(
+            screenshots.file_name(42),
+            PathBuf::from("screenshots/screenshot-000042.png")
+        );
+        screenshots.naming = ScreenshotNaming::Timestamp;
+        let name = screenshots.file_name(42);
+        let stem = name.file_stem().unwrap().to_str().unwrap();
+        assert!(stem["screenshot-".len()..].parse::<u128>().is_ok());
+    }
+
+    #[test]
+    fn swizzle() {
+        let mut pixels = [1, 2, 3, 4, 5, 6, 7, 8];
+        bgra_to_rgba(&mut pixels);
+        assert_eq!(pixels, [3, 2, 1, 4, 7, 6, 5, 8]);
+    }
+}
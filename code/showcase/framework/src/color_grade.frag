@@ -0,0 +1,40 @@
+#version 450
+
+layout(location=0) in vec2 v_ndc;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_input;
+layout(set=0, binding=1) uniform sampler s_input;
+layout(set=0, binding=2)
+uniform Params {
+    float u_lut_size;
+    float u_strength;
+};
+// A size^3 LUT laid out as size slices of size x size side by
+// side, with blue picking the slice.
+layout(set=0, binding=3) uniform texture2D t_lut;
+
+vec3 sample_slice(vec2 rg, float slice) {
+    float width = u_lut_size * u_lut_size;
+    // Sample texel centers so neighbouring slices don't bleed in
+    vec2 texel = rg * (u_lut_size - 1.0) + 0.5;
+    vec2 uv = vec2((slice * u_lut_size + texel.x) / width, texel.y / u_lut_size);
+    return texture(sampler2D(t_lut, s_input), uv).rgb;
+}
+
+void main() {
+    vec2 uv = vec2(v_ndc.x, -v_ndc.y) * 0.5 + 0.5;
+    vec4 color = texture(sampler2D(t_input, s_input), uv);
+    vec3 c = clamp(color.rgb, 0.0, 1.0);
+
+    float blue = c.b * (u_lut_size - 1.0);
+    float slice = floor(blue);
+    vec3 graded = mix(
+        sample_slice(c.rg, slice),
+        sample_slice(c.rg, min(slice + 1.0, u_lut_size - 1.0)),
+        blue - slice
+    );
+
+    f_color = vec4(mix(color.rgb, graded, u_strength), color.a);
+}
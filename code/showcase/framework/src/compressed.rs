@@ -0,0 +1,898 @@
+use anyhow::*;
+
+/**
+ * Texture data read from a KTX2 or DDS container. Unlike the
+ * formats that `image` understands, these can hold block compressed
+ * data, a precomputed mip chain and multiple array layers (or cube
+ * faces), all of which we want to hand to wgpu untouched.
+ */
+#[derive(Debug, Clone)]
+pub struct ContainerImage {
+    pub format: wgpu::TextureFormat,
+    pub width: u32,
+    pub height: u32,
+    /// Array layers. Cube maps store their six faces as layers.
+    pub layers: u32,
+    /// One entry per mip level. Every level holds all of the
+    /// layers back to back, which is the layout `write_texture`
+    /// expects when copying into a 2d array texture.
+    pub levels: Vec<Vec<u8>>,
+}
+
+const KTX2_MAGIC: [u8; 12] = [
+    0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+const DDS_MAGIC: [u8; 4] = *b"DDS ";
+
+impl ContainerImage {
+    /// Sniffs the magic number to pick the right container parser.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.starts_with(&KTX2_MAGIC) {
+            Self::from_ktx2(bytes)
+        } else if bytes.starts_with(&DDS_MAGIC) {
+            Self::from_dds(bytes)
+        } else {
+            bail!("Unrecognized texture container")
+        }
+    }
+
+    #[cfg(feature = "compressed")]
+    pub fn from_ktx2(bytes: &[u8]) -> Result<Self> {
+        let reader = ktx2::Reader::new(bytes).map_err(|e| anyhow!("Invalid KTX2: {:?}", e))?;
+        let header = reader.header();
+
+        if header.supercompression_scheme.is_some() {
+            bail!(
+                "Supercompressed KTX2 ({:?}) is not supported",
+                header.supercompression_scheme
+            );
+        }
+        if header.pixel_depth > 1 {
+            bail!("3d KTX2 textures are not supported");
+        }
+
+        let format = header
+            .format
+            .context("KTX2 textures without a vkFormat are not supported")?;
+        let format = map_ktx2_format(format)?;
+        let layers = header.layer_count.max(1) * header.face_count.max(1);
+
+        // KTX2 already stores each level as layer-major, face-minor
+        // images, so we can take the levels as they are.
+        let levels = reader.levels().map(|level| level.to_vec()).collect();
+
+        let image = Self {
+            format,
+            width: header.pixel_width,
+            height: header.pixel_height.max(1),
+            layers,
+            levels,
+        };
+        image.validate()?;
+        Ok(image)
+    }
+
+    #[cfg(feature = "compressed")]
+    pub fn from_dds(bytes: &[u8]) -> Result<Self> {
+        let dds = ddsfile::Dds::read(bytes).map_err(|e| anyhow!("Invalid DDS: {}", e))?;
+
+        if dds.get_depth() > 1 {
+            bail!("3d DDS textures are not supported");
+        }
+
+        let format = match (dds.get_dxgi_format(), dds.get_d3d_format()) {
+            (Some(format), _) => map_dxgi_format(format)?,
+            (None, Some(format)) => map_d3d_format(format)?,
+            (None, None) => bail!("DDS file has an unknown pixel format"),
+        };
+        let layers = match &dds.header10 {
+            Some(h10) if h10.misc_flag.contains(ddsfile::MiscFlag::TEXTURECUBE) => {
+                h10.array_size.max(1) * 6
+            }
+            _ => dds.get_num_array_layers().max(1),
+        };
+
+        let mut image = Self {
+            format,
+            width: dds.get_width(),
+            height: dds.get_height(),
+            layers,
+            levels: Vec::new(),
+        };
+
+        // DDS stores each layer with its full mip chain, so we
+        // need to shuffle the data into one buffer per level.
+        let num_levels = dds.get_num_mipmap_levels().max(1);
+        let level_sizes = (0..num_levels)
+            .map(|level| image.layer_size(level))
+            .collect::<Vec<_>>();
+        let layer_stride: usize = level_sizes.iter().sum();
+        if dds.data.len() < layer_stride * layers as usize {
+            bail!("DDS file is truncated");
+        }
+
+        image.levels = level_sizes.iter().map(|_| Vec::new()).collect();
+        for layer in 0..layers as usize {
+            let mut offset = layer * layer_stride;
+            for (level, size) in level_sizes.iter().enumerate() {
+                image.levels[level].extend_from_slice(&dds.data[offset..offset + size]);
+                offset += size;
+            }
+        }
+
+        image.validate()?;
+        Ok(image)
+    }
+
+    #[cfg(not(feature = "compressed"))]
+    pub fn from_ktx2(_bytes: &[u8]) -> Result<Self> {
+        bail!("Reading KTX2 files needs the framework's `compressed` feature")
+    }
+
+    #[cfg(not(feature = "compressed"))]
+    pub fn from_dds(_bytes: &[u8]) -> Result<Self> {
+        bail!("Reading DDS files needs the framework's `compressed` feature")
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        block_dimensions(self.format) != (1, 1)
+    }
+
+    /// The size of the given mip level in texels.
+    pub fn level_extent(&self, level: u32) -> (u32, u32) {
+        ((self.width >> level).max(1), (self.height >> level).max(1))
+    }
+
+    /// The number of bytes a single layer takes up at the given
+    /// mip level. Partial blocks round up to a full block.
+    pub fn layer_size(&self, level: u32) -> usize {
+        let (width, height) = self.level_extent(level);
+        let (blocks_wide, blocks_high) = self.blocks(width, height);
+        (blocks_wide * blocks_high * block_bytes(self.format)) as usize
+    }
+
+    /// Bytes per row of blocks at the given mip level.
+    pub fn bytes_per_row(&self, level: u32) -> u32 {
+        let (width, height) = self.level_extent(level);
+        self.blocks(width, height).0 * block_bytes(self.format)
+    }
+
+    fn blocks(&self, width: u32, height: u32) -> (u32, u32) {
+        let (bw, bh) = block_dimensions(self.format);
+        (width.div_ceil(bw), height.div_ceil(bh))
+    }
+
+    #[cfg(feature = "compressed")]
+    fn validate(&self) -> Result<()> {
+        if self.levels.is_empty() {
+            bail!("Texture has no mip levels");
+        }
+        for (level, data) in self.levels.iter().enumerate() {
+            let expected = self.layer_size(level as u32) * self.layers as usize;
+            if data.len() < expected {
+                bail!(
+                    "Mip level {} has {} bytes, expected {}",
+                    level,
+                    data.len(),
+                    expected
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes BCn data into plain 8 bit RGBA on the CPU for devices
+    /// that don't support `Features::TEXTURE_COMPRESSION_BC`. The
+    /// mip chain and layers are preserved. Uncompressed images are
+    /// returned as is.
+    pub fn decompress(&self) -> Result<Self> {
+        let (decode_block, format): (BlockDecoder, _) = match self.format {
+            wgpu::TextureFormat::Bc1RgbaUnorm => (decode_bc1, wgpu::TextureFormat::Rgba8Unorm),
+            wgpu::TextureFormat::Bc1RgbaUnormSrgb => {
+                (decode_bc1, wgpu::TextureFormat::Rgba8UnormSrgb)
+            }
+            wgpu::TextureFormat::Bc2RgbaUnorm => (decode_bc2, wgpu::TextureFormat::Rgba8Unorm),
+            wgpu::TextureFormat::Bc2RgbaUnormSrgb => {
+                (decode_bc2, wgpu::TextureFormat::Rgba8UnormSrgb)
+            }
+            wgpu::TextureFormat::Bc3RgbaUnorm => (decode_bc3, wgpu::TextureFormat::Rgba8Unorm),
+            wgpu::TextureFormat::Bc3RgbaUnormSrgb => {
+                (decode_bc3, wgpu::TextureFormat::Rgba8UnormSrgb)
+            }
+            wgpu::TextureFormat::Bc4RUnorm => (decode_bc4_unorm, wgpu::TextureFormat::Rgba8Unorm),
+            wgpu::TextureFormat::Bc4RSnorm => (decode_bc4_snorm, wgpu::TextureFormat::Rgba8Snorm),
+            wgpu::TextureFormat::Bc5RgUnorm => (decode_bc5_unorm, wgpu::TextureFormat::Rgba8Unorm),
+            wgpu::TextureFormat::Bc5RgSnorm => (decode_bc5_snorm, wgpu::TextureFormat::Rgba8Snorm),
+            wgpu::TextureFormat::Bc7RgbaUnorm => (decode_bc7, wgpu::TextureFormat::Rgba8Unorm),
+            wgpu::TextureFormat::Bc7RgbaUnormSrgb => {
+                (decode_bc7, wgpu::TextureFormat::Rgba8UnormSrgb)
+            }
+            wgpu::TextureFormat::Bc6hRgbUfloat | wgpu::TextureFormat::Bc6hRgbSfloat => {
+                bail!("CPU decompression of BC6H is not supported")
+            }
+            _ => return Ok(self.clone()),
+        };
+
+        let block_size = block_bytes(self.format) as usize;
+        let mut levels = Vec::with_capacity(self.levels.len());
+        for (level, data) in self.levels.iter().enumerate() {
+            let (width, height) = self.level_extent(level as u32);
+            let (blocks_wide, blocks_high) = self.blocks(width, height);
+            let layer_size = self.layer_size(level as u32);
+            let mut rgba = vec![0u8; (width * height * 4) as usize * self.layers as usize];
+            let mut texels = [[0u8; 4]; 16];
+
+            for layer in 0..self.layers as usize {
+                let src = &data[layer * layer_size..(layer + 1) * layer_size];
+                let dst_offset = layer * (width * height * 4) as usize;
+                for (i, block) in src.chunks_exact(block_size).enumerate() {
+                    decode_block(block, &mut texels);
+                    let bx = (i as u32 % blocks_wide) * 4;
+                    let by = (i as u32 / blocks_wide) * 4;
+                    debug_assert!(by < blocks_high * 4);
+                    for (j, texel) in texels.iter().enumerate() {
+                        let x = bx + j as u32 % 4;
+                        let y = by + j as u32 / 4;
+                        // Blocks on the edge of odd sized levels
+                        // hang over the side of the image.
+                        if x < width && y < height {
+                            let p = dst_offset + ((y * width + x) * 4) as usize;
+                            rgba[p..p + 4].copy_from_slice(texel);
+                        }
+                    }
+                }
+            }
+            levels.push(rgba);
+        }
+
+        Ok(Self {
+            format,
+            width: self.width,
+            height: self.height,
+            layers: self.layers,
+            levels,
+        })
+    }
+}
+
+pub(crate) fn block_dimensions(format: wgpu::TextureFormat) -> (u32, u32) {
+    match format {
+        wgpu::TextureFormat::Bc1RgbaUnorm
+        | wgpu::TextureFormat::Bc1RgbaUnormSrgb
+        | wgpu::TextureFormat::Bc2RgbaUnorm
+        | wgpu::TextureFormat::Bc2RgbaUnormSrgb
+        | wgpu::TextureFormat::Bc3RgbaUnorm
+        | wgpu::TextureFormat::Bc3RgbaUnormSrgb
+        | wgpu::TextureFormat::Bc4RUnorm
+        | wgpu::TextureFormat::Bc4RSnorm
+        | wgpu::TextureFormat::Bc5RgUnorm
+        | wgpu::TextureFormat::Bc5RgSnorm
+        | wgpu::TextureFormat::Bc6hRgbUfloat
+        | wgpu::TextureFormat::Bc6hRgbSfloat
+        | wgpu::TextureFormat::Bc7RgbaUnorm
+        | wgpu::TextureFormat::Bc7RgbaUnormSrgb => (4, 4),
+        _ => (1, 1),
+    }
+}
+
+/// Bytes per block for compressed formats, or per texel for the
+/// handful of uncompressed formats we accept from containers.
+pub(crate) fn block_bytes(format: wgpu::TextureFormat) -> u32 {
+    match format {
+        wgpu::TextureFormat::Bc1RgbaUnorm
+        | wgpu::TextureFormat::Bc1RgbaUnormSrgb
+        | wgpu::TextureFormat::Bc4RUnorm
+        | wgpu::TextureFormat::Bc4RSnorm => 8,
+        wgpu::TextureFormat::Bc2RgbaUnorm
+        | wgpu::TextureFormat::Bc2RgbaUnormSrgb
+        | wgpu::TextureFormat::Bc3RgbaUnorm
+        | wgpu::TextureFormat::Bc3RgbaUnormSrgb
+        | wgpu::TextureFormat::Bc5RgUnorm
+        | wgpu::TextureFormat::Bc5RgSnorm
+        | wgpu::TextureFormat::Bc6hRgbUfloat
+        | wgpu::TextureFormat::Bc6hRgbSfloat
+        | wgpu::TextureFormat::Bc7RgbaUnorm
+        | wgpu::TextureFormat::Bc7RgbaUnormSrgb => 16,
+        wgpu::TextureFormat::Rgba16Float => 8,
+        wgpu::TextureFormat::Rgba32Float => 16,
+        _ => 4,
+    }
+}
+
+#[cfg(feature = "compressed")]
+fn map_ktx2_format(format: ktx2::Format) -> Result<wgpu::TextureFormat> {
+    use ktx2::Format as F;
+    use wgpu::TextureFormat as T;
+
+    Ok(match format {
+        F::BC1_RGB_UNORM_BLOCK | F::BC1_RGBA_UNORM_BLOCK => T::Bc1RgbaUnorm,
+        F::BC1_RGB_SRGB_BLOCK | F::BC1_RGBA_SRGB_BLOCK => T::Bc1RgbaUnormSrgb,
+        F::BC2_UNORM_BLOCK => T::Bc2RgbaUnorm,
+        F::BC2_SRGB_BLOCK => T::Bc2RgbaUnormSrgb,
+        F::BC3_UNORM_BLOCK => T::Bc3RgbaUnorm,
+        F::BC3_SRGB_BLOCK => T::Bc3RgbaUnormSrgb,
+        F::BC4_UNORM_BLOCK => T::Bc4RUnorm,
+        F::BC4_SNORM_BLOCK => T::Bc4RSnorm,
+        F::BC5_UNORM_BLOCK => T::Bc5RgUnorm,
+        F::BC5_SNORM_BLOCK => T::Bc5RgSnorm,
+        F::BC6H_UFLOAT_BLOCK => T::Bc6hRgbUfloat,
+        F::BC6H_SFLOAT_BLOCK => T::Bc6hRgbSfloat,
+        F::BC7_UNORM_BLOCK => T::Bc7RgbaUnorm,
+        F::BC7_SRGB_BLOCK => T::Bc7RgbaUnormSrgb,
+        F::R8G8B8A8_UNORM => T::Rgba8Unorm,
+        F::R8G8B8A8_SRGB => T::Rgba8UnormSrgb,
+        F::R16G16B16A16_SFLOAT => T::Rgba16Float,
+        F::R32G32B32A32_SFLOAT => T::Rgba32Float,
+        _ => bail!("Unsupported KTX2 format: {:?}", format),
+    })
+}
+
+#[cfg(feature = "compressed")]
+fn map_dxgi_format(format: ddsfile::DxgiFormat) -> Result<wgpu::TextureFormat> {
+    use ddsfile::DxgiFormat as F;
+    use wgpu::TextureFormat as T;
+
+    Ok(match format {
+        F::BC1_Typeless | F::BC1_UNorm => T::Bc1RgbaUnorm,
+        F::BC1_UNorm_sRGB => T::Bc1RgbaUnormSrgb,
+        F::BC2_Typeless | F::BC2_UNorm => T::Bc2RgbaUnorm,
+        F::BC2_UNorm_sRGB => T::Bc2RgbaUnormSrgb,
+        F::BC3_Typeless | F::BC3_UNorm => T::Bc3RgbaUnorm,
+        F::BC3_UNorm_sRGB => T::Bc3RgbaUnormSrgb,
+        F::BC4_Typeless | F::BC4_UNorm => T::Bc4RUnorm,
+        F::BC4_SNorm => T::Bc4RSnorm,
+        F::BC5_Typeless | F::BC5_UNorm => T::Bc5RgUnorm,
+        F::BC5_SNorm => T::Bc5RgSnorm,
+        F::BC6H_Typeless | F::BC6H_UF16 => T::Bc6hRgbUfloat,
+        F::BC6H_SF16 => T::Bc6hRgbSfloat,
+        F::BC7_Typeless | F::BC7_UNorm => T::Bc7RgbaUnorm,
+        F::BC7_UNorm_sRGB => T::Bc7RgbaUnormSrgb,
+        F::R8G8B8A8_Typeless | F::R8G8B8A8_UNorm => T::Rgba8Unorm,
+        F::R8G8B8A8_UNorm_sRGB => T::Rgba8UnormSrgb,
+        F::R16G16B16A16_Float => T::Rgba16Float,
+        F::R32G32B32A32_Float => T::Rgba32Float,
+        _ => bail!("Unsupported DDS format: {:?}", format),
+    })
+}
+
+#[cfg(feature = "compressed")]
+fn map_d3d_format(format: ddsfile::D3DFormat) -> Result<wgpu::TextureFormat> {
+    use ddsfile::D3DFormat as F;
+    use wgpu::TextureFormat as T;
+
+    Ok(match format {
+        F::DXT1 => T::Bc1RgbaUnorm,
+        F::DXT2 | F::DXT3 => T::Bc2RgbaUnorm,
+        F::DXT4 | F::DXT5 => T::Bc3RgbaUnorm,
+        F::A8B8G8R8 => T::Rgba8Unorm,
+        F::A16B16G16R16F => T::Rgba16Float,
+        F::A32B32G32R32F => T::Rgba32Float,
+        _ => bail!("Unsupported DDS format: {:?}", format),
+    })
+}
+
+type BlockDecoder = fn(&[u8], &mut [[u8; 4]; 16]);
+
+// The decoders below follow the block layouts described in the
+// Khronos Data Format Specification (chapter "BC1 to BC7").
+
+fn unpack_565(c: u16) -> [u8; 3] {
+    let r = ((c >> 11) & 0x1F) as u8;
+    let g = ((c >> 5) & 0x3F) as u8;
+    let b = (c & 0x1F) as u8;
+    [
+        (r << 3) | (r >> 2),
+        (g << 2) | (g >> 4),
+        (b << 3) | (b >> 2),
+    ]
+}
+
+/// Decodes the color half shared by BC1, BC2 and BC3. BC2 and BC3
+/// always use the four color mode regardless of endpoint order.
+fn decode_color_block(block: &[u8], force_four_colors: bool, out: &mut [[u8; 4]; 16]) {
+    let c0 = u16::from_le_bytes([block[0], block[1]]);
+    let c1 = u16::from_le_bytes([block[2], block[3]]);
+    let indices = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+    let e0 = unpack_565(c0);
+    let e1 = unpack_565(c1);
+
+    let mut palette = [[0u8; 4]; 4];
+    palette[0] = [e0[0], e0[1], e0[2], 255];
+    palette[1] = [e1[0], e1[1], e1[2], 255];
+    for i in 0..3 {
+        let (a, b) = (e0[i] as u32, e1[i] as u32);
+        if c0 > c1 || force_four_colors {
+            palette[2][i] = ((2 * a + b + 1) / 3) as u8;
+            palette[3][i] = ((a + 2 * b + 1) / 3) as u8;
+        } else {
+            palette[2][i] = ((a + b) / 2) as u8;
+            palette[3][i] = 0;
+        }
+    }
+    palette[2][3] = 255;
+    palette[3][3] = if c0 > c1 || force_four_colors { 255 } else { 0 };
+
+    for (i, texel) in out.iter_mut().enumerate() {
+        *texel = palette[((indices >> (2 * i)) & 0x3) as usize];
+    }
+}
+
+/// Decodes an 8 byte BC4 style block into 16 single channel values.
+fn decode_bc4_channel(block: &[u8], signed: bool, out: &mut [u8; 16]) {
+    let mut bits = 0u64;
+    for (i, b) in block[2..8].iter().enumerate() {
+        bits |= (*b as u64) << (8 * i);
+    }
+
+    // Work in the signed range for snorm so that the interpolation
+    // rounds the same way the hardware does, then store the raw
+    // two's complement byte.
+    let (e0, e1) = if signed {
+        (
+            (block[0] as i8).max(-127) as i32,
+            (block[1] as i8).max(-127) as i32,
+        )
+    } else {
+        (block[0] as i32, block[1] as i32)
+    };
+    let (min, max) = if signed { (-127, 127) } else { (0, 255) };
+
+    let mut palette = [0i32; 8];
+    palette[0] = e0;
+    palette[1] = e1;
+    if e0 > e1 {
+        for i in 1..7 {
+            palette[i + 1] = ((7 - i as i32) * e0 + i as i32 * e1 + 3) / 7;
+        }
+    } else {
+        for i in 1..5 {
+            palette[i + 1] = ((5 - i as i32) * e0 + i as i32 * e1 + 2) / 5;
+        }
+        palette[6] = min;
+        palette[7] = max;
+    }
+
+    for (i, value) in out.iter_mut().enumerate() {
+        *value = palette[((bits >> (3 * i)) & 0x7) as usize] as u8;
+    }
+}
+
+fn decode_bc1(block: &[u8], out: &mut [[u8; 4]; 16]) {
+    decode_color_block(block, false, out);
+}
+
+fn decode_bc2(block: &[u8], out: &mut [[u8; 4]; 16]) {
+    decode_color_block(&block[8..], true, out);
+    for (i, texel) in out.iter_mut().enumerate() {
+        let alpha = (block[i / 2] >> (4 * (i % 2))) & 0xF;
+        texel[3] = alpha * 17;
+    }
+}
+
+fn decode_bc3(block: &[u8], out: &mut [[u8; 4]; 16]) {
+    decode_color_block(&block[8..], true, out);
+    let mut alpha = [0u8; 16];
+    decode_bc4_channel(&block[..8], false, &mut alpha);
+    for (texel, a) in out.iter_mut().zip(alpha.iter()) {
+        texel[3] = *a;
+    }
+}
+
+fn decode_bc4(block: &[u8], signed: bool, out: &mut [[u8; 4]; 16]) {
+    let mut red = [0u8; 16];
+    decode_bc4_channel(block, signed, &mut red);
+    let one = if signed { 127 } else { 255 };
+    for (texel, r) in out.iter_mut().zip(red.iter()) {
+        *texel = [*r, 0, 0, one];
+    }
+}
+
+fn decode_bc4_unorm(block: &[u8], out: &mut [[u8; 4]; 16]) {
+    decode_bc4(block, false, out);
+}
+
+fn decode_bc4_snorm(block: &[u8], out: &mut [[u8; 4]; 16]) {
+    decode_bc4(block, true, out);
+}
+
+fn decode_bc5(block: &[u8], signed: bool, out: &mut [[u8; 4]; 16]) {
+    let mut red = [0u8; 16];
+    let mut green = [0u8; 16];
+    decode_bc4_channel(&block[..8], signed, &mut red);
+    decode_bc4_channel(&block[8..], signed, &mut green);
+    let one = if signed { 127 } else { 255 };
+    for (i, texel) in out.iter_mut().enumerate() {
+        *texel = [red[i], green[i], 0, one];
+    }
+}
+
+fn decode_bc5_unorm(block: &[u8], out: &mut [[u8; 4]; 16]) {
+    decode_bc5(block, false, out);
+}
+
+fn decode_bc5_snorm(block: &[u8], out: &mut [[u8; 4]; 16]) {
+    decode_bc5(block, true, out);
+}
+
+struct Bc7Mode {
+    subsets: usize,
+    partition_bits: u32,
+    rotation_bits: u32,
+    index_selection_bits: u32,
+    color_bits: u32,
+    alpha_bits: u32,
+    endpoint_pbits: bool,
+    shared_pbits: bool,
+    index_bits: u32,
+    index_bits2: u32,
+}
+
+#[rustfmt::skip]
+const BC7_MODES: [Bc7Mode; 8] = [
+    Bc7Mode { subsets: 3, partition_bits: 4, rotation_bits: 0, index_selection_bits: 0, color_bits: 4, alpha_bits: 0, endpoint_pbits: true,  shared_pbits: false, index_bits: 3, index_bits2: 0 },
+    Bc7Mode { subsets: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 6, alpha_bits: 0, endpoint_pbits: false, shared_pbits: true,  index_bits: 3, index_bits2: 0 },
+    Bc7Mode { subsets: 3, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 5, alpha_bits: 0, endpoint_pbits: false, shared_pbits: false, index_bits: 2, index_bits2: 0 },
+    Bc7Mode { subsets: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 7, alpha_bits: 0, endpoint_pbits: true,  shared_pbits: false, index_bits: 2, index_bits2: 0 },
+    Bc7Mode { subsets: 1, partition_bits: 0, rotation_bits: 2, index_selection_bits: 1, color_bits: 5, alpha_bits: 6, endpoint_pbits: false, shared_pbits: false, index_bits: 2, index_bits2: 3 },
+    Bc7Mode { subsets: 1, partition_bits: 0, rotation_bits: 2, index_selection_bits: 0, color_bits: 7, alpha_bits: 8, endpoint_pbits: false, shared_pbits: false, index_bits: 2, index_bits2: 2 },
+    Bc7Mode { subsets: 1, partition_bits: 0, rotation_bits: 0, index_selection_bits: 0, color_bits: 7, alpha_bits: 7, endpoint_pbits: true,  shared_pbits: false, index_bits: 4, index_bits2: 0 },
+    Bc7Mode { subsets: 2, partition_bits: 6, rotation_bits: 0, index_selection_bits: 0, color_bits: 5, alpha_bits: 5, endpoint_pbits: true,  shared_pbits: false, index_bits: 2, index_bits2: 0 },
+];
+
+/// Two subset partitions, one bit per texel.
+#[rustfmt::skip]
+const BC7_PARTITIONS_2: [u16; 64] = [
+    0xCCCC, 0x8888, 0xEEEE, 0xECC8, 0xC880, 0xFEEC, 0xFEC8, 0xEC80,
+    0xC800, 0xFFEC, 0xFE80, 0xE800, 0xFFE8, 0xFF00, 0xFFF0, 0xF000,
+    0xF710, 0x008E, 0x7100, 0x08CE, 0x008C, 0x7310, 0x3100, 0x8CCE,
+    0x088C, 0x3110, 0x6666, 0x366C, 0x17E8, 0x0FF0, 0x718E, 0x399C,
+    0xAAAA, 0xF0F0, 0x5A5A, 0x33CC, 0x3C3C, 0x55AA, 0x9696, 0xA55A,
+    0x73CE, 0x13C8, 0x324C, 0x3BDC, 0x6996, 0xC33C, 0x9966, 0x0660,
+    0x0272, 0x04E4, 0x4E40, 0x2720, 0xC936, 0x936C, 0x39C6, 0x639C,
+    0x9336, 0x9CC6, 0x817E, 0xE718, 0xCCF0, 0x0FCC, 0x7744, 0xEE22,
+];
+
+/// Three subset partitions, two bits per texel.
+#[rustfmt::skip]
+const BC7_PARTITIONS_3: [u32; 64] = [
+    0xAA685050, 0x6A5A5040, 0x5A5A4200, 0x5450A0A8, 0xA5A50000, 0xA0A05050, 0x5555A0A0, 0x5A5A5050,
+    0xAA550000, 0xAA555500, 0xAAAA5500, 0x90909090, 0x94949494, 0xA4A4A4A4, 0xA9A59450, 0x2A0A4250,
+    0xA5945040, 0x0A425054, 0xA5A5A500, 0x55A0A0A0, 0xA8A85454, 0x6A6A4040, 0xA4A45000, 0x1A1A0500,
+    0x0050A4A4, 0xAAA59090, 0x14696914, 0x69691400, 0xA08585A0, 0xAA821414, 0x50A4A450, 0x6A5A0200,
+    0xA9A58000, 0x5090A0A8, 0xA8A09050, 0x24242424, 0x00AA5500, 0x24924924, 0x24499224, 0x50A50A50,
+    0x500AA550, 0xAAAA4444, 0x66660000, 0xA5A0A5A0, 0x50A050A0, 0x69286928, 0x44AAAA44, 0x66666600,
+    0xAA444444, 0x54A854A8, 0x95809580, 0x96969600, 0xA85454A8, 0x80959580, 0xAA141414, 0x96960000,
+    0xAAAA1414, 0xA05050A0, 0xA0A5A5A0, 0x96000000, 0x40804080, 0xA9A8A9A8, 0xAAAAAA44, 0x2A4A5254,
+];
+
+/// Anchor texel of the second subset for two subset partitions.
+#[rustfmt::skip]
+const BC7_ANCHORS_2: [usize; 64] = [
+    15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15,
+    15,  2,  8,  2,  2,  8,  8, 15,  2,  8,  2,  2,  8,  8,  2,  2,
+    15, 15,  6,  8,  2,  8, 15, 15,  2,  8,  2,  2,  2, 15, 15,  6,
+     6,  2,  6,  8, 15, 15,  2,  2, 15, 15, 15, 15, 15,  2,  2, 15,
+];
+
+/// Anchor texels of the second and third subsets for three subset
+/// partitions.
+#[rustfmt::skip]
+const BC7_ANCHORS_3: [[usize; 2]; 64] = [
+    [ 3, 15], [ 3,  8], [15,  8], [15,  3], [ 8, 15], [ 3, 15], [15,  3], [15,  8],
+    [ 8, 15], [ 8, 15], [ 6, 15], [ 6, 15], [ 6, 15], [ 5, 15], [ 3, 15], [ 3,  8],
+    [ 3, 15], [ 3,  8], [ 8, 15], [15,  3], [ 3, 15], [ 3,  8], [ 6, 15], [10,  8],
+    [ 5,  3], [ 8, 15], [ 8,  6], [ 6, 10], [ 8, 15], [ 5, 15], [15, 10], [15,  8],
+    [ 8, 15], [15,  3], [ 3, 15], [ 5, 10], [ 6, 10], [10,  8], [ 8,  9], [15, 10],
+    [15,  6], [ 3, 15], [15,  8], [ 5, 15], [15,  3], [15,  6], [15,  6], [15,  8],
+    [ 3, 15], [15,  3], [ 5, 15], [ 5, 15], [ 5, 15], [ 8, 15], [ 5, 15], [10, 15],
+    [ 5, 15], [10, 15], [ 8, 15], [13, 15], [15,  3], [12, 15], [ 3, 15], [ 3,  8],
+];
+
+const BC7_WEIGHTS_2: [u32; 4] = [0, 21, 43, 64];
+const BC7_WEIGHTS_3: [u32; 8] = [0, 9, 18, 27, 37, 46, 55, 64];
+const BC7_WEIGHTS_4: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+struct BitReader {
+    bits: u128,
+    offset: u32,
+}
+
+impl BitReader {
+    fn read(&mut self, count: u32) -> u32 {
+        let value = ((self.bits >> self.offset) & ((1u128 << count) - 1)) as u32;
+        self.offset += count;
+        value
+    }
+}
+
+fn bc7_subset(subsets: usize, partition: usize, texel: usize) -> usize {
+    match subsets {
+        2 => ((BC7_PARTITIONS_2[partition] >> texel) & 1) as usize,
+        3 => ((BC7_PARTITIONS_3[partition] >> (2 * texel)) & 3) as usize,
+        _ => 0,
+    }
+}
+
+fn bc7_is_anchor(subsets: usize, partition: usize, texel: usize) -> bool {
+    texel == 0
+        || match subsets {
+            2 => BC7_ANCHORS_2[partition] == texel,
+            3 => BC7_ANCHORS_3[partition].contains(&texel),
+            _ => false,
+        }
+}
+
+fn bc7_interpolate(e0: u8, e1: u8, index: u32, index_bits: u32) -> u8 {
+    let weight = match index_bits {
+        2 => BC7_WEIGHTS_2[index as usize],
+        3 => BC7_WEIGHTS_3[index as usize],
+        _ => BC7_WEIGHTS_4[index as usize],
+    };
+    (((64 - weight) * e0 as u32 + weight * e1 as u32 + 32) >> 6) as u8
+}
+
+fn decode_bc7(block: &[u8], out: &mut [[u8; 4]; 16]) {
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&block[..16]);
+    let mut reader = BitReader {
+        bits: u128::from_le_bytes(bytes),
+        offset: 0,
+    };
+
+    let mode_index = match (0..8).find(|_| reader.read(1) == 1) {
+        Some(i) => i,
+        None => {
+            // Reserved mode, which decodes to transparent black
+            *out = [[0; 4]; 16];
+            return;
+        }
+    };
+    let mode = &BC7_MODES[mode_index];
+
+    let partition = reader.read(mode.partition_bits) as usize;
+    let rotation = reader.read(mode.rotation_bits);
+    let index_selection = reader.read(mode.index_selection_bits);
+
+    // endpoints[subset * 2 + endpoint][channel]
+    let mut endpoints = [[0u32; 4]; 6];
+    let num_endpoints = mode.subsets * 2;
+    for channel in 0..3 {
+        for endpoint in endpoints.iter_mut().take(num_endpoints) {
+            endpoint[channel] = reader.read(mode.color_bits);
+        }
+    }
+    for endpoint in endpoints.iter_mut().take(num_endpoints) {
+        endpoint[3] = if mode.alpha_bits > 0 {
+            reader.read(mode.alpha_bits)
+        } else {
+            255
+        };
+    }
+
+    let mut color_bits = mode.color_bits;
+    let mut alpha_bits = mode.alpha_bits;
+    if mode.endpoint_pbits || mode.shared_pbits {
+        let pbits = if mode.endpoint_pbits {
+            (0..num_endpoints)
+                .map(|_| reader.read(1))
+                .collect::<Vec<_>>()
+        } else {
+            (0..mode.subsets)
+                .flat_map(|_| {
+                    let p = reader.read(1);
+                    vec![p, p]
+                })
+                .collect::<Vec<_>>()
+        };
+        for (endpoint, p) in endpoints.iter_mut().zip(pbits.iter()) {
+            for channel in endpoint.iter_mut().take(3) {
+                *channel = (*channel << 1) | p;
+            }
+            if mode.alpha_bits > 0 {
+                endpoint[3] = (endpoint[3] << 1) | p;
+            }
+        }
+        color_bits += 1;
+        if mode.alpha_bits > 0 {
+            alpha_bits += 1;
+        }
+    }
+
+    // Expand every endpoint to 8 bits by replicating the high bits
+    // into the low bits.
+    let expand = |value: u32, bits: u32| -> u8 {
+        let value = value << (8 - bits);
+        (value | (value >> bits)) as u8
+    };
+    let mut colors = [[0u8; 4]; 6];
+    for (color, endpoint) in colors.iter_mut().zip(endpoints.iter()).take(num_endpoints) {
+        for channel in 0..3 {
+            color[channel] = expand(endpoint[channel], color_bits);
+        }
+        color[3] = if mode.alpha_bits > 0 {
+            expand(endpoint[3], alpha_bits)
+        } else {
+            255
+        };
+    }
+
+    let mut indices = [0u32; 16];
+    for (texel, index) in indices.iter_mut().enumerate() {
+        let bits = if bc7_is_anchor(mode.subsets, partition, texel) {
+            mode.index_bits - 1
+        } else {
+            mode.index_bits
+        };
+        *index = reader.read(bits);
+    }
+    let mut indices2 = [0u32; 16];
+    if mode.index_bits2 > 0 {
+        for (texel, index) in indices2.iter_mut().enumerate() {
+            let bits = if texel == 0 {
+                mode.index_bits2 - 1
+            } else {
+                mode.index_bits2
+            };
+            *index = reader.read(bits);
+        }
+    }
+
+    for (texel, out_texel) in out.iter_mut().enumerate() {
+        let subset = bc7_subset(mode.subsets, partition, texel);
+        let e0 = colors[subset * 2];
+        let e1 = colors[subset * 2 + 1];
+
+        let mut texel_color = [0u8; 4];
+        if mode.index_bits2 == 0 {
+            for channel in 0..4 {
+                texel_color[channel] =
+                    bc7_interpolate(e0[channel], e1[channel], indices[texel], mode.index_bits);
+            }
+        } else {
+            // Modes 4 and 5 store separate color and alpha indices.
+            // The index selection bit swaps which one is which.
+            let (color_index, color_index_bits, alpha_index, alpha_index_bits) =
+                if index_selection == 0 {
+                    (
+                        indices[texel],
+                        mode.index_bits,
+                        indices2[texel],
+                        mode.index_bits2,
+                    )
+                } else {
+                    (
+                        indices2[texel],
+                        mode.index_bits2,
+                        indices[texel],
+                        mode.index_bits,
+                    )
+                };
+            for channel in 0..3 {
+                texel_color[channel] =
+                    bc7_interpolate(e0[channel], e1[channel], color_index, color_index_bits);
+            }
+            texel_color[3] = bc7_interpolate(e0[3], e1[3], alpha_index, alpha_index_bits);
+        }
+
+        match rotation {
+            1 => texel_color.swap(0, 3),
+            2 => texel_color.swap(1, 3),
+            3 => texel_color.swap(2, 3),
+            _ => {}
+        }
+        *out_texel = texel_color;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bc7_anchors_belong_to_their_subset() {
+        for partition in 0..64 {
+            let anchor = BC7_ANCHORS_2[partition];
+            assert_eq!(
+                bc7_subset(2, partition, anchor),
+                1,
+                "partition {}",
+                partition
+            );
+            assert_eq!(bc7_subset(2, partition, 0), 0);
+
+            let [anchor1, anchor2] = BC7_ANCHORS_3[partition];
+            assert_eq!(
+                bc7_subset(3, partition, anchor1),
+                1,
+                "partition {}",
+                partition
+            );
+            assert_eq!(
+                bc7_subset(3, partition, anchor2),
+                2,
+                "partition {}",
+                partition
+            );
+            assert_eq!(bc7_subset(3, partition, 0), 0);
+        }
+    }
+
+    #[test]
+    fn bc1_decodes_endpoints_and_punch_through_alpha() {
+        // c0 = pure red, c1 = pure blue. c0 < c1 selects the three
+        // color mode where index 3 is transparent black.
+        let red = 0xF800u16.to_le_bytes();
+        let blue = 0x001Fu16.to_le_bytes();
+
+        let mut out = [[0u8; 4]; 16];
+        decode_bc1(
+            &[blue[0], blue[1], red[0], red[1], 0b11_10_01_00, 0, 0, 0],
+            &mut out,
+        );
+        assert_eq!(out[0], [0, 0, 255, 255]);
+        assert_eq!(out[1], [255, 0, 0, 255]);
+        assert_eq!(out[2], [127, 0, 127, 255]);
+        assert_eq!(out[3], [0, 0, 0, 0]);
+
+        decode_bc1(
+            &[red[0], red[1], blue[0], blue[1], 0b11_10_01_00, 0, 0, 0],
+            &mut out,
+        );
+        assert_eq!(out[2], [170, 0, 85, 255]);
+        assert_eq!(out[3], [85, 0, 170, 255]);
+    }
+
+    #[test]
+    fn bc7_mode6_solid_block() {
+        // Mode 6 with both endpoints set to 0x7F plus a p-bit of 1
+        // decodes every texel to pure white.
+        let mut bits: u128 = 1 << 6;
+        let mut offset = 7;
+        for _ in 0..8 {
+            bits |= 0x7F << offset;
+            offset += 7;
+        }
+        bits |= 0b11 << offset;
+
+        let mut out = [[0u8; 4]; 16];
+        decode_bc7(&bits.to_le_bytes(), &mut out);
+        assert!(out.iter().all(|texel| *texel == [255; 4]));
+    }
+
+    #[test]
+    #[cfg(feature = "compressed")]
+    fn dds_layers_are_regrouped_by_level() {
+        let mut dds = ddsfile::Dds::new_dxgi(ddsfile::NewDxgiParams {
+            height: 8,
+            width: 8,
+            depth: None,
+            format: ddsfile::DxgiFormat::BC1_UNorm,
+            mipmap_levels: Some(2),
+            array_layers: Some(2),
+            caps2: None,
+            is_cubemap: false,
+            resource_dimension: ddsfile::D3D10ResourceDimension::Texture2D,
+            alpha_mode: ddsfile::AlphaMode::Unknown,
+        })
+        .unwrap();
+        // Layer 0 is 4 blocks + 1 block, then layer 1 the same
+        for (i, block) in dds.data.chunks_mut(8).enumerate() {
+            block[0] = i as u8;
+        }
+        let mut bytes = Vec::new();
+        dds.write(&mut bytes).unwrap();
+
+        let image = ContainerImage::from_bytes(&bytes).unwrap();
+        assert_eq!(image.format, wgpu::TextureFormat::Bc1RgbaUnorm);
+        assert_eq!(image.layers, 2);
+        assert_eq!(image.levels.len(), 2);
+        let firsts = |level: &Vec<u8>| level.chunks(8).map(|b| b[0]).collect::<Vec<_>>();
+        assert_eq!(firsts(&image.levels[0]), vec![0, 1, 2, 3, 5, 6, 7, 8]);
+        assert_eq!(firsts(&image.levels[1]), vec![4, 9]);
+
+        let rgba = image.decompress().unwrap();
+        assert_eq!(rgba.format, wgpu::TextureFormat::Rgba8Unorm);
+        assert_eq!(rgba.levels[0].len(), 8 * 8 * 4 * 2);
+        assert_eq!(rgba.levels[1].len(), 4 * 4 * 4 * 2);
+    }
+}
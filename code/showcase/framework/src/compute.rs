@@ -0,0 +1,206 @@
+use anyhow::*;
+use std::mem;
+
+use crate::buffer::{Buffer, ToRaw};
+
+/// The `local_size_x` the framework's compute helpers assume
+pub const DEFAULT_WORKGROUP_SIZE: u32 = 64;
+
+/// How many workgroups of `workgroup_size` it takes to cover `elements`
+pub fn workgroup_count(elements: u32, workgroup_size: u32) -> u32 {
+    let workgroup_size = workgroup_size.max(1);
+    elements.div_ceil(workgroup_size)
+}
+
+/// One storage buffer in a [StorageBinding]
+#[derive(Copy, Clone)]
+pub struct StorageEntry<'a> {
+    pub buffer: &'a wgpu::Buffer,
+    pub read_only: bool,
+}
+
+impl<'a> StorageEntry<'a> {
+    pub fn read_only<U: ToRaw<Output = R>, R: Copy + bytemuck::Pod + bytemuck::Zeroable>(
+        buffer: &'a Buffer<U, R>,
+    ) -> Self {
+        Self {
+            buffer: &buffer.raw_buffer.buffer,
+            read_only: true,
+        }
+    }
+
+    pub fn read_write<U: ToRaw<Output = R>, R: Copy + bytemuck::Pod + bytemuck::Zeroable>(
+        buffer: &'a Buffer<U, R>,
+    ) -> Self {
+        Self {
+            buffer: &buffer.raw_buffer.buffer,
+            read_only: false,
+        }
+    }
+}
+
+/**
+ * Holds the wgpu::BindGroupLayout and one wgpu::BindGroup for a set
+ * of storage buffers, bound in order starting at binding 0 and only
+ * visible to compute shaders.
+ */
+pub struct StorageBinding {
+    pub layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl StorageBinding {
+    pub fn new(device: &wgpu::Device, entries: &[StorageEntry]) -> Self {
+        let read_only = entries.iter().map(|e| e.read_only).collect::<Vec<_>>();
+        let layout = Self::layout_for(device, &read_only);
+        let bind_group = Self::bind_group_for(device, &layout, entries);
+        Self { layout, bind_group }
+    }
+
+    /// The layout [StorageBinding::new] would make for buffers with these
+    /// `read_only` flags, for when the buffers don't exist yet
+    pub fn layout_for(device: &wgpu::Device, read_only: &[bool]) -> wgpu::BindGroupLayout {
+        let entries = read_only
+            .iter()
+            .enumerate()
+            .map(|(i, &read_only)| wgpu::BindGroupLayoutEntry {
+                binding: i as u32,
+                visibility: wgpu::ShaderStage::COMPUTE,
+                ty: wgpu::BindingType::StorageBuffer {
+                    dynamic: false,
+                    readonly: read_only,
+                    min_binding_size: None,
+                },
+                count: None,
+            })
+            .collect::<Vec<_>>();
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &entries,
+            label: Some("StorageBinding::layout"),
+        })
+    }
+
+    /// Binds `entries` in order to a layout made by
+    /// [StorageBinding::layout_for]
+    pub fn bind_group_for(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        entries: &[StorageEntry],
+    ) -> wgpu::BindGroup {
+        let entries = entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| wgpu::BindGroupEntry {
+                binding: i as u32,
+                resource: wgpu::BindingResource::Buffer(entry.buffer.slice(..)),
+            })
+            .collect::<Vec<_>>();
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &entries,
+            label: Some("StorageBinding::bind_group"),
+        })
+    }
+
+    /// Points the binding at different buffers. `entries` has to match
+    /// what the binding was created with.
+    pub fn rebind(&mut self, device: &wgpu::Device, entries: &[StorageEntry]) {
+        self.bind_group = self.create_bind_group(device, entries);
+    }
+
+    /// Another bind group using this binding's layout, e.g. for the
+    /// other half of a ping-pong pair
+    pub fn create_bind_group(
+        &self,
+        device: &wgpu::Device,
+        entries: &[StorageEntry],
+    ) -> wgpu::BindGroup {
+        Self::bind_group_for(device, &self.layout, entries)
+    }
+}
+
+/// Runs `pipeline` over `elements` invocations in its own compute pass.
+/// `bind_groups` are bound to sets 0, 1, ... in order.
+pub fn dispatch(
+    encoder: &mut wgpu::CommandEncoder,
+    pipeline: &wgpu::ComputePipeline,
+    bind_groups: &[&wgpu::BindGroup],
+    elements: u32,
+    workgroup_size: u32,
+) {
+    let mut pass = encoder.begin_compute_pass();
+    pass.set_pipeline(pipeline);
+    for (i, bind_group) in bind_groups.iter().enumerate() {
+        pass.set_bind_group(i as u32, bind_group, &[]);
+    }
+    pass.dispatch(workgroup_count(elements, workgroup_size), 1, 1);
+}
+
+/**
+ * Copies the first `count` `R`s out of `buffer`, which needs
+ * [wgpu::BufferUsage::COPY_SRC]. The copy is submitted straight away,
+ * so anything that writes to `buffer` needs to be submitted first.
+ */
+pub async fn read_buffer<R: Copy + bytemuck::Pod + bytemuck::Zeroable>(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    buffer: &wgpu::Buffer,
+    count: usize,
+) -> Result<Vec<R>> {
+    let size = (count * mem::size_of::<R>()) as wgpu::BufferAddress;
+    if size == 0 {
+        return Ok(Vec::new());
+    }
+    let staging = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("read_buffer::staging"),
+        size,
+        usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+        mapped_at_creation: false,
+    });
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("read_buffer::encoder"),
+    });
+    encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, size);
+    queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = staging.slice(..);
+    let request = slice.map_async(wgpu::MapMode::Read);
+    // Native backends only make progress when polled
+    device.poll(wgpu::Maintain::Wait);
+    request.await?;
+    let data = bytemuck::cast_slice::<u8, R>(&slice.get_mapped_range()).to_vec();
+    staging.unmap();
+    Ok(data)
+}
+
+impl<U: ToRaw<Output = R>, R: Copy + bytemuck::Pod + bytemuck::Zeroable> Buffer<U, R> {
+    /// Reads the buffer's current contents back from the GPU. See
+    /// [read_buffer].
+    pub async fn read_back(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Vec<R>> {
+        read_buffer(
+            device,
+            queue,
+            &self.raw_buffer.buffer,
+            self.raw_buffer.data.len(),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workgroups() {
+        assert_eq!(workgroup_count(0, 64), 0);
+        assert_eq!(workgroup_count(1, 64), 1);
+        assert_eq!(workgroup_count(64, 64), 1);
+        assert_eq!(workgroup_count(65, 64), 2);
+        assert_eq!(workgroup_count(1000, 256), 4);
+        // A workgroup size of 0 would divide by zero
+        assert_eq!(workgroup_count(3, 0), 3);
+        // Rounding up must not overflow near the top of the range
+        assert_eq!(workgroup_count(u32::MAX, 64), u32::MAX / 64 + 1);
+    }
+}
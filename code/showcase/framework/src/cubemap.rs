@@ -0,0 +1,537 @@
+use anyhow::*;
+use cgmath::*;
+use std::io::BufReader;
+use std::iter;
+use std::path::Path;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+
+use crate::camera::{Camera, Projection};
+use crate::pipeline::RenderPipelineBuilder;
+use crate::texture::Texture;
+
+/// How many faces a cube map has. Faces are always ordered +X, -X, +Y, -Y, +Z, -Z.
+pub const CUBE_FACES: usize = 6;
+
+impl<'a> Texture<'a> {
+    /// Loads a cube map from six images ordered +X, -X, +Y, -Y, +Z, -Z.
+    pub fn load_cube<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        paths: [P; CUBE_FACES],
+    ) -> Result<Self> {
+        let mut faces = Vec::with_capacity(CUBE_FACES);
+        for path in paths.iter() {
+            faces.push(image::open(path)?);
+        }
+        Self::from_cube_faces(device, queue, &faces)
+    }
+
+    pub fn from_cube_faces(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        faces: &[image::DynamicImage],
+    ) -> Result<Self> {
+        if faces.len() != CUBE_FACES {
+            bail!("A cube map needs {} faces, got {}", CUBE_FACES, faces.len());
+        }
+        let size = {
+            use image::GenericImageView;
+            faces[0].dimensions()
+        };
+        if size.0 != size.1 {
+            bail!("Cube map faces must be square");
+        }
+
+        let texture = Self::create_cube(
+            device,
+            size.0,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        );
+
+        for (layer, face) in faces.iter().enumerate() {
+            let rgba = face.to_rgba8();
+            if rgba.dimensions() != size {
+                bail!("Cube map faces must all be the same size");
+            }
+            queue.write_texture(
+                wgpu::TextureCopyView {
+                    texture: &texture.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: layer as u32,
+                    },
+                },
+                &rgba,
+                wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: 4 * size.0,
+                    rows_per_image: size.1,
+                },
+                wgpu::Extent3d {
+                    width: size.0,
+                    height: size.1,
+                    depth: 1,
+                },
+            );
+        }
+
+        Ok(texture)
+    }
+
+    /// Creates an empty cube map. The texture's view is a cube view,
+    /// so it can be bound as a `textureCube` straight away.
+    pub fn create_cube(
+        device: &wgpu::Device,
+        size: u32,
+        format: wgpu::TextureFormat,
+        usage: wgpu::TextureUsage,
+    ) -> Self {
+        let desc = wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth: CUBE_FACES as u32,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage,
+        };
+        let texture = device.create_texture(&desc);
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Self {
+            texture,
+            view,
+            sampler,
+            desc,
+        }
+    }
+
+    /// Loads a Radiance `.hdr` image as an `Rgba16Float` texture.
+    /// Use [EquirectToCube] to turn it into a cube map.
+    pub fn load_hdr<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: P,
+    ) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        let decoder = image::hdr::HdrDecoder::new(BufReader::new(file))?;
+        let meta = decoder.metadata();
+        let pixels = decoder.read_image_hdr()?;
+
+        let data = pixels
+            .iter()
+            .flat_map(|p| {
+                let [r, g, b] = p.0;
+                vec![r, g, b, 1.0]
+            })
+            .map(|c| half::f16::from_f32(c).to_bits())
+            .collect::<Vec<u16>>();
+
+        let size = wgpu::Extent3d {
+            width: meta.width,
+            height: meta.height,
+            depth: 1,
+        };
+        let desc = wgpu::TextureDescriptor {
+            label: None,
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba16Float,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        };
+        let texture = device.create_texture(&desc);
+        queue.write_texture(
+            wgpu::TextureCopyView {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            bytemuck::cast_slice(&data),
+            wgpu::TextureDataLayout {
+                offset: 0,
+                bytes_per_row: 8 * meta.width,
+                rows_per_image: meta.height,
+            },
+            size,
+        );
+
+        let view = texture.create_view(&Default::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            // Wrap horizontally so the seam at the back doesn't show
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Ok(Self {
+            texture,
+            view,
+            sampler,
+            desc,
+        })
+    }
+}
+
+/**
+ * Renders an equirectangular (latitude/longitude) image into the
+ * six faces of a cube map on the GPU.
+ */
+pub struct EquirectToCube {
+    layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::RenderPipeline,
+}
+
+impl EquirectToCube {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+    pub fn new(device: &wgpu::Device) -> Result<Self> {
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("EquirectToCube::layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::SampledTexture {
+                        dimension: wgpu::TextureViewDimension::D2,
+                        component_type: wgpu::TextureComponentType::Float,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("EquirectToCube::pipeline_layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = RenderPipelineBuilder::new()
+            .layout(&pipeline_layout)
+            .vertex_shader(wgpu::include_spirv!("fullscreen.vert.spv"))
+            .fragment_shader(wgpu::include_spirv!("equirect.frag.spv"))
+            .color_solid(Self::FORMAT)
+            .build(device)?;
+
+        Ok(Self { layout, pipeline })
+    }
+
+    /// Creates a `size` x `size` cube map from `equirect`, which
+    /// should usually come from [Texture::load_hdr].
+    pub fn convert<'a>(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        equirect: &Texture,
+        size: u32,
+    ) -> Texture<'a> {
+        let cube = Texture::create_cube(
+            device,
+            size,
+            Self::FORMAT,
+            wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+        );
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("EquirectToCube::encoder"),
+        });
+
+        // Each face needs its own uniform and bind group as the
+        // passes all get submitted at once.
+        let faces = (0..CUBE_FACES as u32)
+            .map(|face| {
+                let buffer = device.create_buffer_init(&BufferInitDescriptor {
+                    label: Some("EquirectToCube::face"),
+                    contents: bytemuck::cast_slice(&[face, 0, 0, 0]),
+                    usage: wgpu::BufferUsage::UNIFORM,
+                });
+                let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("EquirectToCube::bind_group"),
+                    layout: &self.layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: wgpu::BindingResource::TextureView(&equirect.view),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: wgpu::BindingResource::Sampler(&equirect.sampler),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: wgpu::BindingResource::Buffer(buffer.slice(..)),
+                        },
+                    ],
+                });
+                let view = cube.texture.create_view(&wgpu::TextureViewDescriptor {
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: face,
+                    array_layer_count: std::num::NonZeroU32::new(1),
+                    ..Default::default()
+                });
+                (buffer, bind_group, view)
+            })
+            .collect::<Vec<_>>();
+
+        for (_, bind_group, view) in &faces {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+
+        queue.submit(iter::once(encoder.finish()));
+
+        cube
+    }
+}
+
+/**
+ * Holds a cube map in a bind group so lighting shaders can sample
+ * it for reflections. In GLSL this looks like
+ *
+ * ```glsl
+ * layout(set = N, binding = 0) uniform textureCube t_environment;
+ * layout(set = N, binding = 1) uniform sampler s_environment;
+ * ```
+ */
+pub struct EnvironmentBinding {
+    pub layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl EnvironmentBinding {
+    pub fn new(device: &wgpu::Device, environment: &Texture) -> Self {
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("EnvironmentBinding::layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::SampledTexture {
+                        dimension: wgpu::TextureViewDimension::Cube,
+                        component_type: wgpu::TextureComponentType::Float,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                    count: None,
+                },
+            ],
+        });
+        let bind_group = Self::create_bind_group(device, &layout, environment);
+        Self { layout, bind_group }
+    }
+
+    pub fn rebind(&mut self, device: &wgpu::Device, environment: &Texture) {
+        self.bind_group = Self::create_bind_group(device, &self.layout, environment);
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        environment: &Texture,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("EnvironmentBinding::bind_group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&environment.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&environment.sampler),
+                },
+            ],
+        })
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SkyboxUniforms {
+    inv_view_proj: Matrix4<f32>,
+}
+
+unsafe impl bytemuck::Zeroable for SkyboxUniforms {}
+unsafe impl bytemuck::Pod for SkyboxUniforms {}
+
+/**
+ * Draws a cube map behind everything else. The sky is drawn on the
+ * far plane with depth writes off, so draw it in the same render
+ * pass as the scene after the opaque geometry. `sample_count` has to
+ * match that pass's attachments.
+ */
+pub struct Skybox {
+    buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+}
+
+impl Skybox {
+    pub fn new(
+        device: &wgpu::Device,
+        color_format: wgpu::TextureFormat,
+        sample_count: u32,
+        sky: &Texture,
+    ) -> Result<Self> {
+        let buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Skybox::buffer"),
+            contents: bytemuck::cast_slice(&[SkyboxUniforms {
+                inv_view_proj: Matrix4::identity(),
+            }]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Skybox::layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::SampledTexture {
+                        dimension: wgpu::TextureViewDimension::Cube,
+                        component_type: wgpu::TextureComponentType::Float,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                    count: None,
+                },
+            ],
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Skybox::bind_group"),
+            layout: &layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(buffer.slice(..)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&sky.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&sky.sampler),
+                },
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Skybox::pipeline_layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = RenderPipelineBuilder::new()
+            .layout(&pipeline_layout)
+            .vertex_shader(wgpu::include_spirv!("skybox.vert.spv"))
+            .fragment_shader(wgpu::include_spirv!("skybox.frag.spv"))
+            .color_solid(color_format)
+            // The sky sits exactly on the far plane, so it has to
+            // pass when the depth buffer still holds the clear value.
+            .depth_no_stencil(
+                Texture::DEPTH_FORMAT,
+                false,
+                wgpu::CompareFunction::LessEqual,
+            )
+            .sample_count(sample_count)
+            .build(device)?;
+
+        Ok(Self {
+            buffer,
+            bind_group,
+            pipeline,
+        })
+    }
+
+    pub fn update(&self, queue: &wgpu::Queue, camera: &Camera, projection: &Projection) {
+        // Only the camera's rotation matters for the sky
+        let mut view = camera.calc_matrix();
+        view.w = Vector4::new(0.0, 0.0, 0.0, 1.0);
+        let inv_view_proj = (projection.calc_matrix() * view)
+            .invert()
+            .unwrap_or_else(Matrix4::identity);
+        queue.write_buffer(
+            &self.buffer,
+            0,
+            bytemuck::cast_slice(&[SkyboxUniforms { inv_view_proj }]),
+        );
+    }
+
+    pub fn draw<'a, 'b>(&'b self, pass: &mut wgpu::RenderPass<'a>)
+    where
+        'b: 'a,
+    {
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
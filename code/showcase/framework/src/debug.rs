@@ -0,0 +1,427 @@
+use anyhow::*;
+use cgmath::*;
+
+use crate::model::{Model, Vertex};
+use crate::pipeline::RenderPipelineBuilder;
+use crate::texture::Texture;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DebugVertex {
+    pub position: Vector3<f32>,
+    pub color: Vector4<f32>,
+}
+
+unsafe impl bytemuck::Pod for DebugVertex {}
+unsafe impl bytemuck::Zeroable for DebugVertex {}
+
+impl Vertex for DebugVertex {
+    fn desc<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        use std::mem;
+        wgpu::VertexBufferDescriptor {
+            stride: mem::size_of::<DebugVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttributeDescriptor {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float4,
+                },
+            ],
+        }
+    }
+}
+
+/// How many segments circles and spheres are drawn with
+const CIRCLE_SEGMENTS: usize = 24;
+
+/**
+ * The shapes queued for [DebugDraw] this frame, as pairs of line
+ * vertices in world space. Shapes queued while
+ * [DebugLines::depth_test] is false are drawn on top of everything.
+ */
+pub struct DebugLines {
+    pub depth_test: bool,
+    depth_tested: Vec<DebugVertex>,
+    overlay: Vec<DebugVertex>,
+}
+
+impl Default for DebugLines {
+    fn default() -> Self {
+        Self {
+            depth_test: true,
+            depth_tested: Vec::new(),
+            overlay: Vec::new(),
+        }
+    }
+}
+
+impl DebugLines {
+    pub fn clear(&mut self) {
+        self.depth_tested.clear();
+        self.overlay.clear();
+    }
+
+    /// The vertices that are depth tested, then the ones that aren't
+    pub fn vertices(&self) -> (&[DebugVertex], &[DebugVertex]) {
+        (&self.depth_tested, &self.overlay)
+    }
+
+    pub fn line(&mut self, a: Vector3<f32>, b: Vector3<f32>, color: Vector4<f32>) {
+        let lines = if self.depth_test {
+            &mut self.depth_tested
+        } else {
+            &mut self.overlay
+        };
+        lines.push(DebugVertex { position: a, color });
+        lines.push(DebugVertex { position: b, color });
+    }
+
+    /// A line with a small cone of lines at `to`
+    pub fn arrow(&mut self, from: Vector3<f32>, to: Vector3<f32>, color: Vector4<f32>) {
+        self.line(from, to, color);
+        let dir = to - from;
+        let length = dir.magnitude();
+        if length <= f32::EPSILON {
+            return;
+        }
+        let dir = dir / length;
+        let (side, up) = perpendiculars(dir);
+        let head = length * 0.1;
+        let base = to - dir * head;
+        for offset in [side, -side, up, -up].iter() {
+            self.line(to, base + offset * head * 0.5, color);
+        }
+    }
+
+    /// An axis aligned box
+    pub fn wire_box(&mut self, min: Vector3<f32>, max: Vector3<f32>, color: Vector4<f32>) {
+        let corners = [
+            Vector3::new(min.x, min.y, min.z),
+            Vector3::new(max.x, min.y, min.z),
+            Vector3::new(max.x, max.y, min.z),
+            Vector3::new(min.x, max.y, min.z),
+            Vector3::new(min.x, min.y, max.z),
+            Vector3::new(max.x, min.y, max.z),
+            Vector3::new(max.x, max.y, max.z),
+            Vector3::new(min.x, max.y, max.z),
+        ];
+        self.box_corners(&corners, color);
+    }
+
+    /// Draws the edges of a box given its corners. The first four
+    /// corners are one face and the last four the opposite face, in
+    /// the same winding.
+    fn box_corners(&mut self, corners: &[Vector3<f32>; 8], color: Vector4<f32>) {
+        for i in 0..4 {
+            let j = (i + 1) % 4;
+            self.line(corners[i], corners[j], color);
+            self.line(corners[i + 4], corners[j + 4], color);
+            self.line(corners[i], corners[i + 4], color);
+        }
+    }
+
+    pub fn circle(
+        &mut self,
+        center: Vector3<f32>,
+        normal: Vector3<f32>,
+        radius: f32,
+        color: Vector4<f32>,
+    ) {
+        let (u, v) = perpendiculars(normal.normalize());
+        let point = |i: usize| {
+            let angle = i as f32 / CIRCLE_SEGMENTS as f32 * std::f32::consts::PI * 2.0;
+            center + (u * angle.cos() + v * angle.sin()) * radius
+        };
+        for i in 0..CIRCLE_SEGMENTS {
+            self.line(point(i), point(i + 1), color);
+        }
+    }
+
+    /// Three circles, one around each axis
+    pub fn sphere(&mut self, center: Vector3<f32>, radius: f32, color: Vector4<f32>) {
+        self.circle(center, Vector3::unit_x(), radius, color);
+        self.circle(center, Vector3::unit_y(), radius, color);
+        self.circle(center, Vector3::unit_z(), radius, color);
+    }
+
+    /// The volume a camera sees, from its projection times its view
+    /// matrix. This uses wgpu's 0 to 1 depth range, which is what
+    /// [crate::Projection::calc_matrix] gives.
+    pub fn frustum(&mut self, view_proj: Matrix4<f32>, color: Vector4<f32>) {
+        let inv = match view_proj.invert() {
+            Some(inv) => inv,
+            None => return,
+        };
+        let corner = |x: f32, y: f32, z: f32| {
+            let p = inv * Vector4::new(x, y, z, 1.0);
+            p.truncate() / p.w
+        };
+        let corners = [
+            corner(-1.0, -1.0, 0.0),
+            corner(1.0, -1.0, 0.0),
+            corner(1.0, 1.0, 0.0),
+            corner(-1.0, 1.0, 0.0),
+            corner(-1.0, -1.0, 1.0),
+            corner(1.0, -1.0, 1.0),
+            corner(1.0, 1.0, 1.0),
+            corner(-1.0, 1.0, 1.0),
+        ];
+        self.box_corners(&corners, color);
+    }
+
+    /// A square grid on the xz plane with `divisions` cells per side
+    pub fn grid(&mut self, center: Vector3<f32>, size: f32, divisions: u32, color: Vector4<f32>) {
+        let half = size / 2.0;
+        let divisions = divisions.max(1);
+        for i in 0..=divisions {
+            let t = i as f32 / divisions as f32 * size - half;
+            self.line(
+                center + Vector3::new(t, 0.0, -half),
+                center + Vector3::new(t, 0.0, half),
+                color,
+            );
+            self.line(
+                center + Vector3::new(-half, 0.0, t),
+                center + Vector3::new(half, 0.0, t),
+                color,
+            );
+        }
+    }
+
+    /// The x, y and z axes of `transform` in red, green and blue
+    pub fn axes(&mut self, transform: Matrix4<f32>, size: f32) {
+        let origin = transform.w.truncate();
+        let colors = [
+            Vector4::new(1.0, 0.0, 0.0, 1.0),
+            Vector4::new(0.0, 1.0, 0.0, 1.0),
+            Vector4::new(0.0, 0.0, 1.0, 1.0),
+        ];
+        for (i, color) in colors.iter().enumerate() {
+            let axis = transform[i].truncate();
+            self.arrow(origin, origin + axis * size, *color);
+        }
+    }
+
+    /// Draws the normal (blue), tangent (red) and bitangent (green)
+    /// of every vertex in `model`, placed with `transform`.
+    pub fn model_normals(&mut self, model: &Model, transform: Matrix4<f32>, length: f32) {
+        let normal_matrix = Matrix3::from_cols(
+            transform.x.truncate(),
+            transform.y.truncate(),
+            transform.z.truncate(),
+        )
+        .invert()
+        .map(|m| m.transpose())
+        .unwrap_or_else(Matrix3::identity);
+        let red = Vector4::new(1.0, 0.0, 0.0, 1.0);
+        let green = Vector4::new(0.0, 1.0, 0.0, 1.0);
+        let blue = Vector4::new(0.0, 0.0, 1.0, 1.0);
+
+        for mesh in &model.meshes {
+            for v in &mesh.vertices {
+                let p = (transform * v.position.extend(1.0)).truncate();
+                let dir = |d: Vector3<f32>| {
+                    let d = normal_matrix * d;
+                    if d.magnitude2() > 0.0 {
+                        d.normalize() * length
+                    } else {
+                        d
+                    }
+                };
+                self.line(p, p + dir(v.normal), blue);
+                self.line(p, p + dir(v.tangent), red);
+                self.line(p, p + dir(v.bitangent), green);
+            }
+        }
+    }
+}
+
+/// Two unit vectors perpendicular to `dir` and each other
+pub(crate) fn perpendiculars(dir: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let other = if dir.y.abs() < 0.99 {
+        Vector3::unit_y()
+    } else {
+        Vector3::unit_x()
+    };
+    let u = dir.cross(other).normalize();
+    let v = dir.cross(u);
+    (u, v)
+}
+
+/**
+ * Immediate mode debug drawing. Queue shapes on [DebugDraw::lines]
+ * during a frame, then call [DebugDraw::prepare] and draw them all at
+ * once with [DebugDraw::draw] in a pass that has a depth attachment.
+ * The queue is cleared by [DebugDraw::prepare], so shapes have to be
+ * added again every frame.
+ */
+pub struct DebugDraw {
+    pub lines: DebugLines,
+    depth_pipeline: wgpu::RenderPipeline,
+    overlay_pipeline: wgpu::RenderPipeline,
+    buffer: wgpu::Buffer,
+    capacity: usize,
+    depth_tested_count: u32,
+    overlay_count: u32,
+}
+
+impl DebugDraw {
+    pub fn new(
+        device: &wgpu::Device,
+        color_format: wgpu::TextureFormat,
+        sample_count: u32,
+        uniform_layout: &wgpu::BindGroupLayout,
+    ) -> Result<Self> {
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("DebugDraw::layout"),
+            bind_group_layouts: &[uniform_layout],
+            push_constant_ranges: &[],
+        });
+        let build = |depth_write_enabled, depth_compare| {
+            RenderPipelineBuilder::new()
+                .layout(&layout)
+                .vertex_shader(wgpu::include_spirv!("debug_line.vert.spv"))
+                .fragment_shader(wgpu::include_spirv!("debug_line.frag.spv"))
+                .color_state(wgpu::ColorStateDescriptor {
+                    format: color_format,
+                    color_blend: wgpu::BlendDescriptor {
+                        src_factor: wgpu::BlendFactor::SrcAlpha,
+                        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                    write_mask: wgpu::ColorWrite::ALL,
+                })
+                .depth_no_stencil(Texture::DEPTH_FORMAT, depth_write_enabled, depth_compare)
+                .primitive_topology(wgpu::PrimitiveTopology::LineList)
+                .sample_count(sample_count)
+                .vertex_buffer::<DebugVertex>()
+                .build(device)
+        };
+        let depth_pipeline = build(true, wgpu::CompareFunction::Less)?;
+        let overlay_pipeline = build(false, wgpu::CompareFunction::Always)?;
+
+        let capacity = 1024;
+        let buffer = Self::create_buffer(device, capacity);
+
+        Ok(Self {
+            lines: DebugLines::default(),
+            depth_pipeline,
+            overlay_pipeline,
+            buffer,
+            capacity,
+            depth_tested_count: 0,
+            overlay_count: 0,
+        })
+    }
+
+    /// Uploads everything queued on [DebugDraw::lines] and clears it
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let (depth_tested, overlay) = self.lines.vertices();
+        let total = depth_tested.len() + overlay.len();
+        if total > self.capacity {
+            self.capacity = total.next_power_of_two();
+            self.buffer = Self::create_buffer(device, self.capacity);
+        }
+        if !depth_tested.is_empty() {
+            queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(depth_tested));
+        }
+        if !overlay.is_empty() {
+            let offset = std::mem::size_of_val(depth_tested) as wgpu::BufferAddress;
+            queue.write_buffer(&self.buffer, offset, bytemuck::cast_slice(overlay));
+        }
+        self.depth_tested_count = depth_tested.len() as u32;
+        self.overlay_count = overlay.len() as u32;
+        self.lines.clear();
+    }
+
+    pub fn draw<'a, 'b>(&'b self, pass: &mut wgpu::RenderPass<'a>, uniforms: &'b wgpu::BindGroup)
+    where
+        'b: 'a,
+    {
+        if self.depth_tested_count + self.overlay_count == 0 {
+            return;
+        }
+        pass.set_vertex_buffer(0, self.buffer.slice(..));
+        pass.set_bind_group(0, uniforms, &[]);
+        if self.depth_tested_count > 0 {
+            pass.set_pipeline(&self.depth_pipeline);
+            pass.draw(0..self.depth_tested_count, 0..1);
+        }
+        if self.overlay_count > 0 {
+            let start = self.depth_tested_count;
+            pass.set_pipeline(&self.overlay_pipeline);
+            pass.draw(start..start + self.overlay_count, 0..1);
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("DebugDraw::buffer"),
+            size: (capacity * std::mem::size_of::<DebugVertex>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Vector4<f32> = Vector4::new(1.0, 1.0, 1.0, 1.0);
+
+    #[test]
+    fn shapes_are_line_pairs() {
+        let mut lines = DebugLines::default();
+        lines.wire_box(
+            Vector3::new(-1.0, -1.0, -1.0),
+            Vector3::new(1.0, 1.0, 1.0),
+            WHITE,
+        );
+        assert_eq!(lines.vertices().0.len(), 12 * 2);
+
+        lines.clear();
+        lines.sphere(Vector3::new(0.0, 0.0, 0.0), 1.0, WHITE);
+        assert_eq!(lines.vertices().0.len(), 3 * CIRCLE_SEGMENTS * 2);
+        for v in lines.vertices().0 {
+            assert!((v.position.magnitude() - 1.0).abs() < 1e-5);
+        }
+
+        lines.clear();
+        lines.grid(Vector3::new(0.0, 0.0, 0.0), 10.0, 10, WHITE);
+        assert_eq!(lines.vertices().0.len(), 11 * 2 * 2);
+    }
+
+    #[test]
+    fn depth_test_picks_batch() {
+        let mut lines = DebugLines::default();
+        let a = Vector3::new(0.0, 0.0, 0.0);
+        let b = Vector3::new(1.0, 0.0, 0.0);
+        lines.line(a, b, WHITE);
+        lines.depth_test = false;
+        lines.arrow(a, b, WHITE);
+        let (depth_tested, overlay) = lines.vertices();
+        assert_eq!(depth_tested.len(), 2);
+        assert_eq!(overlay.len(), 5 * 2);
+    }
+
+    #[test]
+    fn frustum_corners() {
+        let mut lines = DebugLines::default();
+        // Identity maps the frustum to the NDC box, which is 0 to 1 in z
+        lines.frustum(Matrix4::identity(), WHITE);
+        let vertices = lines.vertices().0;
+        assert_eq!(vertices.len(), 12 * 2);
+        for v in vertices {
+            assert!(v.position.x.abs() == 1.0 && v.position.y.abs() == 1.0);
+            assert!(v.position.z == 0.0 || v.position.z == 1.0);
+        }
+    }
+}
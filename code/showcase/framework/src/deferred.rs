@@ -0,0 +1,585 @@
+use anyhow::*;
+use cgmath::*;
+use std::ops::Range;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+
+use crate::buffer::ToRaw;
+use crate::camera::{Camera, Projection};
+use crate::instance::InstanceRaw;
+use crate::model::{Material, Mesh, Model, ModelVertex};
+use crate::pipeline::RenderPipelineBuilder;
+use crate::texture::Texture;
+
+/**
+ * The render targets the geometry pass of [DeferredRenderer] writes
+ * to. Each one is a regular [Texture], so they can be sampled by
+ * other passes too.
+ */
+pub struct GBuffer<'a> {
+    /// The base color of the surface
+    pub albedo: Texture<'a>,
+    /// World space normals in xyz
+    pub normal: Texture<'a>,
+    /// Specular strength in r and shininess / 256 in g
+    pub material: Texture<'a>,
+    pub depth: Texture<'a>,
+}
+
+impl<'a> GBuffer<'a> {
+    pub const ALBEDO_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+    pub const NORMAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    pub const MATERIAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+    pub fn new(device: &wgpu::Device, width: u32, height: u32) -> Self {
+        let create = |format, label| {
+            Texture::create_render_target(device, width, height, format, Some(label))
+        };
+        Self {
+            albedo: create(Self::ALBEDO_FORMAT, "GBuffer::albedo"),
+            normal: create(Self::NORMAL_FORMAT, "GBuffer::normal"),
+            material: create(Self::MATERIAL_FORMAT, "GBuffer::material"),
+            depth: create(Texture::DEPTH_FORMAT, "GBuffer::depth"),
+        }
+    }
+
+    pub fn color_attachments(&self) -> [wgpu::RenderPassColorAttachmentDescriptor<'_>; 3] {
+        fn attachment<'t>(texture: &'t Texture) -> wgpu::RenderPassColorAttachmentDescriptor<'t> {
+            wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &texture.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: true,
+                },
+            }
+        }
+        [
+            attachment(&self.albedo),
+            attachment(&self.normal),
+            attachment(&self.material),
+        ]
+    }
+
+    pub fn depth_attachment(&self) -> wgpu::RenderPassDepthStencilAttachmentDescriptor<'_> {
+        wgpu::RenderPassDepthStencilAttachmentDescriptor {
+            attachment: &self.depth.view,
+            depth_ops: Some(wgpu::Operations {
+                load: wgpu::LoadOp::Clear(1.0),
+                store: true,
+            }),
+            stencil_ops: None,
+        }
+    }
+}
+
+/// What [DeferredRenderer::light] draws
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GBufferView {
+    /// The normal lit scene
+    Lit = 0,
+    Albedo = 1,
+    /// Normals mapped from -1..1 to 0..1
+    Normal = 2,
+    Material = 3,
+    Depth = 4,
+    /// The world position reconstructed from depth, wrapped every
+    /// unit so it's easy to see
+    Position = 5,
+    /// The texture passed to [DeferredRenderer::set_ambient_occlusion]
+    AmbientOcclusion = 6,
+}
+
+/// A light for [DeferredRenderer]. Its contribution fades to zero
+/// at `radius`.
+#[derive(Debug, Copy, Clone)]
+pub struct PointLight {
+    pub position: Vector3<f32>,
+    pub radius: f32,
+    pub color: Vector3<f32>,
+    pub intensity: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PointLightRaw {
+    position: Vector4<f32>,
+    color: Vector4<f32>,
+}
+
+unsafe impl bytemuck::Pod for PointLightRaw {}
+unsafe impl bytemuck::Zeroable for PointLightRaw {}
+
+impl ToRaw for PointLight {
+    type Output = PointLightRaw;
+
+    fn to_raw(&self) -> Self::Output {
+        PointLightRaw {
+            position: self.position.extend(self.radius),
+            color: self.color.extend(self.intensity),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct DeferredUniforms {
+    inv_view_proj: Matrix4<f32>,
+    view_position: Vector4<f32>,
+    ambient: Vector4<f32>,
+    light_count: u32,
+    debug_view: u32,
+    use_ao: u32,
+    _padding: u32,
+}
+
+unsafe impl bytemuck::Pod for DeferredUniforms {}
+unsafe impl bytemuck::Zeroable for DeferredUniforms {}
+
+/**
+ * Renders models in two passes. The geometry pass writes surface
+ * data into a [GBuffer], then the lighting pass shades each pixel
+ * once for every [PointLight] in a single full-screen pass, so the
+ * cost of a light doesn't depend on how much geometry there is.
+ *
+ * Models should be loaded with [DeferredRenderer::material_layout].
+ * `index_format` has to match their meshes, see
+ * [crate::Model::index_format].
+ * A frame looks like
+ *
+ * ```ignore
+ * renderer.update(device, queue, &camera, &projection);
+ * {
+ *     let mut pass = renderer.begin_geometry_pass(&mut encoder);
+ *     pass.set_vertex_buffer(1, instances.slice(..));
+ *     pass.draw_model_geometry_instanced(&model, 0..n, &uniforms.bind_group);
+ * }
+ * renderer.light(&mut encoder, &frame.view);
+ * ```
+ */
+pub struct DeferredRenderer<'a> {
+    pub gbuffer: GBuffer<'a>,
+    pub material_layout: wgpu::BindGroupLayout,
+    pub lights: Vec<PointLight>,
+    pub ambient: Vector3<f32>,
+    pub debug_view: GBufferView,
+    geometry_pipeline: wgpu::RenderPipeline,
+    lighting_pipeline: wgpu::RenderPipeline,
+    gbuffer_layout: wgpu::BindGroupLayout,
+    gbuffer_bind_group: wgpu::BindGroup,
+    gbuffer_sampler: wgpu::Sampler,
+    lighting_layout: wgpu::BindGroupLayout,
+    lighting_bind_group: wgpu::BindGroup,
+    uniform_buffer: wgpu::Buffer,
+    light_buffer: wgpu::Buffer,
+    light_capacity: usize,
+    has_ao: bool,
+}
+
+impl<'a> DeferredRenderer<'a> {
+    pub fn new(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        output_format: wgpu::TextureFormat,
+        index_format: wgpu::IndexFormat,
+        uniform_layout: &wgpu::BindGroupLayout,
+    ) -> Result<Self> {
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::SampledTexture {
+                dimension: wgpu::TextureViewDimension::D2,
+                component_type: wgpu::TextureComponentType::Float,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let sampler_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::Sampler { comparison: false },
+            count: None,
+        };
+
+        let fragment = wgpu::ShaderStage::FRAGMENT;
+        let material_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("DeferredRenderer::material_layout"),
+            entries: &[
+                texture_entry(0),
+                sampler_entry(1),
+                texture_entry(2),
+                sampler_entry(3),
+            ],
+        });
+        let gbuffer_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("DeferredRenderer::gbuffer_layout"),
+            entries: &[
+                texture_entry(0),
+                texture_entry(1),
+                texture_entry(2),
+                texture_entry(3),
+                sampler_entry(4),
+                texture_entry(5),
+            ],
+        });
+        let lighting_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("DeferredRenderer::lighting_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: fragment,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: fragment,
+                    ty: wgpu::BindingType::StorageBuffer {
+                        dynamic: false,
+                        min_binding_size: None,
+                        readonly: true,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let geometry_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("DeferredRenderer::geometry_layout"),
+            bind_group_layouts: &[&material_layout, uniform_layout],
+            push_constant_ranges: &[],
+        });
+        let geometry_pipeline = RenderPipelineBuilder::new()
+            .layout(&geometry_layout)
+            .vertex_shader(wgpu::include_spirv!("pbr.vert.spv"))
+            .fragment_shader(wgpu::include_spirv!("gbuffer.frag.spv"))
+            .color_solid(GBuffer::ALBEDO_FORMAT)
+            .color_solid(GBuffer::NORMAL_FORMAT)
+            .color_solid(GBuffer::MATERIAL_FORMAT)
+            .depth_format(Texture::DEPTH_FORMAT)
+            .cull_mode(wgpu::CullMode::Back)
+            .vertex_buffer::<ModelVertex>()
+            .vertex_buffer::<InstanceRaw>()
+            .index_format(index_format)
+            .build(device)?;
+
+        let lighting_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("DeferredRenderer::lighting_pipeline_layout"),
+                bind_group_layouts: &[&gbuffer_layout, &lighting_layout],
+                push_constant_ranges: &[],
+            });
+        let lighting_pipeline = RenderPipelineBuilder::new()
+            .layout(&lighting_pipeline_layout)
+            .vertex_shader(wgpu::include_spirv!("fullscreen.vert.spv"))
+            .fragment_shader(wgpu::include_spirv!("deferred_light.frag.spv"))
+            .color_solid(output_format)
+            .build(device)?;
+
+        // The lighting pass uses texelFetch, so filtering doesn't
+        // matter, but a sampler is still needed to build the
+        // combined samplers in GLSL.
+        let gbuffer_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let gbuffer = GBuffer::new(device, width, height);
+        let gbuffer_bind_group = Self::create_gbuffer_bind_group(
+            device,
+            &gbuffer_layout,
+            &gbuffer,
+            &gbuffer_sampler,
+            None,
+        );
+
+        let uniform_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("DeferredRenderer::uniform_buffer"),
+            contents: bytemuck::cast_slice(&[DeferredUniforms {
+                inv_view_proj: Matrix4::identity(),
+                view_position: Vector4::zero(),
+                ambient: Vector4::zero(),
+                light_count: 0,
+                debug_view: 0,
+                use_ao: 0,
+                _padding: 0,
+            }]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+        let light_capacity = 16;
+        let light_buffer = Self::create_light_buffer(device, light_capacity);
+        let lighting_bind_group = Self::create_lighting_bind_group(
+            device,
+            &lighting_layout,
+            &uniform_buffer,
+            &light_buffer,
+        );
+
+        Ok(Self {
+            gbuffer,
+            material_layout,
+            lights: Vec::new(),
+            ambient: Vector3::new(0.05, 0.05, 0.05),
+            debug_view: GBufferView::Lit,
+            geometry_pipeline,
+            lighting_pipeline,
+            gbuffer_layout,
+            gbuffer_bind_group,
+            gbuffer_sampler,
+            lighting_layout,
+            lighting_bind_group,
+            uniform_buffer,
+            light_buffer,
+            light_capacity,
+            has_ao: false,
+        })
+    }
+
+    /// Recreates the [GBuffer]. This also unsets the ambient
+    /// occlusion texture, as it usually gets resized at the same time.
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        self.gbuffer = GBuffer::new(device, width, height);
+        self.set_ambient_occlusion(device, None);
+    }
+
+    /// Multiplies the ambient light by the red channel of `ao`,
+    /// such as [crate::Ssao::ao]. It needs to be the same size as the
+    /// [GBuffer].
+    pub fn set_ambient_occlusion(&mut self, device: &wgpu::Device, ao: Option<&Texture>) {
+        self.has_ao = ao.is_some();
+        self.gbuffer_bind_group = Self::create_gbuffer_bind_group(
+            device,
+            &self.gbuffer_layout,
+            &self.gbuffer,
+            &self.gbuffer_sampler,
+            ao,
+        );
+    }
+
+    /// Uploads the camera, [DeferredRenderer::lights] and the other
+    /// settings. Call this once a frame before [DeferredRenderer::light].
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera: &Camera,
+        projection: &Projection,
+    ) {
+        if self.lights.len() > self.light_capacity {
+            self.light_capacity = self.lights.len().next_power_of_two();
+            self.light_buffer = Self::create_light_buffer(device, self.light_capacity);
+            self.lighting_bind_group = Self::create_lighting_bind_group(
+                device,
+                &self.lighting_layout,
+                &self.uniform_buffer,
+                &self.light_buffer,
+            );
+        }
+        if !self.lights.is_empty() {
+            let raw = self.lights.iter().map(ToRaw::to_raw).collect::<Vec<_>>();
+            queue.write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&raw));
+        }
+
+        let view_proj = projection.calc_matrix() * camera.calc_matrix();
+        let uniforms = DeferredUniforms {
+            inv_view_proj: view_proj.invert().unwrap_or_else(Matrix4::identity),
+            view_position: camera.position.to_homogeneous(),
+            ambient: self.ambient.extend(1.0),
+            light_count: self.lights.len() as u32,
+            debug_view: self.debug_view as u32,
+            use_ao: self.has_ao as u32,
+            _padding: 0,
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniforms]));
+    }
+
+    /// Starts a render pass that clears and draws into the
+    /// [GBuffer]. Draw models with [DrawGeometry].
+    pub fn begin_geometry_pass<'b>(
+        &'b self,
+        encoder: &'b mut wgpu::CommandEncoder,
+    ) -> wgpu::RenderPass<'b> {
+        let color_attachments = self.gbuffer.color_attachments();
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &color_attachments,
+            depth_stencil_attachment: Some(self.gbuffer.depth_attachment()),
+        });
+        pass.set_pipeline(&self.geometry_pipeline);
+        pass
+    }
+
+    /// Shades the [GBuffer] into `output`, or draws
+    /// [DeferredRenderer::debug_view] if it isn't [GBufferView::Lit].
+    pub fn light(&self, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: output,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.lighting_pipeline);
+        pass.set_bind_group(0, &self.gbuffer_bind_group, &[]);
+        pass.set_bind_group(1, &self.lighting_bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+
+    fn create_light_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("DeferredRenderer::light_buffer"),
+            size: (capacity * std::mem::size_of::<PointLightRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_gbuffer_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        gbuffer: &GBuffer,
+        sampler: &wgpu::Sampler,
+        ao: Option<&Texture>,
+    ) -> wgpu::BindGroup {
+        // Something has to be bound even without AO. The shader
+        // won't read it.
+        let ao = ao.unwrap_or(&gbuffer.material);
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("DeferredRenderer::gbuffer_bind_group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&gbuffer.albedo.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&gbuffer.normal.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&gbuffer.material.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&gbuffer.depth.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&ao.view),
+                },
+            ],
+        })
+    }
+
+    fn create_lighting_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform_buffer: &wgpu::Buffer,
+        light_buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("DeferredRenderer::lighting_bind_group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(uniform_buffer.slice(..)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Buffer(light_buffer.slice(..)),
+                },
+            ],
+        })
+    }
+}
+
+/// Like [crate::DrawModel], but for the geometry pass of
+/// [DeferredRenderer], which doesn't use a light bind group.
+/// Instances go in vertex slot 1.
+pub trait DrawGeometry<'a, 'b>
+where
+    'b: 'a,
+{
+    fn draw_mesh_geometry_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'b Material,
+        instances: Range<u32>,
+        uniforms: &'b wgpu::BindGroup,
+    );
+    fn draw_model_geometry_instanced(
+        &mut self,
+        model: &'b Model,
+        instances: Range<u32>,
+        uniforms: &'b wgpu::BindGroup,
+    );
+}
+
+impl<'a, 'b> DrawGeometry<'a, 'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_mesh_geometry_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'b Material,
+        instances: Range<u32>,
+        uniforms: &'b wgpu::BindGroup,
+    ) {
+        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        self.set_index_buffer(mesh.index_buffer.slice(..));
+        self.set_bind_group(0, &material.bind_group, &[]);
+        self.set_bind_group(1, uniforms, &[]);
+        self.draw_indexed(0..mesh.num_elements, 0, instances);
+    }
+
+    fn draw_model_geometry_instanced(
+        &mut self,
+        model: &'b Model,
+        instances: Range<u32>,
+        uniforms: &'b wgpu::BindGroup,
+    ) {
+        for mesh in &model.meshes {
+            let material = &model.materials[mesh.material];
+            self.draw_mesh_geometry_instanced(mesh, material, instances.clone(), uniforms);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn point_light_layout() {
+        // The lighting shader reads lights as two vec4s
+        assert_eq!(std::mem::size_of::<PointLightRaw>(), 32);
+        assert_eq!(std::mem::size_of::<DeferredUniforms>(), 112);
+
+        let light = PointLight {
+            position: Vector3::new(1.0, 2.0, 3.0),
+            radius: 10.0,
+            color: Vector3::new(1.0, 0.5, 0.25),
+            intensity: 4.0,
+        };
+        let raw = light.to_raw();
+        let floats: &[f32] = bytemuck::cast_slice(std::slice::from_ref(&raw));
+        assert_eq!(floats, &[1.0, 2.0, 3.0, 10.0, 1.0, 0.5, 0.25, 4.0]);
+    }
+}
@@ -0,0 +1,108 @@
+#version 450
+
+layout(location=0) in vec2 v_ndc;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_albedo;
+layout(set=0, binding=1) uniform texture2D t_normal;
+layout(set=0, binding=2) uniform texture2D t_material;
+layout(set=0, binding=3) uniform texture2D t_depth;
+layout(set=0, binding=4) uniform sampler s_gbuffer;
+layout(set=0, binding=5) uniform texture2D t_ao;
+
+layout(set=1, binding=0)
+uniform DeferredUniforms {
+    mat4 u_inv_view_proj;
+    vec4 u_view_position;
+    vec4 u_ambient;
+    uint u_light_count;
+    // Matches framework::GBufferView
+    uint u_debug_view;
+    uint u_use_ao;
+};
+
+struct PointLight {
+    // xyz is the position, w the radius
+    vec4 position;
+    // rgb is the color, a the intensity
+    vec4 color;
+};
+
+layout(set=1, binding=1)
+buffer Lights {
+    PointLight s_lights[];
+};
+
+const float MAX_SHININESS = 256.0;
+
+// Falls off with the inverse square of the distance, but reaches
+// zero at the light's radius so lights far away can be skipped
+float attenuation(float dist, float radius) {
+    float ratio = dist / radius;
+    float window = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+    return window * window / (dist * dist + 1.0);
+}
+
+void main() {
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    vec4 albedo = texelFetch(sampler2D(t_albedo, s_gbuffer), pixel, 0);
+    vec3 normal = texelFetch(sampler2D(t_normal, s_gbuffer), pixel, 0).xyz;
+    vec4 material = texelFetch(sampler2D(t_material, s_gbuffer), pixel, 0);
+    float depth = texelFetch(sampler2D(t_depth, s_gbuffer), pixel, 0).r;
+    float ao = u_use_ao != 0u ? texelFetch(sampler2D(t_ao, s_gbuffer), pixel, 0).r : 1.0;
+
+    vec4 world = u_inv_view_proj * vec4(v_ndc, depth, 1.0);
+    vec3 position = world.xyz / world.w;
+
+    if (u_debug_view == 1u) {
+        f_color = vec4(albedo.rgb, 1.0);
+        return;
+    } else if (u_debug_view == 2u) {
+        f_color = vec4(normal * 0.5 + 0.5, 1.0);
+        return;
+    } else if (u_debug_view == 3u) {
+        f_color = vec4(material.rgb, 1.0);
+        return;
+    } else if (u_debug_view == 4u) {
+        // Most of the depth range is bunched up near 1
+        f_color = vec4(vec3(pow(depth, 32.0)), 1.0);
+        return;
+    } else if (u_debug_view == 5u) {
+        f_color = vec4(fract(position), 1.0);
+        return;
+    } else if (u_debug_view == 6u) {
+        f_color = vec4(vec3(ao), 1.0);
+        return;
+    }
+
+    // Nothing was drawn here
+    if (depth >= 1.0) {
+        f_color = vec4(0.0);
+        return;
+    }
+
+    float specular_strength = material.r;
+    float shininess = max(material.g * MAX_SHININESS, 1.0);
+    vec3 view_dir = normalize(u_view_position.xyz - position);
+
+    vec3 color = u_ambient.rgb * albedo.rgb * ao;
+    for (uint i = 0u; i < u_light_count; i++) {
+        PointLight light = s_lights[i];
+        vec3 to_light = light.position.xyz - position;
+        float dist = length(to_light);
+        if (dist > light.position.w) {
+            continue;
+        }
+        vec3 light_dir = to_light / dist;
+        vec3 half_dir = normalize(view_dir + light_dir);
+
+        float diffuse = max(dot(normal, light_dir), 0.0);
+        float specular = pow(max(dot(normal, half_dir), 0.0), shininess) * specular_strength;
+        vec3 radiance = light.color.rgb * light.color.a * attenuation(dist, light.position.w);
+
+        color += (albedo.rgb * diffuse + specular) * radiance;
+    }
+
+    f_color = vec4(color, albedo.a);
+}
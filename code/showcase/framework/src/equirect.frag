@@ -0,0 +1,36 @@
+#version 450
+
+layout(location=0) in vec2 v_ndc;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_equirect;
+layout(set=0, binding=1) uniform sampler s_equirect;
+layout(set=0, binding=2)
+uniform Face {
+    uint u_face;
+};
+
+const float PI = 3.14159265359;
+
+// Maps a texel on one of the cube faces to the direction the
+// hardware uses to look it up. s goes right and t goes down.
+vec3 face_direction(uint face, float s, float t) {
+    switch (face) {
+        case 0: return vec3(1.0, -t, -s);
+        case 1: return vec3(-1.0, -t, s);
+        case 2: return vec3(s, 1.0, t);
+        case 3: return vec3(s, -1.0, -t);
+        case 4: return vec3(s, -t, 1.0);
+        default: return vec3(-s, -t, -1.0);
+    }
+}
+
+void main() {
+    vec3 dir = normalize(face_direction(u_face, v_ndc.x, -v_ndc.y));
+    vec2 uv = vec2(
+        atan(dir.z, dir.x) / (2.0 * PI) + 0.5,
+        acos(clamp(dir.y, -1.0, 1.0)) / PI
+    );
+    f_color = vec4(texture(sampler2D(t_equirect, s_equirect), uv).rgb, 1.0);
+}
@@ -0,0 +1,12 @@
+#version 450
+
+// Draws a single triangle that covers the whole screen. Call
+// with draw(0..3, 0..1) and no vertex buffers.
+
+layout(location=0) out vec2 v_ndc;
+
+void main() {
+    vec2 uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    v_ndc = uv * 2.0 - 1.0;
+    gl_Position = vec4(v_ndc, 0.0, 1.0);
+}
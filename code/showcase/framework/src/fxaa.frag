@@ -0,0 +1,61 @@
+#version 450
+
+layout(location=0) in vec2 v_ndc;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_input;
+layout(set=0, binding=1) uniform sampler s_input;
+layout(set=0, binding=2)
+uniform Params {
+    float u_span_max;
+    float u_reduce_mul;
+    float u_reduce_min;
+};
+
+const vec3 LUMA = vec3(0.299, 0.587, 0.114);
+
+// The "simple" FXAA from Timothy Lottes' original paper. It expects
+// the input to already be tonemapped.
+void main() {
+    vec2 uv = vec2(v_ndc.x, -v_ndc.y) * 0.5 + 0.5;
+    vec2 texel = 1.0 / vec2(textureSize(sampler2D(t_input, s_input), 0));
+
+    vec4 center = texture(sampler2D(t_input, s_input), uv);
+    float luma_nw = dot(texture(sampler2D(t_input, s_input), uv + vec2(-1.0, -1.0) * texel).rgb, LUMA);
+    float luma_ne = dot(texture(sampler2D(t_input, s_input), uv + vec2(1.0, -1.0) * texel).rgb, LUMA);
+    float luma_sw = dot(texture(sampler2D(t_input, s_input), uv + vec2(-1.0, 1.0) * texel).rgb, LUMA);
+    float luma_se = dot(texture(sampler2D(t_input, s_input), uv + vec2(1.0, 1.0) * texel).rgb, LUMA);
+    float luma_m = dot(center.rgb, LUMA);
+
+    float luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    float luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    // Blur along the edge, which is perpendicular to the gradient
+    vec2 dir = vec2(
+        -((luma_nw + luma_ne) - (luma_sw + luma_se)),
+        (luma_nw + luma_sw) - (luma_ne + luma_se)
+    );
+    float dir_reduce = max(
+        (luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * u_reduce_mul,
+        u_reduce_min
+    );
+    float rcp_dir_min = 1.0 / (min(abs(dir.x), abs(dir.y)) + dir_reduce);
+    dir = clamp(dir * rcp_dir_min, vec2(-u_span_max), vec2(u_span_max)) * texel;
+
+    vec3 rgb_a = 0.5 * (
+        texture(sampler2D(t_input, s_input), uv + dir * (1.0 / 3.0 - 0.5)).rgb +
+        texture(sampler2D(t_input, s_input), uv + dir * (2.0 / 3.0 - 0.5)).rgb
+    );
+    vec3 rgb_b = rgb_a * 0.5 + 0.25 * (
+        texture(sampler2D(t_input, s_input), uv + dir * -0.5).rgb +
+        texture(sampler2D(t_input, s_input), uv + dir * 0.5).rgb
+    );
+
+    // If the wider sample strayed past the local contrast it
+    // crossed another edge, so fall back to the narrower one
+    float luma_b = dot(rgb_b, LUMA);
+    vec3 color = (luma_b < luma_min || luma_b > luma_max) ? rgb_a : rgb_b;
+
+    f_color = vec4(color, center.a);
+}
@@ -0,0 +1,37 @@
+#version 450
+
+// Fills the G-buffer for framework::DeferredRenderer. Use with
+// pbr.vert, which outputs world space normals.
+
+layout(location=0) in vec2 v_tex_coords;
+layout(location=1) in vec3 v_position;
+layout(location=2) in vec3 v_normal;
+layout(location=3) in vec3 v_tangent;
+layout(location=4) in vec3 v_bitangent;
+
+layout(location=0) out vec4 f_albedo;
+layout(location=1) out vec4 f_normal;
+layout(location=2) out vec4 f_material;
+
+layout(set=0, binding=0) uniform texture2D t_diffuse;
+layout(set=0, binding=1) uniform sampler s_diffuse;
+layout(set=0, binding=2) uniform texture2D t_normal;
+layout(set=0, binding=3) uniform sampler s_normal;
+
+// framework::Material doesn't have any specular properties, so every
+// surface gets the same ones
+const float SPECULAR_STRENGTH = 0.5;
+const float SHININESS = 32.0;
+const float MAX_SHININESS = 256.0;
+
+void main() {
+    vec4 albedo = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords);
+
+    vec3 tangent_normal = texture(sampler2D(t_normal, s_normal), v_tex_coords).rgb * 2.0 - 1.0;
+    mat3 tbn = mat3(normalize(v_tangent), normalize(v_bitangent), normalize(v_normal));
+    vec3 normal = normalize(tbn * tangent_normal);
+
+    f_albedo = albedo;
+    f_normal = vec4(normal, 0.0);
+    f_material = vec4(SPECULAR_STRENGTH, SHININESS / MAX_SHININESS, 0.0, 1.0);
+}
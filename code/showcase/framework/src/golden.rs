@@ -0,0 +1,441 @@
+use anyhow::*;
+use std::path::{Path, PathBuf};
+
+use crate::capture::read_image;
+use crate::texture::Texture;
+
+/**
+ * A device without a window, for compute work and tests. The backend
+ * comes from `WGPU_BACKEND` (`vulkan`, `gl`, `metal`, `dx12` or
+ * `dx11`) and defaults to Vulkan and GL.
+ */
+pub struct Headless {
+    pub device: wgpu::Device,
+    pub queue: wgpu::Queue,
+    pub info: wgpu::AdapterInfo,
+}
+
+impl Headless {
+    /// Opens a software adapter such as lavapipe or llvmpipe so results
+    /// match across machines. It's an error if there isn't one.
+    pub async fn new() -> Result<Self> {
+        let adapter = adapters()?
+            .into_iter()
+            .find(|a| a.get_info().device_type == wgpu::DeviceType::Cpu)
+            .context("No software adapter found. Install lavapipe or llvmpipe.")?;
+        Self::open(adapter).await
+    }
+
+    /// Like [Headless::new] but falls back to a hardware adapter, for
+    /// compute work that doesn't have to match bit for bit
+    pub async fn any_adapter() -> Result<Self> {
+        let mut adapters = adapters()?;
+        // Software adapters go first
+        adapters.sort_by_key(|a| a.get_info().device_type != wgpu::DeviceType::Cpu);
+        let adapter = adapters.into_iter().next().context("No adapter found")?;
+        Self::open(adapter).await
+    }
+
+    async fn open(adapter: wgpu::Adapter) -> Result<Self> {
+        let info = adapter.get_info();
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    features: wgpu::Features::empty(),
+                    limits: wgpu::Limits::default(),
+                    shader_validation: true,
+                },
+                None,
+            )
+            .await?;
+        Ok(Self {
+            device,
+            queue,
+            info,
+        })
+    }
+}
+
+/**
+ * Checks that every adapter a window could end up on is a software
+ * one, and returns it. Windowed apps pick their own adapter, so the
+ * only way to get a reference image out of them that matches across
+ * machines is to hide the GPU, e.g. by pointing `VK_ICD_FILENAMES` at
+ * lavapipe.
+ */
+pub fn software_adapter() -> Result<wgpu::AdapterInfo> {
+    let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
+    let adapters = instance
+        .enumerate_adapters(wgpu::BackendBit::PRIMARY)
+        .map(|a| a.get_info())
+        .collect::<Vec<_>>();
+    if let Some(info) = adapters
+        .iter()
+        .find(|info| info.device_type != wgpu::DeviceType::Cpu)
+    {
+        bail!(
+            "{} isn't a software adapter. Set VK_ICD_FILENAMES to lavapipe's ICD to hide it.",
+            info.name
+        );
+    }
+    adapters
+        .into_iter()
+        .next()
+        .context("No software adapter found. Install lavapipe or llvmpipe.")
+}
+
+/**
+ * Stands in for a swap chain frame when rendering reference images.
+ * Draw into [GoldenTarget::view] and read the result back with
+ * [GoldenTarget::read].
+ */
+pub struct GoldenTarget {
+    pub texture: Texture<'static>,
+}
+
+impl GoldenTarget {
+    /// `format` has to be 8 bit RGBA or BGRA, like a swap chain's
+    pub fn new(device: &wgpu::Device, format: wgpu::TextureFormat, width: u32, height: u32) -> Self {
+        let texture = Texture::from_descriptor(
+            device,
+            wgpu::TextureDescriptor {
+                label: Some("GoldenTarget::texture"),
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+            },
+        );
+        Self { texture }
+    }
+
+    /// A target the same format and size as `sc_desc`'s frames
+    pub fn for_swap_chain(device: &wgpu::Device, sc_desc: &wgpu::SwapChainDescriptor) -> Self {
+        Self::new(device, sc_desc.format, sc_desc.width, sc_desc.height)
+    }
+
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.texture.view
+    }
+
+    /// Waits for everything drawn so far and reads it back
+    pub fn read(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<image::RgbaImage> {
+        read_image(device, queue, &self.texture)
+    }
+}
+
+fn adapters() -> Result<Vec<wgpu::Adapter>> {
+    let backends = backends_from_env()?;
+    let instance = wgpu::Instance::new(backends);
+    Ok(instance.enumerate_adapters(backends).collect())
+}
+
+fn backends_from_env() -> Result<wgpu::BackendBit> {
+    let backend = match std::env::var("WGPU_BACKEND").ok() {
+        Some(backend) => backend.to_lowercase(),
+        None => return Ok(wgpu::BackendBit::VULKAN | wgpu::BackendBit::GL),
+    };
+    Ok(match backend.as_str() {
+        "vulkan" => wgpu::BackendBit::VULKAN,
+        "gl" => wgpu::BackendBit::GL,
+        "metal" => wgpu::BackendBit::METAL,
+        "dx12" => wgpu::BackendBit::DX12,
+        "dx11" => wgpu::BackendBit::DX11,
+        other => bail!("Unknown WGPU_BACKEND {:?}", other),
+    })
+}
+
+/**
+ * How far a render can drift from its reference. A pixel mismatches
+ * if any channel is more than `per_channel` off, and the image fails
+ * if more than `max_mismatched` of its pixels do or if its structural
+ * similarity drops below `min_ssim`.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Tolerance {
+    pub per_channel: u8,
+    /// A fraction of the pixels, from 0 to 1
+    pub max_mismatched: f32,
+    pub min_ssim: f32,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Self {
+            per_channel: 2,
+            max_mismatched: 0.001,
+            min_ssim: 0.98,
+        }
+    }
+}
+
+pub struct Comparison {
+    pub mismatched: usize,
+    pub largest_difference: u8,
+    pub ssim: f32,
+    /// The reference in gray with mismatched pixels in red
+    pub diff: image::RgbaImage,
+}
+
+impl Comparison {
+    pub fn passes(&self, tolerance: &Tolerance) -> bool {
+        let total = (self.diff.width() * self.diff.height()).max(1) as f32;
+        self.mismatched as f32 / total <= tolerance.max_mismatched
+            && self.ssim >= tolerance.min_ssim
+    }
+}
+
+pub fn compare_images(
+    reference: &image::RgbaImage,
+    actual: &image::RgbaImage,
+    tolerance: &Tolerance,
+) -> Result<Comparison> {
+    if reference.dimensions() != actual.dimensions() {
+        bail!(
+            "Reference is {:?} but the render is {:?}",
+            reference.dimensions(),
+            actual.dimensions()
+        );
+    }
+
+    let mut mismatched = 0;
+    let mut largest_difference = 0;
+    let mut diff = image::RgbaImage::new(reference.width(), reference.height());
+    for ((a, b), d) in reference
+        .pixels()
+        .zip(actual.pixels())
+        .zip(diff.pixels_mut())
+    {
+        let difference = (0..4)
+            .map(|c| a[c].abs_diff(b[c]))
+            .max()
+            .unwrap_or(0);
+        largest_difference = largest_difference.max(difference);
+        *d = if difference > tolerance.per_channel {
+            mismatched += 1;
+            image::Rgba([255, 0, 0, 255])
+        } else {
+            let gray = (luma(a) * 0.25 * 255.0) as u8;
+            image::Rgba([gray, gray, gray, 255])
+        };
+    }
+
+    Ok(Comparison {
+        mismatched,
+        largest_difference,
+        ssim: ssim(reference, actual),
+        diff,
+    })
+}
+
+fn luma(pixel: &image::Rgba<u8>) -> f32 {
+    (0.2126 * pixel[0] as f32 + 0.7152 * pixel[1] as f32 + 0.0722 * pixel[2] as f32) / 255.0
+}
+
+/// The side of the windows [ssim] averages over
+const SSIM_WINDOW: u32 = 8;
+
+/// Mean structural similarity of the luma of `a` and `b`, over 8x8
+/// windows that overlap by half. 1 means identical.
+pub fn ssim(a: &image::RgbaImage, b: &image::RgbaImage) -> f32 {
+    const C1: f32 = 0.01 * 0.01;
+    const C2: f32 = 0.03 * 0.03;
+
+    let (width, height) = a.dimensions();
+    let window = SSIM_WINDOW.min(width).min(height);
+    if window == 0 {
+        return 1.0;
+    }
+    let step = (window / 2).max(1);
+
+    let mut total = 0.0;
+    let mut count = 0;
+    let mut y = 0;
+    while y + window <= height {
+        let mut x = 0;
+        while x + window <= width {
+            let n = (window * window) as f32;
+            let (mut sum_a, mut sum_b) = (0.0, 0.0);
+            let (mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0);
+            for wy in y..y + window {
+                for wx in x..x + window {
+                    let la = luma(a.get_pixel(wx, wy));
+                    let lb = luma(b.get_pixel(wx, wy));
+                    sum_a += la;
+                    sum_b += lb;
+                    sum_aa += la * la;
+                    sum_bb += lb * lb;
+                    sum_ab += la * lb;
+                }
+            }
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            count += 1;
+            x += step;
+        }
+        y += step;
+    }
+    total / count as f32
+}
+
+/**
+ * Compares renders against the PNGs in `reference_dir`. Failures save
+ * `{name}-actual.png` and `{name}-diff.png` to `output_dir`. With
+ * `GOLDEN_BLESS` set, renders overwrite the references instead.
+ */
+pub struct GoldenImages {
+    pub reference_dir: PathBuf,
+    pub output_dir: PathBuf,
+    pub tolerance: Tolerance,
+    pub bless: bool,
+}
+
+impl GoldenImages {
+    pub fn new<P: AsRef<Path>>(reference_dir: P) -> Self {
+        let reference_dir = reference_dir.as_ref().to_path_buf();
+        Self {
+            output_dir: reference_dir.join("failures"),
+            reference_dir,
+            tolerance: Tolerance::default(),
+            bless: std::env::var_os("GOLDEN_BLESS").is_some(),
+        }
+    }
+
+    pub fn reference_path(&self, name: &str) -> PathBuf {
+        self.reference_dir.join(format!("{}.png", name))
+    }
+
+    pub fn check(&self, name: &str, actual: &image::RgbaImage) -> Result<()> {
+        self.check_with(name, actual, &self.tolerance)
+    }
+
+    /// Like [GoldenImages::check] for scenes that need a looser or
+    /// tighter [Tolerance] than the rest
+    pub fn check_with(
+        &self,
+        name: &str,
+        actual: &image::RgbaImage,
+        tolerance: &Tolerance,
+    ) -> Result<()> {
+        let reference_path = self.reference_path(name);
+        if self.bless {
+            std::fs::create_dir_all(&self.reference_dir)?;
+            actual.save(&reference_path)?;
+            log::info!("Blessed {:?}", reference_path);
+            return Ok(());
+        }
+
+        let reference = image::open(&reference_path)
+            .with_context(|| {
+                format!(
+                    "Couldn't load {:?}. Run with GOLDEN_BLESS=1 to create it.",
+                    reference_path
+                )
+            })?
+            .to_rgba8();
+        let comparison = compare_images(&reference, actual, tolerance)?;
+        if comparison.passes(tolerance) {
+            return Ok(());
+        }
+
+        std::fs::create_dir_all(&self.output_dir)?;
+        let actual_path = self.output_dir.join(format!("{}-actual.png", name));
+        let diff_path = self.output_dir.join(format!("{}-diff.png", name));
+        actual.save(&actual_path)?;
+        comparison.diff.save(&diff_path)?;
+        bail!(
+            "{} doesn't match its reference: {} pixels off by up to {}, SSIM {:.4}. See {:?}",
+            name,
+            comparison.mismatched,
+            comparison.largest_difference,
+            comparison.ssim,
+            diff_path
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: u32, height: u32) -> image::RgbaImage {
+        image::RgbaImage::from_fn(width, height, |x, y| {
+            image::Rgba([(x * 8) as u8, (y * 8) as u8, 128, 255])
+        })
+    }
+
+    #[test]
+    fn identical_images_pass() {
+        let image = gradient(32, 32);
+        let comparison = compare_images(&image, &image, &Tolerance::default()).unwrap();
+        assert_eq!(comparison.mismatched, 0);
+        assert_eq!(comparison.largest_difference, 0);
+        assert!((comparison.ssim - 1.0).abs() < 1e-4);
+        assert!(comparison.passes(&Tolerance::default()));
+    }
+
+    #[test]
+    fn small_differences_are_tolerated() {
+        let reference = gradient(32, 32);
+        let mut actual = reference.clone();
+        for pixel in actual.pixels_mut() {
+            pixel[0] = pixel[0].saturating_add(1);
+        }
+        let comparison = compare_images(&reference, &actual, &Tolerance::default()).unwrap();
+        assert_eq!(comparison.mismatched, 0);
+        assert_eq!(comparison.largest_difference, 1);
+        assert!(comparison.passes(&Tolerance::default()));
+    }
+
+    #[test]
+    fn changes_fail_and_show_in_the_diff() {
+        let reference = gradient(32, 32);
+        let mut actual = reference.clone();
+        for y in 8..16 {
+            for x in 8..16 {
+                actual.put_pixel(x, y, image::Rgba([255, 255, 255, 255]));
+            }
+        }
+        let comparison = compare_images(&reference, &actual, &Tolerance::default()).unwrap();
+        assert_eq!(comparison.mismatched, 64);
+        assert!(comparison.ssim < 0.98);
+        assert!(!comparison.passes(&Tolerance::default()));
+        assert_eq!(comparison.diff.get_pixel(10, 10).0, [255, 0, 0, 255]);
+        assert_ne!(comparison.diff.get_pixel(20, 20).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn sizes_must_match() {
+        assert!(compare_images(&gradient(8, 8), &gradient(8, 9), &Tolerance::default()).is_err());
+    }
+
+    #[test]
+    fn blessing() {
+        let dir = std::env::temp_dir().join(format!("golden-test-{}", std::process::id()));
+        let mut golden = GoldenImages::new(&dir);
+        golden.bless = false;
+        let image = gradient(16, 16);
+        assert!(golden.check("scene", &image).is_err());
+
+        golden.bless = true;
+        golden.check("scene", &image).unwrap();
+        golden.bless = false;
+        golden.check("scene", &image).unwrap();
+
+        let other = image::RgbaImage::from_pixel(16, 16, image::Rgba([0, 0, 0, 255]));
+        assert!(golden.check("scene", &other).is_err());
+        assert!(golden.output_dir.join("scene-diff.png").exists());
+        assert!(golden.output_dir.join("scene-actual.png").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,481 @@
+use anyhow::*;
+use cgmath::*;
+use imgui::{im_str, Condition, ImString};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use winit::window::Window;
+
+use crate::camera::{Camera, Projection};
+use crate::light::Light;
+use crate::texture::Texture;
+use crate::{Demo, Display};
+
+/// How many frames [FrameStats] averages over
+pub const FRAME_HISTORY: usize = 120;
+
+/// The widest a texture preview is drawn
+const PREVIEW_WIDTH: f32 = 256.0;
+
+/// Recent frame times, for the stats window
+#[derive(Debug, Clone)]
+pub struct FrameStats {
+    frame_times: VecDeque<f32>,
+}
+
+impl Default for FrameStats {
+    fn default() -> Self {
+        Self {
+            frame_times: VecDeque::with_capacity(FRAME_HISTORY),
+        }
+    }
+}
+
+impl FrameStats {
+    pub fn push(&mut self, dt: Duration) {
+        if self.frame_times.len() == FRAME_HISTORY {
+            self.frame_times.pop_front();
+        }
+        self.frame_times.push_back(dt.as_secs_f32() * 1000.0);
+    }
+
+    /// Frame times in milliseconds, oldest first
+    pub fn frame_times(&self) -> Vec<f32> {
+        self.frame_times.iter().copied().collect()
+    }
+
+    pub fn average_ms(&self) -> f32 {
+        if self.frame_times.is_empty() {
+            return 0.0;
+        }
+        self.frame_times.iter().sum::<f32>() / self.frame_times.len() as f32
+    }
+
+    pub fn fps(&self) -> f32 {
+        let average = self.average_ms();
+        if average > 0.0 {
+            1000.0 / average
+        } else {
+            0.0
+        }
+    }
+
+    /// The fastest and slowest frames in milliseconds
+    pub fn range_ms(&self) -> (f32, f32) {
+        self.frame_times
+            .iter()
+            .fold(None, |range, &t| match range {
+                Some((min, max)) => Some((t.min(min), t.max(max))),
+                None => Some((t, t)),
+            })
+            .unwrap_or((0.0, 0.0))
+    }
+}
+
+/**
+ * What [Demo::ui] gets to build its GUI with, besides the
+ * [imgui::Ui] itself. The built-in panels are methods on this.
+ */
+pub struct Panels<'a> {
+    pub display: &'a Display,
+    renderer: &'a mut imgui_wgpu::Renderer,
+    previews: &'a mut HashMap<String, (imgui::TextureId, wgpu::Extent3d)>,
+}
+
+impl<'a> Panels<'a> {
+    /// A window for moving `camera` around and changing `projection`.
+    /// Returns true if anything changed.
+    pub fn camera(
+        &mut self,
+        ui: &imgui::Ui,
+        camera: &mut Camera,
+        projection: &mut Projection,
+    ) -> bool {
+        let mut changed = false;
+        imgui::Window::new(im_str!("Camera"))
+            .position([10.0, 170.0], Condition::FirstUseEver)
+            .size([300.0, 170.0], Condition::FirstUseEver)
+            .build(ui, || {
+                let mut position: [f32; 3] = camera.position.into();
+                if ui.input_float3(im_str!("Position"), &mut position).build() {
+                    camera.position = position.into();
+                    changed = true;
+                }
+                changed |= input_degrees(ui, im_str!("Yaw"), &mut camera.yaw);
+                changed |= input_degrees(ui, im_str!("Pitch"), &mut camera.pitch);
+                ui.separator();
+                changed |= input_degrees(ui, im_str!("Field of view"), &mut projection.fovy);
+                changed |= ui
+                    .input_float(im_str!("Near"), &mut projection.znear)
+                    .build();
+                changed |= ui.input_float(im_str!("Far"), &mut projection.zfar).build();
+                // Keep the projection usable whatever gets typed in
+                projection.fovy.0 = projection.fovy.0.clamp(0.01, 3.1);
+                projection.znear = projection.znear.max(0.0001);
+                projection.zfar = projection.zfar.max(projection.znear + 0.0001);
+            });
+        changed
+    }
+
+    /// A window for moving and recoloring `light`. Returns true if
+    /// anything changed.
+    pub fn light(&mut self, ui: &imgui::Ui, light: &mut Light) -> bool {
+        let mut changed = false;
+        let mut position: [f32; 3] = light.position().into();
+        let mut color: [f32; 3] = light.color().into();
+        imgui::Window::new(im_str!("Light"))
+            .position([10.0, 350.0], Condition::FirstUseEver)
+            .size([300.0, 80.0], Condition::FirstUseEver)
+            .build(ui, || {
+                changed |= ui.input_float3(im_str!("Position"), &mut position).build();
+                changed |= imgui::ColorEdit::new(im_str!("Color"), &mut color).build(ui);
+            });
+        if changed {
+            light.set(&self.display.queue, position.into(), color.into());
+        }
+        changed
+    }
+
+    /**
+     * Shows the first mip of `texture` in the "Textures" window under
+     * `label`. The texture is copied every time this is called so
+     * render targets stay up to date. It needs
+     * [wgpu::TextureUsage::COPY_SRC] and has to be a single sampled
+     * color texture.
+     */
+    pub fn texture(&mut self, ui: &imgui::Ui, label: &str, texture: &Texture) {
+        imgui::Window::new(im_str!("Textures"))
+            .position([320.0, 10.0], Condition::FirstUseEver)
+            .size([PREVIEW_WIDTH + 20.0, 300.0], Condition::FirstUseEver)
+            .build(ui, || {
+                ui.text(label);
+                match self.preview(label, texture) {
+                    Some((id, size)) => {
+                        imgui::Image::new(id, preview_size(size)).build(ui);
+                    }
+                    None => ui.text(format!("Can't preview {:?}", texture.desc.format)),
+                }
+            });
+    }
+
+    fn preview(
+        &mut self,
+        label: &str,
+        texture: &Texture,
+    ) -> Option<(imgui::TextureId, wgpu::Extent3d)> {
+        let desc = &texture.desc;
+        if desc.sample_count != 1
+            || desc.format == Texture::DEPTH_FORMAT
+            || !desc.usage.contains(wgpu::TextureUsage::COPY_SRC)
+        {
+            return None;
+        }
+        let size = wgpu::Extent3d {
+            depth: 1,
+            ..desc.size
+        };
+        let existing = self.previews.get(label).copied();
+        let id = match existing {
+            Some((id, old_size)) if old_size == size => id,
+            _ => {
+                let preview = imgui_wgpu::Texture::new(
+                    &self.display.device,
+                    self.renderer,
+                    imgui_wgpu::TextureConfig {
+                        size,
+                        label: Some(label),
+                        format: Some(desc.format),
+                        ..imgui_wgpu::TextureConfig::new(size.width, size.height)
+                    },
+                );
+                let id = match existing {
+                    Some((id, _)) => {
+                        self.renderer.textures.replace(id, preview);
+                        id
+                    }
+                    None => self.renderer.textures.insert(preview),
+                };
+                self.previews.insert(label.to_string(), (id, size));
+                id
+            }
+        };
+
+        let preview = self.renderer.textures.get(id)?;
+        let mut encoder =
+            self.display
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Panels::texture"),
+                });
+        encoder.copy_texture_to_texture(
+            wgpu::TextureCopyView {
+                texture: &texture.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::TextureCopyView {
+                texture: preview.texture(),
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            size,
+        );
+        self.display.queue.submit(std::iter::once(encoder.finish()));
+        Some((id, size))
+    }
+}
+
+fn input_degrees(ui: &imgui::Ui, label: &imgui::ImStr, angle: &mut Rad<f32>) -> bool {
+    let mut degrees = Deg::from(*angle).0;
+    if ui.input_float(label, &mut degrees).build() {
+        *angle = Deg(degrees).into();
+        true
+    } else {
+        false
+    }
+}
+
+/// Scales `size` down to fit [PREVIEW_WIDTH], keeping its aspect ratio
+fn preview_size(size: wgpu::Extent3d) -> [f32; 2] {
+    let width = size.width.max(1) as f32;
+    let height = size.height.max(1) as f32;
+    let scale = (PREVIEW_WIDTH / width).min(1.0);
+    [width * scale, height * scale]
+}
+
+/**
+ * The imgui layer [crate::run] draws over demos that set [Demo::GUI].
+ * It's drawn after [Display::end_frame], so screenshots and recordings
+ * don't include it. F1 hides and shows it.
+ */
+pub struct Gui {
+    context: imgui::Context,
+    platform: imgui_winit_support::WinitPlatform,
+    renderer: imgui_wgpu::Renderer,
+    previews: HashMap<String, (imgui::TextureId, wgpu::Extent3d)>,
+    pub stats: FrameStats,
+    pub visible: bool,
+}
+
+impl Gui {
+    pub fn new(window: &Window, display: &Display) -> Self {
+        let mut context = imgui::Context::create();
+        // Demos shouldn't leave imgui.ini files lying around
+        context.set_ini_filename(None);
+        let mut platform = imgui_winit_support::WinitPlatform::init(&mut context);
+        platform.attach_window(
+            context.io_mut(),
+            window,
+            imgui_winit_support::HiDpiMode::Default,
+        );
+        context
+            .fonts()
+            .add_font(&[imgui::FontSource::DefaultFontData { config: None }]);
+        let renderer = imgui_wgpu::Renderer::new(
+            &mut context,
+            &display.device,
+            &display.queue,
+            imgui_wgpu::RendererConfig::new().set_texture_format(display.sc_desc.format),
+        );
+        Self {
+            context,
+            platform,
+            renderer,
+            previews: HashMap::new(),
+            stats: FrameStats::default(),
+            visible: true,
+        }
+    }
+
+    /// Passes `event` to imgui. Returns true if imgui wants it to
+    /// itself, i.e. the demo should ignore it.
+    pub fn handle_event<T>(&mut self, window: &Window, event: &Event<T>) -> bool {
+        if let Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F1),
+                            ..
+                        },
+                    ..
+                },
+            ..
+        } = event
+        {
+            self.visible = !self.visible;
+        }
+        self.platform
+            .handle_event(self.context.io_mut(), window, event);
+
+        if !self.visible {
+            return false;
+        }
+        let io = self.context.io();
+        match event {
+            Event::WindowEvent { event, .. } => match event {
+                WindowEvent::KeyboardInput { .. } | WindowEvent::ReceivedCharacter(_) => {
+                    io.want_capture_keyboard
+                }
+                WindowEvent::MouseInput { .. }
+                | WindowEvent::MouseWheel { .. }
+                | WindowEvent::CursorMoved { .. } => io.want_capture_mouse,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Renders `demo` with the GUI on top
+    pub fn render<D: Demo>(
+        &mut self,
+        display: &mut Display,
+        demo: &mut D,
+        window: &Window,
+        dt: Duration,
+    ) -> Result<()> {
+        self.stats.push(dt);
+        if !self.visible {
+            demo.render(display);
+            return Ok(());
+        }
+
+        let Self {
+            context,
+            platform,
+            renderer,
+            previews,
+            stats,
+            ..
+        } = self;
+        // imgui doesn't like frames that take no time at all
+        context.io_mut().delta_time = dt.as_secs_f32().max(1e-6);
+        platform.prepare_frame(context.io_mut(), window)?;
+        let ui = context.frame();
+        stats_window(&ui, display, stats);
+        demo.ui(
+            &ui,
+            &mut Panels {
+                display,
+                renderer,
+                previews,
+            },
+        );
+        platform.prepare_render(&ui, window);
+        let draw_data = ui.render();
+
+        display.hold_frames = true;
+        demo.render(display);
+        display.hold_frames = false;
+        let frame = match display.held_frame.take() {
+            Some(frame) => frame,
+            // The demo didn't go through Display::end_frame
+            None => return Ok(()),
+        };
+
+        let mut encoder = display
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Gui::render"),
+            });
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: &frame.output.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            renderer
+                .render(draw_data, &display.queue, &display.device, &mut pass)
+                .map_err(|e| anyhow!("Couldn't draw the GUI: {:?}", e))?;
+        }
+        display.queue.submit(std::iter::once(encoder.finish()));
+        Ok(())
+    }
+}
+
+fn stats_window(ui: &imgui::Ui, display: &Display, stats: &FrameStats) {
+    imgui::Window::new(im_str!("Stats"))
+        .position([10.0, 10.0], Condition::FirstUseEver)
+        .size([300.0, 150.0], Condition::FirstUseEver)
+        .build(ui, || {
+            let (min, max) = stats.range_ms();
+            ui.text(format!(
+                "{:.0} fps, {:.2} ms ({:.2} - {:.2})",
+                stats.fps(),
+                stats.average_ms(),
+                min,
+                max
+            ));
+            let times = stats.frame_times();
+            let overlay = ImString::new(format!("{:.2} ms", times.last().unwrap_or(&0.0)));
+            ui.plot_lines(im_str!("##frame times"), &times)
+                .overlay_text(&overlay)
+                .scale_min(0.0)
+                .graph_size([0.0, 40.0])
+                .build();
+            ui.text(format!(
+                "Frame {}, {}x{}, {}x MSAA",
+                display.frame_number(),
+                display.sc_desc.width,
+                display.sc_desc.height,
+                display.sample_count()
+            ));
+            match display.recording.directory() {
+                Some(directory) => ui.text(format!("Recording to {:?}", directory)),
+                None => ui.text("F10 to record, F12 for a screenshot"),
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_stats() {
+        let mut stats = FrameStats::default();
+        assert_eq!(stats.fps(), 0.0);
+        assert_eq!(stats.range_ms(), (0.0, 0.0));
+
+        stats.push(Duration::from_millis(10));
+        stats.push(Duration::from_millis(30));
+        assert!((stats.average_ms() - 20.0).abs() < 1e-3);
+        assert!((stats.fps() - 50.0).abs() < 1e-2);
+        assert_eq!(stats.range_ms(), (10.0, 30.0));
+    }
+
+    #[test]
+    fn frame_history_is_capped() {
+        let mut stats = FrameStats::default();
+        stats.push(Duration::from_millis(100));
+        for _ in 0..FRAME_HISTORY {
+            stats.push(Duration::from_millis(5));
+        }
+        // The slow frame has been forgotten
+        assert_eq!(stats.frame_times().len(), FRAME_HISTORY);
+        assert_eq!(stats.range_ms(), (5.0, 5.0));
+    }
+
+    #[test]
+    fn previews_fit() {
+        let wide = wgpu::Extent3d {
+            width: 1024,
+            height: 512,
+            depth: 1,
+        };
+        assert_eq!(preview_size(wide), [PREVIEW_WIDTH, PREVIEW_WIDTH / 2.0]);
+        let small = wgpu::Extent3d {
+            width: 64,
+            height: 32,
+            depth: 1,
+        };
+        assert_eq!(preview_size(small), [64.0, 32.0]);
+    }
+}
@@ -0,0 +1,512 @@
+use anyhow::*;
+use cgmath::*;
+use std::mem;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+
+use crate::camera::Frustum;
+use crate::compute::{
+    read_buffer, workgroup_count, StorageBinding, StorageEntry, DEFAULT_WORKGROUP_SIZE,
+};
+use crate::instance::InstanceRaw;
+use crate::model::Model;
+use crate::pipeline::ComputePipelineBuilder;
+
+/// The arguments `draw_indexed_indirect` reads, laid out the way the
+/// GPU expects them
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DrawIndexedIndirectArgs {
+    pub index_count: u32,
+    pub instance_count: u32,
+    pub first_index: u32,
+    pub base_vertex: i32,
+    pub first_instance: u32,
+}
+
+unsafe impl bytemuck::Pod for DrawIndexedIndirectArgs {}
+unsafe impl bytemuck::Zeroable for DrawIndexedIndirectArgs {}
+
+impl DrawIndexedIndirectArgs {
+    /// The stride between meshes in [IndirectInstances::args]
+    pub const SIZE: wgpu::BufferAddress = mem::size_of::<Self>() as wgpu::BufferAddress;
+
+    pub fn new(index_count: u32, instance_count: u32) -> Self {
+        Self {
+            index_count,
+            instance_count,
+            first_index: 0,
+            base_vertex: 0,
+            first_instance: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct CullParams {
+    planes: [Vector4<f32>; 6],
+    radius: f32,
+    count: u32,
+    mesh_count: u32,
+    _padding: u32,
+}
+
+unsafe impl bytemuck::Pod for CullParams {}
+unsafe impl bytemuck::Zeroable for CullParams {}
+
+/// Whether any of a model with a [Model::bounding_radius] of `radius` could
+/// be inside `frustum` when drawn with `instance`. This is the same
+/// test `cull.comp` does.
+pub fn instance_visible(instance: &InstanceRaw, radius: f32, frustum: &Frustum) -> bool {
+    let m = instance.model;
+    let scale =
+        m.x.truncate()
+            .magnitude()
+            .max(m.y.truncate().magnitude().max(m.z.truncate().magnitude()));
+    frustum.contains_sphere(Point3::from_vec(m.w.truncate()), radius * scale)
+}
+
+/// The CPU version of [InstanceCuller::cull]. The GPU keeps the
+/// instances in the same order, so the two should match exactly.
+pub fn cull_instances(
+    instances: &[InstanceRaw],
+    radius: f32,
+    frustum: &Frustum,
+) -> Vec<InstanceRaw> {
+    instances
+        .iter()
+        .filter(|i| instance_visible(i, radius, frustum))
+        .copied()
+        .collect()
+}
+
+/// One set of arguments per mesh, each drawing `instance_count`
+/// instances of the whole mesh
+pub fn indirect_args(index_counts: &[u32], instance_count: u32) -> Vec<DrawIndexedIndirectArgs> {
+    index_counts
+        .iter()
+        .map(|&count| DrawIndexedIndirectArgs::new(count, instance_count))
+        .collect()
+}
+
+/**
+ * The compute pipelines that cull instances for
+ * [DrawIndirect::draw_model_indirect]. Culling takes three passes:
+ * `cull.comp` tests each instance and counts the survivors per
+ * workgroup, `cull_scan.comp` turns the counts into offsets and fills
+ * in the indirect arguments, and `cull_compact.comp` copies the
+ * visible instances into place. Going through offsets rather than an
+ * atomic counter keeps the instances in order, so the GPU result can
+ * be checked against [cull_instances].
+ */
+pub struct InstanceCuller {
+    count_layout: wgpu::BindGroupLayout,
+    scan_layout: wgpu::BindGroupLayout,
+    compact_layout: wgpu::BindGroupLayout,
+    params_layout: wgpu::BindGroupLayout,
+    count_pipeline: wgpu::ComputePipeline,
+    scan_pipeline: wgpu::ComputePipeline,
+    compact_pipeline: wgpu::ComputePipeline,
+}
+
+impl InstanceCuller {
+    pub fn new(device: &wgpu::Device) -> Result<Self> {
+        let count_layout = StorageBinding::layout_for(device, &[true, false, false]);
+        let scan_layout = StorageBinding::layout_for(device, &[false, false]);
+        let compact_layout = StorageBinding::layout_for(device, &[true, true, true, false]);
+        let params_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("InstanceCuller::params_layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::COMPUTE,
+                ty: wgpu::BindingType::UniformBuffer {
+                    dynamic: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+
+        let build = |label, storage: &wgpu::BindGroupLayout, src| {
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(label),
+                bind_group_layouts: &[storage, &params_layout],
+                push_constant_ranges: &[],
+            });
+            ComputePipelineBuilder::new()
+                .label(label)
+                .layout(&layout)
+                .compute_shader(src)
+                .build(device)
+        };
+        let count_pipeline = build(
+            "InstanceCuller::count",
+            &count_layout,
+            wgpu::include_spirv!("cull.comp.spv"),
+        )?;
+        let scan_pipeline = build(
+            "InstanceCuller::scan",
+            &scan_layout,
+            wgpu::include_spirv!("cull_scan.comp.spv"),
+        )?;
+        let compact_pipeline = build(
+            "InstanceCuller::compact",
+            &compact_layout,
+            wgpu::include_spirv!("cull_compact.comp.spv"),
+        )?;
+
+        Ok(Self {
+            count_layout,
+            scan_layout,
+            compact_layout,
+            params_layout,
+            count_pipeline,
+            scan_pipeline,
+            compact_pipeline,
+        })
+    }
+
+    /**
+     * Culls `instances` against `frustum`, leaving the survivors in
+     * [IndirectInstances::culled] and their count in every mesh's
+     * [IndirectInstances::args]. Call it before the render pass that
+     * draws them.
+     */
+    pub fn cull(
+        &self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        instances: &IndirectInstances,
+        frustum: &Frustum,
+    ) {
+        queue.write_buffer(
+            &instances.params,
+            0,
+            bytemuck::cast_slice(&[CullParams {
+                planes: frustum.planes,
+                radius: instances.radius,
+                count: instances.count,
+                mesh_count: instances.mesh_count,
+                _padding: 0,
+            }]),
+        );
+
+        let workgroups = workgroup_count(instances.count, DEFAULT_WORKGROUP_SIZE);
+        let passes = [
+            (&self.count_pipeline, &instances.count_group, workgroups),
+            // The scan is a single workgroup, and has to run even with
+            // no instances to zero the arguments
+            (&self.scan_pipeline, &instances.scan_group, 1),
+            (&self.compact_pipeline, &instances.compact_group, workgroups),
+        ];
+        // A pass each so every pass sees the last one's results
+        for &(pipeline, bind_group, workgroups) in &passes {
+            if workgroups == 0 {
+                continue;
+            }
+            let mut pass = encoder.begin_compute_pass();
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.set_bind_group(1, &instances.params_group, &[]);
+            pass.dispatch(workgroups, 1, 1);
+        }
+    }
+}
+
+/**
+ * The GPU buffers for culling and drawing up to
+ * [IndirectInstances::capacity] instances of one [Model]. Upload the
+ * instances with [IndirectInstances::update], cull them with
+ * [InstanceCuller::cull] and draw them with
+ * [DrawIndirect::draw_model_indirect].
+ */
+pub struct IndirectInstances {
+    capacity: u32,
+    count: u32,
+    mesh_count: u32,
+    radius: f32,
+    instances: wgpu::Buffer,
+    /// The instances that passed culling, bound to vertex slot 1 when
+    /// drawing
+    pub culled: wgpu::Buffer,
+    /// A [DrawIndexedIndirectArgs] per mesh in the model
+    pub args: wgpu::Buffer,
+    params: wgpu::Buffer,
+    count_group: wgpu::BindGroup,
+    scan_group: wgpu::BindGroup,
+    compact_group: wgpu::BindGroup,
+    params_group: wgpu::BindGroup,
+}
+
+impl IndirectInstances {
+    /// Makes room for `capacity` instances of `model` and uploads
+    /// `instances`
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        culler: &InstanceCuller,
+        model: &Model,
+        capacity: u32,
+        instances: &[InstanceRaw],
+    ) -> Result<Self> {
+        if model.meshes.is_empty() {
+            bail!("There are no meshes to draw");
+        }
+        // Storage buffers can't be empty
+        let capacity = capacity.max(1);
+        let groups = workgroup_count(capacity, DEFAULT_WORKGROUP_SIZE);
+        let create = |label, size: usize, usage| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: size as wgpu::BufferAddress,
+                usage,
+                mapped_at_creation: false,
+            })
+        };
+        let instance_size = capacity as usize * mem::size_of::<InstanceRaw>();
+        let instance_buffer = create(
+            "IndirectInstances::instances",
+            instance_size,
+            wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+        );
+        // COPY_SRC on the outputs so they can be compared with the CPU
+        let culled = create(
+            "IndirectInstances::culled",
+            instance_size,
+            wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_SRC,
+        );
+        let visible = create(
+            "IndirectInstances::visible",
+            capacity as usize * mem::size_of::<u32>(),
+            wgpu::BufferUsage::STORAGE,
+        );
+        let group_offsets = create(
+            "IndirectInstances::group_offsets",
+            groups as usize * mem::size_of::<u32>(),
+            wgpu::BufferUsage::STORAGE,
+        );
+        let params = create(
+            "IndirectInstances::params",
+            mem::size_of::<CullParams>(),
+            wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        );
+        let index_counts = model
+            .meshes
+            .iter()
+            .map(|m| m.num_elements)
+            .collect::<Vec<_>>();
+        let args = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("IndirectInstances::args"),
+            contents: bytemuck::cast_slice(&indirect_args(&index_counts, 0)),
+            usage: wgpu::BufferUsage::STORAGE
+                | wgpu::BufferUsage::INDIRECT
+                | wgpu::BufferUsage::COPY_SRC,
+        });
+
+        let entry = |buffer, read_only| StorageEntry { buffer, read_only };
+        let count_group = StorageBinding::bind_group_for(
+            device,
+            &culler.count_layout,
+            &[
+                entry(&instance_buffer, true),
+                entry(&visible, false),
+                entry(&group_offsets, false),
+            ],
+        );
+        let scan_group = StorageBinding::bind_group_for(
+            device,
+            &culler.scan_layout,
+            &[entry(&group_offsets, false), entry(&args, false)],
+        );
+        let compact_group = StorageBinding::bind_group_for(
+            device,
+            &culler.compact_layout,
+            &[
+                entry(&instance_buffer, true),
+                entry(&visible, true),
+                entry(&group_offsets, true),
+                entry(&culled, false),
+            ],
+        );
+        let params_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("IndirectInstances::params_group"),
+            layout: &culler.params_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(params.slice(..)),
+            }],
+        });
+
+        let mut indirect = Self {
+            capacity,
+            count: 0,
+            mesh_count: index_counts.len() as u32,
+            radius: model.bounding_radius(),
+            instances: instance_buffer,
+            culled,
+            args,
+            params,
+            count_group,
+            scan_group,
+            compact_group,
+            params_group,
+        };
+        indirect.update(queue, instances)?;
+        Ok(indirect)
+    }
+
+    /// How many instances there's room for
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    /// How many instances were last uploaded, before culling
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// The radius the instances are culled with, see [Model::bounding_radius]
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    /// Replaces the instances to cull
+    pub fn update(&mut self, queue: &wgpu::Queue, instances: &[InstanceRaw]) -> Result<()> {
+        if instances.len() > self.capacity as usize {
+            bail!(
+                "{} instances won't fit, there's only room for {}",
+                instances.len(),
+                self.capacity
+            );
+        }
+        if !instances.is_empty() {
+            queue.write_buffer(&self.instances, 0, bytemuck::cast_slice(instances));
+        }
+        self.count = instances.len() as u32;
+        Ok(())
+    }
+
+    /**
+     * Reads back the visible instances and each mesh's arguments from
+     * the last [InstanceCuller::cull], which needs to have been
+     * submitted. This stalls, so it's for tests and debugging.
+     */
+    pub async fn read_back(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<(Vec<InstanceRaw>, Vec<DrawIndexedIndirectArgs>)> {
+        let args = read_buffer::<DrawIndexedIndirectArgs>(
+            device,
+            queue,
+            &self.args,
+            self.mesh_count as usize,
+        )
+        .await?;
+        let visible = args.first().map_or(0, |a| a.instance_count);
+        let culled = read_buffer(device, queue, &self.culled, visible as usize).await?;
+        Ok((culled, args))
+    }
+}
+
+/**
+ * Draws with arguments from [InstanceCuller::cull] instead of a fixed
+ * instance range, so the CPU never has to know how many instances
+ * survived. The pipeline is the same one [crate::DrawModel] uses.
+ */
+pub trait DrawIndirect<'a, 'b>
+where
+    'b: 'a,
+{
+    /// `instances` has to have been made for `model`
+    fn draw_model_indirect(
+        &mut self,
+        model: &'b Model,
+        instances: &'b IndirectInstances,
+        uniforms: &'b wgpu::BindGroup,
+        light: &'b wgpu::BindGroup,
+    );
+}
+
+impl<'a, 'b> DrawIndirect<'a, 'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_model_indirect(
+        &mut self,
+        model: &'b Model,
+        instances: &'b IndirectInstances,
+        uniforms: &'b wgpu::BindGroup,
+        light: &'b wgpu::BindGroup,
+    ) {
+        self.set_vertex_buffer(1, instances.culled.slice(..));
+        self.set_bind_group(1, uniforms, &[]);
+        self.set_bind_group(2, light, &[]);
+        for (i, mesh) in model.meshes.iter().enumerate() {
+            let material = &model.materials[mesh.material];
+            self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+            self.set_index_buffer(mesh.index_buffer.slice(..));
+            self.set_bind_group(0, &material.bind_group, &[]);
+            self.draw_indexed_indirect(&instances.args, i as u64 * DrawIndexedIndirectArgs::SIZE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::ToRaw;
+    use crate::camera::{Camera, Projection};
+    use crate::instance::Instance;
+
+    fn frustum() -> Frustum {
+        // Looking down +x with a 90 degree field of view
+        let camera = Camera::new((0.0, 0.0, 0.0), Deg(0.0), Deg(0.0));
+        let projection = Projection::new(100, 100, Deg(90.0), 0.1, 100.0);
+        Frustum::from_matrix(projection.calc_matrix() * camera.calc_matrix())
+    }
+
+    fn instance(x: f32, z: f32, scale: f32) -> InstanceRaw {
+        let mut instance = Instance::new(Vector3::new(x, 0.0, z), Quaternion::one());
+        instance.scale = Vector3::new(scale, scale, scale);
+        instance.to_raw()
+    }
+
+    #[test]
+    fn args_layout() {
+        // draw_indexed_indirect expects five tightly packed 32 bit values
+        assert_eq!(DrawIndexedIndirectArgs::SIZE, 20);
+        let args = indirect_args(&[36, 120], 7);
+        let words: &[u32] = bytemuck::cast_slice(&args);
+        assert_eq!(words, &[36, 7, 0, 0, 0, 120, 7, 0, 0, 0]);
+        // The uniform has to match the std140 block in the shaders
+        assert_eq!(mem::size_of::<CullParams>(), 6 * 16 + 16);
+    }
+
+    #[test]
+    fn culling_keeps_order() {
+        let instances = vec![
+            instance(10.0, 0.0, 1.0),
+            instance(-10.0, 0.0, 1.0),
+            instance(20.0, 5.0, 1.0),
+            instance(200.0, 0.0, 1.0),
+            instance(10.0, 12.0, 1.0),
+            instance(30.0, -1.0, 1.0),
+        ];
+        let culled = cull_instances(&instances, 1.0, &frustum());
+        let xs = culled.iter().map(|i| i.model.w.x).collect::<Vec<_>>();
+        assert_eq!(xs, vec![10.0, 20.0, 30.0]);
+    }
+
+    #[test]
+    fn scale_grows_the_sphere() {
+        let frustum = frustum();
+        // Just over a unit sphere's reach from the side plane
+        assert!(!instance_visible(&instance(10.0, 12.0, 1.0), 1.0, &frustum));
+        assert!(instance_visible(&instance(10.0, 12.0, 2.0), 1.0, &frustum));
+        // Squashing one axis doesn't shrink the sphere
+        let mut squashed = Instance::new(Vector3::new(10.0, 0.0, 12.0), Quaternion::one());
+        squashed.scale = Vector3::new(0.1, 2.0, 0.1);
+        assert!(instance_visible(&squashed.to_raw(), 1.0, &frustum));
+    }
+}
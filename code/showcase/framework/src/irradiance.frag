@@ -0,0 +1,50 @@
+#version 450
+
+layout(location=0) in vec2 v_ndc;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform textureCube t_environment;
+layout(set=0, binding=1) uniform sampler s_environment;
+layout(set=0, binding=2)
+uniform Face {
+    uint u_face;
+    float u_roughness; // unused
+};
+
+const float PI = 3.14159265359;
+
+// See equirect.frag
+vec3 face_direction(uint face, float s, float t) {
+    switch (face) {
+        case 0: return vec3(1.0, -t, -s);
+        case 1: return vec3(-1.0, -t, s);
+        case 2: return vec3(s, 1.0, t);
+        case 3: return vec3(s, -1.0, -t);
+        case 4: return vec3(s, -t, 1.0);
+        default: return vec3(-s, -t, -1.0);
+    }
+}
+
+// Convolves the environment with a cosine lobe, giving the diffuse
+// light arriving at a surface facing in the texel's direction.
+void main() {
+    vec3 n = normalize(face_direction(u_face, v_ndc.x, -v_ndc.y));
+    vec3 up = abs(n.y) < 0.999 ? vec3(0.0, 1.0, 0.0) : vec3(0.0, 0.0, 1.0);
+    vec3 right = normalize(cross(up, n));
+    up = cross(n, right);
+
+    float sample_delta = 0.025;
+    float num_samples = 0.0;
+    vec3 irradiance = vec3(0.0);
+    for (float phi = 0.0; phi < 2.0 * PI; phi += sample_delta) {
+        for (float theta = 0.0; theta < 0.5 * PI; theta += sample_delta) {
+            vec3 tangent_sample = vec3(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            vec3 dir = tangent_sample.x * right + tangent_sample.y * up + tangent_sample.z * n;
+            irradiance += texture(samplerCube(t_environment, s_environment), dir).rgb * cos(theta) * sin(theta);
+            num_samples += 1.0;
+        }
+    }
+
+    f_color = vec4(PI * irradiance / num_samples, 1.0);
+}
@@ -1,17 +1,77 @@
+mod animation;
 mod buffer;
 mod camera;
+mod capture;
+mod compressed;
+mod compute;
+mod cubemap;
+mod debug;
+mod deferred;
+mod golden;
+#[cfg(feature = "gui")]
+mod gui;
+mod indirect;
+mod instance;
 mod light;
+mod lod;
 mod model;
+mod optimize;
+mod particles;
+mod pbr;
 mod pipeline;
+mod postprocess;
 pub mod prelude;
+mod recorder;
+mod shapes;
+#[cfg(feature = "gltf")]
+mod skinning;
+mod ssao;
+mod terrain;
+#[cfg(feature = "text")]
+mod text;
 mod texture;
+mod transparency;
+mod video;
+mod wireframe;
 
+pub use animation::*;
 pub use buffer::*;
 pub use camera::*;
+pub use capture::*;
+pub use compressed::*;
+pub use compute::*;
+pub use cubemap::*;
+pub use debug::*;
+pub use deferred::*;
+pub use golden::*;
+#[cfg(feature = "gui")]
+pub use gui::*;
+pub use indirect::*;
+pub use instance::*;
 pub use light::*;
+pub use lod::*;
 pub use model::*;
+pub use optimize::*;
+pub use particles::*;
+pub use pbr::*;
 pub use pipeline::*;
+pub use postprocess::*;
+pub use recorder::*;
+pub use shapes::*;
+#[cfg(feature = "gltf")]
+pub use skinning::*;
+pub use ssao::*;
+pub use terrain::*;
+#[cfg(feature = "text")]
+pub use text::*;
 pub use texture::*;
+pub use transparency::*;
+pub use video::*;
+pub use wireframe::*;
+
+/// The imgui [Demo::ui] builds with
+#[cfg(feature = "gui")]
+pub use imgui;
 
 use anyhow::*;
 use cgmath::*;
@@ -27,10 +87,40 @@ pub struct Display {
     pub swap_chain: wgpu::SwapChain,
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
+    /// Sized to match the swap chain and [Display::sample_count]
+    pub depth_texture: Texture<'static>,
+    sample_count: u32,
+    msaa_texture: Option<Texture<'static>>,
+    /// Set up by [Display::enable_post_process]. While it's on,
+    /// [Display::color_attachment] draws into its HDR texture and
+    /// [Display::end_frame] runs its effects into the frame.
+    pub post_process: Option<PostProcess<'static>>,
+    pub screenshots: Screenshots,
+    pub recording: VideoCapture,
+    /// Drawn over each frame by [Display::end_frame]. Turn it on with
+    /// [Display::enable_text].
+    #[cfg(feature = "text")]
+    pub text: Option<TextRenderer>,
+    frame_number: u64,
+    hold_frames: bool,
+    held_frame: Option<wgpu::SwapChainFrame>,
 }
 
 impl Display {
+    /// The MSAA sample counts [Display::with_sample_count] accepts. These
+    /// are the ones every backend wgpu runs on supports for the swap
+    /// chain and depth formats.
+    pub const SAMPLE_COUNTS: [u32; 4] = [1, 2, 4, 8];
+
     pub async fn new(window: &Window) -> Result<Self, Error> {
+        Self::with_sample_count(window, 1).await
+    }
+
+    /// Creates a display that renders with MSAA. Render into
+    /// [Display::color_attachment] and the samples will get resolved
+    /// into the swap chain.
+    pub async fn with_sample_count(window: &Window, sample_count: u32) -> Result<Self, Error> {
+        Self::check_sample_count(sample_count)?;
         let size = window.inner_size();
         let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
         let surface = unsafe { instance.create_surface(window) };
@@ -41,10 +131,12 @@ impl Display {
             })
             .await
             .unwrap();
+        // Only ask for optional features the adapter actually has
+        let features = adapter.features() & wgpu::Features::TEXTURE_COMPRESSION_BC;
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::empty(),
+                    features,
                     limits: wgpu::Limits::default(),
                     shader_validation: true,
                 },
@@ -60,6 +152,8 @@ impl Display {
             present_mode: wgpu::PresentMode::Fifo,
         };
         let swap_chain = device.create_swap_chain(&surface, &sc_desc);
+        let depth_texture = Texture::create_depth_texture_msaa(&device, &sc_desc, sample_count);
+        let msaa_texture = Self::create_msaa_texture(&device, &sc_desc, sample_count);
 
         Ok(Self {
             surface,
@@ -67,6 +161,17 @@ impl Display {
             swap_chain,
             device,
             queue,
+            depth_texture,
+            sample_count,
+            msaa_texture,
+            post_process: None,
+            screenshots: Screenshots::default(),
+            recording: VideoCapture::default(),
+            #[cfg(feature = "text")]
+            text: None,
+            frame_number: 0,
+            hold_frames: false,
+            held_frame: None,
         })
     }
 
@@ -74,6 +179,150 @@ impl Display {
         self.sc_desc.width = width;
         self.sc_desc.height = height;
         self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
+        self.recreate_targets();
+        #[cfg(feature = "text")]
+        if let Some(text) = &mut self.text {
+            text.resize(width, height);
+        }
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    /// Changes the MSAA sample count. Pipelines built for the old
+    /// sample count need to be rebuilt.
+    pub fn set_sample_count(&mut self, sample_count: u32) -> Result<(), Error> {
+        Self::check_sample_count(sample_count)?;
+        self.sample_count = sample_count;
+        if let Some(old) = self.post_process.take() {
+            let mut post_process = PostProcess::new(&self.device, &self.sc_desc, sample_count)?;
+            post_process.effects = old.effects;
+            for effect in &mut post_process.effects {
+                effect.resize();
+            }
+            self.post_process = Some(post_process);
+        }
+        self.recreate_targets();
+        Ok(())
+    }
+
+    /**
+     * Draws the scene in HDR and runs it through a [PostProcess] stack
+     * on its way to the swap chain. The stack starts out with just a
+     * [Tonemap], more effects can be added to the one this returns.
+     * Pipelines drawing into [Display::color_attachment] have to be
+     * built with [Display::color_format] from then on.
+     */
+    pub fn enable_post_process(&mut self) -> Result<&mut PostProcess<'static>, Error> {
+        if self.post_process.is_none() {
+            let mut post_process =
+                PostProcess::new(&self.device, &self.sc_desc, self.sample_count)?;
+            post_process.push(Tonemap::new(&self.device, TonemapOperator::Aces)?);
+            self.post_process = Some(post_process);
+        }
+        Ok(self.post_process.as_mut().unwrap())
+    }
+
+    /// The format [Display::color_attachment] draws in, which is
+    /// [PostProcess::FORMAT] with post processing on and the swap
+    /// chain's otherwise
+    pub fn color_format(&self) -> wgpu::TextureFormat {
+        match &self.post_process {
+            Some(_) => PostProcess::FORMAT,
+            None => self.sc_desc.format,
+        }
+    }
+
+    fn check_sample_count(sample_count: u32) -> Result<(), Error> {
+        if !Self::SAMPLE_COUNTS.contains(&sample_count) {
+            bail!(
+                "Sample count must be one of {:?}, got {}",
+                Self::SAMPLE_COUNTS,
+                sample_count
+            );
+        }
+        Ok(())
+    }
+
+    /// The color attachment for drawing to `frame`, which should be
+    /// the swap chain's current frame. With MSAA on this draws to a
+    /// multisampled texture that gets resolved into `frame`. With
+    /// post processing on it draws to the HDR texture instead, which
+    /// only reaches `frame` in [Display::end_frame].
+    pub fn color_attachment<'a>(
+        &'a self,
+        frame: &'a wgpu::TextureView,
+        load: wgpu::LoadOp<wgpu::Color>,
+    ) -> wgpu::RenderPassColorAttachmentDescriptor<'a> {
+        if let Some(post_process) = &self.post_process {
+            return post_process.hdr_attachment(load);
+        }
+        let ops = wgpu::Operations { load, store: true };
+        match &self.msaa_texture {
+            Some(msaa) => wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &msaa.view,
+                resolve_target: Some(frame),
+                ops,
+            },
+            None => wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: frame,
+                resolve_target: None,
+                ops,
+            },
+        }
+    }
+
+    /// Clears [Display::depth_texture] to 1.0
+    pub fn depth_attachment(&self) -> wgpu::RenderPassDepthStencilAttachmentDescriptor<'_> {
+        wgpu::RenderPassDepthStencilAttachmentDescriptor {
+            attachment: &self.depth_texture.view,
+            depth_ops: Some(wgpu::Operations {
+                load: wgpu::LoadOp::Clear(1.0),
+                store: true,
+            }),
+            stencil_ops: None,
+        }
+    }
+
+    fn recreate_targets(&mut self) {
+        let sc_desc = self.sc_desc.clone();
+        self.create_targets(&sc_desc);
+    }
+
+    /// Sizes the depth and MSAA textures to `desc`, which only differs
+    /// from the swap chain while taking a supersampled screenshot
+    fn create_targets(&mut self, desc: &wgpu::SwapChainDescriptor) {
+        self.depth_texture =
+            Texture::create_depth_texture_msaa(&self.device, desc, self.sample_count);
+        self.msaa_texture = Self::create_msaa_texture(&self.device, desc, self.sample_count);
+        if let Some(post_process) = &mut self.post_process {
+            post_process.resize(&self.device, desc);
+        }
+    }
+
+    fn create_msaa_texture(
+        device: &wgpu::Device,
+        sc_desc: &wgpu::SwapChainDescriptor,
+        sample_count: u32,
+    ) -> Option<Texture<'static>> {
+        if sample_count <= 1 {
+            return None;
+        }
+        let desc = wgpu::TextureDescriptor {
+            label: Some("Display::msaa_texture"),
+            size: wgpu::Extent3d {
+                width: sc_desc.width,
+                height: sc_desc.height,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: sc_desc.format,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+        };
+        Some(Texture::from_descriptor(device, desc))
     }
 }
 
@@ -178,12 +427,160 @@ impl UniformBinding {
     }
 }
 
+/**
+ * Demos run with [run] get F12 to save a screenshot and, if they set
+ * [Demo::SUPERSAMPLE_SCREENSHOTS], Shift+F12 to save one at twice the
+ * resolution. F10 starts and stops recording
+ * with [Display::recording], as does setting `FRAMEWORK_RECORD` to
+ * `png` or `y4m` before launching. These only work if the demo draws
+ * through [Display::begin_frame] and [Display::end_frame]. F1 hides
+ * and shows the [Gui] of demos that set [Demo::GUI].
+ */
 pub trait Demo: 'static + Sized {
+    /// The MSAA sample count [run] creates the [Display] with
+    const SAMPLE_COUNT: u32 = 1;
+    /// Whether [run] draws a [Gui] over the demo. Needs the `gui`
+    /// feature.
+    const GUI: bool = false;
+    /// Whether [run] turns on [Display::enable_post_process] before
+    /// [Demo::init]. The demo can add effects to
+    /// [Display::post_process] and has to build its pipelines with
+    /// [Display::color_format].
+    const POST_PROCESS: bool = false;
+    /// Whether Shift+F12 renders the screenshot at twice the window's
+    /// size. Only [Display::depth_attachment] and
+    /// [Display::color_attachment] are resized for it, so demos with
+    /// attachments of their own should only set this if they size them
+    /// to [Frame::size]. Otherwise Shift+F12 takes a normal screenshot.
+    const SUPERSAMPLE_SCREENSHOTS: bool = false;
+
     fn init(display: &Display) -> Result<Self, Error>;
     fn process_mouse(&mut self, dx: f64, dy: f64);
     fn resize(&mut self, display: &Display);
     fn update(&mut self, display: &Display, dt: Duration);
     fn render(&mut self, display: &mut Display);
+
+    /// The camera saved alongside each recorded frame
+    fn camera(&self) -> Option<(&Camera, &Projection)> {
+        None
+    }
+
+    /// Builds this frame's GUI, before [Demo::render] is called. The
+    /// frame stats window is always shown, anything else, like
+    /// [Panels::camera], is up to the demo. Only called if
+    /// [Demo::GUI] is set.
+    #[cfg(feature = "gui")]
+    fn ui(&mut self, _ui: &imgui::Ui, _panels: &mut Panels) {}
+}
+
+/// Reads `FRAMEWORK_RECORD` to see whether [run] should start recording
+/// straight away
+fn recording_format_from_env() -> Option<VideoFormat> {
+    match std::env::var("FRAMEWORK_RECORD")
+        .ok()?
+        .to_lowercase()
+        .as_str()
+    {
+        "png" => Some(VideoFormat::PngSequence),
+        "y4m" => Some(VideoFormat::Y4m),
+        other => {
+            log::warn!("Unknown FRAMEWORK_RECORD format {:?}", other);
+            None
+        }
+    }
+}
+
+fn toggle_recording(display: &mut Display) {
+    let result = if display.recording.is_recording() {
+        display.recording.stop()
+    } else {
+        let (width, height) = (display.sc_desc.width, display.sc_desc.height);
+        display.recording.start(width, height).map(|_| ())
+    };
+    if let Err(e) = result {
+        log::error!("Recording failed: {}", e);
+    }
+}
+
+/// Draws `demo`, with `gui` on top if there is one
+fn draw<D: Demo>(
+    display: &mut Display,
+    demo: &mut D,
+    gui: Option<&mut Gui>,
+    window: &Window,
+    dt: Duration,
+) {
+    match gui {
+        Some(gui) => {
+            if let Err(e) = gui.render(display, demo, window, dt) {
+                log::error!("{}", e);
+            }
+        }
+        None => demo.render(display),
+    }
+}
+
+/// Stands in for the imgui layer when the `gui` feature is off, so
+/// [run] works the same either way
+#[cfg(not(feature = "gui"))]
+struct Gui;
+
+#[cfg(not(feature = "gui"))]
+impl Gui {
+    fn new(_window: &Window, _display: &Display) -> Self {
+        Self
+    }
+
+    fn handle_event<T>(&mut self, _window: &Window, _event: &Event<T>) -> bool {
+        false
+    }
+
+    fn render<D: Demo>(
+        &mut self,
+        display: &mut Display,
+        demo: &mut D,
+        _window: &Window,
+        _dt: Duration,
+    ) -> Result<(), Error> {
+        demo.render(display);
+        Ok(())
+    }
+}
+
+/// Steps and draws `demo` once, saving the frame if [Display::recording]
+/// is active
+fn render_frame<D: Demo>(
+    display: &mut Display,
+    demo: &mut D,
+    gui: Option<&mut Gui>,
+    window: &Window,
+    dt: Duration,
+) {
+    if !display.recording.is_recording() {
+        demo.update(display, dt);
+        draw(display, demo, gui, window, dt);
+        return;
+    }
+
+    let start = Instant::now();
+    let timestep = display.recording.timestep();
+    demo.update(display, timestep);
+    display.capture_next_frame_in_memory();
+    draw(display, demo, gui, window, timestep);
+    let render_time = start.elapsed();
+    let camera = demo
+        .camera()
+        .map(|(camera, projection)| CameraState::new(camera, projection));
+    let result = match display.take_captured_frame() {
+        Some(image) => display.recording.write_frame(&image, render_time, camera),
+        None => Err(anyhow!("The demo didn't draw through Display::begin_frame")),
+    };
+    if let Err(e) = result {
+        log::error!("Stopped recording: {}", e);
+        if let Err(e) = display.recording.stop() {
+            log::error!("Couldn't finish the recording: {}", e);
+        }
+    }
 }
 
 pub async fn run<D: Demo>() -> Result<(), Error> {
@@ -191,11 +588,24 @@ pub async fn run<D: Demo>() -> Result<(), Error> {
     let window = WindowBuilder::new()
         .with_title(env!("CARGO_PKG_NAME"))
         .build(&event_loop)?;
-    let mut display = Display::new(&window).await?;
+    let mut display = Display::with_sample_count(&window, D::SAMPLE_COUNT).await?;
+    if D::POST_PROCESS {
+        display.enable_post_process()?;
+    }
     let mut demo = D::init(&mut display)?;
     let mut last_update = Instant::now();
     let mut is_resumed = true;
     let mut is_focused = true;
+    let mut modifiers = ModifiersState::default();
+    let mut gui = if D::GUI {
+        Some(Gui::new(&window, &display))
+    } else {
+        None
+    };
+    if let Some(format) = recording_format_from_env() {
+        display.recording.format = format;
+        toggle_recording(&mut display);
+    }
 
     event_loop.run(move |event, _, control_flow| {
         *control_flow = if is_resumed && is_focused {
@@ -203,6 +613,11 @@ pub async fn run<D: Demo>() -> Result<(), Error> {
         } else {
             ControlFlow::Wait
         };
+        if let Some(gui) = &mut gui {
+            if gui.handle_event(&window, &event) {
+                return;
+            }
+        }
 
         match event {
             Event::Resumed => is_resumed = true,
@@ -213,8 +628,7 @@ pub async fn run<D: Demo>() -> Result<(), Error> {
                     let dt = now - last_update;
                     last_update = now;
 
-                    demo.update(&mut display, dt);
-                    demo.render(&mut display);
+                    render_frame(&mut display, &mut demo, gui.as_mut(), &window, dt);
                 }
             }
             Event::MainEventsCleared => {
@@ -230,8 +644,38 @@ pub async fn run<D: Demo>() -> Result<(), Error> {
             } => {
                 if window_id == window.id() {
                     match event {
-                        WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                        WindowEvent::CloseRequested => {
+                            if let Err(e) = display.recording.stop() {
+                                log::error!("Couldn't finish the recording: {}", e);
+                            }
+                            *control_flow = ControlFlow::Exit
+                        }
                         WindowEvent::Focused(f) => is_focused = f,
+                        WindowEvent::ModifiersChanged(m) => modifiers = m,
+                        WindowEvent::KeyboardInput {
+                            input:
+                                KeyboardInput {
+                                    state: ElementState::Pressed,
+                                    virtual_keycode: Some(VirtualKeyCode::F12),
+                                    ..
+                                },
+                            ..
+                        } => {
+                            if modifiers.shift() && D::SUPERSAMPLE_SCREENSHOTS {
+                                display.capture_next_frame_supersampled(2);
+                            } else {
+                                display.capture_next_frame();
+                            }
+                        }
+                        WindowEvent::KeyboardInput {
+                            input:
+                                KeyboardInput {
+                                    state: ElementState::Pressed,
+                                    virtual_keycode: Some(VirtualKeyCode::F10),
+                                    ..
+                                },
+                            ..
+                        } => toggle_recording(&mut display),
                         WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
                             display.resize(new_inner_size.width, new_inner_size.height);
                             demo.resize(&mut display);
@@ -12,9 +12,7 @@ unsafe impl bytemuck::Pod for LightData {}
 unsafe impl bytemuck::Zeroable for LightData {}
 
 pub struct Light {
-    #[allow(dead_code)]
     data: LightData,
-    #[allow(dead_code)]
     buffer: wgpu::Buffer,
 }
 
@@ -32,9 +30,51 @@ impl Light {
 
         Self { data, buffer }
     }
+
+    pub fn position(&self) -> Vector3<f32> {
+        self.data.position.truncate()
+    }
+
+    pub fn color(&self) -> Vector3<f32> {
+        self.data.color.truncate()
+    }
+
+    /// Moves and recolors the light, uploading the change straight away
+    pub fn set(&mut self, queue: &wgpu::Queue, position: Vector3<f32>, color: Vector3<f32>) {
+        self.data.position = position.extend(1.0);
+        self.data.color = color.extend(1.0);
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.data]));
+    }
 }
 
 pub struct LightBinding {
     pub layout: wgpu::BindGroupLayout,
     pub bind_group: wgpu::BindGroup,
 }
+
+impl LightBinding {
+    pub fn new(device: &wgpu::Device, light: &Light) -> Self {
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::UniformBuffer {
+                    dynamic: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("LightBinding::layout"),
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(light.buffer.slice(..)),
+            }],
+            label: Some("LightBinding::bind_group"),
+        });
+
+        Self { layout, bind_group }
+    }
+}
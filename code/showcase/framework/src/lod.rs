@@ -0,0 +1,886 @@
+use anyhow::*;
+use cgmath::*;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::mem;
+use std::ops::Range;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+
+use crate::buffer::ToRaw;
+use crate::instance::{Instance, InstanceRaw};
+use crate::model::{Mesh, Model, ModelVertex};
+use crate::optimize::index_bytes;
+
+/// Border edges resist moving this much more than the surface does
+const BORDER_WEIGHT: f64 = 10.0;
+
+/// A symmetric 4x4 matrix that sums squared distances to planes
+#[derive(Debug, Copy, Clone, Default)]
+struct Quadric {
+    m: [f64; 10],
+    /// The total area of the planes, so errors can be averaged
+    weight: f64,
+}
+
+impl Quadric {
+    fn plane(normal: Vector3<f64>, point: Vector3<f64>, weight: f64) -> Self {
+        let (a, b, c) = (normal.x, normal.y, normal.z);
+        let d = -normal.dot(point);
+        let m = [
+            a * a,
+            a * b,
+            a * c,
+            a * d,
+            b * b,
+            b * c,
+            b * d,
+            c * c,
+            c * d,
+            d * d,
+        ];
+        let mut q = Self::default();
+        for (out, v) in q.m.iter_mut().zip(&m) {
+            *out = v * weight;
+        }
+        q.weight = weight;
+        q
+    }
+
+    fn add(&mut self, other: &Self) {
+        for (a, b) in self.m.iter_mut().zip(&other.m) {
+            *a += b;
+        }
+        self.weight += other.weight;
+    }
+
+    fn error(&self, p: Vector3<f64>) -> f64 {
+        let m = &self.m;
+        let (x, y, z) = (p.x, p.y, p.z);
+        m[0] * x * x
+            + 2.0 * m[1] * x * y
+            + 2.0 * m[2] * x * z
+            + 2.0 * m[3] * x
+            + m[4] * y * y
+            + 2.0 * m[5] * y * z
+            + 2.0 * m[6] * y
+            + m[7] * z * z
+            + 2.0 * m[8] * z
+            + m[9]
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Collapse {
+    cost: f64,
+    from: usize,
+    to: usize,
+    from_version: u32,
+    to_version: u32,
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Collapse {
+    /// Reversed, so the cheapest collapse is at the top of the heap
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .cost
+            .partial_cmp(&self.cost)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+fn edge(a: usize, b: usize) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+/// The result of [simplify]
+#[derive(Debug, Clone)]
+pub struct Simplified {
+    pub indices: Vec<u32>,
+    /// Roughly how far the surface moved, in model units
+    pub error: f32,
+}
+
+/**
+ * Removes triangles by collapsing edges in order of quadric error
+ * until there are at most `target_index_count` indices left, or the
+ * next collapse would move the surface further than `max_error`.
+ *
+ * Vertices are never moved or added, only dropped, so the result
+ * indexes into the same `vertices`. Vertices on UV seams or hard edges
+ * stay put and open borders only shrink along themselves, which keeps
+ * textures and outlines from tearing.
+ */
+pub fn simplify(
+    vertices: &[ModelVertex],
+    indices: &[u32],
+    target_index_count: usize,
+    max_error: f32,
+) -> Simplified {
+    // Vertices split for seams share a position and have to move as one
+    let mut canonical = Vec::with_capacity(vertices.len());
+    let mut groups: Vec<Vec<u32>> = Vec::new();
+    let mut by_position = HashMap::new();
+    for (i, v) in vertices.iter().enumerate() {
+        let key = [
+            v.position.x.to_bits(),
+            v.position.y.to_bits(),
+            v.position.z.to_bits(),
+        ];
+        let group = *by_position.entry(key).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[group].push(i as u32);
+        canonical.push(group);
+    }
+    let positions = groups
+        .iter()
+        .map(|g| vertices[g[0] as usize].position.cast::<f64>().unwrap())
+        .collect::<Vec<_>>();
+    let mut locked = groups
+        .iter()
+        .map(|g| {
+            let first = &vertices[g[0] as usize];
+            g.iter().any(|&i| {
+                let v = &vertices[i as usize];
+                v.tex_coords != first.tex_coords || v.normal != first.normal
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut tris = Vec::new();
+    let mut corners = Vec::new();
+    for tri in indices.chunks_exact(3) {
+        let c = [
+            canonical[tri[0] as usize],
+            canonical[tri[1] as usize],
+            canonical[tri[2] as usize],
+        ];
+        if c[0] != c[1] && c[1] != c[2] && c[2] != c[0] {
+            tris.push(c);
+            corners.push([tri[0], tri[1], tri[2]]);
+        }
+    }
+
+    let count = groups.len();
+    let mut quadrics = vec![Quadric::default(); count];
+    let mut vertex_tris = vec![Vec::new(); count];
+    let mut edge_uses = HashMap::new();
+    for (t, c) in tris.iter().enumerate() {
+        let (p0, p1, p2) = (positions[c[0]], positions[c[1]], positions[c[2]]);
+        let cross = (p1 - p0).cross(p2 - p0);
+        let area = cross.magnitude() * 0.5;
+        if area > 0.0 {
+            let q = Quadric::plane(cross.normalize(), p0, area);
+            for &v in c {
+                quadrics[v].add(&q);
+            }
+        }
+        for k in 0..3 {
+            vertex_tris[c[k]].push(t);
+            *edge_uses.entry(edge(c[k], c[(k + 1) % 3])).or_insert(0) += 1;
+        }
+    }
+
+    // Edges with one triangle are on a border. Planes through them
+    // keep the border from being pulled inwards.
+    let mut border_edges = HashSet::new();
+    let mut on_border = vec![false; count];
+    for c in &tris {
+        let (p0, p1, p2) = (positions[c[0]], positions[c[1]], positions[c[2]]);
+        let normal = (p1 - p0).cross(p2 - p0);
+        for k in 0..3 {
+            let (a, b) = (c[k], c[(k + 1) % 3]);
+            if edge_uses[&edge(a, b)] != 1 {
+                continue;
+            }
+            border_edges.insert(edge(a, b));
+            on_border[a] = true;
+            on_border[b] = true;
+            let along = positions[b] - positions[a];
+            let out = along.cross(normal);
+            if out.magnitude2() > 0.0 {
+                let q = Quadric::plane(
+                    out.normalize(),
+                    positions[a],
+                    along.magnitude2() * BORDER_WEIGHT,
+                );
+                quadrics[a].add(&q);
+                quadrics[b].add(&q);
+            }
+        }
+        // Edges used by more than two triangles can't be collapsed
+        // without breaking the surface
+        for k in 0..3 {
+            if edge_uses[&edge(c[k], c[(k + 1) % 3])] > 2 {
+                locked[c[k]] = true;
+                locked[c[(k + 1) % 3]] = true;
+            }
+        }
+    }
+
+    let mut alive = vec![true; tris.len()];
+    let mut alive_count = tris.len();
+    let mut removed = vec![false; count];
+    let mut version = vec![0u32; count];
+    let mut parent = (0..count).collect::<Vec<_>>();
+    let mut heap = BinaryHeap::new();
+    let max_cost = (max_error as f64) * (max_error as f64);
+    let mut worst = 0.0f64;
+
+    let cost = |quadrics: &[Quadric], from: usize, to: usize| {
+        let mut q = quadrics[from];
+        q.add(&quadrics[to]);
+        (q.error(positions[to]) / q.weight.max(1e-12)).max(0.0)
+    };
+    let can_collapse = |border_edges: &HashSet<(usize, usize)>, from: usize, to: usize| {
+        !locked[from] && (!on_border[from] || border_edges.contains(&edge(from, to)))
+    };
+    let push = |heap: &mut BinaryHeap<Collapse>,
+                quadrics: &[Quadric],
+                border_edges: &HashSet<(usize, usize)>,
+                version: &[u32],
+                a: usize,
+                b: usize| {
+        for &(from, to) in &[(a, b), (b, a)] {
+            if can_collapse(border_edges, from, to) {
+                heap.push(Collapse {
+                    cost: cost(quadrics, from, to),
+                    from,
+                    to,
+                    from_version: version[from],
+                    to_version: version[to],
+                });
+            }
+        }
+    };
+
+    for &(a, b) in edge_uses.keys() {
+        push(&mut heap, &quadrics, &border_edges, &version, a, b);
+    }
+
+    while alive_count * 3 > target_index_count {
+        let c = match heap.pop() {
+            Some(c) => c,
+            None => break,
+        };
+        if removed[c.from]
+            || removed[c.to]
+            || version[c.from] != c.from_version
+            || version[c.to] != c.to_version
+        {
+            continue;
+        }
+        if c.cost > max_cost {
+            break;
+        }
+        if flips(
+            &tris,
+            &alive,
+            &vertex_tris[c.from],
+            &positions,
+            c.from,
+            c.to,
+        ) {
+            continue;
+        }
+
+        let (from, to) = (c.from, c.to);
+        worst = worst.max(c.cost);
+        removed[from] = true;
+        parent[from] = to;
+        let q = quadrics[from];
+        quadrics[to].add(&q);
+        for t in mem::take(&mut vertex_tris[from]) {
+            if !alive[t] {
+                continue;
+            }
+            if tris[t].contains(&to) {
+                alive[t] = false;
+                alive_count -= 1;
+            } else {
+                for v in tris[t].iter_mut() {
+                    if *v == from {
+                        *v = to;
+                    }
+                }
+                vertex_tris[to].push(t);
+            }
+        }
+        // Borders that ran through `from` now run through `to`
+        let moved = border_edges
+            .iter()
+            .filter(|&&(a, b)| a == from || b == from)
+            .copied()
+            .collect::<Vec<_>>();
+        for (a, b) in moved {
+            border_edges.remove(&(a, b));
+            let other = if a == from { b } else { a };
+            if other != to {
+                border_edges.insert(edge(other, to));
+            }
+        }
+
+        version[to] += 1;
+        vertex_tris[to].retain(|&t| alive[t]);
+        let neighbors = vertex_tris[to]
+            .iter()
+            .flat_map(|&t| tris[t].iter().copied())
+            .filter(|&v| v != to)
+            .collect::<HashSet<_>>();
+        for n in neighbors {
+            push(&mut heap, &quadrics, &border_edges, &version, n, to);
+        }
+    }
+
+    // Point each corner at a real vertex. Corners that moved take the
+    // vertex of their new position with the closest UVs.
+    let mut out = Vec::with_capacity(alive_count * 3);
+    for (t, c) in tris.iter().enumerate() {
+        if !alive[t] {
+            continue;
+        }
+        for k in 0..3 {
+            let original = corners[t][k];
+            if canonical[original as usize] == c[k] {
+                out.push(original);
+                continue;
+            }
+            let uv = vertices[original as usize].tex_coords;
+            let closest = groups[c[k]]
+                .iter()
+                .copied()
+                .min_by(|&a, &b| {
+                    let da = (vertices[a as usize].tex_coords - uv).magnitude2();
+                    let db = (vertices[b as usize].tex_coords - uv).magnitude2();
+                    da.partial_cmp(&db).unwrap_or(Ordering::Equal)
+                })
+                .unwrap_or(original);
+            out.push(closest);
+        }
+    }
+
+    Simplified {
+        indices: out,
+        error: worst.sqrt() as f32,
+    }
+}
+
+/// Whether moving `from` onto `to` would turn any of `from`'s
+/// triangles over
+fn flips(
+    tris: &[[usize; 3]],
+    alive: &[bool],
+    from_tris: &[usize],
+    positions: &[Vector3<f64>],
+    from: usize,
+    to: usize,
+) -> bool {
+    from_tris
+        .iter()
+        .filter(|&&t| alive[t] && !tris[t].contains(&to))
+        .any(|&t| {
+            let c = tris[t];
+            let p = |v: usize| positions[v];
+            let moved = |v: usize| {
+                if v == from {
+                    positions[to]
+                } else {
+                    positions[v]
+                }
+            };
+            let before = (p(c[1]) - p(c[0])).cross(p(c[2]) - p(c[0]));
+            let after = (moved(c[1]) - moved(c[0])).cross(moved(c[2]) - moved(c[0]));
+            after.dot(before) <= 0.0
+        })
+}
+
+/// How many levels to make and when to switch between them
+#[derive(Debug, Clone)]
+pub struct LodSettings {
+    /// The fraction of triangles each level keeps. The first level is
+    /// normally 1.0, the full mesh.
+    pub ratios: Vec<f32>,
+    /// The smallest screen size each level is used at, as a fraction
+    /// of the screen's height. Anything smaller than the last one isn't
+    /// drawn at all.
+    pub screen_sizes: Vec<f32>,
+    /// Levels stop simplifying once the surface would move further
+    /// than this, in model units
+    pub max_error: f32,
+}
+
+impl Default for LodSettings {
+    fn default() -> Self {
+        Self {
+            ratios: vec![1.0, 0.5, 0.25, 0.1],
+            screen_sizes: vec![0.25, 0.1, 0.04, 0.0],
+            max_error: f32::INFINITY,
+        }
+    }
+}
+
+impl LodSettings {
+    /// Every level needs both a ratio and a screen size
+    pub fn validate(&self) -> Result<()> {
+        if self.ratios.is_empty() {
+            bail!("LodSettings needs at least one level");
+        }
+        if self.ratios.len() != self.screen_sizes.len() {
+            bail!(
+                "LodSettings has {} ratios but {} screen sizes",
+                self.ratios.len(),
+                self.screen_sizes.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LodLevel {
+    /// Where this level is in [MeshLods::index_buffer]
+    pub indices: Range<u32>,
+    pub error: f32,
+}
+
+/// Every level of one [Mesh], in one index buffer that uses the mesh's
+/// vertex buffer and index format
+pub struct MeshLods {
+    pub index_buffer: wgpu::Buffer,
+    pub levels: Vec<LodLevel>,
+}
+
+impl MeshLods {
+    /// Each level is simplified from the one before it, so they only
+    /// ever lose detail
+    pub fn generate(mesh: &Mesh, settings: &LodSettings) -> (Vec<u32>, Vec<LodLevel>) {
+        let mut all = Vec::new();
+        let mut levels = Vec::new();
+        let mut previous = mesh.indices.clone();
+        let mut error = 0.0f32;
+        for &ratio in &settings.ratios {
+            let target = ((mesh.indices.len() as f32 * ratio) as usize) / 3 * 3;
+            if target < previous.len() {
+                let simplified = simplify(&mesh.vertices, &previous, target, settings.max_error);
+                previous = simplified.indices;
+                error = error.max(simplified.error);
+            }
+            let start = all.len() as u32;
+            all.extend_from_slice(&previous);
+            levels.push(LodLevel {
+                indices: start..all.len() as u32,
+                error,
+            });
+        }
+        (all, levels)
+    }
+
+    pub fn new(device: &wgpu::Device, mesh: &Mesh, settings: &LodSettings) -> Result<Self> {
+        settings.validate()?;
+        let (indices, levels) = Self::generate(mesh, settings);
+        let index_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some(&format!("{} LOD Index Buffer", mesh.name)),
+            contents: &index_bytes(&indices, mesh.index_format),
+            usage: wgpu::BufferUsage::INDEX,
+        });
+        Ok(Self {
+            index_buffer,
+            levels,
+        })
+    }
+}
+
+/**
+ * LOD levels for every mesh in a [Model]. All the meshes switch level
+ * together, based on the screen size of a sphere around the model's
+ * origin that holds every vertex.
+ */
+pub struct ModelLods {
+    pub meshes: Vec<MeshLods>,
+    pub radius: f32,
+    pub screen_sizes: Vec<f32>,
+}
+
+impl ModelLods {
+    pub fn new(device: &wgpu::Device, model: &Model, settings: &LodSettings) -> Result<Self> {
+        settings.validate()?;
+        let radius = model.bounding_radius();
+        Ok(Self {
+            meshes: model
+                .meshes
+                .iter()
+                .map(|mesh| MeshLods::new(device, mesh, settings))
+                .collect::<Result<_>>()?,
+            radius,
+            screen_sizes: settings.screen_sizes.clone(),
+        })
+    }
+
+    pub fn level_count(&self) -> usize {
+        self.screen_sizes.len()
+    }
+
+    /// The level to draw at `screen_size`, or `None` if it's too small
+    /// to draw
+    pub fn select(&self, screen_size: f32) -> Option<usize> {
+        select_level(&self.screen_sizes, screen_size)
+    }
+}
+
+fn select_level(screen_sizes: &[f32], screen_size: f32) -> Option<usize> {
+    screen_sizes.iter().position(|&min| screen_size >= min)
+}
+
+/// How much of the screen's height a sphere of `radius` covers from
+/// `distance` away
+pub fn screen_size(radius: f32, distance: f32, fovy: Rad<f32>) -> f32 {
+    if distance <= radius {
+        return f32::INFINITY;
+    }
+    radius / (distance * (fovy.0 * 0.5).tan())
+}
+
+/// Sorts `instances` by level, returning their raw data and the range
+/// each level's instances ended up in
+pub fn bucket_instances(
+    instances: &[Instance],
+    radius: f32,
+    screen_sizes: &[f32],
+    camera: Point3<f32>,
+    fovy: Rad<f32>,
+) -> (Vec<InstanceRaw>, Vec<Range<u32>>) {
+    let mut buckets = vec![Vec::new(); screen_sizes.len()];
+    for instance in instances {
+        let scale = instance
+            .scale
+            .x
+            .abs()
+            .max(instance.scale.y.abs())
+            .max(instance.scale.z.abs());
+        let distance = (Point3::from_vec(instance.position) - camera).magnitude();
+        let size = screen_size(radius * scale, distance, fovy);
+        if let Some(level) = select_level(screen_sizes, size) {
+            buckets[level].push(instance.to_raw());
+        }
+    }
+    let mut raw = Vec::with_capacity(instances.len());
+    let mut ranges = Vec::with_capacity(buckets.len());
+    for bucket in buckets {
+        let start = raw.len() as u32;
+        raw.extend(bucket);
+        ranges.push(start..raw.len() as u32);
+    }
+    (raw, ranges)
+}
+
+/// What drawing a [ModelLods] with a [LodInstances] costs
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LodStats {
+    /// Per level
+    pub instances: Vec<u32>,
+    /// Per level
+    pub draw_calls: Vec<u32>,
+    /// Per level, counting every instance
+    pub triangles: Vec<u64>,
+    /// Instances too small to draw
+    pub culled: u32,
+}
+
+impl LodStats {
+    pub fn total_draw_calls(&self) -> u32 {
+        self.draw_calls.iter().sum()
+    }
+}
+
+impl fmt::Display for LodStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (level, ((instances, draws), triangles)) in self
+            .instances
+            .iter()
+            .zip(&self.draw_calls)
+            .zip(&self.triangles)
+            .enumerate()
+        {
+            writeln!(
+                f,
+                "LOD{}: {} draws, {} instances, {} triangles",
+                level, draws, instances, triangles
+            )?;
+        }
+        write!(f, "culled: {}", self.culled)
+    }
+}
+
+/**
+ * An instance buffer that's re-sorted by LOD level every frame. Call
+ * [LodInstances::update] before drawing with
+ * [DrawLod::draw_model_lods].
+ */
+pub struct LodInstances {
+    pub buffer: wgpu::Buffer,
+    pub ranges: Vec<Range<u32>>,
+    capacity: usize,
+    total: usize,
+}
+
+impl LodInstances {
+    pub fn new(device: &wgpu::Device, capacity: usize) -> Self {
+        Self {
+            buffer: Self::create_buffer(device, capacity),
+            ranges: Vec::new(),
+            capacity,
+            total: 0,
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("LodInstances::buffer"),
+            size: (capacity.max(1) * mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Buckets `instances` by how big they are on screen from
+    /// `camera` and uploads them. The buffer grows if it needs to.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        lods: &ModelLods,
+        instances: &[Instance],
+        camera: Point3<f32>,
+        fovy: Rad<f32>,
+    ) {
+        let (raw, ranges) =
+            bucket_instances(instances, lods.radius, &lods.screen_sizes, camera, fovy);
+        if instances.len() > self.capacity {
+            self.capacity = instances.len().next_power_of_two();
+            self.buffer = Self::create_buffer(device, self.capacity);
+        }
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&raw));
+        self.ranges = ranges;
+        self.total = instances.len();
+    }
+
+    pub fn stats(&self, lods: &ModelLods) -> LodStats {
+        let mut stats = LodStats {
+            instances: self.ranges.iter().map(|r| r.end - r.start).collect(),
+            draw_calls: vec![0; self.ranges.len()],
+            triangles: vec![0; self.ranges.len()],
+            culled: 0,
+        };
+        for mesh in &lods.meshes {
+            for (level, range) in self.ranges.iter().enumerate() {
+                let count = (range.end - range.start) as u64;
+                if count == 0 {
+                    continue;
+                }
+                let indices = &mesh.levels[level].indices;
+                stats.draw_calls[level] += 1;
+                stats.triangles[level] += (indices.end - indices.start) as u64 / 3 * count;
+            }
+        }
+        let drawn: u32 = stats.instances.iter().sum();
+        stats.culled = self.total as u32 - drawn;
+        stats
+    }
+}
+
+pub trait DrawLod<'a, 'b>
+where
+    'b: 'a,
+{
+    /// Draws every level that has instances, one draw per mesh per
+    /// level. Uses the same bind groups as [crate::DrawModel].
+    fn draw_model_lods(
+        &mut self,
+        model: &'b Model,
+        lods: &'b ModelLods,
+        instances: &'b LodInstances,
+        uniforms: &'b wgpu::BindGroup,
+        light: &'b wgpu::BindGroup,
+    );
+}
+
+impl<'a, 'b> DrawLod<'a, 'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_model_lods(
+        &mut self,
+        model: &'b Model,
+        lods: &'b ModelLods,
+        instances: &'b LodInstances,
+        uniforms: &'b wgpu::BindGroup,
+        light: &'b wgpu::BindGroup,
+    ) {
+        self.set_vertex_buffer(1, instances.buffer.slice(..));
+        for (mesh, mesh_lods) in model.meshes.iter().zip(&lods.meshes) {
+            let material = &model.materials[mesh.material];
+            self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+            self.set_index_buffer(mesh_lods.index_buffer.slice(..));
+            self.set_bind_group(0, &material.bind_group, &[]);
+            self.set_bind_group(1, uniforms, &[]);
+            self.set_bind_group(2, light, &[]);
+            for (level, range) in instances.ranges.iter().enumerate() {
+                if range.start != range.end {
+                    let indices = mesh_lods.levels[level].indices.clone();
+                    self.draw_indexed(indices, 0, range.clone());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An `n` by `n` grid of quads on the xy plane, lifted by `height`
+    fn grid(n: u32, height: impl Fn(f32, f32) -> f32) -> (Vec<ModelVertex>, Vec<u32>) {
+        let mut vertices = Vec::new();
+        for y in 0..=n {
+            for x in 0..=n {
+                let (u, v) = (x as f32 / n as f32, y as f32 / n as f32);
+                vertices.push(ModelVertex {
+                    position: Vector3::new(u, v, height(u, v)),
+                    tex_coords: Vector2::new(u, v),
+                    normal: Vector3::unit_z(),
+                    tangent: Vector3::unit_x(),
+                    bitangent: Vector3::unit_y(),
+                });
+            }
+        }
+        let mut indices = Vec::new();
+        for y in 0..n {
+            for x in 0..n {
+                let i = y * (n + 1) + x;
+                indices.extend_from_slice(&[i, i + 1, i + n + 2, i, i + n + 2, i + n + 1]);
+            }
+        }
+        (vertices, indices)
+    }
+
+    fn check_valid(vertices: &[ModelVertex], indices: &[u32]) {
+        assert_eq!(indices.len() % 3, 0);
+        for tri in indices.chunks(3) {
+            assert!(tri.iter().all(|&i| (i as usize) < vertices.len()));
+            let p = |i: u32| vertices[i as usize].position;
+            let normal = (p(tri[1]) - p(tri[0])).cross(p(tri[2]) - p(tri[0]));
+            // Nothing degenerate or flipped
+            assert!(normal.z > 0.0, "{:?}", tri);
+        }
+    }
+
+    #[test]
+    fn flat_grids_simplify_for_free() {
+        let (vertices, indices) = grid(16, |_, _| 0.0);
+        let result = simplify(&vertices, &indices, indices.len() / 4, f32::INFINITY);
+        assert!(result.indices.len() <= indices.len() / 4);
+        assert!(result.error < 1e-4, "{}", result.error);
+        check_valid(&vertices, &result.indices);
+
+        // The outline survives, so the area is the same
+        let area: f32 = result
+            .indices
+            .chunks(3)
+            .map(|t| {
+                let p = |i: u32| vertices[i as usize].position;
+                (p(t[1]) - p(t[0])).cross(p(t[2]) - p(t[0])).z * 0.5
+            })
+            .sum();
+        assert!((area - 1.0).abs() < 1e-4, "{}", area);
+    }
+
+    #[test]
+    fn max_error_stops_simplifying() {
+        let (vertices, indices) = grid(12, |u, v| (u * 12.0).sin() * (v * 9.0).cos() * 0.2);
+        let loose = simplify(&vertices, &indices, 0, f32::INFINITY);
+        let tight = simplify(&vertices, &indices, 0, 0.001);
+        assert!(tight.indices.len() > loose.indices.len());
+        assert!(tight.error <= 0.001);
+        assert!(loose.error > tight.error);
+    }
+
+    #[test]
+    fn seams_are_locked() {
+        let (mut vertices, indices) = grid(4, |_, _| 0.0);
+        // Split the middle vertex in two with different UVs, like a seam
+        let middle = 2 * 5 + 2;
+        let mut split = vertices[middle];
+        split.tex_coords = Vector2::new(5.0, 5.0);
+        vertices.push(split);
+        let indices = indices
+            .chunks(3)
+            .enumerate()
+            .flat_map(|(t, tri)| {
+                let swap = t % 2 == 0;
+                tri.iter()
+                    .map(move |&i| if swap && i as usize == middle { 25 } else { i })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let result = simplify(&vertices, &indices, 0, f32::INFINITY);
+        check_valid(&vertices, &result.indices);
+        let used = result.indices.iter().copied().collect::<HashSet<_>>();
+        assert!(used.contains(&(middle as u32)) || used.contains(&25));
+    }
+
+    #[test]
+    fn level_selection() {
+        let sizes = [0.25, 0.1, 0.0];
+        assert_eq!(select_level(&sizes, 0.5), Some(0));
+        assert_eq!(select_level(&sizes, 0.25), Some(0));
+        assert_eq!(select_level(&sizes, 0.2), Some(1));
+        assert_eq!(select_level(&sizes, 0.0), Some(2));
+        assert_eq!(select_level(&[0.25, 0.1], 0.05), None);
+
+        let fovy = Rad(std::f32::consts::FRAC_PI_2);
+        assert!((screen_size(1.0, 10.0, fovy) - 0.1).abs() < 1e-5);
+        assert_eq!(screen_size(1.0, 0.5, fovy), f32::INFINITY);
+    }
+
+    #[test]
+    fn settings_need_matching_levels() {
+        assert!(LodSettings::default().validate().is_ok());
+        let settings = LodSettings {
+            ratios: vec![1.0, 0.5],
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
+        let settings = LodSettings {
+            ratios: Vec::new(),
+            screen_sizes: Vec::new(),
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn instances_are_bucketed_by_level() {
+        let at = |z: f32| Instance::new(Vector3::new(0.0, 0.0, z), Quaternion::one());
+        let instances = [at(100.0), at(2.0), at(20.0), at(3.0), at(1000.0)];
+        let fovy = Rad(std::f32::consts::FRAC_PI_2);
+        let sizes = [0.25, 0.03, 0.005];
+        let (raw, ranges) = bucket_instances(&instances, 1.0, &sizes, Point3::origin(), fovy);
+        // The one 1000 units away is too small to draw
+        assert_eq!(ranges, vec![0..2, 2..3, 3..4]);
+        // Instances keep their order within a level
+        let order = [1, 3, 2, 0];
+        for (raw, &i) in raw.iter().zip(&order) {
+            assert_eq!(raw.model, instances[i].to_raw().model);
+        }
+    }
+}
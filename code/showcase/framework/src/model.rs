@@ -3,6 +3,7 @@ use std::ops::Range;
 use std::path::Path;
 use wgpu::util::DeviceExt;
 
+use crate::buffer::ToRaw;
 use crate::texture;
 
 pub trait Vertex {
@@ -12,11 +13,11 @@ pub trait Vertex {
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct ModelVertex {
-    position: cgmath::Vector3<f32>,
-    tex_coords: cgmath::Vector2<f32>,
-    normal: cgmath::Vector3<f32>,
-    tangent: cgmath::Vector3<f32>,
-    bitangent: cgmath::Vector3<f32>,
+    pub position: cgmath::Vector3<f32>,
+    pub tex_coords: cgmath::Vector2<f32>,
+    pub normal: cgmath::Vector3<f32>,
+    pub tangent: cgmath::Vector3<f32>,
+    pub bitangent: cgmath::Vector3<f32>,
 }
 
 unsafe impl bytemuck::Zeroable for ModelVertex {}
@@ -62,8 +63,16 @@ impl Vertex for ModelVertex {
 
 pub struct Material<'a> {
     pub name: String,
+    /// The base color when used with the PBR pipeline
     pub diffuse_texture: texture::Texture<'a>,
     pub normal_texture: texture::Texture<'a>,
+    // These are only used by the PBR pipeline. Materials created
+    // with Material::new leave them empty.
+    pub metallic_roughness_texture: Option<texture::Texture<'a>>,
+    pub occlusion_texture: Option<texture::Texture<'a>>,
+    pub emissive_texture: Option<texture::Texture<'a>>,
+    pub factors: MaterialFactors,
+    pub factors_buffer: Option<wgpu::Buffer>,
     pub bind_group: wgpu::BindGroup,
 }
 
@@ -102,17 +111,231 @@ impl<'a> Material<'a> {
             name: String::from(name),
             diffuse_texture,
             normal_texture,
+            metallic_roughness_texture: None,
+            occlusion_texture: None,
+            emissive_texture: None,
+            factors: MaterialFactors::default(),
+            factors_buffer: None,
             bind_group,
         }
     }
+
+    /// Creates a material for [crate::PbrPipeline]. Missing textures
+    /// are replaced with 1x1 textures that leave the factors as is.
+    pub fn new_pbr(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        name: &str,
+        textures: PbrTextures<'a>,
+        factors: MaterialFactors,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Result<Self> {
+        let or_color = |texture: Option<texture::Texture<'a>>, color, is_normal_map| match texture {
+            Some(texture) => Ok(texture),
+            None => texture::Texture::from_color(device, queue, color, is_normal_map),
+        };
+        let diffuse_texture = or_color(textures.base_color, [255, 255, 255, 255], false)?;
+        let normal_texture = or_color(textures.normal, [128, 128, 255, 255], true)?;
+        let metallic_roughness_texture =
+            or_color(textures.metallic_roughness, [255, 255, 255, 255], true)?;
+        let occlusion_texture = or_color(textures.occlusion, [255, 255, 255, 255], true)?;
+        let emissive_texture = or_color(textures.emissive, [255, 255, 255, 255], false)?;
+
+        let factors_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Factors", name)),
+            contents: bytemuck::cast_slice(&[factors.to_raw()]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&normal_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&metallic_roughness_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Sampler(&metallic_roughness_texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::TextureView(&occlusion_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: wgpu::BindingResource::Sampler(&occlusion_texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: wgpu::BindingResource::TextureView(&emissive_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: wgpu::BindingResource::Sampler(&emissive_texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: wgpu::BindingResource::Buffer(factors_buffer.slice(..)),
+                },
+            ],
+            label: Some(name),
+        });
+
+        Ok(Self {
+            name: String::from(name),
+            diffuse_texture,
+            normal_texture,
+            metallic_roughness_texture: Some(metallic_roughness_texture),
+            occlusion_texture: Some(occlusion_texture),
+            emissive_texture: Some(emissive_texture),
+            factors,
+            factors_buffer: Some(factors_buffer),
+            bind_group,
+        })
+    }
+
+    /// Uploads [Material::factors] after they've been changed.
+    /// Does nothing for non PBR materials.
+    pub fn update_factors(&self, queue: &wgpu::Queue) {
+        if let Some(buffer) = &self.factors_buffer {
+            queue.write_buffer(buffer, 0, bytemuck::cast_slice(&[self.factors.to_raw()]));
+        }
+    }
+}
+
+/// The textures of a PBR material. Any of them can be left out.
+#[derive(Default)]
+pub struct PbrTextures<'a> {
+    pub base_color: Option<texture::Texture<'a>>,
+    pub normal: Option<texture::Texture<'a>>,
+    /// Roughness in green and metallic in blue, like glTF
+    pub metallic_roughness: Option<texture::Texture<'a>>,
+    pub occlusion: Option<texture::Texture<'a>>,
+    pub emissive: Option<texture::Texture<'a>>,
+}
+
+/// Multipliers for the PBR material textures. The defaults match glTF.
+#[derive(Debug, Copy, Clone)]
+pub struct MaterialFactors {
+    pub base_color: cgmath::Vector4<f32>,
+    pub emissive: cgmath::Vector3<f32>,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub occlusion_strength: f32,
+    pub normal_scale: f32,
+    /// Whether the surface is blended over what's behind it using
+    /// `base_color`'s alpha. Transparent materials are drawn after
+    /// everything opaque, see [crate::DrawTransparent].
+    pub transparent: bool,
+}
+
+impl Default for MaterialFactors {
+    fn default() -> Self {
+        Self {
+            base_color: cgmath::Vector4::new(1.0, 1.0, 1.0, 1.0),
+            emissive: cgmath::Vector3::new(0.0, 0.0, 0.0),
+            metallic: 1.0,
+            roughness: 1.0,
+            occlusion_strength: 1.0,
+            normal_scale: 1.0,
+            transparent: false,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct MaterialFactorsRaw {
+    base_color: cgmath::Vector4<f32>,
+    emissive: cgmath::Vector4<f32>,
+    metallic: f32,
+    roughness: f32,
+    occlusion_strength: f32,
+    normal_scale: f32,
+}
+
+unsafe impl bytemuck::Zeroable for MaterialFactorsRaw {}
+unsafe impl bytemuck::Pod for MaterialFactorsRaw {}
+
+impl ToRaw for MaterialFactors {
+    type Output = MaterialFactorsRaw;
+
+    fn to_raw(&self) -> MaterialFactorsRaw {
+        MaterialFactorsRaw {
+            base_color: self.base_color,
+            emissive: self.emissive.extend(0.0),
+            metallic: self.metallic,
+            roughness: self.roughness,
+            occlusion_strength: self.occlusion_strength,
+            normal_scale: self.normal_scale,
+        }
+    }
 }
 
 pub struct Mesh {
     pub name: String,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
+    /// The format of [Mesh::index_buffer]. Pipelines drawing this
+    /// mesh need the same one in [crate::RenderPipelineBuilder::index_format].
+    pub index_format: wgpu::IndexFormat,
     pub num_elements: u32,
     pub material: usize,
+    /// A CPU side copy of what's in the vertex buffer
+    pub vertices: Vec<ModelVertex>,
+    /// A CPU side copy of what's in the index buffer. This is always
+    /// `u32`, whatever [Mesh::index_format] is.
+    pub indices: Vec<u32>,
+}
+
+impl Mesh {
+    /// Uploads `vertices` and `indices`, which should already have
+    /// their tangents filled in
+    pub fn new(
+        device: &wgpu::Device,
+        name: &str,
+        vertices: Vec<ModelVertex>,
+        indices: Vec<u32>,
+        material: usize,
+    ) -> Self {
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Vertex Buffer", name)),
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsage::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Index Buffer", name)),
+            contents: bytemuck::cast_slice(&indices),
+            usage: wgpu::BufferUsage::INDEX,
+        });
+        Self {
+            name: name.to_string(),
+            vertex_buffer,
+            index_buffer,
+            index_format: wgpu::IndexFormat::Uint32,
+            num_elements: indices.len() as u32,
+            material,
+            vertices,
+            indices,
+        }
+    }
 }
 
 pub struct Model<'a> {
@@ -151,98 +374,219 @@ impl<'a> Model<'a> {
             ));
         }
 
-        let mut meshes = Vec::new();
-        for m in obj_models {
-            let mut vertices = Vec::new();
-            for i in 0..m.mesh.positions.len() / 3 {
-                vertices.push(ModelVertex {
-                    position: [
-                        m.mesh.positions[i * 3],
-                        m.mesh.positions[i * 3 + 1],
-                        m.mesh.positions[i * 3 + 2],
-                    ]
-                    .into(),
-                    tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]].into(),
-                    normal: [
-                        m.mesh.normals[i * 3],
-                        m.mesh.normals[i * 3 + 1],
-                        m.mesh.normals[i * 3 + 2],
-                    ]
-                    .into(),
-                    // We'll calculate these later
-                    tangent: [0.0; 3].into(),
-                    bitangent: [0.0; 3].into(),
-                });
+        let meshes = load_meshes(device, path.as_ref(), obj_models);
+
+        Ok(Self { meshes, materials })
+    }
+
+    /// Loads an obj for use with [crate::PbrPipeline]. The PBR
+    /// extension to the mtl format supplies the extra maps and
+    /// factors: `Pm`/`map_Pm` for metallic, `Pr`/`map_Pr` for
+    /// roughness and `Ke`/`map_Ke` for emissive. `map_Ka` is used
+    /// as the ambient occlusion map.
+    pub fn load_pbr<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        path: P,
+    ) -> Result<Self> {
+        let (obj_models, obj_materials) = tobj::load_obj(path.as_ref(), true)?;
+        let containing_folder = path.as_ref().parent().context("Directory has no parent")?;
+
+        let load_optional = |file: &str, is_normal_map| -> Result<Option<texture::Texture<'a>>> {
+            if file.is_empty() {
+                Ok(None)
+            } else {
+                let path = containing_folder.join(file);
+                Ok(Some(texture::Texture::load(
+                    device,
+                    queue,
+                    path,
+                    is_normal_map,
+                )?))
             }
+        };
+        let param = |mat: &tobj::Material, key: &str| -> Option<Vec<f32>> {
+            mat.unknown_param.get(key).map(|v| {
+                v.split_whitespace()
+                    .filter_map(|f| f.parse().ok())
+                    .collect()
+            })
+        };
 
-            let indices = &m.mesh.indices;
-
-            // Calculate tangents and bitangets. We're going to
-            // use the triangles, so we need to loop through the
-            // indices in chunks of 3
-            for c in indices.chunks(3) {
-                let v0 = vertices[c[0] as usize];
-                let v1 = vertices[c[1] as usize];
-                let v2 = vertices[c[2] as usize];
-
-                let pos0 = v0.position;
-                let pos1 = v1.position;
-                let pos2 = v2.position;
-
-                let uv0 = v0.tex_coords;
-                let uv1 = v1.tex_coords;
-                let uv2 = v2.tex_coords;
-
-                // Calculate the edges of the triangle
-                let delta_pos1 = pos1 - pos0;
-                let delta_pos2 = pos2 - pos0;
-
-                // This will give us a direction to calculate the
-                // tangent and bitangent
-                let delta_uv1 = uv1 - uv0;
-                let delta_uv2 = uv2 - uv0;
-
-                // Solving the following system of equations will
-                // give us the tangent and bitangent.
-                //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
-                //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
-                // Luckily, the place I found this equation provided
-                // the solution!
-                let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
-                let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
-                let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * r;
-
-                // We'll use the same tangent/bitangent for each vertex in the triangle
-                vertices[c[0] as usize].tangent = tangent;
-                vertices[c[1] as usize].tangent = tangent;
-                vertices[c[2] as usize].tangent = tangent;
-
-                vertices[c[0] as usize].bitangent = bitangent;
-                vertices[c[1] as usize].bitangent = bitangent;
-                vertices[c[2] as usize].bitangent = bitangent;
+        let mut materials = Vec::new();
+        for mat in obj_materials {
+            let empty = String::new();
+            let metallic_map = mat.unknown_param.get("map_Pm").unwrap_or(&empty);
+            let roughness_map = mat.unknown_param.get("map_Pr").unwrap_or(&empty);
+            let emissive_map = mat.unknown_param.get("map_Ke").unwrap_or(&empty);
+
+            let metallic_roughness = if metallic_map.is_empty() && roughness_map.is_empty() {
+                None
+            } else {
+                Some(texture::Texture::pack_metallic_roughness(
+                    device,
+                    queue,
+                    (!metallic_map.is_empty()).then(|| containing_folder.join(metallic_map)),
+                    (!roughness_map.is_empty()).then(|| containing_folder.join(roughness_map)),
+                )?)
+            };
+
+            let textures = PbrTextures {
+                base_color: load_optional(&mat.diffuse_texture, false)?,
+                normal: load_optional(&mat.normal_texture, true)?,
+                metallic_roughness,
+                occlusion: load_optional(&mat.ambient_texture, true)?,
+                emissive: load_optional(emissive_map, false)?,
+            };
+
+            let mut factors = MaterialFactors::default();
+            if textures.base_color.is_none() {
+                let [r, g, b] = mat.diffuse;
+                factors.base_color = cgmath::Vector4::new(r, g, b, mat.dissolve);
+                factors.transparent = mat.dissolve < 1.0;
+            }
+            // Most obj files aren't metals, so unlike glTF we only
+            // treat a surface as metallic if the mtl says so.
+            factors.metallic = match param(&mat, "Pm").and_then(|v| v.first().copied()) {
+                Some(metallic) => metallic,
+                None if textures.metallic_roughness.is_some() => 1.0,
+                None => 0.0,
+            };
+            if let Some(roughness) = param(&mat, "Pr").and_then(|v| v.first().copied()) {
+                factors.roughness = roughness;
+            }
+            match param(&mat, "Ke") {
+                Some(ke) if ke.len() >= 3 => {
+                    factors.emissive = cgmath::Vector3::new(ke[0], ke[1], ke[2]);
+                }
+                _ if textures.emissive.is_some() => {
+                    factors.emissive = cgmath::Vector3::new(1.0, 1.0, 1.0);
+                }
+                _ => {}
             }
 
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", path.as_ref())),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsage::VERTEX,
-            });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", path.as_ref())),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsage::INDEX,
-            });
+            materials.push(Material::new_pbr(
+                device, queue, &mat.name, textures, factors, layout,
+            )?);
+        }
 
-            meshes.push(Mesh {
-                name: m.name,
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: m.mesh.material_id.unwrap_or(0),
+        let meshes = load_meshes(device, path.as_ref(), obj_models);
+
+        Ok(Self { meshes, materials })
+    }
+
+    /// The radius of a sphere around the model's origin that holds
+    /// every vertex
+    pub fn bounding_radius(&self) -> f32 {
+        use cgmath::InnerSpace;
+        self.meshes
+            .iter()
+            .flat_map(|m| m.vertices.iter())
+            .map(|v| v.position.magnitude())
+            .fold(0.0, f32::max)
+    }
+}
+
+fn load_meshes(device: &wgpu::Device, path: &Path, obj_models: Vec<tobj::Model>) -> Vec<Mesh> {
+    let mut meshes = Vec::new();
+    for m in obj_models {
+        let mut vertices = Vec::new();
+        for i in 0..m.mesh.positions.len() / 3 {
+            vertices.push(ModelVertex {
+                position: [
+                    m.mesh.positions[i * 3],
+                    m.mesh.positions[i * 3 + 1],
+                    m.mesh.positions[i * 3 + 2],
+                ]
+                .into(),
+                tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]].into(),
+                normal: [
+                    m.mesh.normals[i * 3],
+                    m.mesh.normals[i * 3 + 1],
+                    m.mesh.normals[i * 3 + 2],
+                ]
+                .into(),
+                // We'll calculate these later
+                tangent: [0.0; 3].into(),
+                bitangent: [0.0; 3].into(),
             });
         }
 
-        Ok(Self { meshes, materials })
+        calc_tangents(&mut vertices, &m.mesh.indices);
+
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Vertex Buffer", path)),
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsage::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Index Buffer", path)),
+            contents: bytemuck::cast_slice(&m.mesh.indices),
+            usage: wgpu::BufferUsage::INDEX,
+        });
+
+        meshes.push(Mesh {
+            name: m.name,
+            vertex_buffer,
+            index_buffer,
+            index_format: wgpu::IndexFormat::Uint32,
+            num_elements: m.mesh.indices.len() as u32,
+            material: m.mesh.material_id.unwrap_or(0),
+            vertices,
+            indices: m.mesh.indices,
+        });
+    }
+
+    meshes
+}
+
+/// Fills in `tangent` and `bitangent` from the positions and UVs of
+/// the triangles in `indices`
+pub(crate) fn calc_tangents(vertices: &mut [ModelVertex], indices: &[u32]) {
+    // Calculate tangents and bitangets. We're going to
+    // use the triangles, so we need to loop through the
+    // indices in chunks of 3
+    for c in indices.chunks(3) {
+        let v0 = vertices[c[0] as usize];
+        let v1 = vertices[c[1] as usize];
+        let v2 = vertices[c[2] as usize];
+
+        let pos0 = v0.position;
+        let pos1 = v1.position;
+        let pos2 = v2.position;
+
+        let uv0 = v0.tex_coords;
+        let uv1 = v1.tex_coords;
+        let uv2 = v2.tex_coords;
+
+        // Calculate the edges of the triangle
+        let delta_pos1 = pos1 - pos0;
+        let delta_pos2 = pos2 - pos0;
+
+        // This will give us a direction to calculate the
+        // tangent and bitangent
+        let delta_uv1 = uv1 - uv0;
+        let delta_uv2 = uv2 - uv0;
+
+        // Solving the following system of equations will
+        // give us the tangent and bitangent.
+        //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
+        //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
+        // Luckily, the place I found this equation provided
+        // the solution!
+        let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
+        let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
+        let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * r;
+
+        // We'll use the same tangent/bitangent for each vertex in the triangle
+        vertices[c[0] as usize].tangent = tangent;
+        vertices[c[1] as usize].tangent = tangent;
+        vertices[c[2] as usize].tangent = tangent;
+
+        vertices[c[0] as usize].bitangent = bitangent;
+        vertices[c[1] as usize].bitangent = bitangent;
+        vertices[c[2] as usize].bitangent = bitangent;
     }
 }
 
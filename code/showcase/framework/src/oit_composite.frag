@@ -0,0 +1,26 @@
+#version 450
+
+// Resolves framework::WeightedBlendedOit's targets into an average
+// color, blended over the opaque scene by how much of it is covered.
+
+layout(location=0) in vec2 v_ndc;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_accum;
+layout(set=0, binding=1) uniform texture2D t_revealage;
+// texelFetch ignores filtering, but GLSL needs a sampler to combine
+// the textures with
+layout(set=0, binding=2) uniform sampler s_oit;
+
+void main() {
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    float revealage = texelFetch(sampler2D(t_revealage, s_oit), pixel, 0).r;
+    // Nothing transparent covers this pixel
+    if (revealage >= 1.0) {
+        discard;
+    }
+    vec4 accum = texelFetch(sampler2D(t_accum, s_oit), pixel, 0);
+    vec3 average = accum.rgb / max(accum.a, 1e-5);
+    f_color = vec4(average, 1.0 - revealage);
+}
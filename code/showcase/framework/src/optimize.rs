@@ -0,0 +1,493 @@
+use std::collections::HashMap;
+use std::fmt;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+
+use crate::model::{Mesh, Model, ModelVertex};
+
+/// Marks vertices in a remap table that no index uses
+pub const UNUSED: u32 = u32::MAX;
+
+/// The post transform cache [optimize_vertex_cache] optimizes for
+const CACHE_SIZE: usize = 32;
+
+/// The FIFO cache [OptimizeStats] measures ACMR with. Most GPUs have
+/// something about this size.
+pub const ACMR_CACHE_SIZE: usize = 16;
+
+/**
+ * Merges vertices whose bytes are identical. `indices` is rewritten to
+ * use the merged vertices. Returns a remap table from old vertex to
+ * new, for [remap_vertices], and the number of vertices left.
+ */
+pub fn dedup_vertices<V: bytemuck::Pod>(vertices: &[V], indices: &mut [u32]) -> (Vec<u32>, usize) {
+    let mut remap = vec![UNUSED; vertices.len()];
+    let mut unique = HashMap::new();
+    for (i, vertex) in vertices.iter().enumerate() {
+        let next = unique.len() as u32;
+        remap[i] = *unique.entry(bytemuck::bytes_of(vertex)).or_insert(next);
+    }
+    for index in indices.iter_mut() {
+        *index = remap[*index as usize];
+    }
+    (remap, unique.len())
+}
+
+/**
+ * Renumbers vertices in the order `indices` first uses them, so the
+ * vertex shader reads through the vertex buffer mostly in order.
+ * Vertices no index uses are dropped. Returns a remap table like
+ * [dedup_vertices].
+ */
+pub fn optimize_vertex_fetch(indices: &mut [u32], vertex_count: usize) -> (Vec<u32>, usize) {
+    let mut remap = vec![UNUSED; vertex_count];
+    let mut next = 0;
+    for index in indices.iter_mut() {
+        let new = &mut remap[*index as usize];
+        if *new == UNUSED {
+            *new = next;
+            next += 1;
+        }
+        *index = *new;
+    }
+    (remap, next as usize)
+}
+
+/// Moves each vertex to where `remap` says it goes. Use this on any
+/// data that runs parallel to the vertices.
+pub fn remap_vertices<V: Copy>(vertices: &[V], remap: &[u32], count: usize) -> Vec<V> {
+    let mut out = vec![None; count];
+    for (vertex, &new) in vertices.iter().zip(remap) {
+        if new != UNUSED {
+            out[new as usize] = Some(*vertex);
+        }
+    }
+    out.into_iter()
+        .map(|v| v.expect("Remap left a vertex empty"))
+        .collect()
+}
+
+fn vertex_score(cache_position: Option<usize>, remaining: u32) -> f32 {
+    if remaining == 0 {
+        return -1.0;
+    }
+    let cache_score = match cache_position {
+        None => 0.0,
+        // The last triangle's vertices score a little lower, so the
+        // next triangle doesn't just reuse the same edge
+        Some(p) if p < 3 => 0.75,
+        Some(p) => (1.0 - (p - 3) as f32 / (CACHE_SIZE - 3) as f32).powf(1.5),
+    };
+    // Vertices with few triangles left get finished off quickly
+    cache_score + 2.0 * (remaining as f32).powf(-0.5)
+}
+
+/**
+ * Reorders triangles so their vertices are more likely to already be
+ * in the GPU's post transform cache, using Tom Forsyth's linear-speed
+ * vertex cache optimization. The triangles themselves don't change,
+ * so this can be used on any triangle list.
+ */
+pub fn optimize_vertex_cache(indices: &[u32], vertex_count: usize) -> Vec<u32> {
+    let triangle_count = indices.len() / 3;
+
+    // Each vertex's triangles, packed into one array
+    let mut remaining = vec![0u32; vertex_count];
+    for &index in indices {
+        remaining[index as usize] += 1;
+    }
+    let mut offsets = vec![0usize; vertex_count + 1];
+    for v in 0..vertex_count {
+        offsets[v + 1] = offsets[v] + remaining[v] as usize;
+    }
+    let mut adjacency = vec![0usize; indices.len()];
+    let mut fill = offsets.clone();
+    for (t, triangle) in indices.chunks_exact(3).enumerate() {
+        for &v in triangle {
+            adjacency[fill[v as usize]] = t;
+            fill[v as usize] += 1;
+        }
+    }
+
+    let mut scores = remaining
+        .iter()
+        .map(|&r| vertex_score(None, r))
+        .collect::<Vec<_>>();
+    let triangle_score = |scores: &[f32], t: usize| -> f32 {
+        indices[t * 3..t * 3 + 3]
+            .iter()
+            .map(|&v| scores[v as usize])
+            .sum()
+    };
+
+    let mut emitted = vec![false; triangle_count];
+    let mut cache: Vec<u32> = Vec::with_capacity(CACHE_SIZE + 3);
+    let mut out = Vec::with_capacity(triangle_count * 3);
+    let mut next_unemitted = 0;
+    let mut best = (0..triangle_count).max_by(|&a, &b| {
+        triangle_score(&scores, a)
+            .partial_cmp(&triangle_score(&scores, b))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    while let Some(t) = best {
+        emitted[t] = true;
+        let triangle = &indices[t * 3..t * 3 + 3];
+        out.extend_from_slice(triangle);
+
+        for &v in triangle {
+            let v = v as usize;
+            let list = &mut adjacency[offsets[v]..offsets[v] + remaining[v] as usize];
+            if let Some(i) = list.iter().position(|&other| other == t) {
+                let last = list.len() - 1;
+                list.swap(i, last);
+                remaining[v] -= 1;
+            }
+        }
+
+        // The triangle's vertices move to the front of the cache
+        let mut new_cache = triangle.to_vec();
+        new_cache.extend(cache.iter().filter(|v| !triangle.contains(v)));
+        for (i, &v) in new_cache.iter().enumerate() {
+            let position = if i < CACHE_SIZE { Some(i) } else { None };
+            scores[v as usize] = vertex_score(position, remaining[v as usize]);
+        }
+
+        // Only triangles touching the cache changed score, so the
+        // best one is almost always among them
+        best = None;
+        let mut best_score = f32::MIN;
+        for &v in &new_cache {
+            let v = v as usize;
+            for &other in &adjacency[offsets[v]..offsets[v] + remaining[v] as usize] {
+                let score = triangle_score(&scores, other);
+                if score > best_score {
+                    best = Some(other);
+                    best_score = score;
+                }
+            }
+        }
+        new_cache.truncate(CACHE_SIZE);
+        cache = new_cache;
+
+        if best.is_none() {
+            while next_unemitted < triangle_count && emitted[next_unemitted] {
+                next_unemitted += 1;
+            }
+            if next_unemitted < triangle_count {
+                best = Some(next_unemitted);
+            }
+        }
+    }
+
+    out
+}
+
+/**
+ * The average cache miss ratio: how many vertices a FIFO cache of
+ * `cache_size` has to transform per triangle. 3.0 is the worst, and
+ * around 0.5 to 0.7 is as good as most meshes get.
+ */
+pub fn acmr(indices: &[u32], cache_size: usize) -> f32 {
+    let triangles = indices.len() / 3;
+    if triangles == 0 {
+        return 0.0;
+    }
+    let mut cache = std::collections::VecDeque::with_capacity(cache_size);
+    let mut misses = 0;
+    for &index in indices {
+        if !cache.contains(&index) {
+            misses += 1;
+            if cache.len() == cache_size {
+                cache.pop_front();
+            }
+            cache.push_back(index);
+        }
+    }
+    misses as f32 / triangles as f32
+}
+
+/// [wgpu::IndexFormat::Uint16] if every vertex fits in 16 bits
+pub fn index_format_for(vertex_count: usize) -> wgpu::IndexFormat {
+    if vertex_count <= u16::MAX as usize + 1 {
+        wgpu::IndexFormat::Uint16
+    } else {
+        wgpu::IndexFormat::Uint32
+    }
+}
+
+/// Packs `indices` for an index buffer of `format`. 16 bit indices
+/// are padded to a multiple of 4 bytes, which wgpu needs for copies.
+pub fn index_bytes(indices: &[u32], format: wgpu::IndexFormat) -> Vec<u8> {
+    match format {
+        wgpu::IndexFormat::Uint16 => {
+            let mut short = indices.iter().map(|&i| i as u16).collect::<Vec<_>>();
+            if short.len() % 2 == 1 {
+                short.push(0);
+            }
+            bytemuck::cast_slice(&short).to_vec()
+        }
+        wgpu::IndexFormat::Uint32 => bytemuck::cast_slice(indices).to_vec(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptimizeStats {
+    pub vertices_before: usize,
+    pub vertices_after: usize,
+    /// Measured with [ACMR_CACHE_SIZE]
+    pub acmr_before: f32,
+    pub acmr_after: f32,
+    /// The smallest format that fits from [optimize_mesh], or the one
+    /// the mesh ended up with from [Mesh::optimize]
+    pub index_format: wgpu::IndexFormat,
+}
+
+impl fmt::Display for OptimizeStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "vertices {} -> {}, ACMR {:.3} -> {:.3}, {:?} indices",
+            self.vertices_before,
+            self.vertices_after,
+            self.acmr_before,
+            self.acmr_after,
+            self.index_format
+        )
+    }
+}
+
+/// The result of [optimize_mesh]
+#[derive(Debug, Clone)]
+pub struct Optimized<V> {
+    pub vertices: Vec<V>,
+    pub indices: Vec<u32>,
+    pub stats: OptimizeStats,
+}
+
+/**
+ * Runs every pass in the order they work best in: merge duplicate
+ * vertices, reorder triangles for the vertex cache, then reorder
+ * vertices for fetching.
+ */
+pub fn optimize_mesh<V: bytemuck::Pod>(vertices: &[V], indices: &[u32]) -> Optimized<V> {
+    let acmr_before = acmr(indices, ACMR_CACHE_SIZE);
+
+    let mut indices = indices.to_vec();
+    let (remap, count) = dedup_vertices(vertices, &mut indices);
+    let deduped = remap_vertices(vertices, &remap, count);
+
+    let mut indices = optimize_vertex_cache(&indices, count);
+    let (remap, count) = optimize_vertex_fetch(&mut indices, count);
+    let vertices_out = remap_vertices(&deduped, &remap, count);
+
+    let stats = OptimizeStats {
+        vertices_before: vertices.len(),
+        vertices_after: count,
+        acmr_before,
+        acmr_after: acmr(&indices, ACMR_CACHE_SIZE),
+        index_format: index_format_for(count),
+    };
+    Optimized {
+        vertices: vertices_out,
+        indices,
+        stats,
+    }
+}
+
+impl Mesh {
+    /// Optimizes the mesh with [optimize_mesh] and replaces its
+    /// buffers, switching to the smallest index format that fits. Pipelines
+    /// drawing it have to be built with the new [Mesh::index_format].
+    pub fn optimize(&mut self, device: &wgpu::Device) -> OptimizeStats {
+        let optimized = optimize_mesh(&self.vertices, &self.indices);
+        let format = optimized.stats.index_format;
+        self.replace_buffers(device, optimized, format)
+    }
+
+    /// Like [Mesh::optimize], but keeps `index_format` whatever the
+    /// vertex count is, so the mesh can share a pipeline with others
+    pub fn optimize_with_format(
+        &mut self,
+        device: &wgpu::Device,
+        index_format: wgpu::IndexFormat,
+    ) -> OptimizeStats {
+        let optimized = optimize_mesh(&self.vertices, &self.indices);
+        self.replace_buffers(device, optimized, index_format)
+    }
+
+    fn replace_buffers(
+        &mut self,
+        device: &wgpu::Device,
+        mut optimized: Optimized<ModelVertex>,
+        format: wgpu::IndexFormat,
+    ) -> OptimizeStats {
+        optimized.stats.index_format = format;
+        self.vertex_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some(&format!("{} Vertex Buffer", self.name)),
+            contents: bytemuck::cast_slice(&optimized.vertices),
+            usage: wgpu::BufferUsage::VERTEX,
+        });
+        self.index_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some(&format!("{} Index Buffer", self.name)),
+            contents: &index_bytes(&optimized.indices, format),
+            usage: wgpu::BufferUsage::INDEX,
+        });
+        self.index_format = format;
+        self.num_elements = optimized.indices.len() as u32;
+        self.vertices = optimized.vertices;
+        self.indices = optimized.indices;
+        optimized.stats
+    }
+}
+
+impl<'a> Model<'a> {
+    /**
+     * Optimizes every mesh and switches them all to the smallest index
+     * format that fits the biggest one, so one pipeline built with
+     * [Model::index_format] can still draw the whole model.
+     */
+    pub fn optimize(&mut self, device: &wgpu::Device) -> Vec<OptimizeStats> {
+        let largest = self
+            .meshes
+            .iter()
+            .map(|m| {
+                let mut indices = m.indices.clone();
+                dedup_vertices(&m.vertices, &mut indices).1
+            })
+            .max()
+            .unwrap_or(0);
+        self.optimize_with_format(device, index_format_for(largest))
+    }
+
+    /// Optimizes every mesh, giving them all `index_format`
+    pub fn optimize_with_format(
+        &mut self,
+        device: &wgpu::Device,
+        index_format: wgpu::IndexFormat,
+    ) -> Vec<OptimizeStats> {
+        self.meshes
+            .iter_mut()
+            .map(|m| m.optimize_with_format(device, index_format))
+            .collect()
+    }
+
+    pub fn index_format(&self) -> wgpu::IndexFormat {
+        self.meshes
+            .first()
+            .map(|m| m.index_format)
+            .unwrap_or(wgpu::IndexFormat::Uint32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An `n` by `n` grid of quads, with the triangles in a scattered
+    /// order
+    fn scattered_grid(n: u32) -> (Vec<[f32; 2]>, Vec<u32>) {
+        let mut vertices = Vec::new();
+        for y in 0..=n {
+            for x in 0..=n {
+                vertices.push([x as f32, y as f32]);
+            }
+        }
+        let mut triangles = Vec::new();
+        for y in 0..n {
+            for x in 0..n {
+                let i = y * (n + 1) + x;
+                triangles.push([i, i + 1, i + n + 2]);
+                triangles.push([i, i + n + 2, i + n + 1]);
+            }
+        }
+        let count = triangles.len();
+        let mut indices = Vec::new();
+        // 7919 is prime, so this visits every triangle once
+        for k in 0..count {
+            indices.extend_from_slice(&triangles[(k * 7919) % count]);
+        }
+        (vertices, indices)
+    }
+
+    fn sorted_triangles(vertices: &[[f32; 2]], indices: &[u32]) -> Vec<[[u32; 2]; 3]> {
+        let mut triangles = indices
+            .chunks(3)
+            .map(|t| {
+                let mut corners = [[0; 2]; 3];
+                for (corner, &i) in corners.iter_mut().zip(t) {
+                    let v = vertices[i as usize];
+                    *corner = [v[0].to_bits(), v[1].to_bits()];
+                }
+                // Rotate so the smallest corner is first, keeping winding
+                let first = (0..3).min_by_key(|&k| corners[k]).unwrap();
+                corners.rotate_left(first);
+                corners
+            })
+            .collect::<Vec<_>>();
+        triangles.sort();
+        triangles
+    }
+
+    #[test]
+    fn dedup_merges_identical_vertices() {
+        let vertices = [
+            [0.0, 0.0],
+            [1.0, 0.0],
+            [1.0, 1.0],
+            [0.0, 0.0],
+            [1.0, 1.0],
+            [0.0, 1.0f32],
+        ];
+        let mut indices = vec![0, 1, 2, 3, 4, 5];
+        let (remap, count) = dedup_vertices(&vertices, &mut indices);
+        assert_eq!(count, 4);
+        assert_eq!(remap, vec![0, 1, 2, 0, 2, 3]);
+        assert_eq!(indices, vec![0, 1, 2, 0, 2, 3]);
+        let merged = remap_vertices(&vertices, &remap, count);
+        assert_eq!(merged, vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]]);
+    }
+
+    #[test]
+    fn fetch_order_follows_first_use() {
+        let mut indices = vec![5, 2, 7, 2, 7, 3];
+        let (remap, count) = optimize_vertex_fetch(&mut indices, 8);
+        assert_eq!(count, 4);
+        assert_eq!(indices, vec![0, 1, 2, 1, 2, 3]);
+        assert_eq!(remap[5], 0);
+        assert_eq!(remap[0], UNUSED);
+    }
+
+    #[test]
+    fn acmr_counts_misses_per_triangle() {
+        assert_eq!(acmr(&[0, 1, 2, 3, 4, 5], 16), 3.0);
+        assert_eq!(acmr(&[0, 1, 2, 2, 1, 3], 16), 2.0);
+        // Everything falls out of a cache of 3 before it's reused
+        assert_eq!(acmr(&[0, 1, 2, 3, 4, 5, 0, 1, 2], 3), 3.0);
+        assert_eq!(acmr(&[], 16), 0.0);
+    }
+
+    #[test]
+    fn cache_optimization_keeps_triangles_and_lowers_acmr() {
+        let (vertices, indices) = scattered_grid(24);
+        let optimized = optimize_mesh(&vertices, &indices);
+        assert_eq!(
+            sorted_triangles(&vertices, &indices),
+            sorted_triangles(&optimized.vertices, &optimized.indices)
+        );
+        assert_eq!(optimized.stats.vertices_after, vertices.len());
+        assert!(optimized.stats.acmr_before > 2.0, "{}", optimized.stats);
+        assert!(optimized.stats.acmr_after < 1.0, "{}", optimized.stats);
+    }
+
+    #[test]
+    fn index_format_follows_vertex_count() {
+        assert_eq!(index_format_for(100), wgpu::IndexFormat::Uint16);
+        assert_eq!(index_format_for(65536), wgpu::IndexFormat::Uint16);
+        assert_eq!(index_format_for(65537), wgpu::IndexFormat::Uint32);
+
+        let bytes = index_bytes(&[1, 2, 3], wgpu::IndexFormat::Uint16);
+        assert_eq!(bytes.len(), 8);
+        let bytes = index_bytes(&[1, 2, 3], wgpu::IndexFormat::Uint32);
+        assert_eq!(bytes.len(), 12);
+    }
+}
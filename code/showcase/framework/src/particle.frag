@@ -0,0 +1,13 @@
+#version 450
+
+layout(location=0) in vec4 v_color;
+layout(location=1) in vec2 v_uv;
+
+layout(location=0) out vec4 f_color;
+
+void main() {
+    // A soft round dot
+    float d = length(v_uv * 2.0 - 1.0);
+    float alpha = v_color.a * (1.0 - smoothstep(0.5, 1.0, d));
+    f_color = vec4(v_color.rgb, alpha);
+}
@@ -0,0 +1,70 @@
+#version 450
+
+layout(location=0) out vec4 v_color;
+layout(location=1) out vec2 v_uv;
+
+struct Particle {
+    vec4 position_age;
+    vec4 velocity_lifetime;
+};
+
+layout(set=0, binding=0)
+uniform Uniforms {
+    vec4 u_view_position;
+    mat4 u_view_proj;
+};
+
+layout(set=1, binding=0) readonly buffer Particles {
+    Particle particles[];
+};
+layout(set=1, binding=1) readonly buffer Indices {
+    uint indices[];
+};
+// CURVE_SAMPLES evenly spaced samples of each curve over a lifetime
+layout(set=1, binding=2)
+uniform Curves {
+    vec4 u_colors[16];
+    vec4 u_sizes[4];
+};
+
+const vec2 CORNERS[6] = vec2[6](
+    vec2(-1.0, -1.0), vec2(1.0, -1.0), vec2(1.0, 1.0),
+    vec2(-1.0, -1.0), vec2(1.0, 1.0), vec2(-1.0, 1.0)
+);
+
+float size_at(uint i) {
+    return u_sizes[i / 4][i % 4];
+}
+
+void main() {
+    Particle p = particles[indices[gl_InstanceIndex]];
+    float age = p.position_age.w;
+    float lifetime = p.velocity_lifetime.w;
+    if (age >= lifetime) {
+        // Outside the clip volume, so nothing gets drawn
+        gl_Position = vec4(0.0, 0.0, -2.0, 1.0);
+        v_color = vec4(0.0);
+        v_uv = vec2(0.0);
+        return;
+    }
+
+    float t = clamp(age / lifetime, 0.0, 1.0) * 15.0;
+    uint a = uint(floor(t));
+    uint b = min(a + 1, 15);
+    float f = t - float(a);
+    v_color = mix(u_colors[a], u_colors[b], f);
+    float size = mix(size_at(a), size_at(b), f);
+
+    // Face the camera's position rather than its direction so
+    // particles don't rotate as the camera turns
+    vec3 position = p.position_age.xyz;
+    vec3 to_camera = normalize(u_view_position.xyz - position);
+    vec3 right = cross(vec3(0.0, 1.0, 0.0), to_camera);
+    right = length(right) > 0.001 ? normalize(right) : vec3(1.0, 0.0, 0.0);
+    vec3 up = cross(to_camera, right);
+
+    vec2 corner = CORNERS[gl_VertexIndex];
+    vec3 world = position + (right * corner.x + up * corner.y) * size * 0.5;
+    v_uv = corner * 0.5 + 0.5;
+    gl_Position = u_view_proj * vec4(world, 1.0);
+}
@@ -0,0 +1,1086 @@
+use anyhow::*;
+use cgmath::*;
+use std::mem;
+use std::path::Path;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+
+use crate::compute::{workgroup_count, StorageBinding, StorageEntry, DEFAULT_WORKGROUP_SIZE};
+use crate::debug::perpendiculars;
+use crate::model::Mesh;
+use crate::pipeline::{BlendMode, ComputePipelineBuilder, RenderPipelineBuilder};
+use crate::texture::Texture;
+
+/// How many evenly spaced samples of each curve the GPU gets
+pub const CURVE_SAMPLES: usize = 16;
+
+/// Sort steps use dynamic offsets, which have to be this far apart
+const SORT_STEP_STRIDE: wgpu::BufferAddress = 256;
+
+/// Values a [Curve] can blend between
+pub trait CurveValue: Copy {
+    fn lerp(self, other: Self, t: f32) -> Self;
+}
+
+impl CurveValue for f32 {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl CurveValue for Vector4<f32> {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+/**
+ * A value over a particle's life, from 0 when it spawns to 1 when it
+ * dies. Values between keys are linearly interpolated and values past
+ * the first and last keys are held.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Curve<T> {
+    keys: Vec<(f32, T)>,
+}
+
+impl<T: CurveValue> Curve<T> {
+    pub fn constant(value: T) -> Self {
+        Self {
+            keys: vec![(0.0, value)],
+        }
+    }
+
+    /// Only for building up a curve with [Curve::key]. Sampling it
+    /// before a key is added panics.
+    fn empty() -> Self {
+        Self { keys: Vec::new() }
+    }
+
+    /// Goes from `from` at birth to `to` at death
+    pub fn linear(from: T, to: T) -> Self {
+        Self {
+            keys: vec![(0.0, from), (1.0, to)],
+        }
+    }
+
+    /// Adds a key at `t`, replacing any key already there
+    pub fn key(&mut self, t: f32, value: T) -> &mut Self {
+        let t = t.clamp(0.0, 1.0);
+        self.keys.retain(|(key, _)| (key - t).abs() > f32::EPSILON);
+        let index = self
+            .keys
+            .iter()
+            .position(|(key, _)| *key > t)
+            .unwrap_or(self.keys.len());
+        self.keys.insert(index, (t, value));
+        self
+    }
+
+    pub fn sample(&self, t: f32) -> T {
+        let (first, last) = (self.keys[0], self.keys[self.keys.len() - 1]);
+        if t <= first.0 {
+            return first.1;
+        }
+        if t >= last.0 {
+            return last.1;
+        }
+        let next = self.keys.iter().position(|(key, _)| *key > t).unwrap_or(0);
+        let (t0, a) = self.keys[next - 1];
+        let (t1, b) = self.keys[next];
+        a.lerp(b, (t - t0) / (t1 - t0))
+    }
+
+    /// [CURVE_SAMPLES] evenly spaced samples from birth to death
+    pub fn bake(&self) -> [T; CURVE_SAMPLES] {
+        let mut samples = [self.keys[0].1; CURVE_SAMPLES];
+        for (i, sample) in samples.iter_mut().enumerate() {
+            *sample = self.sample(i as f32 / (CURVE_SAMPLES - 1) as f32);
+        }
+        samples
+    }
+}
+
+/// Triangles to spawn particles on, picked in proportion to their area
+#[derive(Debug, Clone)]
+pub struct MeshSurface {
+    triangles: Vec<[Vector3<f32>; 3]>,
+    /// The running total of the triangles' areas
+    areas: Vec<f32>,
+}
+
+impl MeshSurface {
+    pub fn new(positions: &[Vector3<f32>], indices: &[u32]) -> Self {
+        let mut triangles = Vec::with_capacity(indices.len() / 3);
+        let mut areas = Vec::with_capacity(indices.len() / 3);
+        let mut total = 0.0;
+        for tri in indices.chunks_exact(3) {
+            let corners = [
+                positions[tri[0] as usize],
+                positions[tri[1] as usize],
+                positions[tri[2] as usize],
+            ];
+            let area = (corners[1] - corners[0])
+                .cross(corners[2] - corners[0])
+                .magnitude()
+                * 0.5;
+            if area <= 0.0 {
+                continue;
+            }
+            total += area;
+            triangles.push(corners);
+            areas.push(total);
+        }
+        Self { triangles, areas }
+    }
+
+    pub fn from_mesh(mesh: &Mesh) -> Self {
+        let positions = mesh.vertices.iter().map(|v| v.position).collect::<Vec<_>>();
+        Self::new(&positions, &mesh.indices)
+    }
+
+    pub fn area(&self) -> f32 {
+        self.areas.last().copied().unwrap_or(0.0)
+    }
+
+    /// A random point on the surface and the normal there
+    fn sample(&self, rng: &mut Rng) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        let target = rng.next_f32() * self.area();
+        let index = self
+            .areas
+            .iter()
+            .position(|&total| total >= target)
+            .or_else(|| self.triangles.len().checked_sub(1))?;
+        let [a, b, c] = self.triangles[index];
+        // Folding the square onto the triangle keeps points uniform
+        let (mut u, mut v) = (rng.next_f32(), rng.next_f32());
+        if u + v > 1.0 {
+            u = 1.0 - u;
+            v = 1.0 - v;
+        }
+        let point = a + (b - a) * u + (c - a) * v;
+        let normal = (b - a).cross(c - a).normalize();
+        Some((point, normal))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum EmitterShape {
+    Point,
+    /// Spawns on the surface of a sphere
+    Sphere {
+        radius: f32,
+    },
+    /// Spawns on the surface of a mesh
+    Mesh(MeshSurface),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParticleBlend {
+    /// Sorted back to front if [ParticleEffect::sort] is set
+    Alpha,
+    /// Order doesn't matter, so sorting can be turned off
+    Additive,
+}
+
+/**
+ * Everything about how a [ParticleSystem] looks and moves. Changes
+ * take effect on the next [ParticleSystem::update], except
+ * `max_particles`, which is fixed when the system is created.
+ *
+ * Effects can also be loaded from text with [ParticleEffect::parse],
+ * one setting per line:
+ *
+ * ```text
+ * max_particles 4096
+ * spawn_rate 200
+ * shape sphere 0.5
+ * position 0 1 0
+ * direction 0 1 0
+ * spread 20
+ * speed 1 2
+ * lifetime 2 4
+ * gravity 0 -1 0
+ * drag 0.5
+ * color 0 1 0.8 0.2 1
+ * color 1 0.2 0.2 0.2 0
+ * size 0 0.1
+ * size 1 0.5
+ * blend alpha
+ * sort true
+ * ```
+ */
+#[derive(Debug, Clone)]
+pub struct ParticleEffect {
+    pub max_particles: u32,
+    /// Particles spawned per second
+    pub spawn_rate: f32,
+    pub shape: EmitterShape,
+    /// Where the emitter is in the world
+    pub position: Vector3<f32>,
+    /// Which way particles start moving. If it's zero they move along
+    /// the emitter's surface normal, or any direction for points.
+    pub direction: Vector3<f32>,
+    /// How far from `direction` particles can start moving
+    pub spread: Rad<f32>,
+    /// The smallest and largest starting speed
+    pub speed: (f32, f32),
+    /// The shortest and longest life in seconds
+    pub lifetime: (f32, f32),
+    pub gravity: Vector3<f32>,
+    /// How much of its velocity a particle loses per second
+    pub drag: f32,
+    pub color_over_life: Curve<Vector4<f32>>,
+    pub size_over_life: Curve<f32>,
+    pub blend: ParticleBlend,
+    /// Sorts particles back to front every frame
+    pub sort: bool,
+}
+
+impl Default for ParticleEffect {
+    fn default() -> Self {
+        Self {
+            max_particles: 1024,
+            spawn_rate: 100.0,
+            shape: EmitterShape::Point,
+            position: Vector3::zero(),
+            direction: Vector3::unit_y(),
+            spread: Deg(30.0).into(),
+            speed: (1.0, 2.0),
+            lifetime: (1.0, 2.0),
+            gravity: Vector3::new(0.0, -9.81, 0.0),
+            drag: 0.0,
+            color_over_life: Curve::linear(
+                Vector4::new(1.0, 1.0, 1.0, 1.0),
+                Vector4::new(1.0, 1.0, 1.0, 0.0),
+            ),
+            size_over_life: Curve::constant(0.1),
+            blend: ParticleBlend::Alpha,
+            sort: true,
+        }
+    }
+}
+
+impl ParticleEffect {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path)?;
+        Self::parse(&src).with_context(|| format!("Couldn't load {:?}", path))
+    }
+
+    /// Reads an effect in the format described on [ParticleEffect].
+    /// Anything not mentioned keeps its default. Mesh emitters have
+    /// to be set up in code.
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut effect = Self::default();
+        let mut colors: Option<Curve<Vector4<f32>>> = None;
+        let mut sizes: Option<Curve<f32>> = None;
+        for (number, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let key = words.next().unwrap_or("");
+            let args = words.collect::<Vec<_>>();
+            let floats = || -> Result<Vec<f32>> {
+                args.iter()
+                    .map(|a| {
+                        a.parse::<f32>()
+                            .with_context(|| format!("{:?} isn't a number", a))
+                    })
+                    .collect()
+            };
+            let result = (|| -> Result<()> {
+                match key {
+                    "max_particles" => effect.max_particles = one(&args)?.parse()?,
+                    "spawn_rate" => effect.spawn_rate = one(&args)?.parse()?,
+                    "position" => effect.position = vec3(&floats()?)?,
+                    "direction" => effect.direction = vec3(&floats()?)?,
+                    "spread" => effect.spread = Deg(one(&args)?.parse::<f32>()?).into(),
+                    "speed" => effect.speed = range(&floats()?)?,
+                    "lifetime" => effect.lifetime = range(&floats()?)?,
+                    "gravity" => effect.gravity = vec3(&floats()?)?,
+                    "drag" => effect.drag = one(&args)?.parse()?,
+                    "shape" => {
+                        effect.shape = match args.as_slice() {
+                            ["point"] => EmitterShape::Point,
+                            ["sphere", radius] => EmitterShape::Sphere {
+                                radius: radius.parse()?,
+                            },
+                            _ => bail!("Expected `point` or `sphere <radius>`"),
+                        }
+                    }
+                    "color" => match floats()?.as_slice() {
+                        &[t, r, g, b, a] => {
+                            colors
+                                .get_or_insert_with(Curve::empty)
+                                .key(t, Vector4::new(r, g, b, a));
+                        }
+                        _ => bail!("Expected `color <t> <r> <g> <b> <a>`"),
+                    },
+                    "size" => match floats()?.as_slice() {
+                        &[t, size] => {
+                            sizes.get_or_insert_with(Curve::empty).key(t, size);
+                        }
+                        _ => bail!("Expected `size <t> <size>`"),
+                    },
+                    "blend" => {
+                        effect.blend = match one(&args)? {
+                            "alpha" => ParticleBlend::Alpha,
+                            "additive" => ParticleBlend::Additive,
+                            other => bail!("Unknown blend mode {:?}", other),
+                        }
+                    }
+                    "sort" => effect.sort = one(&args)?.parse()?,
+                    other => bail!("Unknown setting {:?}", other),
+                }
+                Ok(())
+            })();
+            result.with_context(|| format!("Line {}: {}", number + 1, line))?;
+        }
+        if let Some(colors) = colors {
+            effect.color_over_life = colors;
+        }
+        if let Some(sizes) = sizes {
+            effect.size_over_life = sizes;
+        }
+        Ok(effect)
+    }
+}
+
+fn one<'a>(args: &[&'a str]) -> Result<&'a str> {
+    match args {
+        [arg] => Ok(arg),
+        _ => bail!("Expected one value, got {}", args.len()),
+    }
+}
+
+fn vec3(values: &[f32]) -> Result<Vector3<f32>> {
+    match *values {
+        [x, y, z] => Ok(Vector3::new(x, y, z)),
+        _ => bail!("Expected x y z"),
+    }
+}
+
+fn range(values: &[f32]) -> Result<(f32, f32)> {
+    match *values {
+        [value] => Ok((value, value)),
+        [min, max] => Ok((min.min(max), min.max(max))),
+        _ => bail!("Expected a value or a min and max"),
+    }
+}
+
+/// A small xorshift generator, so spawning doesn't need a crate and
+/// can be seeded for tests
+#[derive(Debug, Clone)]
+struct Rng(u32);
+
+impl Rng {
+    fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// Between 0 and 1
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1u32 << 24) as f32
+    }
+
+    fn range(&mut self, (min, max): (f32, f32)) -> f32 {
+        min + (max - min) * self.next_f32()
+    }
+
+    fn unit_vector(&mut self) -> Vector3<f32> {
+        let z = self.next_f32() * 2.0 - 1.0;
+        let angle = self.next_f32() * std::f32::consts::PI * 2.0;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        Vector3::new(r * angle.cos(), r * angle.sin(), z)
+    }
+
+    /// A direction at most `spread` away from `dir`
+    fn cone(&mut self, dir: Vector3<f32>, spread: Rad<f32>) -> Vector3<f32> {
+        let cos_theta = 1.0 - self.next_f32() * (1.0 - spread.0.cos());
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let angle = self.next_f32() * std::f32::consts::PI * 2.0;
+        let (u, v) = perpendiculars(dir);
+        dir * cos_theta + (u * angle.cos() + v * angle.sin()) * sin_theta
+    }
+}
+
+/// A particle as the compute shaders see it
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GpuParticle {
+    pub position: Vector3<f32>,
+    /// Seconds since it spawned
+    pub age: f32,
+    pub velocity: Vector3<f32>,
+    /// It dies once `age` gets to this
+    pub lifetime: f32,
+}
+
+unsafe impl bytemuck::Pod for GpuParticle {}
+unsafe impl bytemuck::Zeroable for GpuParticle {}
+
+/// Decides what to spawn each frame. Spawning happens on the CPU and
+/// everything after that on the GPU.
+#[derive(Debug, Clone)]
+struct Spawner {
+    rng: Rng,
+    /// Fractions of a particle left over from previous frames
+    owed: f32,
+    bursts: u32,
+}
+
+impl Spawner {
+    fn new(seed: u32) -> Self {
+        Self {
+            rng: Rng(seed.max(1)),
+            owed: 0.0,
+            bursts: 0,
+        }
+    }
+
+    fn spawn(&mut self, effect: &ParticleEffect, dt: f32) -> Vec<GpuParticle> {
+        self.owed += effect.spawn_rate.max(0.0) * dt;
+        let count = self.owed.floor() as u32 + mem::replace(&mut self.bursts, 0);
+        self.owed = self.owed.fract();
+        // Spawning more than fit would only overwrite its own particles
+        let count = count.min(effect.max_particles);
+        (0..count).filter_map(|_| self.particle(effect)).collect()
+    }
+
+    fn particle(&mut self, effect: &ParticleEffect) -> Option<GpuParticle> {
+        let (offset, normal) = match &effect.shape {
+            EmitterShape::Point => (Vector3::zero(), self.rng.unit_vector()),
+            EmitterShape::Sphere { radius } => {
+                let normal = self.rng.unit_vector();
+                (normal * *radius, normal)
+            }
+            EmitterShape::Mesh(surface) => surface.sample(&mut self.rng)?,
+        };
+        let dir = if effect.direction.magnitude2() > 0.0 {
+            effect.direction.normalize()
+        } else {
+            normal
+        };
+        let velocity = self.rng.cone(dir, effect.spread) * self.rng.range(effect.speed);
+        Some(GpuParticle {
+            position: effect.position + offset,
+            age: 0.0,
+            velocity,
+            lifetime: self.rng.range(effect.lifetime).max(0.0),
+        })
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct SimParams {
+    gravity: Vector3<f32>,
+    dt: f32,
+    camera: Vector3<f32>,
+    drag: f32,
+    count: u32,
+    sort_count: u32,
+    _padding: [u32; 2],
+}
+
+unsafe impl bytemuck::Pod for SimParams {}
+unsafe impl bytemuck::Zeroable for SimParams {}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct SortStep {
+    k: u32,
+    j: u32,
+    count: u32,
+    _padding: u32,
+}
+
+unsafe impl bytemuck::Pod for SortStep {}
+unsafe impl bytemuck::Zeroable for SortStep {}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct CurveData {
+    colors: [Vector4<f32>; CURVE_SAMPLES],
+    sizes: [f32; CURVE_SAMPLES],
+}
+
+unsafe impl bytemuck::Pod for CurveData {}
+unsafe impl bytemuck::Zeroable for CurveData {}
+
+impl CurveData {
+    fn new(effect: &ParticleEffect) -> Self {
+        Self {
+            colors: effect.color_over_life.bake(),
+            sizes: effect.size_over_life.bake(),
+        }
+    }
+}
+
+/// The `(k, j)` compare and swap steps of a bitonic sort of `count`
+/// keys, which has to be a power of 2
+pub fn bitonic_passes(count: u32) -> Vec<(u32, u32)> {
+    let mut passes = Vec::new();
+    let mut k = 2;
+    while k <= count {
+        let mut j = k / 2;
+        while j > 0 {
+            passes.push((k, j));
+            j /= 2;
+        }
+        k *= 2;
+    }
+    passes
+}
+
+/**
+ * Particles simulated and sorted with compute shaders and drawn as
+ * camera facing quads, one instance per particle. Call
+ * [ParticleSystem::update] once a frame before the render pass, then
+ * [ParticleSystem::draw] inside it after the opaque geometry.
+ */
+pub struct ParticleSystem {
+    pub effect: ParticleEffect,
+    capacity: u32,
+    sort_count: u32,
+    spawner: Spawner,
+    /// The next slot to spawn into. The oldest particles get replaced
+    /// once every slot has been used.
+    cursor: u32,
+    particles: wgpu::Buffer,
+    params: wgpu::Buffer,
+    curves: wgpu::Buffer,
+    simulate_pipeline: wgpu::ComputePipeline,
+    simulate_storage: StorageBinding,
+    simulate_params: wgpu::BindGroup,
+    sort_pipeline: wgpu::ComputePipeline,
+    sort_storage: StorageBinding,
+    sort_steps: wgpu::BindGroup,
+    sort_passes: Vec<(u32, u32)>,
+    alpha_pipeline: wgpu::RenderPipeline,
+    additive_pipeline: wgpu::RenderPipeline,
+    render_bind_group: wgpu::BindGroup,
+}
+
+impl ParticleSystem {
+    /// `uniform_layout` is [crate::UniformBinding]'s layout, which
+    /// [ParticleSystem::draw] expects at set 0
+    pub fn new(
+        device: &wgpu::Device,
+        color_format: wgpu::TextureFormat,
+        sample_count: u32,
+        uniform_layout: &wgpu::BindGroupLayout,
+        effect: ParticleEffect,
+    ) -> Result<Self> {
+        let capacity = effect.max_particles.max(1);
+        let sort_count = capacity.next_power_of_two();
+
+        let particles = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("ParticleSystem::particles"),
+            size: capacity as wgpu::BufferAddress * mem::size_of::<GpuParticle>() as u64,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+            // Zeroed, so every particle starts out dead
+            mapped_at_creation: false,
+        });
+        let keys = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("ParticleSystem::keys"),
+            size: sort_count as wgpu::BufferAddress * mem::size_of::<f32>() as u64,
+            usage: wgpu::BufferUsage::STORAGE,
+            mapped_at_creation: false,
+        });
+        let indices = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("ParticleSystem::indices"),
+            size: sort_count as wgpu::BufferAddress * mem::size_of::<u32>() as u64,
+            usage: wgpu::BufferUsage::STORAGE,
+            mapped_at_creation: false,
+        });
+        let params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("ParticleSystem::params"),
+            size: mem::size_of::<SimParams>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let curves = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("ParticleSystem::curves"),
+            contents: bytemuck::cast_slice(&[CurveData::new(&effect)]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        // Simulation
+        let simulate_storage = StorageBinding::new(
+            device,
+            &[
+                StorageEntry {
+                    buffer: &particles,
+                    read_only: false,
+                },
+                StorageEntry {
+                    buffer: &keys,
+                    read_only: false,
+                },
+                StorageEntry {
+                    buffer: &indices,
+                    read_only: false,
+                },
+            ],
+        );
+        let params_layout = uniform_layout_entry(
+            device,
+            "ParticleSystem::params_layout",
+            wgpu::ShaderStage::COMPUTE,
+            false,
+        );
+        let simulate_params = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("ParticleSystem::simulate_params"),
+            layout: &params_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(params.slice(..)),
+            }],
+        });
+        let simulate_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("ParticleSystem::simulate_layout"),
+            bind_group_layouts: &[&simulate_storage.layout, &params_layout],
+            push_constant_ranges: &[],
+        });
+        let simulate_pipeline = ComputePipelineBuilder::new()
+            .label("ParticleSystem::simulate")
+            .layout(&simulate_layout)
+            .compute_shader(wgpu::include_spirv!("particle_simulate.comp.spv"))
+            .build(device)?;
+
+        // Sorting
+        let sort_storage = StorageBinding::new(
+            device,
+            &[
+                StorageEntry {
+                    buffer: &keys,
+                    read_only: false,
+                },
+                StorageEntry {
+                    buffer: &indices,
+                    read_only: false,
+                },
+            ],
+        );
+        let sort_passes = bitonic_passes(sort_count);
+        let mut step_data = vec![0u8; sort_passes.len().max(1) * SORT_STEP_STRIDE as usize];
+        for (i, &(k, j)) in sort_passes.iter().enumerate() {
+            let step = SortStep {
+                k,
+                j,
+                count: sort_count,
+                _padding: 0,
+            };
+            let start = i * SORT_STEP_STRIDE as usize;
+            step_data[start..start + mem::size_of::<SortStep>()]
+                .copy_from_slice(bytemuck::bytes_of(&step));
+        }
+        let steps = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("ParticleSystem::sort_steps"),
+            contents: &step_data,
+            usage: wgpu::BufferUsage::UNIFORM,
+        });
+        let steps_layout = uniform_layout_entry(
+            device,
+            "ParticleSystem::sort_steps_layout",
+            wgpu::ShaderStage::COMPUTE,
+            true,
+        );
+        let sort_steps = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("ParticleSystem::sort_steps"),
+            layout: &steps_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(
+                    steps.slice(0..mem::size_of::<SortStep>() as wgpu::BufferAddress),
+                ),
+            }],
+        });
+        let sort_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("ParticleSystem::sort_layout"),
+            bind_group_layouts: &[&sort_storage.layout, &steps_layout],
+            push_constant_ranges: &[],
+        });
+        let sort_pipeline = ComputePipelineBuilder::new()
+            .label("ParticleSystem::sort")
+            .layout(&sort_layout)
+            .compute_shader(wgpu::include_spirv!("particle_sort.comp.spv"))
+            .build(device)?;
+
+        // Rendering
+        let render_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("ParticleSystem::render_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::StorageBuffer {
+                        dynamic: false,
+                        readonly: true,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::StorageBuffer {
+                        dynamic: false,
+                        readonly: true,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let render_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("ParticleSystem::render_bind_group"),
+            layout: &render_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(particles.slice(..)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Buffer(indices.slice(..)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Buffer(curves.slice(..)),
+                },
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("ParticleSystem::render_pipeline_layout"),
+            bind_group_layouts: &[uniform_layout, &render_layout],
+            push_constant_ranges: &[],
+        });
+        let build = |mode| {
+            RenderPipelineBuilder::new()
+                .layout(&pipeline_layout)
+                .vertex_shader(wgpu::include_spirv!("particle.vert.spv"))
+                .fragment_shader(wgpu::include_spirv!("particle.frag.spv"))
+                .color_blend(color_format, mode)
+                // Particles are tested against the scene but don't hide
+                // each other
+                .depth_read_only(Texture::DEPTH_FORMAT)
+                .sample_count(sample_count)
+                .build(device)
+        };
+        let alpha_pipeline = build(BlendMode::Alpha)?;
+        let additive_pipeline = build(BlendMode::Additive)?;
+
+        Ok(Self {
+            effect,
+            capacity,
+            sort_count,
+            spawner: Spawner::new(0x9E37_79B9),
+            cursor: 0,
+            particles,
+            params,
+            curves,
+            simulate_pipeline,
+            simulate_storage,
+            simulate_params,
+            sort_pipeline,
+            sort_storage,
+            sort_steps,
+            sort_passes,
+            alpha_pipeline,
+            additive_pipeline,
+            render_bind_group,
+        })
+    }
+
+    /// How many particles can be alive at once
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    /// Spawns `count` extra particles on the next update
+    pub fn burst(&mut self, count: u32) {
+        self.spawner.bursts += count;
+    }
+
+    /// Spawns this frame's particles, then steps and sorts everything
+    /// on the GPU. `camera_position` is what particles are sorted by.
+    pub fn update(
+        &mut self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        dt: f32,
+        camera_position: Vector3<f32>,
+    ) {
+        let spawned = self.spawner.spawn(&self.effect, dt);
+        self.write_particles(queue, &spawned);
+
+        queue.write_buffer(
+            &self.params,
+            0,
+            bytemuck::cast_slice(&[SimParams {
+                gravity: self.effect.gravity,
+                dt,
+                camera: camera_position,
+                drag: self.effect.drag.max(0.0),
+                count: self.capacity,
+                sort_count: self.sort_count,
+                _padding: [0; 2],
+            }]),
+        );
+        queue.write_buffer(
+            &self.curves,
+            0,
+            bytemuck::cast_slice(&[CurveData::new(&self.effect)]),
+        );
+
+        let workgroups = workgroup_count(self.sort_count, DEFAULT_WORKGROUP_SIZE);
+        {
+            let mut pass = encoder.begin_compute_pass();
+            pass.set_pipeline(&self.simulate_pipeline);
+            pass.set_bind_group(0, &self.simulate_storage.bind_group, &[]);
+            pass.set_bind_group(1, &self.simulate_params, &[]);
+            pass.dispatch(workgroups, 1, 1);
+        }
+        if !self.effect.sort {
+            return;
+        }
+        // A pass per step so each one sees the last one's swaps
+        for i in 0..self.sort_passes.len() {
+            let offset = (i as wgpu::BufferAddress * SORT_STEP_STRIDE) as wgpu::DynamicOffset;
+            let mut pass = encoder.begin_compute_pass();
+            pass.set_pipeline(&self.sort_pipeline);
+            pass.set_bind_group(0, &self.sort_storage.bind_group, &[]);
+            pass.set_bind_group(1, &self.sort_steps, &[offset]);
+            pass.dispatch(workgroups, 1, 1);
+        }
+    }
+
+    /// Writes `spawned` into the ring of particle slots
+    fn write_particles(&mut self, queue: &wgpu::Queue, spawned: &[GpuParticle]) {
+        let mut remaining = spawned;
+        while !remaining.is_empty() {
+            let room = (self.capacity - self.cursor) as usize;
+            let (now, later) = remaining.split_at(room.min(remaining.len()));
+            let offset = self.cursor as wgpu::BufferAddress * mem::size_of::<GpuParticle>() as u64;
+            queue.write_buffer(&self.particles, offset, bytemuck::cast_slice(now));
+            self.cursor = (self.cursor + now.len() as u32) % self.capacity;
+            remaining = later;
+        }
+    }
+
+    /// Draws every particle. `pass` needs a depth attachment with the
+    /// scene's depth in it.
+    pub fn draw<'a, 'b>(&'b self, pass: &mut wgpu::RenderPass<'a>, uniforms: &'b wgpu::BindGroup)
+    where
+        'b: 'a,
+    {
+        let pipeline = match self.effect.blend {
+            ParticleBlend::Alpha => &self.alpha_pipeline,
+            ParticleBlend::Additive => &self.additive_pipeline,
+        };
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, uniforms, &[]);
+        pass.set_bind_group(1, &self.render_bind_group, &[]);
+        pass.draw(0..6, 0..self.capacity);
+    }
+}
+
+fn uniform_layout_entry(
+    device: &wgpu::Device,
+    label: &str,
+    visibility: wgpu::ShaderStage,
+    dynamic: bool,
+) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some(label),
+        entries: &[wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility,
+            ty: wgpu::BindingType::UniformBuffer {
+                dynamic,
+                min_binding_size: None,
+            },
+            count: None,
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curves() {
+        let mut curve = Curve::linear(0.0, 1.0);
+        assert_eq!(curve.sample(-1.0), 0.0);
+        assert_eq!(curve.sample(0.25), 0.25);
+        assert_eq!(curve.sample(2.0), 1.0);
+
+        curve.key(0.5, 4.0);
+        assert_eq!(curve.sample(0.25), 2.0);
+        assert_eq!(curve.sample(0.75), 2.5);
+        // Replacing a key doesn't add another
+        curve.key(0.5, 0.0);
+        assert_eq!(curve.keys.len(), 3);
+
+        let baked = Curve::constant(3.0).bake();
+        assert!(baked.iter().all(|&v| v == 3.0));
+        let baked = Curve::linear(0.0, 1.0).bake();
+        assert_eq!(baked[0], 0.0);
+        assert_eq!(baked[CURVE_SAMPLES - 1], 1.0);
+    }
+
+    #[test]
+    fn bitonic_sorts_descending() {
+        let mut rng = Rng(7);
+        for &count in &[1u32, 2, 8, 64, 256] {
+            let mut keys = (0..count).map(|_| rng.next_f32()).collect::<Vec<_>>();
+            // Run the same compare and swaps as particle_sort.comp
+            for (k, j) in bitonic_passes(count) {
+                for i in 0..count {
+                    let l = i ^ j;
+                    if l <= i {
+                        continue;
+                    }
+                    let (a, b) = (keys[i as usize], keys[l as usize]);
+                    let descending = i & k == 0;
+                    if (descending && a < b) || (!descending && a > b) {
+                        keys.swap(i as usize, l as usize);
+                    }
+                }
+            }
+            assert!(keys.windows(2).all(|w| w[0] >= w[1]), "{} keys", count);
+        }
+    }
+
+    #[test]
+    fn spawn_rate_carries_over() {
+        let effect = ParticleEffect {
+            spawn_rate: 10.0,
+            ..Default::default()
+        };
+        let mut spawner = Spawner::new(1);
+        // 10 per second at 60fps is one every 6 frames
+        let total: usize = (0..60)
+            .map(|_| spawner.spawn(&effect, 1.0 / 60.0).len())
+            .sum();
+        assert!((9..=10).contains(&total), "{}", total);
+
+        spawner.bursts = 5;
+        assert_eq!(spawner.spawn(&effect, 0.0).len(), 5);
+    }
+
+    #[test]
+    fn spawned_particles_follow_the_effect() {
+        let effect = ParticleEffect {
+            shape: EmitterShape::Sphere { radius: 2.0 },
+            position: Vector3::new(0.0, 5.0, 0.0),
+            direction: Vector3::unit_y(),
+            spread: Deg(10.0).into(),
+            speed: (1.0, 3.0),
+            lifetime: (0.5, 1.0),
+            spawn_rate: 1000.0,
+            ..Default::default()
+        };
+        let mut spawner = Spawner::new(42);
+        let particles = spawner.spawn(&effect, 1.0);
+        assert_eq!(particles.len(), 1000);
+        for p in particles {
+            assert!(((p.position - effect.position).magnitude() - 2.0).abs() < 1e-4);
+            let speed = p.velocity.magnitude();
+            assert!((1.0 - 1e-4..=3.0 + 1e-4).contains(&speed));
+            let angle = p.velocity.normalize().angle(Vector3::unit_y());
+            assert!(angle.0 <= Rad::from(Deg(10.0)).0 + 1e-3);
+            assert!(p.lifetime >= 0.5 && p.lifetime <= 1.0);
+            assert_eq!(p.age, 0.0);
+        }
+    }
+
+    #[test]
+    fn mesh_surface_sampling() {
+        // Two triangles, the second with three times the area
+        let positions = [
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(1.0, 0.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+            Vector3::new(10.0, 0.0, 0.0),
+            Vector3::new(13.0, 0.0, 0.0),
+            Vector3::new(10.0, 0.0, 1.0),
+        ];
+        let surface = MeshSurface::new(&positions, &[0, 1, 2, 3, 4, 5]);
+        assert!((surface.area() - 2.0).abs() < 1e-5);
+
+        let mut rng = Rng(3);
+        let mut on_second = 0;
+        for _ in 0..4000 {
+            let (p, normal) = surface.sample(&mut rng).unwrap();
+            if p.x >= 10.0 {
+                on_second += 1;
+                assert!(p.y.abs() < 1e-5 && p.x <= 13.0 && p.z <= 1.0);
+                assert!((normal.y.abs() - 1.0).abs() < 1e-5);
+            } else {
+                assert!(p.z.abs() < 1e-5 && p.x + p.y <= 1.0 + 1e-5);
+            }
+        }
+        // About three quarters should land on the bigger triangle
+        assert!((2800..3200).contains(&on_second), "{}", on_second);
+        assert!(MeshSurface::new(&positions, &[]).sample(&mut rng).is_none());
+    }
+
+    #[test]
+    fn parse_effect() {
+        let effect = ParticleEffect::parse(
+            "
+            # Smoke
+            max_particles 4096
+            spawn_rate 200
+            shape sphere 0.5
+            position 0 1 0
+            speed 1 2
+            lifetime 3
+            drag 0.5
+            color 0 1 1 1 1
+            color 1 1 1 1 0
+            size 0 0.1
+            size 1 0.5
+            blend additive
+            sort false
+            ",
+        )
+        .unwrap();
+        assert_eq!(effect.max_particles, 4096);
+        assert_eq!(effect.spawn_rate, 200.0);
+        match effect.shape {
+            EmitterShape::Sphere { radius } => assert_eq!(radius, 0.5),
+            _ => panic!("Wrong shape"),
+        }
+        assert_eq!(effect.position, Vector3::new(0.0, 1.0, 0.0));
+        assert_eq!(effect.lifetime, (3.0, 3.0));
+        assert_eq!(effect.color_over_life.sample(0.5).w, 0.5);
+        assert_eq!(effect.size_over_life.sample(0.5), 0.3);
+        assert_eq!(effect.blend, ParticleBlend::Additive);
+        assert!(!effect.sort);
+
+        let error = ParticleEffect::parse("spawn_rate 1\nwobble 3").unwrap_err();
+        assert!(format!("{:#}", error).contains("Line 2"));
+        assert!(ParticleEffect::parse("shape cube").is_err());
+    }
+}
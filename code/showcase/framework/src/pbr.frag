@@ -0,0 +1,10 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "pbr_lighting.glsl"
+
+layout(location=0) out vec4 f_color;
+
+void main() {
+    f_color = shade();
+}
@@ -0,0 +1,460 @@
+use anyhow::*;
+use std::iter;
+use std::num::NonZeroU32;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+
+use crate::cubemap::CUBE_FACES;
+use crate::instance::InstanceRaw;
+use crate::model::ModelVertex;
+use crate::pipeline::{BlendMode, RenderPipelineBuilder};
+use crate::texture::Texture;
+use crate::transparency::{TransparencyMode, WeightedBlendedOit};
+
+pub(crate) fn texture_entry(
+    binding: u32,
+    dimension: wgpu::TextureViewDimension,
+) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStage::FRAGMENT,
+        ty: wgpu::BindingType::SampledTexture {
+            dimension,
+            component_type: wgpu::TextureComponentType::Float,
+            multisampled: false,
+        },
+        count: None,
+    }
+}
+
+pub(crate) fn sampler_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStage::FRAGMENT,
+        ty: wgpu::BindingType::Sampler { comparison: false },
+        count: None,
+    }
+}
+
+fn uniform_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStage::FRAGMENT,
+        ty: wgpu::BindingType::UniformBuffer {
+            dynamic: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+/**
+ * A metallic-roughness pipeline for models loaded with
+ * [crate::Model::load_pbr]. The bind groups are
+ *
+ * 0. the material, using [PbrPipeline::material_layout]
+ * 1. the [crate::UniformBinding]
+ * 2. the [crate::LightBinding]
+ * 3. an [IblEnvironment], using [PbrPipeline::ibl_layout]
+ *
+ * Instances go in vertex slot 1 as [InstanceRaw]. `index_format` has
+ * to match the models' meshes, see [crate::Model::index_format]. All
+ * three pipelines use `sample_count`, so it has to match the
+ * [WeightedBlendedOit] the OIT pipeline draws into as well.
+ * Materials with [crate::MaterialFactors::transparent] set are drawn
+ * after the opaque ones with [PbrPipeline::transparent], see
+ * [crate::DrawTransparent].
+ */
+pub struct PbrPipeline {
+    pub material_layout: wgpu::BindGroupLayout,
+    pub ibl_layout: wgpu::BindGroupLayout,
+    /// For opaque materials
+    pub pipeline: wgpu::RenderPipeline,
+    /// Alpha blends without writing depth, for transparent materials
+    /// drawn back to front after everything opaque
+    pub transparent_pipeline: wgpu::RenderPipeline,
+    /// Draws transparent materials into [WeightedBlendedOit]'s targets
+    pub oit_pipeline: wgpu::RenderPipeline,
+}
+
+impl PbrPipeline {
+    pub fn new(
+        device: &wgpu::Device,
+        color_format: wgpu::TextureFormat,
+        sample_count: u32,
+        index_format: wgpu::IndexFormat,
+        uniform_layout: &wgpu::BindGroupLayout,
+        light_layout: &wgpu::BindGroupLayout,
+    ) -> Result<Self> {
+        let d2 = wgpu::TextureViewDimension::D2;
+        let cube = wgpu::TextureViewDimension::Cube;
+        let material_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("PbrPipeline::material_layout"),
+            entries: &[
+                // base color
+                texture_entry(0, d2),
+                sampler_entry(1),
+                // normal
+                texture_entry(2, d2),
+                sampler_entry(3),
+                // metallic roughness
+                texture_entry(4, d2),
+                sampler_entry(5),
+                // occlusion
+                texture_entry(6, d2),
+                sampler_entry(7),
+                // emissive
+                texture_entry(8, d2),
+                sampler_entry(9),
+                uniform_entry(10),
+            ],
+        });
+        let ibl_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("PbrPipeline::ibl_layout"),
+            entries: &[
+                texture_entry(0, cube),
+                texture_entry(1, cube),
+                sampler_entry(2),
+                texture_entry(3, d2),
+                sampler_entry(4),
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("PbrPipeline::pipeline_layout"),
+            bind_group_layouts: &[&material_layout, uniform_layout, light_layout, &ibl_layout],
+            push_constant_ranges: &[],
+        });
+        let build = |fragment_shader, color_states: &[wgpu::ColorStateDescriptor], depth_write| {
+            let mut builder = RenderPipelineBuilder::new();
+            builder
+                .layout(&pipeline_layout)
+                .vertex_shader(wgpu::include_spirv!("pbr.vert.spv"))
+                .fragment_shader(fragment_shader);
+            for color_state in color_states {
+                builder.color_state(color_state.clone());
+            }
+            builder
+                .depth_no_stencil(
+                    Texture::DEPTH_FORMAT,
+                    depth_write,
+                    wgpu::CompareFunction::Less,
+                )
+                .vertex_buffer::<ModelVertex>()
+                .vertex_buffer::<InstanceRaw>()
+                .index_format(index_format)
+                .sample_count(sample_count)
+                .build(device)
+        };
+        let pipeline = build(
+            wgpu::include_spirv!("pbr.frag.spv"),
+            &[BlendMode::Replace.color_state(color_format)],
+            true,
+        )?;
+        let transparent_pipeline = build(
+            wgpu::include_spirv!("pbr.frag.spv"),
+            &[BlendMode::Alpha.color_state(color_format)],
+            false,
+        )?;
+        let oit_pipeline = build(
+            wgpu::include_spirv!("pbr_oit.frag.spv"),
+            &WeightedBlendedOit::color_states(),
+            false,
+        )?;
+
+        Ok(Self {
+            material_layout,
+            ibl_layout,
+            pipeline,
+            transparent_pipeline,
+            oit_pipeline,
+        })
+    }
+
+    /// The pipeline transparent materials are drawn with in `mode`
+    pub fn transparent(&self, mode: TransparencyMode) -> &wgpu::RenderPipeline {
+        match mode {
+            TransparencyMode::Sorted => &self.transparent_pipeline,
+            TransparencyMode::WeightedBlended => &self.oit_pipeline,
+        }
+    }
+}
+
+/**
+ * The precomputed lighting [PbrPipeline] needs from an environment
+ * cube map: a diffuse irradiance cube, a specular cube prefiltered
+ * per roughness level in its mips, and the split-sum BRDF lookup
+ * table.
+ */
+pub struct IblEnvironment<'a> {
+    pub irradiance: Texture<'a>,
+    pub prefiltered: Texture<'a>,
+    pub brdf_lut: Texture<'a>,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl<'a> IblEnvironment<'a> {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    pub const LUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
+    pub const IRRADIANCE_SIZE: u32 = 32;
+    pub const PREFILTERED_SIZE: u32 = 128;
+    /// Mip 0 is for roughness 0, the last mip for roughness 1
+    pub const PREFILTERED_MIP_LEVELS: u32 = 5;
+    pub const LUT_SIZE: u32 = 256;
+
+    /// Bakes the lighting for `environment`, which is usually made
+    /// with [crate::EquirectToCube].
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        pipeline: &PbrPipeline,
+        environment: &Texture,
+    ) -> Result<Self> {
+        let face_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("IblEnvironment::face_layout"),
+            entries: &[
+                texture_entry(0, wgpu::TextureViewDimension::Cube),
+                sampler_entry(1),
+                uniform_entry(2),
+            ],
+        });
+        let face_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("IblEnvironment::face_pipeline_layout"),
+            bind_group_layouts: &[&face_layout],
+            push_constant_ranges: &[],
+        });
+        let irradiance_pipeline = RenderPipelineBuilder::new()
+            .layout(&face_pipeline_layout)
+            .vertex_shader(wgpu::include_spirv!("fullscreen.vert.spv"))
+            .fragment_shader(wgpu::include_spirv!("irradiance.frag.spv"))
+            .color_solid(Self::FORMAT)
+            .build(device)?;
+        let prefilter_pipeline = RenderPipelineBuilder::new()
+            .layout(&face_pipeline_layout)
+            .vertex_shader(wgpu::include_spirv!("fullscreen.vert.spv"))
+            .fragment_shader(wgpu::include_spirv!("prefilter.frag.spv"))
+            .color_solid(Self::FORMAT)
+            .build(device)?;
+        let lut_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("IblEnvironment::lut_pipeline_layout"),
+            bind_group_layouts: &[],
+            push_constant_ranges: &[],
+        });
+        let lut_pipeline = RenderPipelineBuilder::new()
+            .layout(&lut_pipeline_layout)
+            .vertex_shader(wgpu::include_spirv!("fullscreen.vert.spv"))
+            .fragment_shader(wgpu::include_spirv!("brdf_lut.frag.spv"))
+            .color_solid(Self::LUT_FORMAT)
+            .build(device)?;
+
+        let usage = wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::OUTPUT_ATTACHMENT;
+        let irradiance = Texture::create_cube(device, Self::IRRADIANCE_SIZE, Self::FORMAT, usage);
+        let prefiltered = Self::create_prefiltered(device);
+        let brdf_lut = Self::create_lut(device);
+
+        // Every pass gets its own target view and bind group as
+        // they're all submitted together.
+        let mut targets = Vec::new();
+        let face_bind_group = |face: u32, roughness: f32| {
+            let mut contents = [0u8; 16];
+            contents[0..4].copy_from_slice(bytemuck::bytes_of(&face));
+            contents[4..8].copy_from_slice(bytemuck::bytes_of(&roughness));
+            let buffer = device.create_buffer_init(&BufferInitDescriptor {
+                label: Some("IblEnvironment::face"),
+                contents: &contents,
+                usage: wgpu::BufferUsage::UNIFORM,
+            });
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("IblEnvironment::face_bind_group"),
+                layout: &face_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&environment.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&environment.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::Buffer(buffer.slice(..)),
+                    },
+                ],
+            });
+            (buffer, bind_group)
+        };
+        for face in 0..CUBE_FACES as u32 {
+            let view = face_view(&irradiance.texture, face, 0);
+            targets.push((&irradiance_pipeline, view, Some(face_bind_group(face, 0.0))));
+        }
+        for mip in 0..Self::PREFILTERED_MIP_LEVELS {
+            let roughness = mip as f32 / (Self::PREFILTERED_MIP_LEVELS - 1) as f32;
+            for face in 0..CUBE_FACES as u32 {
+                let view = face_view(&prefiltered.texture, face, mip);
+                targets.push((
+                    &prefilter_pipeline,
+                    view,
+                    Some(face_bind_group(face, roughness)),
+                ));
+            }
+        }
+        let lut_view = brdf_lut.texture.create_view(&Default::default());
+        targets.push((&lut_pipeline, lut_view, None));
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("IblEnvironment::encoder"),
+        });
+        for (pipeline, view, bind_group) in &targets {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            pass.set_pipeline(pipeline);
+            if let Some((_, bind_group)) = bind_group {
+                pass.set_bind_group(0, bind_group, &[]);
+            }
+            pass.draw(0..3, 0..1);
+        }
+        queue.submit(iter::once(encoder.finish()));
+
+        let bind_group = Self::create_bind_group(
+            device,
+            &pipeline.ibl_layout,
+            &irradiance,
+            &prefiltered,
+            &brdf_lut,
+        );
+
+        Ok(Self {
+            irradiance,
+            prefiltered,
+            brdf_lut,
+            bind_group,
+        })
+    }
+
+    fn create_prefiltered(device: &wgpu::Device) -> Texture<'a> {
+        let desc = wgpu::TextureDescriptor {
+            label: Some("IblEnvironment::prefiltered"),
+            size: wgpu::Extent3d {
+                width: Self::PREFILTERED_SIZE,
+                height: Self::PREFILTERED_SIZE,
+                depth: CUBE_FACES as u32,
+            },
+            mip_level_count: Self::PREFILTERED_MIP_LEVELS,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::FORMAT,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+        };
+        let texture = device.create_texture(&desc);
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+        // Blend between mips so roughness changes smoothly
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Texture {
+            texture,
+            view,
+            sampler,
+            desc,
+        }
+    }
+
+    fn create_lut(device: &wgpu::Device) -> Texture<'a> {
+        let desc = wgpu::TextureDescriptor {
+            label: Some("IblEnvironment::brdf_lut"),
+            size: wgpu::Extent3d {
+                width: Self::LUT_SIZE,
+                height: Self::LUT_SIZE,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::LUT_FORMAT,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+        };
+        let texture = device.create_texture(&desc);
+        let view = texture.create_view(&Default::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Texture {
+            texture,
+            view,
+            sampler,
+            desc,
+        }
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        irradiance: &Texture,
+        prefiltered: &Texture,
+        brdf_lut: &Texture,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("IblEnvironment::bind_group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&irradiance.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&prefiltered.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&prefiltered.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&brdf_lut.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Sampler(&brdf_lut.sampler),
+                },
+            ],
+        })
+    }
+}
+
+/// A view of a single face and mip of a cube map for rendering into
+fn face_view(texture: &wgpu::Texture, face: u32, mip: u32) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2),
+        base_mip_level: mip,
+        level_count: NonZeroU32::new(1),
+        base_array_layer: face,
+        array_layer_count: NonZeroU32::new(1),
+        ..Default::default()
+    })
+}
@@ -0,0 +1,41 @@
+#version 450
+
+layout(location=0) in vec3 a_position;
+layout(location=1) in vec2 a_tex_coords;
+layout(location=2) in vec3 a_normal;
+layout(location=3) in vec3 a_tangent;
+layout(location=4) in vec3 a_bitangent;
+
+// framework::InstanceRaw
+layout(location=5) in vec4 a_model_0;
+layout(location=6) in vec4 a_model_1;
+layout(location=7) in vec4 a_model_2;
+layout(location=8) in vec4 a_model_3;
+
+layout(location=0) out vec2 v_tex_coords;
+layout(location=1) out vec3 v_position;
+layout(location=2) out vec3 v_normal;
+layout(location=3) out vec3 v_tangent;
+layout(location=4) out vec3 v_bitangent;
+
+layout(set=1, binding=0)
+uniform Uniforms {
+    vec4 u_view_position;
+    mat4 u_view_proj;
+};
+
+void main() {
+    mat4 model_matrix = mat4(a_model_0, a_model_1, a_model_2, a_model_3);
+    mat3 normal_matrix = mat3(transpose(inverse(model_matrix)));
+
+    // Lighting is done in world space so that the normals can be
+    // used to look up the environment maps.
+    v_normal = normalize(normal_matrix * a_normal);
+    v_tangent = normalize(normal_matrix * a_tangent);
+    v_bitangent = normalize(normal_matrix * a_bitangent);
+    v_tex_coords = a_tex_coords;
+
+    vec4 world_position = model_matrix * vec4(a_position, 1.0);
+    v_position = world_position.xyz;
+    gl_Position = u_view_proj * world_position;
+}
@@ -0,0 +1,124 @@
+// The inputs, bindings and lighting shared by pbr.frag and pbr_oit.frag.
+// Included by both, so it isn't compiled on its own.
+
+layout(location=0) in vec2 v_tex_coords;
+layout(location=1) in vec3 v_position;
+layout(location=2) in vec3 v_normal;
+layout(location=3) in vec3 v_tangent;
+layout(location=4) in vec3 v_bitangent;
+
+layout(set=0, binding=0) uniform texture2D t_base_color;
+layout(set=0, binding=1) uniform sampler s_base_color;
+layout(set=0, binding=2) uniform texture2D t_normal;
+layout(set=0, binding=3) uniform sampler s_normal;
+layout(set=0, binding=4) uniform texture2D t_metallic_roughness;
+layout(set=0, binding=5) uniform sampler s_metallic_roughness;
+layout(set=0, binding=6) uniform texture2D t_occlusion;
+layout(set=0, binding=7) uniform sampler s_occlusion;
+layout(set=0, binding=8) uniform texture2D t_emissive;
+layout(set=0, binding=9) uniform sampler s_emissive;
+layout(set=0, binding=10)
+uniform MaterialFactors {
+    vec4 u_base_color_factor;
+    vec4 u_emissive_factor;
+    float u_metallic_factor;
+    float u_roughness_factor;
+    float u_occlusion_strength;
+    float u_normal_scale;
+};
+
+layout(set=1, binding=0)
+uniform Uniforms {
+    vec4 u_view_position;
+    mat4 u_view_proj; // unused
+};
+
+layout(set=2, binding=0)
+uniform Light {
+    vec4 light_position;
+    vec4 light_color;
+};
+
+layout(set=3, binding=0) uniform textureCube t_irradiance;
+layout(set=3, binding=1) uniform textureCube t_prefiltered;
+layout(set=3, binding=2) uniform sampler s_environment;
+layout(set=3, binding=3) uniform texture2D t_brdf_lut;
+layout(set=3, binding=4) uniform sampler s_brdf_lut;
+
+const float PI = 3.14159265359;
+// Must match framework::IblEnvironment::PREFILTERED_MIP_LEVELS - 1
+const float MAX_REFLECTION_LOD = 4.0;
+
+// Trowbridge-Reitz GGX normal distribution
+float distribution_ggx(float n_dot_h, float roughness) {
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float denom = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * denom * denom);
+}
+
+// Schlick-GGX, combined for the view and light directions using
+// Smith's method
+float geometry_smith(float n_dot_v, float n_dot_l, float roughness) {
+    float r = roughness + 1.0;
+    float k = (r * r) / 8.0;
+    float ggx_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    float ggx_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return ggx_v * ggx_l;
+}
+
+vec3 fresnel_schlick(float cos_theta, vec3 f0) {
+    return f0 + (1.0 - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// Rough surfaces reflect less of the environment at grazing angles
+vec3 fresnel_schlick_roughness(float cos_theta, vec3 f0, float roughness) {
+    return f0 + (max(vec3(1.0 - roughness), f0) - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// The lit color of the surface, with base_color's alpha
+vec4 shade() {
+    vec4 base_color = texture(sampler2D(t_base_color, s_base_color), v_tex_coords) * u_base_color_factor;
+    // glTF convention: roughness in green, metallic in blue
+    vec4 metallic_roughness = texture(sampler2D(t_metallic_roughness, s_metallic_roughness), v_tex_coords);
+    float metallic = clamp(metallic_roughness.b * u_metallic_factor, 0.0, 1.0);
+    float roughness = clamp(metallic_roughness.g * u_roughness_factor, 0.04, 1.0);
+    float occlusion = mix(1.0, texture(sampler2D(t_occlusion, s_occlusion), v_tex_coords).r, u_occlusion_strength);
+    vec3 emissive = texture(sampler2D(t_emissive, s_emissive), v_tex_coords).rgb * u_emissive_factor.rgb;
+
+    vec3 tangent_normal = texture(sampler2D(t_normal, s_normal), v_tex_coords).rgb * 2.0 - 1.0;
+    tangent_normal.xy *= u_normal_scale;
+    mat3 tbn = mat3(normalize(v_tangent), normalize(v_bitangent), normalize(v_normal));
+    vec3 n = normalize(tbn * tangent_normal);
+
+    vec3 v = normalize(u_view_position.xyz - v_position);
+    vec3 l = normalize(light_position.xyz - v_position);
+    vec3 h = normalize(v + l);
+    float n_dot_v = max(dot(n, v), 1e-4);
+    float n_dot_l = max(dot(n, l), 0.0);
+    float n_dot_h = max(dot(n, h), 0.0);
+
+    // Dielectrics reflect about 4% of light head on, metals tint
+    // their reflections with their base color.
+    vec3 f0 = mix(vec3(0.04), base_color.rgb, metallic);
+
+    // Direct lighting. Like the Blinn-Phong shaders, the light
+    // doesn't fall off with distance.
+    vec3 f = fresnel_schlick(max(dot(h, v), 0.0), f0);
+    float d = distribution_ggx(n_dot_h, roughness);
+    float g = geometry_smith(n_dot_v, n_dot_l, roughness);
+    vec3 specular = d * g * f / (4.0 * n_dot_v * max(n_dot_l, 1e-4));
+    vec3 k_d = (1.0 - f) * (1.0 - metallic);
+    vec3 direct = (k_d * base_color.rgb / PI + specular) * light_color.rgb * n_dot_l;
+
+    // Image based lighting using the split sum approximation
+    vec3 f_ambient = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+    vec3 k_d_ambient = (1.0 - f_ambient) * (1.0 - metallic);
+    vec3 irradiance = texture(samplerCube(t_irradiance, s_environment), n).rgb;
+    vec3 r = reflect(-v, n);
+    vec3 prefiltered = textureLod(samplerCube(t_prefiltered, s_environment), r, roughness * MAX_REFLECTION_LOD).rgb;
+    vec2 brdf = texture(sampler2D(t_brdf_lut, s_brdf_lut), vec2(n_dot_v, roughness)).rg;
+    vec3 ambient = (k_d_ambient * irradiance * base_color.rgb + prefiltered * (f_ambient * brdf.x + brdf.y)) * occlusion;
+
+    return vec4(ambient + direct + emissive, base_color.a);
+}
@@ -0,0 +1,23 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+// pbr.frag for framework::WeightedBlendedOit. Surfaces add their
+// weighted color to the accumulation target and multiply the
+// revealage target by how much they let through, so the order they're
+// drawn in doesn't matter.
+
+#include "pbr_lighting.glsl"
+
+layout(location=0) out vec4 f_accum;
+layout(location=1) out float f_revealage;
+
+void main() {
+    vec4 color = shade();
+    float a = color.a;
+    // Equation 10 from McGuire and Bavoil's paper. Nearer and more
+    // opaque surfaces get more say in the final color.
+    float z = gl_FragCoord.z;
+    float weight = clamp(pow(min(1.0, a * 10.0) + 0.01, 3.0) * 1e8 * pow(1.0 - z * 0.9, 3.0), 1e-2, 3e3);
+    f_accum = vec4(color.rgb * a, a) * weight;
+    f_revealage = a;
+}
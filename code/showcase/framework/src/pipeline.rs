@@ -1,6 +1,53 @@
 use crate::model::Vertex;
 use anyhow::*;
 
+/// How a pipeline's output is combined with what's already in the
+/// color target
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BlendMode {
+    /// Overwrites the target
+    Replace,
+    /// Classic `src * a + dst * (1 - a)` transparency
+    Alpha,
+    /// Adds `src * a` to the target, for glows and fire
+    Additive,
+    /// Like [BlendMode::Alpha] for colors that have already been
+    /// multiplied by their alpha
+    Premultiplied,
+}
+
+impl BlendMode {
+    pub fn color_state(self, format: wgpu::TextureFormat) -> wgpu::ColorStateDescriptor {
+        let blend = |src_factor, dst_factor| wgpu::BlendDescriptor {
+            src_factor,
+            dst_factor,
+            operation: wgpu::BlendOperation::Add,
+        };
+        use wgpu::BlendFactor::*;
+        let (color_blend, alpha_blend) = match self {
+            BlendMode::Replace => (
+                wgpu::BlendDescriptor::REPLACE,
+                wgpu::BlendDescriptor::REPLACE,
+            ),
+            BlendMode::Alpha => (
+                blend(SrcAlpha, OneMinusSrcAlpha),
+                blend(One, OneMinusSrcAlpha),
+            ),
+            // The target's alpha is left alone
+            BlendMode::Additive => (blend(SrcAlpha, One), blend(Zero, One)),
+            BlendMode::Premultiplied => {
+                (blend(One, OneMinusSrcAlpha), blend(One, OneMinusSrcAlpha))
+            }
+        };
+        wgpu::ColorStateDescriptor {
+            format,
+            alpha_blend,
+            color_blend,
+            write_mask: wgpu::ColorWrite::ALL,
+        }
+    }
+}
+
 pub struct RenderPipelineBuilder<'a> {
     layout: Option<&'a wgpu::PipelineLayout>,
     vertex_shader: Option<wgpu::ShaderModuleSource<'a>>,
@@ -69,13 +116,11 @@ impl<'a> RenderPipelineBuilder<'a> {
         self
     }
 
-    #[allow(dead_code)]
     pub fn depth_bias(&mut self, db: i32) -> &mut Self {
         self.depth_bias = db;
         self
     }
 
-    #[allow(dead_code)]
     pub fn depth_bias_slope_scale(&mut self, dbss: f32) -> &mut Self {
         self.depth_bias_slope_scale = dbss;
         self
@@ -87,7 +132,6 @@ impl<'a> RenderPipelineBuilder<'a> {
         self
     }
 
-    #[allow(dead_code)]
     pub fn primitive_topology(&mut self, pt: wgpu::PrimitiveTopology) -> &mut Self {
         self.primitive_topology = pt;
         self
@@ -100,12 +144,27 @@ impl<'a> RenderPipelineBuilder<'a> {
 
     /// Helper method for [RenderPipelineBuilder::color_state]
     pub fn color_solid(&mut self, format: wgpu::TextureFormat) -> &mut Self {
-        self.color_state(wgpu::ColorStateDescriptor {
-            format,
-            alpha_blend: wgpu::BlendDescriptor::REPLACE,
-            color_blend: wgpu::BlendDescriptor::REPLACE,
-            write_mask: wgpu::ColorWrite::ALL,
-        })
+        self.color_blend(format, BlendMode::Replace)
+    }
+
+    /// Helper method for [RenderPipelineBuilder::color_state]
+    pub fn color_blend(&mut self, format: wgpu::TextureFormat, mode: BlendMode) -> &mut Self {
+        self.color_state(mode.color_state(format))
+    }
+
+    /// Helper method for [RenderPipelineBuilder::color_blend]
+    pub fn color_alpha_blend(&mut self, format: wgpu::TextureFormat) -> &mut Self {
+        self.color_blend(format, BlendMode::Alpha)
+    }
+
+    /// Helper method for [RenderPipelineBuilder::color_blend]
+    pub fn color_additive(&mut self, format: wgpu::TextureFormat) -> &mut Self {
+        self.color_blend(format, BlendMode::Additive)
+    }
+
+    /// Helper method for [RenderPipelineBuilder::color_blend]
+    pub fn color_premultiplied(&mut self, format: wgpu::TextureFormat) -> &mut Self {
+        self.color_blend(format, BlendMode::Premultiplied)
     }
 
     pub fn depth_stencil_state(&mut self, dss: wgpu::DepthStencilStateDescriptor) -> &mut Self {
@@ -133,6 +192,13 @@ impl<'a> RenderPipelineBuilder<'a> {
         self.depth_no_stencil(format, true, wgpu::CompareFunction::Less)
     }
 
+    /// Helper method for [RenderPipelineBuilder::depth_no_stencil].
+    /// Tests against the depth buffer without writing to it, which is
+    /// what blended surfaces want.
+    pub fn depth_read_only(&mut self, format: wgpu::TextureFormat) -> &mut Self {
+        self.depth_no_stencil(format, false, wgpu::CompareFunction::Less)
+    }
+
     #[allow(dead_code)]
     pub fn index_format(&mut self, ifmt: wgpu::IndexFormat) -> &mut Self {
         self.index_format = ifmt;
@@ -149,12 +215,17 @@ impl<'a> RenderPipelineBuilder<'a> {
         self
     }
 
-    #[allow(dead_code)]
     pub fn sample_count(&mut self, sc: u32) -> &mut Self {
         self.sample_count = sc;
         self
     }
 
+    /// Matches the pipeline's sample count to `display` so it can
+    /// draw to [crate::Display::color_attachment]
+    pub fn for_display(&mut self, display: &crate::Display) -> &mut Self {
+        self.sample_count(display.sample_count())
+    }
+
     #[allow(dead_code)]
     pub fn sample_mask(&mut self, sm: u32) -> &mut Self {
         self.sample_mask = sm;
@@ -236,6 +307,67 @@ impl<'a> RenderPipelineBuilder<'a> {
     }
 }
 
+/**
+ * Builds compute pipelines the same way [RenderPipelineBuilder] builds
+ * render pipelines. Only the layout and shader are required.
+ */
+pub struct ComputePipelineBuilder<'a> {
+    label: Option<&'a str>,
+    layout: Option<&'a wgpu::PipelineLayout>,
+    compute_shader: Option<wgpu::ShaderModuleSource<'a>>,
+    entry_point: &'a str,
+}
+
+impl<'a> ComputePipelineBuilder<'a> {
+    pub fn new() -> Self {
+        Self {
+            label: None,
+            layout: None,
+            compute_shader: None,
+            entry_point: "main",
+        }
+    }
+
+    pub fn label(&mut self, label: &'a str) -> &mut Self {
+        self.label = Some(label);
+        self
+    }
+
+    pub fn layout(&mut self, layout: &'a wgpu::PipelineLayout) -> &mut Self {
+        self.layout = Some(layout);
+        self
+    }
+
+    pub fn compute_shader(&mut self, src: wgpu::ShaderModuleSource<'a>) -> &mut Self {
+        self.compute_shader = Some(src);
+        self
+    }
+
+    pub fn entry_point(&mut self, entry_point: &'a str) -> &mut Self {
+        self.entry_point = entry_point;
+        self
+    }
+
+    pub fn build(&mut self, device: &wgpu::Device) -> Result<wgpu::ComputePipeline> {
+        let layout = self.layout.context("No pipeline layout supplied!")?;
+        let cs = create_shader_module(
+            device,
+            self.compute_shader
+                .take()
+                .context("Please include a compute shader")?,
+        );
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(self.label.unwrap_or("Compute Pipeline")),
+            layout: Some(layout),
+            compute_stage: wgpu::ProgrammableStageDescriptor {
+                module: &cs,
+                entry_point: self.entry_point,
+            },
+        });
+        Ok(pipeline)
+    }
+}
+
 fn create_shader_module(
     device: &wgpu::Device,
     spirv: wgpu::ShaderModuleSource,
@@ -0,0 +1,817 @@
+use anyhow::*;
+use std::collections::HashMap;
+use std::path::Path;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+
+use crate::pipeline::RenderPipelineBuilder;
+use crate::texture::Texture;
+
+/**
+ * One step of a [PostProcess] stack. Effects read `input` and draw
+ * a fullscreen triangle into `output`, which is always a
+ * [PostProcess::FORMAT] texture of the same `size`.
+ */
+pub trait PostEffect {
+    fn apply(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        input: &wgpu::TextureView,
+        output: &wgpu::TextureView,
+        size: wgpu::Extent3d,
+    );
+
+    /// Called when the textures the effect reads from and draws to
+    /// have been recreated, so it can drop anything it built for the
+    /// old ones
+    fn resize(&mut self) {}
+}
+
+/**
+ * A fullscreen pass using `fullscreen.vert`. The fragment shader
+ * gets the input texture at binding 0, a linear sampler at binding
+ * 1, an optional uniform at binding 2 and an optional extra texture
+ * at binding 3.
+ *
+ * Bind groups are built the first time a set of inputs is drawn and
+ * then reused. They're looked up by the inputs' addresses, so
+ * [FullscreenPass::invalidate] has to be called whenever a texture
+ * the pass reads is recreated.
+ */
+pub(crate) struct FullscreenPass {
+    layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::RenderPipeline,
+    sampler: wgpu::Sampler,
+    has_params: bool,
+    bind_groups: HashMap<BindGroupKey, wgpu::BindGroup>,
+}
+
+/// The addresses of the input view, params buffer and extra view a
+/// [FullscreenPass] bind group was built for
+type BindGroupKey = (usize, usize, usize);
+
+fn address<T>(resource: Option<&T>) -> usize {
+    resource.map_or(0, |r| r as *const T as usize)
+}
+
+impl FullscreenPass {
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        label: &str,
+        fragment_shader: wgpu::ShaderModuleSource,
+        format: wgpu::TextureFormat,
+        has_params: bool,
+        has_extra: bool,
+    ) -> Result<Self> {
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::SampledTexture {
+                dimension: wgpu::TextureViewDimension::D2,
+                component_type: wgpu::TextureComponentType::Float,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let mut entries = vec![
+            texture_entry(0),
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Sampler { comparison: false },
+                count: None,
+            },
+        ];
+        if has_params {
+            entries.push(wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::UniformBuffer {
+                    dynamic: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            });
+        }
+        if has_extra {
+            entries.push(texture_entry(3));
+        }
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some(label),
+            entries: &entries,
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some(label),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = RenderPipelineBuilder::new()
+            .layout(&pipeline_layout)
+            .vertex_shader(wgpu::include_spirv!("fullscreen.vert.spv"))
+            .fragment_shader(fragment_shader)
+            .color_solid(format)
+            .build(device)?;
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Ok(Self {
+            layout,
+            pipeline,
+            sampler,
+            has_params,
+            bind_groups: HashMap::new(),
+        })
+    }
+
+    /// Drops every cached bind group
+    pub(crate) fn invalidate(&mut self) {
+        self.bind_groups.clear();
+    }
+
+    fn create_params(device: &wgpu::Device, label: &str, contents: &[u8]) -> wgpu::Buffer {
+        device.create_buffer_init(&BufferInitDescriptor {
+            label: Some(label),
+            contents,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        })
+    }
+
+    pub(crate) fn draw(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        input: &wgpu::TextureView,
+        params: Option<&wgpu::Buffer>,
+        extra: Option<&wgpu::TextureView>,
+        output: &wgpu::TextureView,
+    ) {
+        debug_assert_eq!(self.has_params, params.is_some());
+        let key = (address(Some(input)), address(params), address(extra));
+        if !self.bind_groups.contains_key(&key) {
+            let bind_group = self.create_bind_group(device, input, params, extra);
+            self.bind_groups.insert(key, bind_group);
+        }
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: output,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_groups[&key], &[]);
+        pass.draw(0..3, 0..1);
+    }
+
+    fn create_bind_group(
+        &self,
+        device: &wgpu::Device,
+        input: &wgpu::TextureView,
+        params: Option<&wgpu::Buffer>,
+        extra: Option<&wgpu::TextureView>,
+    ) -> wgpu::BindGroup {
+        let mut entries = vec![
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(input),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&self.sampler),
+            },
+        ];
+        if let Some(params) = params {
+            entries.push(wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::Buffer(params.slice(..)),
+            });
+        }
+        if let Some(extra) = extra {
+            entries.push(wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(extra),
+            });
+        }
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.layout,
+            entries: &entries,
+        })
+    }
+}
+
+/**
+ * Renders the scene into an HDR texture and then runs it through a
+ * list of [PostEffect]s before drawing it to the screen. Draw the
+ * scene into [PostProcess::hdr_attachment] instead of the swap chain,
+ * then call [PostProcess::present] with the frame's view.
+ *
+ * With a `sample_count` above 1 the scene is drawn into a
+ * multisampled texture that gets resolved into [PostProcess::hdr].
+ * The effects themselves always run on resolved, single sampled
+ * textures.
+ *
+ * Effects run in the order they're in [PostProcess::effects], so
+ * reordering that list reorders the stack. A usual order is
+ * [Bloom], [Tonemap], [ColorGrade], [Vignette] then [Fxaa].
+ */
+pub struct PostProcess<'a> {
+    pub hdr: Texture<'a>,
+    pub effects: Vec<Box<dyn PostEffect>>,
+    sample_count: u32,
+    hdr_msaa: Option<Texture<'a>>,
+    targets: [Texture<'a>; 2],
+    blit: FullscreenPass,
+}
+
+impl<'a> PostProcess<'a> {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+    /// `sample_count` has to match the pipelines the scene is drawn
+    /// with, usually [crate::Display::sample_count]
+    pub fn new(
+        device: &wgpu::Device,
+        sc_desc: &wgpu::SwapChainDescriptor,
+        sample_count: u32,
+    ) -> Result<Self> {
+        let blit = FullscreenPass::new(
+            device,
+            "PostProcess::blit",
+            wgpu::include_spirv!("blit.frag.spv"),
+            sc_desc.format,
+            false,
+            false,
+        )?;
+        let (hdr, hdr_msaa, targets) =
+            Self::create_targets(device, sc_desc.width, sc_desc.height, sample_count);
+
+        Ok(Self {
+            hdr,
+            effects: Vec::new(),
+            sample_count,
+            hdr_msaa,
+            targets,
+            blit,
+        })
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    /// The color attachment to draw the scene with. With MSAA on this
+    /// draws to a multisampled texture that gets resolved into
+    /// [PostProcess::hdr].
+    pub fn hdr_attachment(
+        &self,
+        load: wgpu::LoadOp<wgpu::Color>,
+    ) -> wgpu::RenderPassColorAttachmentDescriptor<'_> {
+        let ops = wgpu::Operations { load, store: true };
+        match &self.hdr_msaa {
+            Some(msaa) => wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &msaa.view,
+                resolve_target: Some(&self.hdr.view),
+                ops,
+            },
+            None => wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &self.hdr.view,
+                resolve_target: None,
+                ops,
+            },
+        }
+    }
+
+    /// Adds an effect to the end of the stack
+    pub fn push<E: PostEffect + 'static>(&mut self, effect: E) -> &mut Self {
+        self.effects.push(Box::new(effect));
+        self
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, sc_desc: &wgpu::SwapChainDescriptor) {
+        let (hdr, hdr_msaa, targets) =
+            Self::create_targets(device, sc_desc.width, sc_desc.height, self.sample_count);
+        self.hdr = hdr;
+        self.hdr_msaa = hdr_msaa;
+        self.targets = targets;
+        self.blit.invalidate();
+        for effect in &mut self.effects {
+            effect.resize();
+        }
+    }
+
+    /// Runs every effect on [PostProcess::hdr] and draws the result
+    /// to `output`, which should be the swap chain's current frame.
+    pub fn present(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+    ) {
+        let size = self.hdr.desc.size;
+        let mut input = &self.hdr.view;
+        // Ping pong between the two targets so an effect never
+        // reads the texture it's writing to.
+        for (i, effect) in self.effects.iter_mut().enumerate() {
+            let target = &self.targets[i % 2].view;
+            effect.apply(device, queue, encoder, input, target, size);
+            input = target;
+        }
+        self.blit.draw(device, encoder, input, None, None, output);
+    }
+
+    fn create_targets(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        sample_count: u32,
+    ) -> (Texture<'a>, Option<Texture<'a>>, [Texture<'a>; 2]) {
+        let create =
+            |label| Texture::create_render_target(device, width, height, Self::FORMAT, Some(label));
+        let hdr_msaa = if sample_count > 1 {
+            Some(Texture::from_descriptor(
+                device,
+                wgpu::TextureDescriptor {
+                    label: Some("PostProcess::hdr_msaa"),
+                    size: wgpu::Extent3d {
+                        width,
+                        height,
+                        depth: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: Self::FORMAT,
+                    usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+                },
+            ))
+        } else {
+            None
+        };
+        (
+            create("PostProcess::hdr"),
+            hdr_msaa,
+            [create("PostProcess::ping"), create("PostProcess::pong")],
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TonemapOperator {
+    /// A fit of the ACES filmic curve
+    Aces = 0,
+    Reinhard = 1,
+    /// Just clamps, for comparing against the others
+    None = 2,
+}
+
+/**
+ * Scales the scene by `exposure` and maps it into 0 to 1 range.
+ * Effects after this one work with LDR colors.
+ */
+pub struct Tonemap {
+    pub exposure: f32,
+    pub operator: TonemapOperator,
+    pass: FullscreenPass,
+    params: wgpu::Buffer,
+}
+
+impl Tonemap {
+    pub fn new(device: &wgpu::Device, operator: TonemapOperator) -> Result<Self> {
+        let pass = FullscreenPass::new(
+            device,
+            "Tonemap",
+            wgpu::include_spirv!("tonemap.frag.spv"),
+            PostProcess::FORMAT,
+            true,
+            false,
+        )?;
+        let params = FullscreenPass::create_params(device, "Tonemap::params", &[0; 16]);
+        Ok(Self {
+            exposure: 1.0,
+            operator,
+            pass,
+            params,
+        })
+    }
+}
+
+impl PostEffect for Tonemap {
+    fn apply(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        input: &wgpu::TextureView,
+        output: &wgpu::TextureView,
+        _size: wgpu::Extent3d,
+    ) {
+        let params = [self.exposure.to_bits(), self.operator as u32, 0, 0];
+        queue.write_buffer(&self.params, 0, bytemuck::cast_slice(&params));
+        self.pass
+            .draw(device, encoder, input, Some(&self.params), None, output);
+    }
+
+    fn resize(&mut self) {
+        self.pass.invalidate();
+    }
+}
+
+/**
+ * Makes bright parts of the scene glow. Pixels brighter than
+ * `threshold` are blurred at half resolution and added back on top
+ * of the scene. Put this before [Tonemap] so it sees HDR values.
+ */
+pub struct Bloom {
+    pub threshold: f32,
+    /// Softens the cutoff at the threshold. 0 is a hard cutoff.
+    pub knee: f32,
+    pub intensity: f32,
+    /// How many times the horizontal and vertical blur are run
+    pub blur_passes: u32,
+    threshold_pass: FullscreenPass,
+    blur_pass: FullscreenPass,
+    composite_pass: FullscreenPass,
+    threshold_params: wgpu::Buffer,
+    horizontal_params: wgpu::Buffer,
+    vertical_params: wgpu::Buffer,
+    composite_params: wgpu::Buffer,
+    targets: Option<[Texture<'static>; 2]>,
+}
+
+impl Bloom {
+    pub fn new(device: &wgpu::Device) -> Result<Self> {
+        let format = PostProcess::FORMAT;
+        let threshold_pass = FullscreenPass::new(
+            device,
+            "Bloom::threshold",
+            wgpu::include_spirv!("bloom_threshold.frag.spv"),
+            format,
+            true,
+            false,
+        )?;
+        let blur_pass = FullscreenPass::new(
+            device,
+            "Bloom::blur",
+            wgpu::include_spirv!("blur.frag.spv"),
+            format,
+            true,
+            false,
+        )?;
+        let composite_pass = FullscreenPass::new(
+            device,
+            "Bloom::composite",
+            wgpu::include_spirv!("bloom_composite.frag.spv"),
+            format,
+            true,
+            true,
+        )?;
+        let direction = |x: f32, y: f32| [x, y, 0.0, 0.0];
+        Ok(Self {
+            threshold: 1.0,
+            knee: 0.5,
+            intensity: 0.5,
+            blur_passes: 2,
+            threshold_pass,
+            blur_pass,
+            composite_pass,
+            threshold_params: FullscreenPass::create_params(device, "Bloom::threshold", &[0; 16]),
+            horizontal_params: FullscreenPass::create_params(
+                device,
+                "Bloom::horizontal",
+                bytemuck::cast_slice(&direction(1.0, 0.0)),
+            ),
+            vertical_params: FullscreenPass::create_params(
+                device,
+                "Bloom::vertical",
+                bytemuck::cast_slice(&direction(0.0, 1.0)),
+            ),
+            composite_params: FullscreenPass::create_params(device, "Bloom::composite", &[0; 16]),
+            targets: None,
+        })
+    }
+}
+
+impl PostEffect for Bloom {
+    fn apply(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        input: &wgpu::TextureView,
+        output: &wgpu::TextureView,
+        size: wgpu::Extent3d,
+    ) {
+        let width = (size.width / 2).max(1);
+        let height = (size.height / 2).max(1);
+        let stale = match &self.targets {
+            Some([t, _]) => t.desc.size.width != width || t.desc.size.height != height,
+            None => true,
+        };
+        if stale {
+            let create = |label| {
+                Texture::create_render_target(
+                    device,
+                    width,
+                    height,
+                    PostProcess::FORMAT,
+                    Some(label),
+                )
+            };
+            self.targets = Some([create("Bloom::ping"), create("Bloom::pong")]);
+            self.resize();
+        }
+        let [a, b] = self.targets.as_ref().unwrap();
+
+        queue.write_buffer(
+            &self.threshold_params,
+            0,
+            bytemuck::cast_slice(&[self.threshold, self.knee, 0.0, 0.0]),
+        );
+        queue.write_buffer(
+            &self.composite_params,
+            0,
+            bytemuck::cast_slice(&[self.intensity, 0.0, 0.0, 0.0]),
+        );
+
+        self.threshold_pass.draw(
+            device,
+            encoder,
+            input,
+            Some(&self.threshold_params),
+            None,
+            &a.view,
+        );
+        for _ in 0..self.blur_passes {
+            self.blur_pass.draw(
+                device,
+                encoder,
+                &a.view,
+                Some(&self.horizontal_params),
+                None,
+                &b.view,
+            );
+            self.blur_pass.draw(
+                device,
+                encoder,
+                &b.view,
+                Some(&self.vertical_params),
+                None,
+                &a.view,
+            );
+        }
+        self.composite_pass.draw(
+            device,
+            encoder,
+            input,
+            Some(&self.composite_params),
+            Some(&a.view),
+            output,
+        );
+    }
+
+    fn resize(&mut self) {
+        self.threshold_pass.invalidate();
+        self.blur_pass.invalidate();
+        self.composite_pass.invalidate();
+    }
+}
+
+/**
+ * Fast approximate anti-aliasing. This expects LDR input, so it
+ * should come after [Tonemap], usually as the last effect.
+ */
+pub struct Fxaa {
+    /// The furthest, in pixels, FXAA will blur along an edge
+    pub span_max: f32,
+    pub reduce_mul: f32,
+    pub reduce_min: f32,
+    pass: FullscreenPass,
+    params: wgpu::Buffer,
+}
+
+impl Fxaa {
+    pub fn new(device: &wgpu::Device) -> Result<Self> {
+        let pass = FullscreenPass::new(
+            device,
+            "Fxaa",
+            wgpu::include_spirv!("fxaa.frag.spv"),
+            PostProcess::FORMAT,
+            true,
+            false,
+        )?;
+        let params = FullscreenPass::create_params(device, "Fxaa::params", &[0; 16]);
+        Ok(Self {
+            span_max: 8.0,
+            reduce_mul: 1.0 / 8.0,
+            reduce_min: 1.0 / 128.0,
+            pass,
+            params,
+        })
+    }
+}
+
+impl PostEffect for Fxaa {
+    fn apply(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        input: &wgpu::TextureView,
+        output: &wgpu::TextureView,
+        _size: wgpu::Extent3d,
+    ) {
+        let params = [self.span_max, self.reduce_mul, self.reduce_min, 0.0];
+        queue.write_buffer(&self.params, 0, bytemuck::cast_slice(&params));
+        self.pass
+            .draw(device, encoder, input, Some(&self.params), None, output);
+    }
+
+    fn resize(&mut self) {
+        self.pass.invalidate();
+    }
+}
+
+/**
+ * Darkens the edges of the screen. `radius` and `softness` are
+ * fractions of the distance from the center to a corner.
+ */
+pub struct Vignette {
+    pub strength: f32,
+    pub radius: f32,
+    pub softness: f32,
+    pass: FullscreenPass,
+    params: wgpu::Buffer,
+}
+
+impl Vignette {
+    pub fn new(device: &wgpu::Device) -> Result<Self> {
+        let pass = FullscreenPass::new(
+            device,
+            "Vignette",
+            wgpu::include_spirv!("vignette.frag.spv"),
+            PostProcess::FORMAT,
+            true,
+            false,
+        )?;
+        let params = FullscreenPass::create_params(device, "Vignette::params", &[0; 16]);
+        Ok(Self {
+            strength: 0.5,
+            radius: 0.9,
+            softness: 0.5,
+            pass,
+            params,
+        })
+    }
+}
+
+impl PostEffect for Vignette {
+    fn apply(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        input: &wgpu::TextureView,
+        output: &wgpu::TextureView,
+        _size: wgpu::Extent3d,
+    ) {
+        let params = [self.strength, self.radius, self.softness, 0.0];
+        queue.write_buffer(&self.params, 0, bytemuck::cast_slice(&params));
+        self.pass
+            .draw(device, encoder, input, Some(&self.params), None, output);
+    }
+
+    fn resize(&mut self) {
+        self.pass.invalidate();
+    }
+}
+
+/**
+ * Remaps colors with a 3D lookup table stored as a 2D strip: `size`
+ * slices of `size` x `size` pixels side by side, where red goes
+ * across each slice, green goes down and blue picks the slice. This
+ * is the layout most image editors export "neutral LUT" images in.
+ */
+pub struct ColorGrade {
+    /// 0 leaves the image alone, 1 fully applies the LUT
+    pub strength: f32,
+    lut: Texture<'static>,
+    lut_size: u32,
+    pass: FullscreenPass,
+    params: wgpu::Buffer,
+}
+
+impl ColorGrade {
+    pub fn new(device: &wgpu::Device, lut: Texture<'static>) -> Result<Self> {
+        let size = lut.desc.size;
+        if size.width != size.height * size.height {
+            bail!(
+                "A LUT strip should be size^2 x size, got {}x{}",
+                size.width,
+                size.height
+            );
+        }
+        let pass = FullscreenPass::new(
+            device,
+            "ColorGrade",
+            wgpu::include_spirv!("color_grade.frag.spv"),
+            PostProcess::FORMAT,
+            true,
+            true,
+        )?;
+        let params = FullscreenPass::create_params(device, "ColorGrade::params", &[0; 16]);
+        Ok(Self {
+            strength: 1.0,
+            lut_size: size.height,
+            lut,
+            pass,
+            params,
+        })
+    }
+
+    /// Loads a LUT strip image. LUTs are stored as sRGB but are
+    /// sampled as is, since the colors they're indexed with have
+    /// already been tonemapped.
+    pub fn load<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: P,
+    ) -> Result<Self> {
+        let lut = Texture::load(device, queue, path, true)?;
+        Self::new(device, lut)
+    }
+
+    /// A LUT that maps every color to itself. Handy as a starting
+    /// point to edit in an image editor.
+    pub fn neutral_lut(size: u32) -> image::RgbaImage {
+        let max = (size - 1).max(1) as f32;
+        image::RgbaImage::from_fn(size * size, size, |x, y| {
+            let r = (x % size) as f32 / max;
+            let g = y as f32 / max;
+            let b = (x / size) as f32 / max;
+            image::Rgba([
+                (r * 255.0).round() as u8,
+                (g * 255.0).round() as u8,
+                (b * 255.0).round() as u8,
+                255,
+            ])
+        })
+    }
+}
+
+impl PostEffect for ColorGrade {
+    fn apply(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        input: &wgpu::TextureView,
+        output: &wgpu::TextureView,
+        _size: wgpu::Extent3d,
+    ) {
+        let params = [self.lut_size as f32, self.strength, 0.0, 0.0];
+        queue.write_buffer(&self.params, 0, bytemuck::cast_slice(&params));
+        self.pass.draw(
+            device,
+            encoder,
+            input,
+            Some(&self.params),
+            Some(&self.lut.view),
+            output,
+        );
+    }
+
+    fn resize(&mut self) {
+        self.pass.invalidate();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neutral_lut_layout() {
+        let lut = ColorGrade::neutral_lut(16);
+        assert_eq!(lut.dimensions(), (256, 16));
+        assert_eq!(lut.get_pixel(0, 0).0, [0, 0, 0, 255]);
+        // Last texel of the first slice: full red and green, no blue
+        assert_eq!(lut.get_pixel(15, 15).0, [255, 255, 0, 255]);
+        // First texel of the last slice: only blue
+        assert_eq!(lut.get_pixel(240, 0).0, [0, 0, 255, 255]);
+        assert_eq!(lut.get_pixel(255, 15).0, [255, 255, 255, 255]);
+    }
+}
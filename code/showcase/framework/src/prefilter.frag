@@ -0,0 +1,76 @@
+#version 450
+
+layout(location=0) in vec2 v_ndc;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform textureCube t_environment;
+layout(set=0, binding=1) uniform sampler s_environment;
+layout(set=0, binding=2)
+uniform Face {
+    uint u_face;
+    float u_roughness;
+};
+
+const float PI = 3.14159265359;
+const uint SAMPLE_COUNT = 512u;
+
+// See equirect.frag
+vec3 face_direction(uint face, float s, float t) {
+    switch (face) {
+        case 0: return vec3(1.0, -t, -s);
+        case 1: return vec3(-1.0, -t, s);
+        case 2: return vec3(s, 1.0, t);
+        case 3: return vec3(s, -1.0, -t);
+        case 4: return vec3(s, -t, 1.0);
+        default: return vec3(-s, -t, -1.0);
+    }
+}
+
+float radical_inverse_vdc(uint bits) {
+    bits = (bits << 16u) | (bits >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return float(bits) * 2.3283064365386963e-10;
+}
+
+vec2 hammersley(uint i, uint n) {
+    return vec2(float(i) / float(n), radical_inverse_vdc(i));
+}
+
+vec3 importance_sample_ggx(vec2 xi, vec3 n, float roughness) {
+    float a = roughness * roughness;
+    float phi = 2.0 * PI * xi.x;
+    float cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    float sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    vec3 h = vec3(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+
+    vec3 up = abs(n.z) < 0.999 ? vec3(0.0, 0.0, 1.0) : vec3(1.0, 0.0, 0.0);
+    vec3 tangent = normalize(cross(up, n));
+    vec3 bitangent = cross(n, tangent);
+    return normalize(tangent * h.x + bitangent * h.y + n * h.z);
+}
+
+// Pre-convolves the environment with the GGX lobe for the roughness
+// of this mip level, assuming the view direction equals the normal.
+void main() {
+    vec3 n = normalize(face_direction(u_face, v_ndc.x, -v_ndc.y));
+    vec3 v = n;
+
+    float total_weight = 0.0;
+    vec3 color = vec3(0.0);
+    for (uint i = 0u; i < SAMPLE_COUNT; i++) {
+        vec2 xi = hammersley(i, SAMPLE_COUNT);
+        vec3 h = importance_sample_ggx(xi, n, u_roughness);
+        vec3 l = normalize(2.0 * dot(v, h) * h - v);
+        float n_dot_l = max(dot(n, l), 0.0);
+        if (n_dot_l > 0.0) {
+            color += texture(samplerCube(t_environment, s_environment), l).rgb * n_dot_l;
+            total_weight += n_dot_l;
+        }
+    }
+
+    f_color = vec4(color / max(total_weight, 1e-4), 1.0);
+}
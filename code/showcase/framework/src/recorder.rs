@@ -0,0 +1,695 @@
+use anyhow::*;
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+use winit::event::Event;
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::platform::desktop::EventLoopExtDesktop;
+use winit::window::WindowBuilder;
+
+use crate::{Demo, Display};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AnimationFormat {
+    /// Palettized, so [AnimationRecorder::quantization] and
+    /// [AnimationRecorder::dither] apply
+    Gif,
+    /// Lossless 8 bit RGBA
+    Apng,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Dither {
+    None,
+    /// Error diffusion. Looks the best on gradients but the noise
+    /// changes every frame, which makes the GIF bigger.
+    FloydSteinberg,
+    /// A 4x4 Bayer pattern that stays put between frames
+    Ordered,
+}
+
+/**
+ * How GIF frames are reduced to a palette. Frames that already have
+ * no more than `colors` colors keep them exactly, the rest go through
+ * NeuQuant. `speed` is NeuQuant's sampling factor, from 1 (slowest,
+ * best) to 30.
+ */
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Quantization {
+    /// At most 256
+    pub colors: usize,
+    pub speed: i32,
+    /// Share one palette between all frames instead of building one
+    /// per frame. This avoids the palette flickering, but frames with
+    /// very different colors will look worse.
+    pub global_palette: bool,
+}
+
+impl Default for Quantization {
+    fn default() -> Self {
+        Self {
+            colors: 256,
+            speed: 10,
+            global_palette: false,
+        }
+    }
+}
+
+struct RecordedFrame {
+    image: image::RgbaImage,
+    /// How many simulated frames this image stands in for. This is
+    /// more than 1 when duplicate frames were elided.
+    frames: u32,
+}
+
+/**
+ * Records a [Demo] into a GIF or APNG. Time is simulated so every
+ * frame advances the demo by exactly `1 / frame_rate` seconds no
+ * matter how long it takes to render and encode.
+ */
+pub struct AnimationRecorder {
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate: u32,
+    /// How long each frame is shown for when played back. Defaults to
+    /// `1 / frame_rate` seconds so the animation plays in real time.
+    pub frame_delay: Option<Duration>,
+    /// How many times the animation plays. `None` loops forever.
+    pub loop_count: Option<u16>,
+    /// Merge identical consecutive frames into one longer frame
+    pub elide_duplicates: bool,
+    pub format: AnimationFormat,
+    pub quantization: Quantization,
+    pub dither: Dither,
+    frames: Vec<RecordedFrame>,
+}
+
+impl AnimationRecorder {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            frame_rate: 30,
+            frame_delay: None,
+            loop_count: None,
+            elide_duplicates: true,
+            format: AnimationFormat::Gif,
+            quantization: Quantization::default(),
+            dither: Dither::FloydSteinberg,
+            frames: Vec::new(),
+        }
+    }
+
+    /// How many images will be encoded, after duplicates are elided
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    /// Adds a frame to the end of the animation. Frames that aren't
+    /// `width` by `height` get resized. Frames are treated as opaque.
+    pub fn push_frame(&mut self, image: image::RgbaImage) {
+        let mut image = if image.dimensions() != (self.width, self.height) {
+            image::imageops::resize(
+                &image,
+                self.width,
+                self.height,
+                image::imageops::FilterType::Triangle,
+            )
+        } else {
+            image
+        };
+        for pixel in image.pixels_mut() {
+            pixel[3] = 255;
+        }
+
+        if self.elide_duplicates {
+            if let Some(last) = self.frames.last_mut() {
+                if last.image == image {
+                    last.frames += 1;
+                    return;
+                }
+            }
+        }
+        self.frames.push(RecordedFrame { image, frames: 1 });
+    }
+
+    /// Steps `demo` forward `frame_count` times, capturing each frame
+    /// it renders. The display is resized to the recorder's size while
+    /// recording and put back afterwards. `demo` has to draw through
+    /// [Display::begin_frame] and [Display::end_frame].
+    pub fn record_frames<D: Demo>(
+        &mut self,
+        display: &mut Display,
+        demo: &mut D,
+        frame_count: u32,
+    ) -> Result<()> {
+        if self.frame_rate == 0 {
+            bail!("Frame rate must be at least 1");
+        }
+        let (old_width, old_height) = (display.sc_desc.width, display.sc_desc.height);
+        display.resize(self.width, self.height);
+        demo.resize(display);
+
+        let dt = Duration::from_secs_f64(1.0 / self.frame_rate as f64);
+        let mut result = Ok(());
+        for i in 0..frame_count {
+            demo.update(display, dt);
+            display.capture_next_frame_in_memory();
+            demo.render(display);
+            match display.take_captured_frame() {
+                Some(image) => self.push_frame(image),
+                None => {
+                    result = Err(anyhow!(
+                        "Frame {} wasn't captured, does the demo use Display::begin_frame?",
+                        i
+                    ));
+                    break;
+                }
+            }
+        }
+
+        display.resize(old_width, old_height);
+        demo.resize(display);
+        result
+    }
+
+    /// Encodes everything recorded so far with [AnimationRecorder::format]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        if self.frames.is_empty() {
+            bail!("There are no frames to save");
+        }
+        let file = BufWriter::new(std::fs::File::create(path.as_ref())?);
+        match self.format {
+            AnimationFormat::Gif => self.write_gif(file),
+            AnimationFormat::Apng => self.write_apng(file),
+        }
+    }
+
+    fn frame_delay(&self) -> Duration {
+        self.frame_delay
+            .unwrap_or_else(|| Duration::from_secs_f64(1.0 / self.frame_rate.max(1) as f64))
+    }
+
+    /// How long `frame` stays on screen in milliseconds
+    fn delay_ms(&self, frame: &RecordedFrame) -> u32 {
+        (self.frame_delay().as_secs_f64() * 1000.0 * frame.frames as f64).round() as u32
+    }
+
+    fn write_gif<W: Write>(&self, w: W) -> Result<()> {
+        use image::gif::{GifEncoder, Repeat};
+
+        if self.width > u16::MAX as u32 || self.height > u16::MAX as u32 {
+            bail!("GIFs can be at most {0}x{0}", u16::MAX);
+        }
+        let colors = self.quantization.colors.clamp(2, 256);
+        let speed = self.quantization.speed.clamp(1, 30);
+
+        let global = if self.quantization.global_palette {
+            let pixels = self
+                .frames
+                .iter()
+                .flat_map(|f| f.image.as_raw().iter().copied())
+                .collect::<Vec<_>>();
+            Some(Palette::new(&pixels, colors, speed))
+        } else {
+            None
+        };
+
+        let mut encoder = GifEncoder::new_with_speed(w, speed);
+        match self.loop_count {
+            None => encoder.set_repeat(Repeat::Infinite)?,
+            // GIFs without a repeat extension play once
+            Some(n) if n <= 1 => {}
+            Some(n) => encoder.set_repeat(Repeat::Finite(n - 1))?,
+        }
+
+        for recorded in &self.frames {
+            let pixels = recorded.image.as_raw();
+            let local;
+            let palette = match &global {
+                Some(palette) => palette,
+                None => {
+                    local = Palette::new(pixels, colors, speed);
+                    &local
+                }
+            };
+            let colors = palette.colors();
+            let indices = index_pixels(pixels, self.width, colors, self.dither, |c| {
+                palette.index_of(c)
+            });
+            // With at most 256 colors left, the encoder uses them as
+            // the frame's palette as is instead of quantizing again
+            let width = self.width;
+            let quantized = image::RgbaImage::from_fn(width, self.height, |x, y| {
+                let i = indices[(y * width + x) as usize] as usize * 3;
+                image::Rgba([colors[i], colors[i + 1], colors[i + 2], 255])
+            });
+            // GIF delays are in hundredths of a second
+            let delay = ((self.delay_ms(recorded) + 5) / 10).min(u16::MAX as u32) * 10;
+            encoder.encode_frame(image::Frame::from_parts(
+                quantized,
+                0,
+                0,
+                image::Delay::from_numer_denom_ms(delay, 1),
+            ))?;
+        }
+        Ok(())
+    }
+
+    /**
+     * `image` only writes still PNGs, so each frame is encoded as one
+     * and its compressed data is moved into the animation: the first
+     * frame's IDAT chunks stay as they are and later frames' become
+     * fdAT chunks, each frame preceded by an fcTL chunk.
+     */
+    fn write_apng<W: Write>(&self, mut w: W) -> Result<()> {
+        w.write_all(&PNG_SIGNATURE)?;
+        let mut sequence = 0u32;
+        for (i, recorded) in self.frames.iter().enumerate() {
+            let mut png = Vec::new();
+            image::png::PngEncoder::new(&mut png).encode(
+                recorded.image.as_raw(),
+                self.width,
+                self.height,
+                image::ColorType::Rgba8,
+            )?;
+            let chunks = png_chunks(&png)?;
+
+            if i == 0 {
+                let header = chunks
+                    .iter()
+                    .find(|(ty, _)| ty == b"IHDR")
+                    .context("PNG has no IHDR chunk")?;
+                write_chunk(&mut w, b"IHDR", header.1)?;
+                // APNG uses 0 plays to mean forever
+                let plays = self.loop_count.map(|n| n.max(1) as u32).unwrap_or(0);
+                let mut control = Vec::with_capacity(8);
+                control.extend_from_slice(&(self.frames.len() as u32).to_be_bytes());
+                control.extend_from_slice(&plays.to_be_bytes());
+                write_chunk(&mut w, b"acTL", &control)?;
+            }
+
+            let delay = self.delay_ms(recorded).min(u16::MAX as u32) as u16;
+            let mut frame_control = Vec::with_capacity(26);
+            frame_control.extend_from_slice(&sequence.to_be_bytes());
+            frame_control.extend_from_slice(&self.width.to_be_bytes());
+            frame_control.extend_from_slice(&self.height.to_be_bytes());
+            // x and y offsets
+            frame_control.extend_from_slice(&[0; 8]);
+            frame_control.extend_from_slice(&delay.to_be_bytes());
+            frame_control.extend_from_slice(&1000u16.to_be_bytes());
+            // Don't dispose, and replace the previous frame
+            frame_control.extend_from_slice(&[0, 0]);
+            write_chunk(&mut w, b"fcTL", &frame_control)?;
+            sequence += 1;
+
+            for (_, data) in chunks.iter().filter(|(ty, _)| ty == b"IDAT") {
+                if i == 0 {
+                    write_chunk(&mut w, b"IDAT", data)?;
+                } else {
+                    let mut frame_data = Vec::with_capacity(data.len() + 4);
+                    frame_data.extend_from_slice(&sequence.to_be_bytes());
+                    frame_data.extend_from_slice(data);
+                    write_chunk(&mut w, b"fdAT", &frame_data)?;
+                    sequence += 1;
+                }
+            }
+        }
+        write_chunk(&mut w, b"IEND", &[])?;
+        Ok(())
+    }
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Splits a PNG file into its chunks' types and data
+fn png_chunks(png: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    if !png.starts_with(&PNG_SIGNATURE) {
+        bail!("Not a PNG");
+    }
+    let mut chunks = Vec::new();
+    let mut rest = &png[PNG_SIGNATURE.len()..];
+    while !rest.is_empty() {
+        if rest.len() < 12 {
+            bail!("PNG chunk is cut off");
+        }
+        let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if rest.len() < length + 12 {
+            bail!("PNG chunk is cut off");
+        }
+        let ty = [rest[4], rest[5], rest[6], rest[7]];
+        chunks.push((ty, &rest[8..8 + length]));
+        rest = &rest[length + 12..];
+    }
+    Ok(chunks)
+}
+
+fn write_chunk<W: Write>(w: &mut W, ty: &[u8; 4], data: &[u8]) -> Result<()> {
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(ty)?;
+    w.write_all(data)?;
+    w.write_all(&chunk_crc(ty, data).to_be_bytes())?;
+    Ok(())
+}
+
+/// The CRC-32 every PNG chunk ends with, taken over its type and data
+fn chunk_crc(ty: &[u8; 4], data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in ty.iter().chain(data) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// A GIF palette as packed RGB
+enum Palette {
+    /// Every color of the frames, with its index
+    Exact(Vec<u8>, HashMap<[u8; 3], usize>),
+    Quantized(color_quant::NeuQuant, Vec<u8>),
+}
+
+impl Palette {
+    fn new(pixels: &[u8], colors: usize, speed: i32) -> Self {
+        let mut exact = HashMap::new();
+        for pixel in pixels.chunks_exact(4) {
+            let color = [pixel[0], pixel[1], pixel[2]];
+            if !exact.contains_key(&color) {
+                if exact.len() == colors {
+                    let nq = color_quant::NeuQuant::new(speed, colors, pixels);
+                    let palette = nq.color_map_rgb();
+                    return Palette::Quantized(nq, palette);
+                }
+                let index = exact.len();
+                exact.insert(color, index);
+            }
+        }
+        let mut palette = vec![0; exact.len() * 3];
+        for (color, &index) in &exact {
+            palette[index * 3..index * 3 + 3].copy_from_slice(color);
+        }
+        Palette::Exact(palette, exact)
+    }
+
+    fn colors(&self) -> &[u8] {
+        match self {
+            Palette::Exact(palette, _) | Palette::Quantized(_, palette) => palette,
+        }
+    }
+
+    fn index_of(&self, color: [u8; 3]) -> usize {
+        match self {
+            Palette::Exact(palette, indices) => indices.get(&color).copied().unwrap_or_else(|| {
+                // Dithering moves colors off the palette
+                let distance = |c: &[u8]| {
+                    (0..3)
+                        .map(|i| (c[i] as i32 - color[i] as i32).pow(2))
+                        .sum::<i32>()
+                };
+                palette
+                    .chunks_exact(3)
+                    .enumerate()
+                    .min_by_key(|(_, c)| distance(c))
+                    .map_or(0, |(i, _)| i)
+            }),
+            Palette::Quantized(nq, _) => nq.index_of(&[color[0], color[1], color[2], 255]),
+        }
+    }
+}
+
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Maps RGBA `pixels` to indices into `palette`, which is packed RGB.
+/// `index_of` finds the closest palette entry to a color.
+fn index_pixels<F: Fn([u8; 3]) -> usize>(
+    pixels: &[u8],
+    width: u32,
+    palette: &[u8],
+    dither: Dither,
+    index_of: F,
+) -> Vec<u8> {
+    let width = width as usize;
+    let mut indices = Vec::with_capacity(pixels.len() / 4);
+    match dither {
+        Dither::None => {
+            for pixel in pixels.chunks_exact(4) {
+                indices.push(index_of([pixel[0], pixel[1], pixel[2]]) as u8);
+            }
+        }
+        Dither::Ordered => {
+            // Roughly the distance between neighbouring palette colors
+            let colors = (palette.len() / 3).max(2) as f32;
+            let spread = 255.0 / (colors.cbrt() - 1.0).max(1.0);
+            for (i, pixel) in pixels.chunks_exact(4).enumerate() {
+                let (x, y) = (i % width, i / width);
+                let threshold = (BAYER_4X4[y % 4][x % 4] as f32 + 0.5) / 16.0 - 0.5;
+                let offset = threshold * spread;
+                let color = [
+                    clamp_channel(pixel[0] as f32 + offset),
+                    clamp_channel(pixel[1] as f32 + offset),
+                    clamp_channel(pixel[2] as f32 + offset),
+                ];
+                indices.push(index_of(color) as u8);
+            }
+        }
+        Dither::FloydSteinberg => {
+            // Error carried into the current and the next row, with a
+            // pixel of padding on either side
+            let mut current = vec![[0.0f32; 3]; width + 2];
+            let mut next = vec![[0.0f32; 3]; width + 2];
+            for row in pixels.chunks_exact(width * 4) {
+                for x in 0..width {
+                    let pixel = &row[x * 4..x * 4 + 3];
+                    let mut wanted = [0.0; 3];
+                    let mut color = [0u8; 3];
+                    for c in 0..3 {
+                        wanted[c] = pixel[c] as f32 + current[x + 1][c];
+                        color[c] = clamp_channel(wanted[c]);
+                    }
+                    let index = index_of(color);
+                    indices.push(index as u8);
+
+                    let chosen = &palette[index * 3..index * 3 + 3];
+                    for c in 0..3 {
+                        let error = wanted[c] - chosen[c] as f32;
+                        current[x + 2][c] += error * 7.0 / 16.0;
+                        next[x][c] += error * 3.0 / 16.0;
+                        next[x + 1][c] += error * 5.0 / 16.0;
+                        next[x + 2][c] += error * 1.0 / 16.0;
+                    }
+                }
+                std::mem::swap(&mut current, &mut next);
+                for e in next.iter_mut() {
+                    *e = [0.0; 3];
+                }
+            }
+        }
+    }
+    indices
+}
+
+fn clamp_channel(value: f32) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+/**
+ * Opens a window, runs `D` for `frame_count` frames at the recorder's
+ * frame rate and saves the result to `path`. This is the recording
+ * counterpart to [crate::run].
+ */
+pub async fn record<D: Demo, P: AsRef<Path>>(
+    mut recorder: AnimationRecorder,
+    frame_count: u32,
+    path: P,
+) -> Result<()> {
+    let mut event_loop = EventLoop::new();
+    let window = WindowBuilder::new()
+        .with_title(env!("CARGO_PKG_NAME"))
+        .with_inner_size(winit::dpi::PhysicalSize::new(
+            recorder.width,
+            recorder.height,
+        ))
+        .with_resizable(false)
+        .build(&event_loop)?;
+    let mut display = Display::with_sample_count(&window, D::SAMPLE_COUNT).await?;
+    let mut demo = D::init(&display)?;
+
+    // Wait for the window to show up before drawing to it
+    let mut result = None;
+    event_loop.run_return(|event, _, control_flow| {
+        *control_flow = ControlFlow::Poll;
+        if let Event::MainEventsCleared = event {
+            result = Some(
+                recorder
+                    .record_frames(&mut display, &mut demo, frame_count)
+                    .and_then(|_| recorder.save(path.as_ref())),
+            );
+            *control_flow = ControlFlow::Exit;
+        }
+    });
+    result.unwrap_or_else(|| Err(anyhow!("The event loop exited before recording")))?;
+    log::info!("Saved {} frames to {:?}", recorder.len(), path.as_ref());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(width: u32, height: u32, color: [u8; 4]) -> image::RgbaImage {
+        image::RgbaImage::from_pixel(width, height, image::Rgba(color))
+    }
+
+    #[test]
+    fn duplicates_are_elided() {
+        let mut recorder = AnimationRecorder::new(4, 4);
+        recorder.push_frame(solid(4, 4, [0, 0, 0, 255]));
+        recorder.push_frame(solid(4, 4, [0, 0, 0, 255]));
+        recorder.push_frame(solid(4, 4, [255, 0, 0, 255]));
+        // Alpha is ignored so this is the same as the previous frame
+        recorder.push_frame(solid(4, 4, [255, 0, 0, 0]));
+        assert_eq!(recorder.len(), 2);
+        assert_eq!(recorder.frames[0].frames, 2);
+        assert_eq!(recorder.frames[1].frames, 2);
+
+        recorder.clear();
+        recorder.elide_duplicates = false;
+        recorder.push_frame(solid(4, 4, [0, 0, 0, 255]));
+        recorder.push_frame(solid(4, 4, [0, 0, 0, 255]));
+        assert_eq!(recorder.len(), 2);
+    }
+
+    #[test]
+    fn frames_are_resized() {
+        let mut recorder = AnimationRecorder::new(6, 3);
+        recorder.push_frame(solid(12, 12, [10, 20, 30, 255]));
+        assert_eq!(recorder.frames[0].image.dimensions(), (6, 3));
+        assert_eq!(
+            recorder.frames[0].image.get_pixel(2, 1).0,
+            [10, 20, 30, 255]
+        );
+    }
+
+    #[test]
+    fn delays() {
+        let mut recorder = AnimationRecorder::new(1, 1);
+        recorder.frame_rate = 25;
+        let frame = RecordedFrame {
+            image: solid(1, 1, [0; 4]),
+            frames: 3,
+        };
+        assert_eq!(recorder.delay_ms(&frame), 120);
+        recorder.frame_delay = Some(Duration::from_millis(100));
+        assert_eq!(recorder.delay_ms(&frame), 300);
+    }
+
+    fn two_frames(format: AnimationFormat) -> Vec<u8> {
+        let mut recorder = AnimationRecorder::new(4, 2);
+        recorder.format = format;
+        recorder.dither = Dither::None;
+        recorder.loop_count = Some(1);
+        recorder.push_frame(solid(4, 2, [255, 0, 0, 255]));
+        recorder.push_frame(solid(4, 2, [255, 0, 0, 255]));
+        recorder.push_frame(solid(4, 2, [0, 0, 255, 255]));
+        let mut bytes = Vec::new();
+        match format {
+            AnimationFormat::Gif => recorder.write_gif(&mut bytes),
+            AnimationFormat::Apng => recorder.write_apng(&mut bytes),
+        }
+        .unwrap();
+        bytes
+    }
+
+    fn decoded_frames<'a, D: image::AnimationDecoder<'a>>(decoder: D) -> Vec<image::Frame> {
+        decoder.into_frames().collect_frames().unwrap()
+    }
+
+    #[test]
+    fn gifs_decode() {
+        let bytes = two_frames(AnimationFormat::Gif);
+        let decoder = image::gif::GifDecoder::new(&bytes[..]).unwrap();
+        let frames = decoded_frames(decoder);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].buffer().dimensions(), (4, 2));
+        assert_eq!(frames[0].buffer().get_pixel(3, 1).0, [255, 0, 0, 255]);
+        assert_eq!(frames[1].buffer().get_pixel(0, 0).0, [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn apngs_decode() {
+        let bytes = two_frames(AnimationFormat::Apng);
+        assert!(png_chunks(&bytes)
+            .unwrap()
+            .iter()
+            .any(|(ty, _)| ty == b"fdAT"));
+        let decoder = image::png::PngDecoder::new(&bytes[..]).unwrap();
+        assert!(decoder.is_apng());
+        let frames = decoded_frames(decoder.apng());
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].buffer().get_pixel(3, 1).0, [255, 0, 0, 255]);
+        assert_eq!(frames[1].buffer().get_pixel(0, 0).0, [0, 0, 255, 255]);
+        // The elided duplicate doubles the first frame's delay
+        let (numer, denom) = frames[0].delay().numer_denom_ms();
+        assert_eq!((numer as f32 / denom as f32).round(), 67.0);
+    }
+
+    #[test]
+    fn chunk_crcs() {
+        // Every PNG ends with this exact IEND chunk
+        assert_eq!(chunk_crc(b"IEND", &[]), 0xae42_6082);
+    }
+
+    fn black_and_white(c: [u8; 3]) -> usize {
+        let sum = c[0] as u32 + c[1] as u32 + c[2] as u32;
+        if sum >= 3 * 128 {
+            1
+        } else {
+            0
+        }
+    }
+
+    #[test]
+    fn dithering() {
+        let palette = [0, 0, 0, 255, 255, 255];
+        let gray = solid(8, 8, [128, 128, 128, 255]);
+        let white = |indices: &[u8]| indices.iter().filter(|&&i| i == 1).count();
+
+        let plain = index_pixels(gray.as_raw(), 8, &palette, Dither::None, black_and_white);
+        assert_eq!(white(&plain), 64);
+
+        // Both dithers should come out about half and half
+        for &dither in &[Dither::FloydSteinberg, Dither::Ordered] {
+            let indices = index_pixels(gray.as_raw(), 8, &palette, dither, black_and_white);
+            assert_eq!(indices.len(), 64);
+            let count = white(&indices);
+            assert!(
+                (28..=36).contains(&count),
+                "{:?} gave {} white",
+                dither,
+                count
+            );
+        }
+
+        // Colors already in the palette are left alone
+        let black = solid(8, 8, [0, 0, 0, 255]);
+        for &dither in &[Dither::None, Dither::FloydSteinberg, Dither::Ordered] {
+            let indices = index_pixels(black.as_raw(), 8, &palette, dither, black_and_white);
+            assert_eq!(white(&indices), 0);
+        }
+    }
+}
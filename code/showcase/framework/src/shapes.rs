@@ -0,0 +1,658 @@
+use cgmath::*;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use crate::debug::perpendiculars;
+use crate::model::{Mesh, ModelVertex};
+
+/**
+ * Meshes that are generated rather than loaded, for demos and tests
+ * that don't want to ship asset files. Everything is centered on the
+ * origin with y up, faces counter clockwise from the outside, and UVs
+ * run left to right and top to bottom when looking at the surface.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Shape {
+    /// Each face gets the whole texture
+    Cube { size: f32 },
+    /// A flat grid on the xz plane, facing up
+    Plane {
+        width: f32,
+        depth: f32,
+        columns: u32,
+        rows: u32,
+    },
+    UvSphere {
+        radius: f32,
+        segments: u32,
+        rings: u32,
+    },
+    /// A sphere with evenly sized triangles, made by subdividing an
+    /// icosahedron
+    Icosphere { radius: f32, subdivisions: u32 },
+    /// Capped at both ends
+    Cylinder {
+        radius: f32,
+        height: f32,
+        segments: u32,
+    },
+    /// Pointing up, with a cap on the bottom
+    Cone {
+        radius: f32,
+        height: f32,
+        segments: u32,
+    },
+    /// Lying flat on the xz plane
+    Torus {
+        radius: f32,
+        tube_radius: f32,
+        segments: u32,
+        sides: u32,
+    },
+    /// A cylinder of `height` with a hemisphere on each end
+    Capsule {
+        radius: f32,
+        height: f32,
+        segments: u32,
+        rings: u32,
+    },
+}
+
+impl Shape {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Shape::Cube { .. } => "Cube",
+            Shape::Plane { .. } => "Plane",
+            Shape::UvSphere { .. } => "UvSphere",
+            Shape::Icosphere { .. } => "Icosphere",
+            Shape::Cylinder { .. } => "Cylinder",
+            Shape::Cone { .. } => "Cone",
+            Shape::Torus { .. } => "Torus",
+            Shape::Capsule { .. } => "Capsule",
+        }
+    }
+
+    /// The shape's vertices, with tangents, and triangle list indices
+    pub fn geometry(&self) -> (Vec<ModelVertex>, Vec<u32>) {
+        let mut builder = Builder::default();
+        match *self {
+            Shape::Cube { size } => builder.cube(size * 0.5),
+            Shape::Plane {
+                width,
+                depth,
+                columns,
+                rows,
+            } => {
+                let (columns, rows) = (columns.max(1), rows.max(1));
+                builder.grid(columns, rows, |u, v| {
+                    let position = Vector3::new((u - 0.5) * width, 0.0, (v - 0.5) * depth);
+                    (position, Vector3::unit_y())
+                });
+            }
+            Shape::UvSphere {
+                radius,
+                segments,
+                rings,
+            } => {
+                let rings = rings.max(2);
+                let profile = (0..=rings)
+                    .map(|ring| {
+                        let v = ring as f32 / rings as f32;
+                        let (sin, cos) = pole_sin_cos(ring, rings, v * PI);
+                        Profile::new(radius * sin, radius * cos, sin, cos, v)
+                    })
+                    .collect::<Vec<_>>();
+                builder.lathe(&profile, segments);
+            }
+            Shape::Icosphere {
+                radius,
+                subdivisions,
+            } => builder.icosphere(radius, subdivisions),
+            Shape::Cylinder {
+                radius,
+                height,
+                segments,
+            } => {
+                let h = height * 0.5;
+                builder.lathe(
+                    &[
+                        Profile::new(radius, h, 1.0, 0.0, 0.0),
+                        Profile::new(radius, -h, 1.0, 0.0, 1.0),
+                    ],
+                    segments,
+                );
+                builder.disc(radius, h, segments, true);
+                builder.disc(radius, -h, segments, false);
+            }
+            Shape::Cone {
+                radius,
+                height,
+                segments,
+            } => {
+                let h = height * 0.5;
+                let normal = Vector2::new(height, radius).normalize();
+                builder.lathe(
+                    &[
+                        Profile::new(0.0, h, normal.x, normal.y, 0.0),
+                        Profile::new(radius, -h, normal.x, normal.y, 1.0),
+                    ],
+                    segments,
+                );
+                builder.disc(radius, -h, segments, false);
+            }
+            Shape::Torus {
+                radius,
+                tube_radius,
+                segments,
+                sides,
+            } => {
+                let sides = sides.max(3);
+                let profile = (0..=sides)
+                    .map(|side| {
+                        let v = side as f32 / sides as f32;
+                        let (sin, cos) = (v * 2.0 * PI).sin_cos();
+                        Profile::new(radius + tube_radius * cos, -tube_radius * sin, cos, -sin, v)
+                    })
+                    .collect::<Vec<_>>();
+                builder.lathe(&profile, segments);
+            }
+            Shape::Capsule {
+                radius,
+                height,
+                segments,
+                rings,
+            } => {
+                // Rings per hemisphere. The straight part is the gap
+                // between the two hemispheres' equators.
+                let rings = rings.max(1);
+                let length = PI * radius + height;
+                let mut profile = Vec::new();
+                for &(offset, first_ring, arc_offset) in
+                    &[(height * 0.5, 0, 0.0), (-height * 0.5, rings, height)]
+                {
+                    for ring in first_ring..=first_ring + rings {
+                        let angle = ring as f32 / (rings * 2) as f32 * PI;
+                        let (sin, cos) = pole_sin_cos(ring, rings * 2, angle);
+                        let arc = angle * radius + arc_offset;
+                        profile.push(Profile::new(
+                            radius * sin,
+                            radius * cos + offset,
+                            sin,
+                            cos,
+                            arc / length,
+                        ));
+                    }
+                }
+                builder.lathe(&profile, segments);
+            }
+        }
+        builder.calc_tangents();
+        (builder.vertices, builder.indices)
+    }
+
+    pub fn mesh(&self, device: &wgpu::Device, material: usize) -> Mesh {
+        let (vertices, indices) = self.geometry();
+        Mesh::new(device, self.name(), vertices, indices, material)
+    }
+}
+
+/// Exact values at the poles, so the triangles that collapse there
+/// can be spotted and skipped
+fn pole_sin_cos(ring: u32, rings: u32, angle: f32) -> (f32, f32) {
+    if ring == 0 {
+        (0.0, 1.0)
+    } else if ring == rings {
+        (0.0, -1.0)
+    } else {
+        angle.sin_cos()
+    }
+}
+
+/// One point on the outline of a surface of revolution
+#[derive(Debug, Copy, Clone)]
+struct Profile {
+    radius: f32,
+    y: f32,
+    /// The normal, in the plane of the outline
+    normal: Vector2<f32>,
+    v: f32,
+}
+
+impl Profile {
+    fn new(radius: f32, y: f32, normal_radius: f32, normal_y: f32, v: f32) -> Self {
+        Self {
+            radius,
+            y,
+            normal: Vector2::new(normal_radius, normal_y),
+            v,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Builder {
+    vertices: Vec<ModelVertex>,
+    indices: Vec<u32>,
+}
+
+impl Builder {
+    fn vertex(&mut self, position: Vector3<f32>, normal: Vector3<f32>, uv: Vector2<f32>) -> u32 {
+        self.vertices.push(ModelVertex {
+            position,
+            tex_coords: uv,
+            normal,
+            tangent: Vector3::zero(),
+            bitangent: Vector3::zero(),
+        });
+        self.vertices.len() as u32 - 1
+    }
+
+    /// Skips triangles that collapsed to a line, like the ones
+    /// touching a pole
+    fn triangle(&mut self, a: u32, b: u32, c: u32) {
+        let p = |i: u32| self.vertices[i as usize].position;
+        if p(a) != p(b) && p(b) != p(c) && p(c) != p(a) {
+            self.indices.extend_from_slice(&[a, b, c]);
+        }
+    }
+
+    /**
+     * A `columns` by `rows` grid of quads, with `surface` giving the
+     * position and normal at each UV. Moving along u has to look like
+     * moving right from the outside, and v like moving down.
+     */
+    fn grid(
+        &mut self,
+        columns: u32,
+        rows: u32,
+        surface: impl Fn(f32, f32) -> (Vector3<f32>, Vector3<f32>),
+    ) {
+        let start = self.vertices.len() as u32;
+        for row in 0..=rows {
+            for column in 0..=columns {
+                let uv = Vector2::new(column as f32 / columns as f32, row as f32 / rows as f32);
+                let (position, normal) = surface(uv.x, uv.y);
+                self.vertex(position, normal, uv);
+            }
+        }
+        let stride = columns + 1;
+        for row in 0..rows {
+            for column in 0..columns {
+                let a = start + row * stride + column;
+                let (b, c, d) = (a + stride, a + stride + 1, a + 1);
+                self.triangle(a, b, c);
+                self.triangle(a, c, d);
+            }
+        }
+    }
+
+    /// Spins `profile`, listed top to bottom, around the y axis. u goes
+    /// once around, starting and ending on +z.
+    fn lathe(&mut self, profile: &[Profile], segments: u32) {
+        let segments = segments.max(3);
+        let start = self.vertices.len() as u32;
+        for point in profile {
+            for segment in 0..=segments {
+                let u = segment as f32 / segments as f32;
+                let (sin, cos) = if segment == segments {
+                    (0.0, 1.0)
+                } else {
+                    (u * 2.0 * PI).sin_cos()
+                };
+                let position = Vector3::new(point.radius * sin, point.y, point.radius * cos);
+                let normal =
+                    Vector3::new(point.normal.x * sin, point.normal.y, point.normal.x * cos);
+                self.vertex(position, normal.normalize(), Vector2::new(u, point.v));
+            }
+        }
+        let stride = segments + 1;
+        for row in 0..profile.len() as u32 - 1 {
+            for segment in 0..segments {
+                let a = start + row * stride + segment;
+                let (b, c, d) = (a + stride, a + stride + 1, a + 1);
+                self.triangle(a, b, c);
+                self.triangle(a, c, d);
+            }
+        }
+    }
+
+    /// A flat cap at `y`, facing up or down
+    fn disc(&mut self, radius: f32, y: f32, segments: u32, up: bool) {
+        let segments = segments.max(3);
+        let normal = if up {
+            Vector3::unit_y()
+        } else {
+            -Vector3::unit_y()
+        };
+        // Seen from outside, +x is right on both caps. z is down on
+        // the top one and up on the bottom one.
+        let uv = |x: f32, z: f32| {
+            let v = if up { z } else { -z };
+            Vector2::new(x / radius * 0.5 + 0.5, v / radius * 0.5 + 0.5)
+        };
+        let center = self.vertex(Vector3::new(0.0, y, 0.0), normal, uv(0.0, 0.0));
+        let ring = self.vertices.len() as u32;
+        for segment in 0..segments {
+            let (sin, cos) = (segment as f32 / segments as f32 * 2.0 * PI).sin_cos();
+            let (x, z) = (radius * sin, radius * cos);
+            self.vertex(Vector3::new(x, y, z), normal, uv(x, z));
+        }
+        for segment in 0..segments {
+            let a = ring + segment;
+            let b = ring + (segment + 1) % segments;
+            if up {
+                self.triangle(center, a, b);
+            } else {
+                self.triangle(center, b, a);
+            }
+        }
+    }
+
+    fn cube(&mut self, h: f32) {
+        let x = Vector3::unit_x();
+        let y = Vector3::unit_y();
+        let z = Vector3::unit_z();
+        // (normal, right, up) as seen from outside each face
+        let faces = [
+            (x, -z, y),
+            (-x, z, y),
+            (y, x, -z),
+            (-y, x, z),
+            (z, x, y),
+            (-z, -x, y),
+        ];
+        for &(normal, right, up) in &faces {
+            self.grid(1, 1, |u, v| {
+                let position = (normal + right * (u * 2.0 - 1.0) + up * (1.0 - v * 2.0)) * h;
+                (position, normal)
+            });
+        }
+    }
+
+    fn icosphere(&mut self, radius: f32, subdivisions: u32) {
+        let t = (1.0 + 5.0f32.sqrt()) * 0.5;
+        let mut points = [
+            [-1.0, t, 0.0],
+            [1.0, t, 0.0],
+            [-1.0, -t, 0.0],
+            [1.0, -t, 0.0],
+            [0.0, -1.0, t],
+            [0.0, 1.0, t],
+            [0.0, -1.0, -t],
+            [0.0, 1.0, -t],
+            [t, 0.0, -1.0],
+            [t, 0.0, 1.0],
+            [-t, 0.0, -1.0],
+            [-t, 0.0, 1.0],
+        ]
+        .iter()
+        .map(|&p| Vector3::from(p).normalize())
+        .collect::<Vec<_>>();
+        #[rustfmt::skip]
+        let mut triangles: Vec<[usize; 3]> = vec![
+            [0, 11, 5], [0, 5, 1], [0, 1, 7], [0, 7, 10], [0, 10, 11],
+            [1, 5, 9], [5, 11, 4], [11, 10, 2], [10, 7, 6], [7, 1, 8],
+            [3, 9, 4], [3, 4, 2], [3, 2, 6], [3, 6, 8], [3, 8, 9],
+            [4, 9, 5], [2, 4, 11], [6, 2, 10], [8, 6, 7], [9, 8, 1],
+        ];
+
+        for _ in 0..subdivisions {
+            let mut midpoints = HashMap::new();
+            let mut midpoint = |a: usize, b: usize| {
+                *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                    points.push((points[a] + points[b]).normalize());
+                    points.len() - 1
+                })
+            };
+            triangles = triangles
+                .iter()
+                .flat_map(|&[a, b, c]| {
+                    let (ab, bc, ca) = (midpoint(a, b), midpoint(b, c), midpoint(c, a));
+                    vec![[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+                })
+                .collect();
+        }
+
+        // The same mapping as a UV sphere. Triangles that straddle the
+        // seam get copies of their vertices on the far side of it, and
+        // each triangle touching a pole gets its own pole vertex, with
+        // the u of the other two corners.
+        let uv = |p: Vector3<f32>| {
+            let u = (p.x.atan2(p.z) / (2.0 * PI)).rem_euclid(1.0);
+            Vector2::new(u, p.y.clamp(-1.0, 1.0).acos() / PI)
+        };
+        let start = self.vertices.len() as u32;
+        for &p in &points {
+            self.vertex(p * radius, p, uv(p));
+        }
+        let is_pole = |p: Vector3<f32>| p.y.abs() > 1.0 - 1e-6;
+        let mut wrapped = HashMap::new();
+        for [a, b, c] in triangles {
+            let mut corners = [a as u32 + start, b as u32 + start, c as u32 + start];
+            let around = corners
+                .iter()
+                .map(|&i| self.vertices[i as usize])
+                .filter(|v| !is_pole(v.normal))
+                .map(|v| v.tex_coords.x)
+                .collect::<Vec<_>>();
+            let (min, max) = around
+                .iter()
+                .fold((1.0f32, 0.0f32), |(lo, hi), &u| (lo.min(u), hi.max(u)));
+            let straddles = max - min > 0.5;
+            let mut u_sum = 0.0;
+            for corner in corners.iter_mut() {
+                let vertex = self.vertices[*corner as usize];
+                if straddles && vertex.tex_coords.x < 0.5 && !is_pole(vertex.normal) {
+                    *corner = *wrapped.entry(*corner).or_insert_with(|| {
+                        let uv = vertex.tex_coords + Vector2::unit_x();
+                        self.vertex(vertex.position, vertex.normal, uv)
+                    });
+                }
+                u_sum += self.vertices[*corner as usize].tex_coords.x;
+            }
+            for corner in corners.iter_mut() {
+                let vertex = self.vertices[*corner as usize];
+                if is_pole(vertex.normal) {
+                    let u = (u_sum - vertex.tex_coords.x) / around.len() as f32;
+                    let uv = Vector2::new(u, vertex.tex_coords.y);
+                    *corner = self.vertex(vertex.position, vertex.normal, uv);
+                }
+            }
+            self.triangle(corners[0], corners[1], corners[2]);
+        }
+    }
+
+    /**
+     * Fills in tangents and bitangents from each vertex's triangles.
+     * They're kept perpendicular to the normal, and ones that can't be
+     * worked out, where the UVs collapse at a pole, are made up.
+     */
+    fn calc_tangents(&mut self) {
+        let mut tangents = vec![Vector3::zero(); self.vertices.len()];
+        let mut bitangents = vec![Vector3::zero(); self.vertices.len()];
+        for tri in self.indices.chunks(3) {
+            let v = |k: usize| &self.vertices[tri[k] as usize];
+            let (e1, e2) = (v(1).position - v(0).position, v(2).position - v(0).position);
+            let (d1, d2) = (
+                v(1).tex_coords - v(0).tex_coords,
+                v(2).tex_coords - v(0).tex_coords,
+            );
+            let det = d1.x * d2.y - d1.y * d2.x;
+            if det.abs() < 1e-12 {
+                continue;
+            }
+            let tangent = (e1 * d2.y - e2 * d1.y) / det;
+            let bitangent = (e2 * d1.x - e1 * d2.x) / det;
+            for &i in tri {
+                tangents[i as usize] += tangent;
+                bitangents[i as usize] += bitangent;
+            }
+        }
+        for ((vertex, tangent), bitangent) in self.vertices.iter_mut().zip(tangents).zip(bitangents)
+        {
+            let n = vertex.normal;
+            let mut t = tangent - n * n.dot(tangent);
+            if t.magnitude2() < 1e-12 {
+                t = perpendiculars(n).0;
+            }
+            let t = t.normalize();
+            let b = n.cross(t);
+            vertex.tangent = t;
+            vertex.bitangent = if b.dot(bitangent) < 0.0 { -b } else { b };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_shapes() -> Vec<Shape> {
+        vec![
+            Shape::Cube { size: 2.0 },
+            Shape::Plane {
+                width: 2.0,
+                depth: 3.0,
+                columns: 4,
+                rows: 3,
+            },
+            Shape::UvSphere {
+                radius: 1.0,
+                segments: 48,
+                rings: 24,
+            },
+            Shape::Icosphere {
+                radius: 1.0,
+                subdivisions: 3,
+            },
+            Shape::Cylinder {
+                radius: 0.5,
+                height: 2.0,
+                segments: 48,
+            },
+            Shape::Cone {
+                radius: 1.0,
+                height: 1.5,
+                segments: 48,
+            },
+            Shape::Torus {
+                radius: 1.0,
+                tube_radius: 0.25,
+                segments: 48,
+                sides: 24,
+            },
+            Shape::Capsule {
+                radius: 0.5,
+                height: 1.0,
+                segments: 48,
+                rings: 12,
+            },
+        ]
+    }
+
+    /// The divergence theorem only gives the volume if the mesh is
+    /// closed and every triangle faces out
+    fn volume(vertices: &[ModelVertex], indices: &[u32]) -> f32 {
+        indices
+            .chunks(3)
+            .map(|t| {
+                let p = |k: usize| vertices[t[k] as usize].position;
+                p(0).dot(p(1).cross(p(2))) / 6.0
+            })
+            .sum()
+    }
+
+    #[test]
+    fn vertices_are_well_formed() {
+        for shape in all_shapes() {
+            let (vertices, indices) = shape.geometry();
+            assert!(!indices.is_empty(), "{:?}", shape);
+            assert_eq!(indices.len() % 3, 0);
+            assert!(indices.iter().all(|&i| (i as usize) < vertices.len()));
+            for v in &vertices {
+                assert!((v.normal.magnitude() - 1.0).abs() < 1e-4, "{:?}", shape);
+                assert!((v.tangent.magnitude() - 1.0).abs() < 1e-4, "{:?}", shape);
+                assert!((v.bitangent.magnitude() - 1.0).abs() < 1e-4, "{:?}", shape);
+                assert!(v.normal.dot(v.tangent).abs() < 1e-4, "{:?}", shape);
+            }
+        }
+    }
+
+    #[test]
+    fn triangles_face_along_their_normals() {
+        for shape in all_shapes() {
+            let (vertices, indices) = shape.geometry();
+            for t in indices.chunks(3) {
+                let v = |k: usize| &vertices[t[k] as usize];
+                let face = (v(1).position - v(0).position).cross(v(2).position - v(0).position);
+                let normal = v(0).normal + v(1).normal + v(2).normal;
+                assert!(face.dot(normal) > 0.0, "{:?} {:?}", shape, t);
+            }
+        }
+    }
+
+    #[test]
+    fn closed_shapes_have_the_right_volume() {
+        let expected = |shape: &Shape| match *shape {
+            Shape::Cube { size } => size.powi(3),
+            Shape::UvSphere { radius, .. } | Shape::Icosphere { radius, .. } => {
+                4.0 / 3.0 * PI * radius.powi(3)
+            }
+            Shape::Cylinder { radius, height, .. } => PI * radius * radius * height,
+            Shape::Cone { radius, height, .. } => PI * radius * radius * height / 3.0,
+            Shape::Torus {
+                radius,
+                tube_radius,
+                ..
+            } => 2.0 * PI * PI * radius * tube_radius * tube_radius,
+            Shape::Capsule { radius, height, .. } => {
+                PI * radius * radius * height + 4.0 / 3.0 * PI * radius.powi(3)
+            }
+            Shape::Plane { .. } => unreachable!(),
+        };
+        let closed = all_shapes().into_iter().filter(|s| s.name() != "Plane");
+        for shape in closed {
+            let (vertices, indices) = shape.geometry();
+            let (actual, expected) = (volume(&vertices, &indices), expected(&shape));
+            // Curved shapes come up a little short
+            assert!(
+                actual <= expected + 1e-4 && actual > expected * 0.97,
+                "{:?}: {} vs {}",
+                shape,
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn uvs_follow_the_surface() {
+        let (vertices, indices) = Shape::Plane {
+            width: 2.0,
+            depth: 2.0,
+            columns: 2,
+            rows: 2,
+        }
+        .geometry();
+        assert_eq!(vertices.len(), 9);
+        assert_eq!(indices.len(), 24);
+        // Top left of the texture is the far left corner
+        assert_eq!(vertices[0].position, Vector3::new(-1.0, 0.0, -1.0));
+        assert_eq!(vertices[0].tex_coords, Vector2::new(0.0, 0.0));
+        // The tangent follows u and the bitangent follows v
+        assert!((vertices[4].tangent - Vector3::unit_x()).magnitude() < 1e-5);
+        assert!((vertices[4].bitangent - Vector3::unit_z()).magnitude() < 1e-5);
+
+        // No triangle's UVs wrap the whole way around the seam
+        let (vertices, indices) = Shape::Icosphere {
+            radius: 1.0,
+            subdivisions: 2,
+        }
+        .geometry();
+        for t in indices.chunks(3) {
+            let us = t.iter().map(|&i| vertices[i as usize].tex_coords.x);
+            let (min, max) = us.fold((f32::MAX, f32::MIN), |(lo, hi), u| (lo.min(u), hi.max(u)));
+            assert!(max - min < 0.5, "{:?}", t);
+        }
+    }
+}
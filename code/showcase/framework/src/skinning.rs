@@ -0,0 +1,1104 @@
+use anyhow::*;
+use cgmath::*;
+use std::collections::HashMap;
+use std::path::Path;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+
+use crate::animation::{hermite, segment, Animatable, Interpolation, PlayMode};
+use crate::compute::{workgroup_count, StorageBinding, StorageEntry, DEFAULT_WORKGROUP_SIZE};
+use crate::model::{
+    calc_tangents, Material, MaterialFactors, Mesh, Model, ModelVertex, PbrTextures,
+};
+use crate::pipeline::ComputePipelineBuilder;
+use crate::texture;
+
+/// Which joints move a vertex and by how much. `weights` add up to 1.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SkinVertex {
+    pub joints: [u32; 4],
+    pub weights: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for SkinVertex {}
+unsafe impl bytemuck::Zeroable for SkinVertex {}
+
+/// A position, rotation and scale relative to a parent
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: Vector3::zero(),
+            rotation: Quaternion::one(),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+impl Transform {
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    /// Blends towards `other`, taking the short way around for the
+    /// rotation
+    pub fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            translation: Animatable::lerp(self.translation, other.translation, t),
+            rotation: Animatable::lerp(self.rotation, other.rotation, t),
+            scale: Animatable::lerp(self.scale, other.scale, t),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Joint {
+    pub name: String,
+    /// Always another joint in the same [Skeleton]
+    pub parent: Option<usize>,
+    /// Takes a vertex from model space into the joint's space when the
+    /// mesh is in its bind pose
+    pub inverse_bind: Matrix4<f32>,
+    /// The joint's transform when nothing is animating it
+    pub rest: Transform,
+}
+
+/**
+ * A hierarchy of joints. Joints can be in any order, as vertices refer
+ * to them by index, so the skeleton works out an order that visits
+ * parents before their children.
+ */
+#[derive(Debug, Clone)]
+pub struct Skeleton {
+    pub joints: Vec<Joint>,
+    /// Applied above the root joints, e.g. from nodes in a glTF scene
+    /// that hold the skeleton but aren't joints themselves
+    pub root: Matrix4<f32>,
+    order: Vec<usize>,
+}
+
+impl Skeleton {
+    pub fn new(joints: Vec<Joint>) -> Result<Self> {
+        let mut order = Vec::with_capacity(joints.len());
+        let mut placed = vec![false; joints.len()];
+        for start in 0..joints.len() {
+            // Walk up to the root or a joint that's already placed, then
+            // place the chain from the top down
+            let mut chain = Vec::new();
+            let mut current = Some(start);
+            while let Some(i) = current {
+                if placed[i] {
+                    break;
+                }
+                if chain.contains(&i) {
+                    bail!("Joint {:?} is its own ancestor", joints[i].name);
+                }
+                chain.push(i);
+                current = joints[i].parent;
+                if let Some(parent) = current {
+                    if parent >= joints.len() {
+                        bail!(
+                            "Joint {:?} has parent {}, but there are only {} joints",
+                            joints[i].name,
+                            parent,
+                            joints.len()
+                        );
+                    }
+                }
+            }
+            for &i in chain.iter().rev() {
+                placed[i] = true;
+                order.push(i);
+            }
+        }
+        Ok(Self {
+            joints,
+            root: Matrix4::identity(),
+            order,
+        })
+    }
+
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.joints.iter().position(|j| j.name == name)
+    }
+
+    pub fn rest_pose(&self) -> Pose {
+        Pose {
+            locals: self.joints.iter().map(|j| j.rest).collect(),
+        }
+    }
+
+    /// Where each joint is in model space
+    pub fn world_matrices(&self, pose: &Pose) -> Vec<Matrix4<f32>> {
+        let mut world = vec![Matrix4::identity(); self.joints.len()];
+        for &i in &self.order {
+            let parent = match self.joints[i].parent {
+                Some(parent) => world[parent],
+                None => self.root,
+            };
+            world[i] = parent * pose.locals[i].matrix();
+        }
+        world
+    }
+
+    /// What the skinning shader multiplies bind pose vertices by
+    pub fn skinning_matrices(&self, pose: &Pose) -> Vec<Matrix4<f32>> {
+        self.world_matrices(pose)
+            .into_iter()
+            .zip(&self.joints)
+            .map(|(world, joint)| world * joint.inverse_bind)
+            .collect()
+    }
+}
+
+/// The local transform of every joint in a [Skeleton]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pose {
+    pub locals: Vec<Transform>,
+}
+
+impl Pose {
+    /// `t` of 0 is all `self` and 1 is all `other`
+    pub fn blend(&self, other: &Pose, t: f32) -> Pose {
+        Pose {
+            locals: self
+                .locals
+                .iter()
+                .zip(&other.locals)
+                .map(|(a, b)| a.lerp(b, t))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChannelValues {
+    Translation(Vec<Vector3<f32>>),
+    Rotation(Vec<Quaternion<f32>>),
+    Scale(Vec<Vector3<f32>>),
+}
+
+/// Keyframes for one property of one joint
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub joint: usize,
+    /// In seconds, in increasing order
+    pub times: Vec<f32>,
+    pub values: ChannelValues,
+    pub interpolation: Interpolation,
+}
+
+impl Channel {
+    fn sample<T: Animatable>(&self, values: &[T], time: f32) -> T {
+        let (a, b, t) = segment(&self.times, time);
+        match self.interpolation {
+            Interpolation::Step => values[a],
+            Interpolation::Linear => values[a].lerp(values[b], t),
+            Interpolation::CubicSpline => {
+                // Three values per key: in tangent, value, out tangent
+                let dt = self.times[b] - self.times[a];
+                hermite(
+                    values[a * 3 + 1],
+                    values[a * 3 + 2] * dt,
+                    values[b * 3 + 1],
+                    values[b * 3] * dt,
+                    t,
+                )
+            }
+        }
+    }
+
+    fn apply(&self, time: f32, pose: &mut Pose) {
+        if self.times.is_empty() {
+            return;
+        }
+        let local = &mut pose.locals[self.joint];
+        match &self.values {
+            ChannelValues::Translation(values) => local.translation = self.sample(values, time),
+            ChannelValues::Rotation(values) => local.rotation = self.sample(values, time),
+            ChannelValues::Scale(values) => local.scale = self.sample(values, time),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimationClip {
+    pub name: String,
+    /// When the last key of any channel is, in seconds
+    pub duration: f32,
+    pub channels: Vec<Channel>,
+}
+
+impl AnimationClip {
+    pub fn new(name: &str, channels: Vec<Channel>) -> Self {
+        let duration = channels
+            .iter()
+            .filter_map(|c| c.times.last().copied())
+            .fold(0.0, f32::max);
+        Self {
+            name: String::from(name),
+            duration,
+            channels,
+        }
+    }
+
+    /// Overwrites the joints this clip animates with their values at
+    /// `time`. Times outside the clip hold the first or last key.
+    pub fn sample(&self, time: f32, pose: &mut Pose) {
+        for channel in &self.channels {
+            channel.apply(time, pose);
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Fade {
+    clip: usize,
+    time: f32,
+    elapsed: f32,
+    duration: f32,
+}
+
+/**
+ * Plays clips on a skeleton, cross fading when switching between
+ * them. Call [AnimationPlayer::advance] every frame and pass
+ * [AnimationPlayer::pose] to [SkinnedModel::set_pose].
+ */
+#[derive(Debug, Clone)]
+pub struct AnimationPlayer {
+    /// The clip playing, as an index into the clips passed to
+    /// [AnimationPlayer::advance]
+    pub clip: usize,
+    pub time: f32,
+    pub speed: f32,
+    pub mode: PlayMode,
+    fade: Option<Fade>,
+}
+
+impl AnimationPlayer {
+    pub fn new(clip: usize) -> Self {
+        Self {
+            clip,
+            time: 0.0,
+            speed: 1.0,
+            mode: PlayMode::Loop,
+            fade: None,
+        }
+    }
+
+    /// Starts `clip` from the beginning, blending in over `fade`
+    /// seconds from whatever is playing now
+    pub fn play(&mut self, clip: usize, fade: f32) {
+        self.fade = if fade > 0.0 {
+            Some(Fade {
+                clip: self.clip,
+                time: self.time,
+                elapsed: 0.0,
+                duration: fade,
+            })
+        } else {
+            None
+        };
+        self.clip = clip;
+        self.time = 0.0;
+    }
+
+    pub fn is_fading(&self) -> bool {
+        self.fade.is_some()
+    }
+
+    pub fn advance(&mut self, dt: f32, clips: &[AnimationClip]) {
+        let step = dt * self.speed;
+        self.time = self.mode.wrap(self.time + step, clips[self.clip].duration);
+        if let Some(fade) = &mut self.fade {
+            fade.time = self.mode.wrap(fade.time + step, clips[fade.clip].duration);
+            fade.elapsed += dt;
+            if fade.elapsed >= fade.duration {
+                self.fade = None;
+            }
+        }
+    }
+
+    pub fn pose(&self, skeleton: &Skeleton, clips: &[AnimationClip]) -> Pose {
+        let mut pose = skeleton.rest_pose();
+        clips[self.clip].sample(self.time, &mut pose);
+        match self.fade {
+            Some(fade) => {
+                let mut from = skeleton.rest_pose();
+                clips[fade.clip].sample(fade.time, &mut from);
+                from.blend(&pose, fade.elapsed / fade.duration)
+            }
+            None => pose,
+        }
+    }
+}
+
+/// Skins on the CPU, doing the same maths as the skinning shader
+pub fn skin_vertex(
+    rest: &ModelVertex,
+    skin: &SkinVertex,
+    matrices: &[Matrix4<f32>],
+) -> ModelVertex {
+    let m = (0..4)
+        .map(|i| matrices[skin.joints[i] as usize] * skin.weights[i])
+        .fold(Matrix4::zero(), |sum, m| sum + m);
+    let n = Matrix3::from_cols(m.x.truncate(), m.y.truncate(), m.z.truncate());
+    let normalize = |v: Vector3<f32>| {
+        if v.magnitude2() > 0.0 {
+            v.normalize()
+        } else {
+            v
+        }
+    };
+    ModelVertex {
+        position: (m * rest.position.extend(1.0)).truncate(),
+        tex_coords: rest.tex_coords,
+        normal: normalize(n * rest.normal),
+        tangent: normalize(n * rest.tangent),
+        bitangent: normalize(n * rest.bitangent),
+    }
+}
+
+/// What a [SkinnedModel] needs to skin one of its meshes
+pub struct MeshSkin {
+    pub skin: Vec<SkinVertex>,
+    pub skin_buffer: wgpu::Buffer,
+    /// The bind pose. The mesh's own vertex buffer holds the skinned
+    /// vertices.
+    pub rest_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+/**
+ * Runs the skinning compute shader. It writes posed vertices into the
+ * vertex buffers of a [SkinnedModel]'s meshes, so the model can then
+ * be drawn with [crate::DrawModel] and any pipeline that takes
+ * [ModelVertex].
+ */
+pub struct Skinner {
+    pub mesh_layout: wgpu::BindGroupLayout,
+    pub joint_layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::ComputePipeline,
+}
+
+impl Skinner {
+    pub fn new(device: &wgpu::Device) -> Result<Self> {
+        let mesh_layout = StorageBinding::layout_for(device, &[true, true, false]);
+        let joint_layout = StorageBinding::layout_for(device, &[true]);
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Skinner::layout"),
+            bind_group_layouts: &[&mesh_layout, &joint_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = ComputePipelineBuilder::new()
+            .label("Skinner::pipeline")
+            .layout(&layout)
+            .compute_shader(wgpu::include_spirv!("skin.comp.spv"))
+            .build(device)?;
+        Ok(Self {
+            mesh_layout,
+            joint_layout,
+            pipeline,
+        })
+    }
+
+    /// Poses every mesh in `model` with the matrices from the last
+    /// [SkinnedModel::set_pose]
+    pub fn skin(&self, encoder: &mut wgpu::CommandEncoder, model: &SkinnedModel) {
+        let mut pass = encoder.begin_compute_pass();
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(1, &model.joint_bind_group, &[]);
+        for skin in &model.skins {
+            pass.set_bind_group(0, &skin.bind_group, &[]);
+            pass.dispatch(
+                workgroup_count(skin.skin.len() as u32, DEFAULT_WORKGROUP_SIZE),
+                1,
+                1,
+            );
+        }
+    }
+}
+
+/**
+ * A glTF model with a skeleton and animations. `model` draws like any
+ * other [Model], with its vertex buffers holding whatever pose
+ * [Skinner::skin] last wrote. Until then they hold the bind pose.
+ */
+pub struct SkinnedModel<'a> {
+    pub model: Model<'a>,
+    /// One per mesh in `model`
+    pub skins: Vec<MeshSkin>,
+    pub skeleton: Skeleton,
+    pub clips: Vec<AnimationClip>,
+    joint_buffer: wgpu::Buffer,
+    joint_bind_group: wgpu::BindGroup,
+}
+
+impl<'a> SkinnedModel<'a> {
+    /**
+     * Loads the first skin in a glTF file, along with the meshes that
+     * use it and the animations that move its joints. Materials are
+     * made for [crate::PbrPipeline], so `material_layout` should be
+     * [crate::PbrPipeline::material_layout].
+     */
+    pub fn load<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        skinner: &Skinner,
+        material_layout: &wgpu::BindGroupLayout,
+        path: P,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let (document, buffers, images) =
+            gltf::import(path).with_context(|| format!("Couldn't load {:?}", path))?;
+        let read = |buffer: gltf::Buffer| Some(&buffers[buffer.index()].0[..]);
+        let gltf_skin = document
+            .skins()
+            .next()
+            .with_context(|| format!("{:?} has no skins", path))?;
+
+        // Nodes only know their children, so work out their parents
+        let mut parents = vec![None; document.nodes().len()];
+        for node in document.nodes() {
+            for child in node.children() {
+                parents[child.index()] = Some(node.index());
+            }
+        }
+        let joint_nodes = gltf_skin.joints().collect::<Vec<_>>();
+        let joint_of_node = joint_nodes
+            .iter()
+            .enumerate()
+            .map(|(joint, node)| (node.index(), joint))
+            .collect::<HashMap<_, _>>();
+        let inverse_binds = gltf_skin
+            .reader(read)
+            .read_inverse_bind_matrices()
+            .map(|m| m.map(Matrix4::from).collect::<Vec<_>>())
+            .unwrap_or_else(|| vec![Matrix4::identity(); joint_nodes.len()]);
+
+        let mut joints = Vec::with_capacity(joint_nodes.len());
+        let mut root = None;
+        for (i, node) in joint_nodes.iter().enumerate() {
+            // Nodes between two joints aren't animated, so they're
+            // skipped
+            let mut parent = parents[node.index()];
+            while let Some(p) = parent {
+                if joint_of_node.contains_key(&p) {
+                    break;
+                }
+                parent = parents[p];
+            }
+            if parent.is_none() && root.is_none() {
+                root = Some(global_matrix(&document, &parents, parents[node.index()]));
+            }
+            joints.push(Joint {
+                name: node.name().unwrap_or("").to_string(),
+                parent: parent.map(|p| joint_of_node[&p]),
+                inverse_bind: inverse_binds
+                    .get(i)
+                    .copied()
+                    .unwrap_or_else(Matrix4::identity),
+                rest: node_transform(node),
+            });
+        }
+        let mut skeleton = Skeleton::new(joints)?;
+        skeleton.root = root.unwrap_or_else(Matrix4::identity);
+
+        let mut materials = document
+            .materials()
+            .map(|material| load_material(device, queue, material_layout, &material, &images))
+            .collect::<Result<Vec<_>>>()?;
+        let mut default_material = None;
+
+        let mut meshes = Vec::new();
+        let mut skins = Vec::new();
+        for node in document.nodes() {
+            let gltf_mesh = match node.mesh() {
+                Some(mesh) => mesh,
+                None => continue,
+            };
+            if node.skin().map(|s| s.index()) != Some(gltf_skin.index()) {
+                log::warn!(
+                    "Skipping mesh {:?}, it doesn't use the first skin",
+                    gltf_mesh.name()
+                );
+                continue;
+            }
+            for primitive in gltf_mesh.primitives() {
+                if primitive.mode() != gltf::mesh::Mode::Triangles {
+                    log::warn!(
+                        "Skipping a non triangle primitive in {:?}",
+                        gltf_mesh.name()
+                    );
+                    continue;
+                }
+                let reader = primitive.reader(read);
+                let positions = reader
+                    .read_positions()
+                    .context("Primitive has no positions")?
+                    .collect::<Vec<_>>();
+                let count = positions.len();
+                let normals = reader
+                    .read_normals()
+                    .map(|n| n.collect())
+                    .unwrap_or_else(|| vec![[0.0, 0.0, 1.0]; count]);
+                let tex_coords = reader
+                    .read_tex_coords(0)
+                    .map(|t| t.into_f32().collect())
+                    .unwrap_or_else(|| vec![[0.0, 0.0]; count]);
+                let joint_indices = reader
+                    .read_joints(0)
+                    .context("Skinned primitive has no JOINTS_0")?
+                    .into_u16()
+                    .collect::<Vec<_>>();
+                let weights = reader
+                    .read_weights(0)
+                    .context("Skinned primitive has no WEIGHTS_0")?
+                    .into_f32()
+                    .collect::<Vec<_>>();
+                let indices = reader
+                    .read_indices()
+                    .map(|i| i.into_u32().collect())
+                    .unwrap_or_else(|| (0..count as u32).collect::<Vec<_>>());
+
+                let mut vertices = (0..count)
+                    .map(|i| ModelVertex {
+                        position: positions[i].into(),
+                        tex_coords: tex_coords[i].into(),
+                        normal: normals[i].into(),
+                        tangent: Vector3::zero(),
+                        bitangent: Vector3::zero(),
+                    })
+                    .collect::<Vec<_>>();
+                match reader.read_tangents() {
+                    Some(tangents) => {
+                        for (v, t) in vertices.iter_mut().zip(tangents) {
+                            v.tangent = Vector3::new(t[0], t[1], t[2]);
+                            v.bitangent = v.normal.cross(v.tangent) * t[3];
+                        }
+                    }
+                    None if !indices.is_empty() => calc_tangents(&mut vertices, &indices),
+                    None => {}
+                }
+                let skin = joint_indices
+                    .iter()
+                    .zip(&weights)
+                    .map(|(j, w)| skin_weights(*j, *w, skeleton.joints.len()))
+                    .collect::<Result<Vec<_>>>()?;
+
+                let material = match primitive.material().index() {
+                    Some(index) => index,
+                    None => *default_material.get_or_insert(materials.len()),
+                };
+                if material == materials.len() {
+                    materials.push(Material::new_pbr(
+                        device,
+                        queue,
+                        "Default",
+                        PbrTextures::default(),
+                        MaterialFactors::default(),
+                        material_layout,
+                    )?);
+                }
+
+                let name = gltf_mesh.name().unwrap_or("").to_string();
+                let (mesh, mesh_skin) =
+                    create_skinned_mesh(device, skinner, name, vertices, indices, skin, material);
+                meshes.push(mesh);
+                skins.push(mesh_skin);
+            }
+        }
+
+        let clips = document
+            .animations()
+            .map(|animation| load_clip(&animation, &joint_of_node, read))
+            .collect::<Result<Vec<_>>>()?;
+
+        let matrices = joint_data(&skeleton.skinning_matrices(&skeleton.rest_pose()));
+        let joint_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("SkinnedModel::joints"),
+            contents: bytemuck::cast_slice(&matrices),
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+        });
+        let joint_bind_group = StorageBinding::bind_group_for(
+            device,
+            &skinner.joint_layout,
+            &[StorageEntry {
+                buffer: &joint_buffer,
+                read_only: true,
+            }],
+        );
+
+        Ok(Self {
+            model: Model { meshes, materials },
+            skins,
+            skeleton,
+            clips,
+            joint_buffer,
+            joint_bind_group,
+        })
+    }
+
+    pub fn find_clip(&self, name: &str) -> Option<usize> {
+        self.clips.iter().position(|c| c.name == name)
+    }
+
+    /// Uploads the matrices for `pose`. They're used the next time
+    /// [Skinner::skin] runs.
+    pub fn set_pose(&self, queue: &wgpu::Queue, pose: &Pose) {
+        let matrices = joint_data(&self.skeleton.skinning_matrices(pose));
+        queue.write_buffer(&self.joint_buffer, 0, bytemuck::cast_slice(&matrices));
+    }
+}
+
+/// cgmath's matrices aren't `Pod`, so they're copied into plain arrays
+/// before they're uploaded.
+fn joint_data(matrices: &[Matrix4<f32>]) -> Vec<[[f32; 4]; 4]> {
+    matrices.iter().map(|&m| m.into()).collect()
+}
+
+fn create_skinned_mesh(
+    device: &wgpu::Device,
+    skinner: &Skinner,
+    name: String,
+    vertices: Vec<ModelVertex>,
+    indices: Vec<u32>,
+    skin: Vec<SkinVertex>,
+    material: usize,
+) -> (Mesh, MeshSkin) {
+    let vertex_buffer = device.create_buffer_init(&BufferInitDescriptor {
+        label: Some(&format!("{} Skinned Vertex Buffer", name)),
+        contents: bytemuck::cast_slice(&vertices),
+        usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::STORAGE,
+    });
+    let rest_buffer = device.create_buffer_init(&BufferInitDescriptor {
+        label: Some(&format!("{} Rest Vertex Buffer", name)),
+        contents: bytemuck::cast_slice(&vertices),
+        usage: wgpu::BufferUsage::STORAGE,
+    });
+    let skin_buffer = device.create_buffer_init(&BufferInitDescriptor {
+        label: Some(&format!("{} Skin Buffer", name)),
+        contents: bytemuck::cast_slice(&skin),
+        usage: wgpu::BufferUsage::STORAGE,
+    });
+    let index_buffer = device.create_buffer_init(&BufferInitDescriptor {
+        label: Some(&format!("{} Index Buffer", name)),
+        contents: bytemuck::cast_slice(&indices),
+        usage: wgpu::BufferUsage::INDEX,
+    });
+    let bind_group = StorageBinding::bind_group_for(
+        device,
+        &skinner.mesh_layout,
+        &[
+            StorageEntry {
+                buffer: &rest_buffer,
+                read_only: true,
+            },
+            StorageEntry {
+                buffer: &skin_buffer,
+                read_only: true,
+            },
+            StorageEntry {
+                buffer: &vertex_buffer,
+                read_only: false,
+            },
+        ],
+    );
+    let mesh = Mesh {
+        name,
+        vertex_buffer,
+        index_buffer,
+        index_format: wgpu::IndexFormat::Uint32,
+        num_elements: indices.len() as u32,
+        material,
+        vertices,
+        indices,
+    };
+    let mesh_skin = MeshSkin {
+        skin,
+        skin_buffer,
+        rest_buffer,
+        bind_group,
+    };
+    (mesh, mesh_skin)
+}
+
+/// Checks the joints exist and makes the weights add up to 1, which
+/// exporters don't always get exactly right
+fn skin_weights(joints: [u16; 4], weights: [f32; 4], joint_count: usize) -> Result<SkinVertex> {
+    let total: f32 = weights.iter().sum();
+    let mut skin = SkinVertex {
+        joints: [0; 4],
+        weights: [0.0; 4],
+    };
+    for (i, (&joint, &weight)) in joints.iter().zip(&weights).enumerate() {
+        if weight <= 0.0 {
+            continue;
+        }
+        if joint as usize >= joint_count {
+            bail!(
+                "Vertex uses joint {}, but the skin only has {}",
+                joint,
+                joint_count
+            );
+        }
+        skin.joints[i] = joint as u32;
+        skin.weights[i] = weight / total;
+    }
+    if total <= 0.0 {
+        // Unweighted vertices stick to the first joint
+        skin.weights[0] = 1.0;
+    }
+    Ok(skin)
+}
+
+fn node_transform(node: &gltf::Node) -> Transform {
+    let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+    Transform {
+        translation: translation.into(),
+        rotation: Quaternion::new(w, x, y, z),
+        scale: scale.into(),
+    }
+}
+
+/// The transform from `node`'s space to the scene's
+fn global_matrix(
+    document: &gltf::Document,
+    parents: &[Option<usize>],
+    node: Option<usize>,
+) -> Matrix4<f32> {
+    let mut matrix = Matrix4::identity();
+    let mut current = node;
+    while let Some(i) = current {
+        let node = document.nodes().nth(i).unwrap();
+        matrix = Matrix4::from(node.transform().matrix()) * matrix;
+        current = parents[i];
+    }
+    matrix
+}
+
+fn load_clip<'s, F>(
+    animation: &gltf::Animation,
+    joint_of_node: &HashMap<usize, usize>,
+    read: F,
+) -> Result<AnimationClip>
+where
+    F: Clone + Fn(gltf::Buffer) -> Option<&'s [u8]>,
+{
+    use gltf::animation::util::ReadOutputs;
+
+    let mut channels = Vec::new();
+    for channel in animation.channels() {
+        // Only joints can be animated here
+        let joint = match joint_of_node.get(&channel.target().node().index()) {
+            Some(&joint) => joint,
+            None => continue,
+        };
+        let reader = channel.reader(read.clone());
+        let times = reader
+            .read_inputs()
+            .context("Animation channel has no times")?
+            .collect::<Vec<_>>();
+        let values = match reader
+            .read_outputs()
+            .context("Animation channel has no values")?
+        {
+            ReadOutputs::Translations(t) => {
+                ChannelValues::Translation(t.map(Vector3::from).collect())
+            }
+            ReadOutputs::Rotations(r) => ChannelValues::Rotation(
+                r.into_f32()
+                    .map(|[x, y, z, w]| Quaternion::new(w, x, y, z))
+                    .collect(),
+            ),
+            ReadOutputs::Scales(s) => ChannelValues::Scale(s.map(Vector3::from).collect()),
+            ReadOutputs::MorphTargetWeights(_) => continue,
+        };
+        let interpolation = match channel.sampler().interpolation() {
+            gltf::animation::Interpolation::Step => Interpolation::Step,
+            gltf::animation::Interpolation::Linear => Interpolation::Linear,
+            gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+        };
+        channels.push(Channel {
+            joint,
+            times,
+            values,
+            interpolation,
+        });
+    }
+    Ok(AnimationClip::new(animation.name().unwrap_or(""), channels))
+}
+
+fn load_material<'a>(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    material: &gltf::Material,
+    images: &[gltf::image::Data],
+) -> Result<Material<'a>> {
+    let load =
+        |texture: Option<gltf::Texture>, is_normal_map| -> Result<Option<texture::Texture<'a>>> {
+            match texture {
+                Some(texture) => {
+                    let img = gltf_image(&images[texture.source().index()])?;
+                    Ok(Some(texture::Texture::from_image(
+                        device,
+                        queue,
+                        &img,
+                        None,
+                        is_normal_map,
+                    )?))
+                }
+                None => Ok(None),
+            }
+        };
+    let pbr = material.pbr_metallic_roughness();
+    let textures = PbrTextures {
+        base_color: load(pbr.base_color_texture().map(|i| i.texture()), false)?,
+        normal: load(material.normal_texture().map(|i| i.texture()), true)?,
+        metallic_roughness: load(pbr.metallic_roughness_texture().map(|i| i.texture()), true)?,
+        occlusion: load(material.occlusion_texture().map(|i| i.texture()), true)?,
+        emissive: load(material.emissive_texture().map(|i| i.texture()), false)?,
+    };
+    let factors = MaterialFactors {
+        base_color: pbr.base_color_factor().into(),
+        emissive: material.emissive_factor().into(),
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+        occlusion_strength: material
+            .occlusion_texture()
+            .map(|o| o.strength())
+            .unwrap_or(1.0),
+        normal_scale: material.normal_texture().map(|n| n.scale()).unwrap_or(1.0),
+        transparent: material.alpha_mode() == gltf::material::AlphaMode::Blend,
+    };
+    Material::new_pbr(
+        device,
+        queue,
+        material.name().unwrap_or("glTF Material"),
+        textures,
+        factors,
+        layout,
+    )
+}
+
+fn gltf_image(data: &gltf::image::Data) -> Result<image::DynamicImage> {
+    use gltf::image::Format;
+
+    let (width, height, pixels) = (data.width, data.height, data.pixels.clone());
+    let img =
+        match data.format {
+            Format::R8 => image::GrayImage::from_raw(width, height, pixels)
+                .map(image::DynamicImage::ImageLuma8),
+            Format::R8G8B8 => {
+                image::RgbImage::from_raw(width, height, pixels).map(image::DynamicImage::ImageRgb8)
+            }
+            Format::R8G8B8A8 => image::RgbaImage::from_raw(width, height, pixels)
+                .map(image::DynamicImage::ImageRgba8),
+            other => bail!("Unsupported glTF image format {:?}", other),
+        };
+    img.context("glTF image has the wrong number of pixels")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approx(a: Vector3<f32>, b: Vector3<f32>) -> bool {
+        (a - b).magnitude() < 1e-4
+    }
+
+    fn joint(name: &str, parent: Option<usize>, translation: Vector3<f32>) -> Joint {
+        Joint {
+            name: name.to_string(),
+            parent,
+            inverse_bind: Matrix4::identity(),
+            rest: Transform {
+                translation,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// A chain of two bones, given child first to check the ordering
+    fn arm() -> Skeleton {
+        Skeleton::new(vec![
+            joint("forearm", Some(1), Vector3::new(0.0, 1.0, 0.0)),
+            joint("upper", None, Vector3::new(0.0, 0.0, 0.0)),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn world_matrices_follow_parents() {
+        let skeleton = arm();
+        let mut pose = skeleton.rest_pose();
+        pose.locals[1].rotation = Quaternion::from_angle_z(Deg(90.0));
+        let world = skeleton.world_matrices(&pose);
+        let elbow = (world[0] * Vector4::new(0.0, 0.0, 0.0, 1.0)).truncate();
+        // Rotating the upper arm swings the forearm from +y to -x
+        assert!(approx(elbow, Vector3::new(-1.0, 0.0, 0.0)));
+        assert_eq!(skeleton.find("upper"), Some(1));
+    }
+
+    #[test]
+    fn bad_hierarchies() {
+        let looped = Skeleton::new(vec![
+            joint("a", Some(1), Vector3::zero()),
+            joint("b", Some(0), Vector3::zero()),
+        ]);
+        assert!(looped.is_err());
+        assert!(Skeleton::new(vec![joint("a", Some(5), Vector3::zero())]).is_err());
+    }
+
+    #[test]
+    fn channel_interpolation() {
+        let channel = |interpolation, values| Channel {
+            joint: 0,
+            times: vec![0.0, 1.0, 3.0],
+            values: ChannelValues::Translation(values),
+            interpolation,
+        };
+        let keys = vec![
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(2.0, 0.0, 0.0),
+            Vector3::new(2.0, 4.0, 0.0),
+        ];
+        let linear = channel(Interpolation::Linear, keys.clone());
+        let step = channel(Interpolation::Step, keys.clone());
+        let sample = |c: &Channel, t| match &c.values {
+            ChannelValues::Translation(v) => c.sample(v, t),
+            _ => unreachable!(),
+        };
+        assert!(approx(sample(&linear, 0.5), Vector3::new(1.0, 0.0, 0.0)));
+        assert!(approx(sample(&linear, 2.0), Vector3::new(2.0, 2.0, 0.0)));
+        assert!(approx(sample(&step, 2.9), Vector3::new(2.0, 0.0, 0.0)));
+        // Outside the keys the ends are held
+        assert!(approx(sample(&linear, -1.0), keys[0]));
+        assert!(approx(sample(&linear, 10.0), keys[2]));
+
+        // Flat tangents pass through the keys and ease between them
+        let mut cubic = Vec::new();
+        for key in &keys {
+            cubic.extend_from_slice(&[Vector3::zero(), *key, Vector3::zero()]);
+        }
+        let cubic = channel(Interpolation::CubicSpline, cubic);
+        assert!(approx(sample(&cubic, 1.0), keys[1]));
+        assert!(approx(sample(&cubic, 0.5), Vector3::new(1.0, 0.0, 0.0)));
+        assert!(sample(&cubic, 0.25).x < 0.5);
+    }
+
+    #[test]
+    fn rotations_take_the_short_way() {
+        let clip = AnimationClip::new(
+            "turn",
+            vec![Channel {
+                joint: 1,
+                times: vec![0.0, 2.0],
+                values: ChannelValues::Rotation(vec![
+                    Quaternion::from_angle_z(Deg(-10.0)),
+                    // The same as +10 degrees, but on the other side
+                    -Quaternion::from_angle_z(Deg(10.0)),
+                ]),
+                interpolation: Interpolation::Linear,
+            }],
+        );
+        assert_eq!(clip.duration, 2.0);
+        let skeleton = arm();
+        let mut pose = skeleton.rest_pose();
+        clip.sample(1.0, &mut pose);
+        let halfway = pose.locals[1].rotation;
+        assert!((halfway.s.abs() - 1.0).abs() < 1e-4);
+        // Untouched joints keep their rest pose
+        assert_eq!(pose.locals[0], skeleton.joints[0].rest);
+    }
+
+    #[test]
+    fn player_loops_and_fades() {
+        let skeleton = arm();
+        let move_to = |x| {
+            AnimationClip::new(
+                "move",
+                vec![Channel {
+                    joint: 1,
+                    times: vec![0.0, 1.0],
+                    values: ChannelValues::Translation(vec![
+                        Vector3::zero(),
+                        Vector3::new(x, 0.0, 0.0),
+                    ]),
+                    interpolation: Interpolation::Linear,
+                }],
+            )
+        };
+        let clips = vec![move_to(1.0), move_to(-1.0)];
+        let mut player = AnimationPlayer::new(0);
+        player.advance(1.25, &clips);
+        assert!((player.time - 0.25).abs() < 1e-5);
+
+        player.mode = PlayMode::Once;
+        player.advance(5.0, &clips);
+        assert_eq!(player.time, 1.0);
+
+        // Halfway through the fade, clip 0 is at 1 and clip 1 at -0.5
+        player.play(1, 1.0);
+        player.advance(0.5, &clips);
+        assert!(player.is_fading());
+        let pose = player.pose(&skeleton, &clips);
+        assert!((pose.locals[1].translation.x - 0.25).abs() < 1e-5);
+        player.advance(0.5, &clips);
+        assert!(!player.is_fading());
+    }
+
+    #[test]
+    fn cpu_skinning() {
+        let rest = ModelVertex {
+            position: Vector3::new(1.0, 0.0, 0.0),
+            tex_coords: Vector2::new(0.5, 0.5),
+            normal: Vector3::unit_x(),
+            tangent: Vector3::zero(),
+            bitangent: Vector3::zero(),
+        };
+        let matrices = [
+            Matrix4::identity(),
+            Matrix4::from_translation(Vector3::new(0.0, 2.0, 0.0)),
+            Matrix4::from_angle_z(Deg(90.0)),
+        ];
+        let half = SkinVertex {
+            joints: [0, 1, 0, 0],
+            weights: [0.5, 0.5, 0.0, 0.0],
+        };
+        let skinned = skin_vertex(&rest, &half, &matrices);
+        assert!(approx(skinned.position, Vector3::new(1.0, 1.0, 0.0)));
+        assert!(approx(skinned.normal, Vector3::unit_x()));
+        assert_eq!(skinned.tex_coords, rest.tex_coords);
+        assert_eq!(skinned.tangent, Vector3::zero());
+
+        let turned = SkinVertex {
+            joints: [2, 0, 0, 0],
+            weights: [1.0, 0.0, 0.0, 0.0],
+        };
+        let skinned = skin_vertex(&rest, &turned, &matrices);
+        assert!(approx(skinned.position, Vector3::unit_y()));
+        assert!(approx(skinned.normal, Vector3::unit_y()));
+    }
+
+    #[test]
+    fn weights_are_normalized() {
+        let skin = skin_weights([3, 1, 0, 0], [2.0, 2.0, 0.0, 0.0], 4).unwrap();
+        assert_eq!(skin.joints, [3, 1, 0, 0]);
+        assert_eq!(skin.weights, [0.5, 0.5, 0.0, 0.0]);
+        assert_eq!(
+            skin_weights([0; 4], [0.0; 4], 1).unwrap().weights,
+            [1.0, 0.0, 0.0, 0.0]
+        );
+        assert!(skin_weights([9, 0, 0, 0], [1.0, 0.0, 0.0, 0.0], 4).is_err());
+    }
+}
@@ -0,0 +1,22 @@
+#version 450
+
+layout(location=0) out vec3 v_direction;
+
+layout(set=0, binding=0)
+uniform SkyboxUniforms {
+    mat4 u_inv_view_proj;
+};
+
+void main() {
+    vec2 uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    vec2 ndc = uv * 2.0 - 1.0;
+
+    // u_inv_view_proj has no translation, so un-projecting a point
+    // on the far plane gives us the view direction for this pixel.
+    vec4 far = u_inv_view_proj * vec4(ndc, 1.0, 1.0);
+    v_direction = far.xyz / far.w;
+
+    // Put the sky on the far plane so that it only fills pixels the
+    // scene didn't draw to.
+    gl_Position = vec4(ndc, 1.0, 1.0);
+}
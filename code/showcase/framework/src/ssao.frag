@@ -0,0 +1,81 @@
+#version 450
+
+layout(location=0) in vec2 v_ndc;
+
+layout(location=0) out float f_occlusion;
+
+layout(set=0, binding=0) uniform texture2D t_depth;
+layout(set=0, binding=1) uniform texture2D t_normal;
+layout(set=0, binding=2) uniform texture2D t_noise;
+layout(set=0, binding=3) uniform sampler s_ssao;
+
+// Must match framework::Ssao::MAX_SAMPLES
+const uint MAX_SAMPLES = 64;
+
+layout(set=0, binding=4)
+uniform SsaoUniforms {
+    mat4 u_proj;
+    mat4 u_inv_proj;
+    mat4 u_view;
+    float u_radius;
+    float u_bias;
+    float u_power;
+    uint u_sample_count;
+    // 0 if t_normal is a placeholder and normals should be
+    // rebuilt from depth
+    uint u_has_normals;
+    vec4 u_kernel[MAX_SAMPLES];
+};
+
+vec3 view_position(vec2 ndc, float depth) {
+    vec4 view = u_inv_proj * vec4(ndc, depth, 1.0);
+    return view.xyz / view.w;
+}
+
+void main() {
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    ivec2 size = textureSize(sampler2D(t_depth, s_ssao), 0);
+    float depth = texelFetch(sampler2D(t_depth, s_ssao), pixel, 0).r;
+    if (depth >= 1.0) {
+        f_occlusion = 1.0;
+        return;
+    }
+    vec3 position = view_position(v_ndc, depth);
+
+    vec3 normal;
+    if (u_has_normals != 0u) {
+        // The G-buffer stores world space normals
+        vec3 world_normal = texelFetch(sampler2D(t_normal, s_ssao), pixel, 0).xyz;
+        normal = normalize(mat3(u_view) * world_normal);
+    } else {
+        normal = normalize(cross(dFdx(position), dFdy(position)));
+    }
+
+    // Tile the noise over the screen to rotate the kernel per pixel
+    ivec2 noise_size = textureSize(sampler2D(t_noise, s_ssao), 0);
+    vec3 random = texelFetch(sampler2D(t_noise, s_ssao), pixel % noise_size, 0).xyz * 2.0 - 1.0;
+    vec3 tangent = normalize(random - normal * dot(random, normal));
+    vec3 bitangent = cross(normal, tangent);
+    mat3 tbn = mat3(tangent, bitangent, normal);
+
+    float occlusion = 0.0;
+    uint count = min(u_sample_count, MAX_SAMPLES);
+    for (uint i = 0u; i < count; i++) {
+        vec3 sample_position = position + tbn * u_kernel[i].xyz * u_radius;
+
+        vec4 clip = u_proj * vec4(sample_position, 1.0);
+        vec2 sample_ndc = clip.xy / clip.w;
+        vec2 uv = vec2(sample_ndc.x, -sample_ndc.y) * 0.5 + 0.5;
+        ivec2 sample_pixel = clamp(ivec2(uv * vec2(size)), ivec2(0), size - 1);
+        float scene_depth = texelFetch(sampler2D(t_depth, s_ssao), sample_pixel, 0).r;
+        float scene_z = view_position(sample_ndc, scene_depth).z;
+
+        // The camera looks down -z, so bigger is closer. Geometry
+        // far outside the radius shouldn't darken the edges of
+        // objects in front of it.
+        float range_check = smoothstep(0.0, 1.0, u_radius / abs(position.z - scene_z));
+        occlusion += (scene_z >= sample_position.z + u_bias ? 1.0 : 0.0) * range_check;
+    }
+
+    f_occlusion = pow(1.0 - occlusion / float(max(count, 1u)), u_power);
+}
@@ -0,0 +1,401 @@
+use anyhow::*;
+use cgmath::*;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+
+use crate::camera::{Camera, Projection};
+use crate::pipeline::RenderPipelineBuilder;
+use crate::texture::Texture;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SsaoUniforms {
+    proj: Matrix4<f32>,
+    inv_proj: Matrix4<f32>,
+    view: Matrix4<f32>,
+    radius: f32,
+    bias: f32,
+    power: f32,
+    sample_count: u32,
+    has_normals: u32,
+    _padding: [u32; 3],
+    kernel: [[f32; 4]; Ssao::MAX_SAMPLES],
+}
+
+unsafe impl bytemuck::Pod for SsaoUniforms {}
+unsafe impl bytemuck::Zeroable for SsaoUniforms {}
+
+/// Where [SsaoUniforms::has_normals] is, after the matrices and the
+/// first four settings
+const HAS_NORMALS_OFFSET: wgpu::BufferAddress = 64 * 3 + 16;
+
+/**
+ * Screen-space ambient occlusion. Darkens creases and corners by
+ * checking how much of a hemisphere around each pixel is inside
+ * other geometry according to the depth buffer.
+ *
+ * The result is blurred into [Ssao::ao], a single channel texture
+ * where 1 is fully unoccluded, meant to be multiplied into ambient
+ * light. [crate::DeferredRenderer::set_ambient_occlusion] does that
+ * for the deferred renderer.
+ */
+pub struct Ssao<'a> {
+    /// How far around each pixel to look for occluders, in world units
+    pub radius: f32,
+    /// Stops flat surfaces from occluding themselves
+    pub bias: f32,
+    /// Values above 1 darken the occlusion
+    pub power: f32,
+    /// Up to [Ssao::MAX_SAMPLES]
+    pub sample_count: u32,
+    pub ao: Texture<'a>,
+    raw: Texture<'a>,
+    kernel: Vec<Vector3<f32>>,
+    noise: Texture<'a>,
+    placeholder_normal: Texture<'a>,
+    sampler: wgpu::Sampler,
+    uniform_buffer: wgpu::Buffer,
+    ssao_layout: wgpu::BindGroupLayout,
+    ssao_pipeline: wgpu::RenderPipeline,
+    blur_layout: wgpu::BindGroupLayout,
+    blur_pipeline: wgpu::RenderPipeline,
+    has_normals: bool,
+}
+
+impl<'a> Ssao<'a> {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+    pub const MAX_SAMPLES: usize = 64;
+    pub const NOISE_SIZE: u32 = 4;
+
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        width: u32,
+        height: u32,
+    ) -> Result<Self> {
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::SampledTexture {
+                dimension: wgpu::TextureViewDimension::D2,
+                component_type: wgpu::TextureComponentType::Float,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let sampler_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::Sampler { comparison: false },
+            count: None,
+        };
+        let ssao_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Ssao::ssao_layout"),
+            entries: &[
+                texture_entry(0),
+                texture_entry(1),
+                texture_entry(2),
+                sampler_entry(3),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let blur_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Ssao::blur_layout"),
+            entries: &[texture_entry(0), sampler_entry(1)],
+        });
+
+        let ssao_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Ssao::ssao_pipeline_layout"),
+            bind_group_layouts: &[&ssao_layout],
+            push_constant_ranges: &[],
+        });
+        let ssao_pipeline = RenderPipelineBuilder::new()
+            .layout(&ssao_pipeline_layout)
+            .vertex_shader(wgpu::include_spirv!("fullscreen.vert.spv"))
+            .fragment_shader(wgpu::include_spirv!("ssao.frag.spv"))
+            .color_solid(Self::FORMAT)
+            .build(device)?;
+        let blur_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Ssao::blur_pipeline_layout"),
+            bind_group_layouts: &[&blur_layout],
+            push_constant_ranges: &[],
+        });
+        let blur_pipeline = RenderPipelineBuilder::new()
+            .layout(&blur_pipeline_layout)
+            .vertex_shader(wgpu::include_spirv!("fullscreen.vert.spv"))
+            .fragment_shader(wgpu::include_spirv!("ssao_blur.frag.spv"))
+            .color_solid(Self::FORMAT)
+            .build(device)?;
+
+        let mut rng = XorShift::new(0x5EED);
+        let kernel = generate_kernel(&mut rng, Self::MAX_SAMPLES);
+        let noise_pixels = (0..Self::NOISE_SIZE * Self::NOISE_SIZE)
+            .flat_map(|_| {
+                // Random rotations around the z axis, mapped to 0..1
+                let x = rng.next_f32() * 2.0 - 1.0;
+                let y = rng.next_f32() * 2.0 - 1.0;
+                let v = Vector2::new(x, y).normalize() * 0.5 + Vector2::new(0.5, 0.5);
+                vec![(v.x * 255.0) as u8, (v.y * 255.0) as u8, 128, 255]
+            })
+            .collect::<Vec<u8>>();
+        let noise_image =
+            image::RgbaImage::from_raw(Self::NOISE_SIZE, Self::NOISE_SIZE, noise_pixels)
+                .context("Noise texture is the wrong size")?;
+        let noise = Texture::from_image(
+            device,
+            queue,
+            &image::DynamicImage::ImageRgba8(noise_image),
+            Some("Ssao::noise"),
+            true,
+        )?;
+        let placeholder_normal = Texture::from_color(device, queue, [0, 0, 255, 255], true)?;
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let uniform_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Ssao::uniform_buffer"),
+            contents: bytemuck::cast_slice(&[SsaoUniforms {
+                proj: Matrix4::identity(),
+                inv_proj: Matrix4::identity(),
+                view: Matrix4::identity(),
+                radius: 0.0,
+                bias: 0.0,
+                power: 1.0,
+                sample_count: 0,
+                has_normals: 0,
+                _padding: [0; 3],
+                kernel: [[0.0; 4]; Self::MAX_SAMPLES],
+            }]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let (raw, ao) = Self::create_targets(device, width, height);
+
+        Ok(Self {
+            radius: 0.5,
+            bias: 0.025,
+            power: 1.0,
+            sample_count: 32,
+            ao,
+            raw,
+            kernel,
+            noise,
+            placeholder_normal,
+            sampler,
+            uniform_buffer,
+            ssao_layout,
+            ssao_pipeline,
+            blur_layout,
+            blur_pipeline,
+            has_normals: false,
+        })
+    }
+
+    /// Recreates [Ssao::ao], so anything sampling it needs to be
+    /// rebound.
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        let (raw, ao) = Self::create_targets(device, width, height);
+        self.raw = raw;
+        self.ao = ao;
+    }
+
+    /// Uploads the camera and settings. Call this once a frame before
+    /// [Ssao::run].
+    pub fn update(&self, queue: &wgpu::Queue, camera: &Camera, projection: &Projection) {
+        let proj = projection.calc_matrix();
+        let mut kernel = [[0.0; 4]; Self::MAX_SAMPLES];
+        for (k, v) in kernel.iter_mut().zip(self.kernel.iter()) {
+            *k = [v.x, v.y, v.z, 0.0];
+        }
+        let uniforms = SsaoUniforms {
+            proj,
+            inv_proj: proj.invert().unwrap_or_else(Matrix4::identity),
+            view: camera.calc_matrix(),
+            radius: self.radius,
+            bias: self.bias,
+            power: self.power,
+            sample_count: self.sample_count.min(Self::MAX_SAMPLES as u32),
+            has_normals: self.has_normals as u32,
+            _padding: [0; 3],
+            kernel,
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniforms]));
+    }
+
+    /// Computes and blurs the occlusion for `depth`, which should
+    /// be the same size as [Ssao::ao]. `normals` should hold world
+    /// space normals like [crate::GBuffer::normal]. Without them the
+    /// normals are rebuilt from depth, which looks blockier.
+    pub fn run(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        depth: &wgpu::TextureView,
+        normals: Option<&wgpu::TextureView>,
+    ) {
+        self.has_normals = normals.is_some();
+        queue.write_buffer(
+            &self.uniform_buffer,
+            HAS_NORMALS_OFFSET,
+            bytemuck::bytes_of(&(self.has_normals as u32)),
+        );
+        let normals = normals.unwrap_or(&self.placeholder_normal.view);
+
+        let ssao_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Ssao::ssao_bind_group"),
+            layout: &self.ssao_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(depth),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(normals),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&self.noise.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::Buffer(self.uniform_buffer.slice(..)),
+                },
+            ],
+        });
+        let blur_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Ssao::blur_bind_group"),
+            layout: &self.blur_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&self.raw.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+        });
+
+        let passes = [
+            (&self.ssao_pipeline, &ssao_bind_group, &self.raw.view),
+            (&self.blur_pipeline, &blur_bind_group, &self.ao.view),
+        ];
+        for (pipeline, bind_group, target) in passes.iter() {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+    }
+
+    fn create_targets(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+    ) -> (Texture<'a>, Texture<'a>) {
+        let create =
+            |label| Texture::create_render_target(device, width, height, Self::FORMAT, Some(label));
+        (create("Ssao::raw"), create("Ssao::ao"))
+    }
+}
+
+/// Points in the +z hemisphere, bunched up towards the center so
+/// nearby geometry counts for more
+fn generate_kernel(rng: &mut XorShift, count: usize) -> Vec<Vector3<f32>> {
+    (0..count)
+        .map(|i| {
+            let v = Vector3::new(
+                rng.next_f32() * 2.0 - 1.0,
+                rng.next_f32() * 2.0 - 1.0,
+                rng.next_f32(),
+            );
+            let v = if v.magnitude2() > 0.0 {
+                v.normalize()
+            } else {
+                Vector3::unit_z()
+            };
+            let scale = i as f32 / count as f32;
+            let scale = 0.1 + (1.0 - 0.1) * scale * scale;
+            v * rng.next_f32() * scale
+        })
+        .collect()
+}
+
+/// A tiny deterministic random number generator, so the kernel and
+/// noise come out the same every run
+struct XorShift(u32);
+
+impl XorShift {
+    fn new(seed: u32) -> Self {
+        Self(seed.max(1))
+    }
+
+    fn next_f32(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernel_is_in_hemisphere() {
+        let mut rng = XorShift::new(0x5EED);
+        let kernel = generate_kernel(&mut rng, Ssao::MAX_SAMPLES);
+        assert_eq!(kernel.len(), Ssao::MAX_SAMPLES);
+        for v in &kernel {
+            assert!(v.z >= 0.0);
+            assert!(v.magnitude() <= 1.0);
+        }
+    }
+
+    #[test]
+    fn uniform_layout() {
+        // 3 mat4s, 2 vec4s of settings and the kernel
+        assert_eq!(
+            std::mem::size_of::<SsaoUniforms>(),
+            64 * 3 + 32 + 16 * Ssao::MAX_SAMPLES
+        );
+
+        let mut uniforms: SsaoUniforms = bytemuck::Zeroable::zeroed();
+        uniforms.has_normals = 1;
+        let bytes = bytemuck::bytes_of(&uniforms);
+        let offset = HAS_NORMALS_OFFSET as usize;
+        assert_eq!(&bytes[offset..offset + 4], &1u32.to_ne_bytes());
+    }
+}
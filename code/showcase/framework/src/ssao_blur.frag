@@ -0,0 +1,24 @@
+#version 450
+
+layout(location=0) in vec2 v_ndc;
+
+layout(location=0) out float f_occlusion;
+
+layout(set=0, binding=0) uniform texture2D t_input;
+layout(set=0, binding=1) uniform sampler s_input;
+
+// Averages a 4x4 block, the same size as the noise texture, so the
+// pattern from rotating the kernel goes away
+void main() {
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    ivec2 size = textureSize(sampler2D(t_input, s_input), 0);
+
+    float result = 0.0;
+    for (int x = -2; x < 2; x++) {
+        for (int y = -2; y < 2; y++) {
+            ivec2 offset = clamp(pixel + ivec2(x, y), ivec2(0), size - 1);
+            result += texelFetch(sampler2D(t_input, s_input), offset, 0).r;
+        }
+    }
+    f_occlusion = result / 16.0;
+}
@@ -0,0 +1,39 @@
+#version 450
+
+layout(location=0) in vec3 v_position;
+layout(location=1) in vec3 v_normal;
+layout(location=2) in vec2 v_splat_coords;
+layout(location=3) in vec2 v_layer_coords;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=1) uniform texture2D t_splat;
+layout(set=0, binding=2) uniform sampler s_splat;
+layout(set=0, binding=3) uniform texture2D t_layer_r;
+layout(set=0, binding=4) uniform texture2D t_layer_g;
+layout(set=0, binding=5) uniform texture2D t_layer_b;
+layout(set=0, binding=6) uniform texture2D t_layer_a;
+layout(set=0, binding=7) uniform sampler s_layer;
+
+layout(set=2, binding=0)
+uniform Light {
+    vec4 light_position;
+    vec4 light_color;
+};
+
+void main() {
+    vec4 weights = texture(sampler2D(t_splat, s_splat), v_splat_coords);
+    weights /= max(dot(weights, vec4(1.0)), 0.0001);
+
+    vec3 albedo = texture(sampler2D(t_layer_r, s_layer), v_layer_coords).rgb * weights.r
+        + texture(sampler2D(t_layer_g, s_layer), v_layer_coords).rgb * weights.g
+        + texture(sampler2D(t_layer_b, s_layer), v_layer_coords).rgb * weights.b
+        + texture(sampler2D(t_layer_a, s_layer), v_layer_coords).rgb * weights.a;
+
+    vec3 normal = normalize(v_normal);
+    vec3 light_dir = normalize(light_position.xyz - v_position);
+    float diffuse = max(dot(normal, light_dir), 0.0);
+    float ambient = 0.1;
+
+    f_color = vec4(albedo * light_color.rgb * (ambient + diffuse), 1.0);
+}
@@ -0,0 +1,907 @@
+use anyhow::*;
+use cgmath::*;
+use std::mem;
+use std::path::Path;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+
+use crate::camera::Frustum;
+use crate::model::Vertex;
+use crate::optimize::{index_bytes, index_format_for};
+use crate::pbr::{sampler_entry, texture_entry};
+use crate::pipeline::RenderPipelineBuilder;
+use crate::texture::Texture;
+
+/// Must match `MAX_LEVELS` in terrain.vert
+pub const MAX_TERRAIN_LEVELS: usize = 8;
+
+/// Heights on a regular grid, one per texel of the source image
+#[derive(Debug, Clone)]
+pub struct Heightmap {
+    width: u32,
+    depth: u32,
+    heights: Vec<f32>,
+}
+
+impl Heightmap {
+    /// `heights` is row major, `width` samples along x by `depth` along z
+    pub fn new(width: u32, depth: u32, heights: Vec<f32>) -> Result<Self> {
+        if width < 2 || depth < 2 {
+            bail!(
+                "A heightmap needs at least 2x2 samples, not {}x{}",
+                width,
+                depth
+            );
+        }
+        if heights.len() != (width * depth) as usize {
+            bail!(
+                "{}x{} heightmap given {} heights",
+                width,
+                depth,
+                heights.len()
+            );
+        }
+        Ok(Self {
+            width,
+            depth,
+            heights,
+        })
+    }
+
+    pub fn from_fn(width: u32, depth: u32, f: impl Fn(u32, u32) -> f32) -> Result<Self> {
+        let heights = (0..depth)
+            .flat_map(|z| (0..width).map(move |x| (x, z)))
+            .map(|(x, z)| f(x, z))
+            .collect();
+        Self::new(width, depth, heights)
+    }
+
+    pub fn from_noise(width: u32, depth: u32, noise: &Noise) -> Result<Self> {
+        Self::from_fn(width, depth, |x, z| noise.sample(x as f32, z as f32))
+    }
+
+    /// Black is 0 and white is `max_height`. 16 bit images keep their
+    /// precision, which avoids terracing on gentle slopes.
+    pub fn from_image(img: &image::DynamicImage, max_height: f32) -> Result<Self> {
+        let luma = img.to_luma16();
+        let heights = luma
+            .pixels()
+            .map(|p| p.0[0] as f32 / u16::MAX as f32 * max_height)
+            .collect();
+        Self::new(luma.width(), luma.height(), heights)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, max_height: f32) -> Result<Self> {
+        let img = image::open(path)?;
+        Self::from_image(&img, max_height)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// Samples outside the map repeat the nearest edge
+    pub fn get(&self, x: i64, z: i64) -> f32 {
+        let x = x.clamp(0, self.width as i64 - 1) as usize;
+        let z = z.clamp(0, self.depth as i64 - 1) as usize;
+        self.heights[z * self.width as usize + x]
+    }
+
+    /// The lowest and highest heights
+    pub fn range(&self) -> (f32, f32) {
+        self.heights
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(lo, hi), &h| (lo.min(h), hi.max(h)))
+    }
+}
+
+/// Fractal value noise, for making up terrain
+#[derive(Debug, Copy, Clone)]
+pub struct Noise {
+    pub seed: u32,
+    /// Features per heightmap sample in the first octave
+    pub frequency: f32,
+    pub octaves: u32,
+    /// How much each octave's amplitude shrinks
+    pub persistence: f32,
+    /// How much each octave's frequency grows
+    pub lacunarity: f32,
+    /// The output is between 0 and this
+    pub amplitude: f32,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            frequency: 1.0 / 64.0,
+            octaves: 5,
+            persistence: 0.5,
+            lacunarity: 2.0,
+            amplitude: 24.0,
+        }
+    }
+}
+
+impl Noise {
+    fn hash(&self, x: i32, z: i32) -> f32 {
+        let mut h = (x as u32).wrapping_mul(0x27d4_eb2d)
+            ^ (z as u32).wrapping_mul(0x1656_67b1)
+            ^ self.seed.wrapping_mul(0x9e37_79b9);
+        h ^= h >> 15;
+        h = h.wrapping_mul(0x2c1b_3c6d);
+        h ^= h >> 12;
+        h = h.wrapping_mul(0x297a_2d39);
+        h ^= h >> 15;
+        h as f32 / u32::MAX as f32
+    }
+
+    fn value(&self, x: f32, z: f32) -> f32 {
+        let (x0, z0) = (x.floor(), z.floor());
+        let (fx, fz) = (x - x0, z - z0);
+        let (x0, z0) = (x0 as i32, z0 as i32);
+        let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+        let (sx, sz) = (smooth(fx), smooth(fz));
+        let top = lerp(self.hash(x0, z0), self.hash(x0 + 1, z0), sx);
+        let bottom = lerp(self.hash(x0, z0 + 1), self.hash(x0 + 1, z0 + 1), sx);
+        lerp(top, bottom, sz)
+    }
+
+    pub fn sample(&self, x: f32, z: f32) -> f32 {
+        let mut frequency = self.frequency;
+        let mut amplitude = 1.0;
+        let mut total = 0.0;
+        let mut sum = 0.0;
+        for octave in 0..self.octaves {
+            // Offset each octave so their lattices don't line up
+            let offset = octave as f32 * 17.31;
+            sum += self.value(x * frequency + offset, z * frequency - offset) * amplitude;
+            total += amplitude;
+            frequency *= self.lacunarity;
+            amplitude *= self.persistence;
+        }
+        if total > 0.0 {
+            sum / total * self.amplitude
+        } else {
+            0.0
+        }
+    }
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TerrainSettings {
+    /// World units between heightmap samples
+    pub cell_size: f32,
+    /// Quads along each side of a chunk at full detail. Each level
+    /// halves this, so it has to divide by `2^(lod_levels - 1)`.
+    pub chunk_size: u32,
+    pub lod_levels: u32,
+    /// Chunks closer than this are drawn at full detail. Each level
+    /// after covers twice the distance of the one before. To keep
+    /// neighboring chunks within a level of each other, this should be
+    /// more than a chunk's diagonal divided by `1 - morph_fraction`.
+    pub lod_distance: f32,
+    /// How much of each level's range is spent morphing into the next
+    pub morph_fraction: f32,
+    /// How many times the layer textures repeat per world unit
+    pub layer_tiling: f32,
+}
+
+impl Default for TerrainSettings {
+    fn default() -> Self {
+        Self {
+            cell_size: 1.0,
+            chunk_size: 32,
+            lod_levels: 4,
+            lod_distance: 100.0,
+            morph_fraction: 0.3,
+            layer_tiling: 0.25,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct TerrainVertex {
+    pub position: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    /// Points along +x, for shaders that want to add normal maps
+    pub tangent: Vector3<f32>,
+    /// Where this vertex ends up when its chunk has fully morphed
+    /// into the next level
+    pub morph_height: f32,
+}
+
+unsafe impl bytemuck::Pod for TerrainVertex {}
+unsafe impl bytemuck::Zeroable for TerrainVertex {}
+
+impl Vertex for TerrainVertex {
+    fn desc<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        wgpu::VertexBufferDescriptor {
+            stride: mem::size_of::<TerrainVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttributeDescriptor {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: mem::size_of::<[f32; 6]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: mem::size_of::<[f32; 9]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float,
+                },
+            ],
+        }
+    }
+}
+
+/// One chunk to draw this frame
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ChunkDraw {
+    pub chunk: usize,
+    pub level: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TerrainStats {
+    pub chunks_per_level: Vec<u32>,
+    pub culled: u32,
+    pub triangles: u64,
+}
+
+/**
+ * The CPU side of a terrain: the heightmap, how it's split into
+ * chunks, and height queries. The grid starts at the origin and runs
+ * along +x and +z.
+ */
+pub struct Terrain {
+    pub heightmap: Heightmap,
+    pub settings: TerrainSettings,
+    chunks_x: u32,
+    /// The min and max corners of each chunk
+    bounds: Vec<(Point3<f32>, Point3<f32>)>,
+}
+
+impl Terrain {
+    pub fn new(heightmap: Heightmap, settings: TerrainSettings) -> Result<Self> {
+        if settings.lod_levels == 0 || settings.lod_levels as usize > MAX_TERRAIN_LEVELS {
+            bail!("lod_levels has to be 1 to {}", MAX_TERRAIN_LEVELS);
+        }
+        let coarsest = 1 << (settings.lod_levels - 1);
+        // coarsest is a power of two
+        if settings.chunk_size == 0 || settings.chunk_size & (coarsest - 1) != 0 {
+            bail!(
+                "chunk_size {} doesn't divide into {} levels",
+                settings.chunk_size,
+                settings.lod_levels
+            );
+        }
+        // The last chunk along each side can hang off the edge
+        let chunks =
+            |samples: u32| ((samples - 1) as f32 / settings.chunk_size as f32).ceil() as u32;
+        let (chunks_x, chunks_z) = (chunks(heightmap.width), chunks(heightmap.depth));
+
+        let mut terrain = Self {
+            heightmap,
+            settings,
+            chunks_x,
+            bounds: Vec::new(),
+        };
+        for cz in 0..chunks_z {
+            for cx in 0..chunks_x {
+                let (x0, z0) = terrain.chunk_origin(cx as usize + (cz * chunks_x) as usize);
+                let mut lo = f32::MAX;
+                let mut hi = f32::MIN;
+                for z in z0..=z0 + settings.chunk_size as i64 {
+                    for x in x0..=x0 + settings.chunk_size as i64 {
+                        let h = terrain.heightmap.get(x, z);
+                        lo = lo.min(h);
+                        hi = hi.max(h);
+                    }
+                }
+                let cell = settings.cell_size;
+                let size = settings.chunk_size as f32 * cell;
+                let min = Point3::new(x0 as f32 * cell, lo, z0 as f32 * cell);
+                terrain
+                    .bounds
+                    .push((min, Point3::new(min.x + size, hi, min.z + size)));
+            }
+        }
+        Ok(terrain)
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.bounds.len()
+    }
+
+    pub fn chunk_bounds(&self, chunk: usize) -> (Point3<f32>, Point3<f32>) {
+        self.bounds[chunk]
+    }
+
+    /// The world size of the area the heightmap covers, along x and z
+    pub fn size(&self) -> Vector2<f32> {
+        Vector2::new(
+            (self.heightmap.width - 1) as f32,
+            (self.heightmap.depth - 1) as f32,
+        ) * self.settings.cell_size
+    }
+
+    fn chunk_origin(&self, chunk: usize) -> (i64, i64) {
+        let size = self.settings.chunk_size as i64;
+        let x = (chunk % self.chunks_x as usize) as i64;
+        let z = (chunk / self.chunks_x as usize) as i64;
+        (x * size, z * size)
+    }
+
+    /**
+     * The height at a world position, matching the full detail mesh
+     * exactly, so things placed with it sit on the surface. Positions
+     * off the edge of the map get the height of the nearest edge.
+     */
+    pub fn height_at(&self, x: f32, z: f32) -> f32 {
+        let gx = x / self.settings.cell_size;
+        let gz = z / self.settings.cell_size;
+        let (x0, z0) = (gx.floor(), gz.floor());
+        let (fx, fz) = (gx - x0, gz - z0);
+        let (x0, z0) = (x0 as i64, z0 as i64);
+        let h = |dx: i64, dz: i64| self.heightmap.get(x0 + dx, z0 + dz);
+        // Each quad is split along the diagonal from (0, 0) to (1, 1)
+        if fx > fz {
+            h(0, 0) + fx * (h(1, 0) - h(0, 0)) + fz * (h(1, 1) - h(1, 0))
+        } else {
+            h(0, 0) + fz * (h(0, 1) - h(0, 0)) + fx * (h(1, 1) - h(0, 1))
+        }
+    }
+
+    /// The smoothed normal at a world position, the same one the
+    /// mesh is lit with
+    pub fn normal_at(&self, x: f32, z: f32) -> Vector3<f32> {
+        let cell = self.settings.cell_size;
+        let (gx, gz) = ((x / cell).floor() as i64, (z / cell).floor() as i64);
+        let (fx, fz) = (x / cell - gx as f32, z / cell - gz as f32);
+        let top = self
+            .grid_normal(gx, gz)
+            .lerp(self.grid_normal(gx + 1, gz), fx);
+        let bottom = self
+            .grid_normal(gx, gz + 1)
+            .lerp(self.grid_normal(gx + 1, gz + 1), fx);
+        top.lerp(bottom, fz).normalize()
+    }
+
+    fn grid_normal(&self, x: i64, z: i64) -> Vector3<f32> {
+        let h = |x, z| self.heightmap.get(x, z);
+        let dx = (h(x + 1, z) - h(x - 1, z)) / (2.0 * self.settings.cell_size);
+        let dz = (h(x, z + 1) - h(x, z - 1)) / (2.0 * self.settings.cell_size);
+        Vector3::new(-dx, 1.0, -dz).normalize()
+    }
+
+    fn grid_vertex(&self, x: i64, z: i64, step: i64, last_level: bool) -> TerrainVertex {
+        let h = |x, z| self.heightmap.get(x, z);
+        let cell = self.settings.cell_size;
+        let height = h(x, z);
+        let normal = self.grid_normal(x, z);
+        let along = Vector3::unit_x();
+        let tangent = (along - normal * normal.dot(along)).normalize();
+
+        // The next level's grid skips every other vertex, which then
+        // sit halfway along its edges or diagonals
+        let (odd_x, odd_z) = ((x / step) % 2 == 1, (z / step) % 2 == 1);
+        let morph_height = match (odd_x, odd_z) {
+            _ if last_level => height,
+            (false, false) => height,
+            (true, false) => (h(x - step, z) + h(x + step, z)) * 0.5,
+            (false, true) => (h(x, z - step) + h(x, z + step)) * 0.5,
+            (true, true) => (h(x - step, z - step) + h(x + step, z + step)) * 0.5,
+        };
+
+        TerrainVertex {
+            position: Vector3::new(x as f32 * cell, height, z as f32 * cell),
+            normal,
+            tangent,
+            morph_height,
+        }
+    }
+
+    /// A chunk's vertices at `level`, row by row along +z
+    pub fn chunk_vertices(&self, chunk: usize, level: u32) -> Vec<TerrainVertex> {
+        let (x0, z0) = self.chunk_origin(chunk);
+        let step = 1i64 << level;
+        let n = (self.settings.chunk_size >> level) as i64;
+        let last_level = level + 1 == self.settings.lod_levels;
+        let mut vertices = Vec::with_capacity(((n + 1) * (n + 1)) as usize);
+        for j in 0..=n {
+            for i in 0..=n {
+                vertices.push(self.grid_vertex(x0 + i * step, z0 + j * step, step, last_level));
+            }
+        }
+        vertices
+    }
+
+    /// The indices for every chunk at `level`
+    pub fn level_indices(&self, level: u32) -> Vec<u32> {
+        let n = self.settings.chunk_size >> level;
+        let stride = n + 1;
+        let mut indices = Vec::with_capacity((n * n * 6) as usize);
+        for j in 0..n {
+            for i in 0..n {
+                let a = j * stride + i;
+                let (b, c, d) = (a + stride, a + stride + 1, a + 1);
+                indices.extend_from_slice(&[a, b, c, a, c, d]);
+            }
+        }
+        indices
+    }
+
+    /// Where each level's range ends
+    fn level_end(&self, level: u32) -> f32 {
+        self.settings.lod_distance * (1 << level) as f32
+    }
+
+    /// The distances each level starts and finishes morphing over, as
+    /// terrain.vert wants them
+    pub fn morph_ranges(&self) -> [[f32; 4]; MAX_TERRAIN_LEVELS] {
+        let mut ranges = [[0.0; 4]; MAX_TERRAIN_LEVELS];
+        for level in 0..self.settings.lod_levels {
+            let end = self.level_end(level);
+            let previous = if level == 0 {
+                0.0
+            } else {
+                self.level_end(level - 1)
+            };
+            ranges[level as usize] = if level + 1 == self.settings.lod_levels {
+                // There's nothing to morph into
+                [f32::MAX, f32::MAX, 0.0, 0.0]
+            } else {
+                [
+                    end - (end - previous) * self.settings.morph_fraction,
+                    end,
+                    0.0,
+                    0.0,
+                ]
+            };
+        }
+        ranges
+    }
+
+    /// The level a chunk this far away is drawn at
+    pub fn select_level(&self, distance: f32) -> u32 {
+        (0..self.settings.lod_levels)
+            .find(|&level| distance < self.level_end(level))
+            .unwrap_or(self.settings.lod_levels - 1)
+    }
+
+    /**
+     * Picks the chunks to draw from `camera` and the level to draw
+     * each at. Chunks outside `frustum` are skipped. Distances are to
+     * the nearest point of each chunk's bounds, which is never further
+     * than any of its vertices, so a chunk's edges have always fully
+     * morphed by the time its neighbor drops a level.
+     */
+    pub fn select(&self, camera: Point3<f32>, frustum: &Frustum) -> (Vec<ChunkDraw>, TerrainStats) {
+        let mut draws = Vec::new();
+        let mut stats = TerrainStats {
+            chunks_per_level: vec![0; self.settings.lod_levels as usize],
+            ..Default::default()
+        };
+        for (chunk, &(min, max)) in self.bounds.iter().enumerate() {
+            if !frustum.intersects_aabb(min, max) {
+                stats.culled += 1;
+                continue;
+            }
+            let nearest = Point3::new(
+                camera.x.clamp(min.x, max.x),
+                camera.y.clamp(min.y, max.y),
+                camera.z.clamp(min.z, max.z),
+            );
+            let level = self.select_level(camera.distance(nearest));
+            let n = (self.settings.chunk_size >> level) as u64;
+            stats.chunks_per_level[level as usize] += 1;
+            stats.triangles += n * n * 2;
+            draws.push(ChunkDraw { chunk, level });
+        }
+        (draws, stats)
+    }
+
+    /**
+     * A splat map from the shape of the terrain, one texel per height
+     * sample. Red is for low ground, green for grass, blue for steep
+     * rock and alpha for high peaks.
+     */
+    pub fn splat_map(&self) -> image::RgbaImage {
+        let (lo, hi) = self.heightmap.range();
+        let range = (hi - lo).max(1e-6);
+        image::RgbaImage::from_fn(self.heightmap.width, self.heightmap.depth, |x, z| {
+            let height = (self.heightmap.get(x as i64, z as i64) - lo) / range;
+            let slope = 1.0 - self.grid_normal(x as i64, z as i64).y;
+            let rock = smoothstep(0.15, 0.35, slope);
+            let snow = smoothstep(0.7, 0.85, height) * (1.0 - rock);
+            let sand = (1.0 - smoothstep(0.05, 0.15, height)) * (1.0 - rock);
+            let grass = (1.0 - rock - snow - sand).max(0.0);
+            let total = sand + grass + rock + snow;
+            let byte = |w: f32| (w / total * 255.0).round() as u8;
+            image::Rgba([byte(sand), byte(grass), byte(rock), byte(snow)])
+        })
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct TerrainUniforms {
+    /// World width, world depth, layer tiling, unused
+    size: [f32; 4],
+    morph: [[f32; 4]; MAX_TERRAIN_LEVELS],
+}
+
+unsafe impl bytemuck::Pod for TerrainUniforms {}
+unsafe impl bytemuck::Zeroable for TerrainUniforms {}
+
+struct Chunk {
+    /// One per level
+    vertex_buffers: Vec<wgpu::Buffer>,
+}
+
+/**
+ * Draws a [Terrain], blending four layer textures with a splat map.
+ * Chunks are culled and given a level with [TerrainRenderer::update],
+ * and morph smoothly into the next level in the vertex shader, so
+ * there's no popping or cracks between levels.
+ *
+ * Bind groups follow [crate::DrawModel]: the terrain's own textures,
+ * then [crate::UniformBinding], then [crate::LightBinding].
+ */
+pub struct TerrainRenderer<'a> {
+    pub splat: Texture<'a>,
+    pub layers: [Texture<'a>; 4],
+    pub pipeline: wgpu::RenderPipeline,
+    pub material_layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+    pub uniform_buffer: wgpu::Buffer,
+    chunks: Vec<Chunk>,
+    /// One per level, shared by every chunk
+    index_buffers: Vec<(wgpu::Buffer, u32)>,
+    draws: Vec<ChunkDraw>,
+    stats: TerrainStats,
+}
+
+impl<'a> TerrainRenderer<'a> {
+    /// `layers` are the textures for the splat map's red, green, blue
+    /// and alpha channels. Make a splat map with [Terrain::splat_map]
+    /// or load one with [Texture::load] as a normal map, so it isn't
+    /// treated as sRGB.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &wgpu::Device,
+        color_format: wgpu::TextureFormat,
+        sample_count: u32,
+        uniform_layout: &wgpu::BindGroupLayout,
+        light_layout: &wgpu::BindGroupLayout,
+        terrain: &Terrain,
+        splat: Texture<'a>,
+        layers: [Texture<'a>; 4],
+    ) -> Result<Self> {
+        let d2 = wgpu::TextureViewDimension::D2;
+        let material_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("TerrainRenderer::material_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                texture_entry(1, d2),
+                sampler_entry(2),
+                texture_entry(3, d2),
+                texture_entry(4, d2),
+                texture_entry(5, d2),
+                texture_entry(6, d2),
+                sampler_entry(7),
+            ],
+        });
+
+        let size = terrain.size();
+        let uniforms = TerrainUniforms {
+            size: [size.x, size.y, terrain.settings.layer_tiling, 0.0],
+            morph: terrain.morph_ranges(),
+        };
+        let uniform_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("TerrainRenderer::uniform_buffer"),
+            contents: bytemuck::cast_slice(&[uniforms]),
+            usage: wgpu::BufferUsage::UNIFORM,
+        });
+        // Layers tile, where the splat map is stretched over the whole
+        // terrain and can use its own sampler
+        let layer_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("TerrainRenderer::layer_sampler"),
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("TerrainRenderer::bind_group"),
+            layout: &material_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(uniform_buffer.slice(..)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&splat.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&splat.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&layers[0].view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&layers[1].view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&layers[2].view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::TextureView(&layers[3].view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: wgpu::BindingResource::Sampler(&layer_sampler),
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("TerrainRenderer::pipeline_layout"),
+            bind_group_layouts: &[&material_layout, uniform_layout, light_layout],
+            push_constant_ranges: &[],
+        });
+        // Every level shares the finest level's vertex count at most
+        let chunk_size = terrain.settings.chunk_size as usize;
+        let index_format = index_format_for((chunk_size + 1) * (chunk_size + 1));
+        let pipeline = RenderPipelineBuilder::new()
+            .layout(&pipeline_layout)
+            .vertex_shader(wgpu::include_spirv!("terrain.vert.spv"))
+            .fragment_shader(wgpu::include_spirv!("terrain.frag.spv"))
+            .color_solid(color_format)
+            .depth_format(Texture::DEPTH_FORMAT)
+            .cull_mode(wgpu::CullMode::Back)
+            .vertex_buffer::<TerrainVertex>()
+            .index_format(index_format)
+            .sample_count(sample_count)
+            .build(device)?;
+
+        let chunks = (0..terrain.chunk_count())
+            .map(|chunk| Chunk {
+                vertex_buffers: (0..terrain.settings.lod_levels)
+                    .map(|level| {
+                        device.create_buffer_init(&BufferInitDescriptor {
+                            label: Some(&format!("Terrain Chunk {} Level {}", chunk, level)),
+                            contents: bytemuck::cast_slice(&terrain.chunk_vertices(chunk, level)),
+                            usage: wgpu::BufferUsage::VERTEX,
+                        })
+                    })
+                    .collect(),
+            })
+            .collect();
+        let index_buffers = (0..terrain.settings.lod_levels)
+            .map(|level| {
+                let indices = terrain.level_indices(level);
+                let buffer = device.create_buffer_init(&BufferInitDescriptor {
+                    label: Some(&format!("Terrain Level {} Index Buffer", level)),
+                    contents: &index_bytes(&indices, index_format),
+                    usage: wgpu::BufferUsage::INDEX,
+                });
+                (buffer, indices.len() as u32)
+            })
+            .collect();
+
+        Ok(Self {
+            splat,
+            layers,
+            pipeline,
+            material_layout,
+            bind_group,
+            uniform_buffer,
+            chunks,
+            index_buffers,
+            draws: Vec::new(),
+            stats: TerrainStats::default(),
+        })
+    }
+
+    /// Culls and picks levels for this frame. `view_proj` is the same
+    /// matrix as in [crate::Uniforms].
+    pub fn update(&mut self, terrain: &Terrain, camera: Point3<f32>, view_proj: Matrix4<f32>) {
+        let (draws, stats) = terrain.select(camera, &Frustum::from_matrix(view_proj));
+        self.draws = draws;
+        self.stats = stats;
+    }
+
+    pub fn stats(&self) -> &TerrainStats {
+        &self.stats
+    }
+
+    pub fn draw<'b>(
+        &'b self,
+        pass: &mut wgpu::RenderPass<'b>,
+        uniforms: &'b wgpu::BindGroup,
+        light: &'b wgpu::BindGroup,
+    ) {
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.set_bind_group(1, uniforms, &[]);
+        pass.set_bind_group(2, light, &[]);
+        for draw in &self.draws {
+            let level = draw.level as usize;
+            let (index_buffer, count) = &self.index_buffers[level];
+            pass.set_vertex_buffer(0, self.chunks[draw.chunk].vertex_buffers[level].slice(..));
+            pass.set_index_buffer(index_buffer.slice(..));
+            // terrain.vert reads the level from the instance index
+            pass.draw_indexed(0..*count, 0, draw.level..draw.level + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hills() -> Terrain {
+        let noise = Noise {
+            frequency: 1.0 / 16.0,
+            ..Default::default()
+        };
+        let heightmap = Heightmap::from_noise(65, 65, &noise).unwrap();
+        let settings = TerrainSettings {
+            cell_size: 2.0,
+            chunk_size: 16,
+            lod_levels: 3,
+            lod_distance: 80.0,
+            ..Default::default()
+        };
+        Terrain::new(heightmap, settings).unwrap()
+    }
+
+    #[test]
+    fn height_queries_match_the_mesh() {
+        let terrain = hills();
+        assert_eq!(terrain.chunk_count(), 16);
+        assert_eq!(terrain.size(), Vector2::new(128.0, 128.0));
+
+        // On grid points it's exactly the heightmap
+        assert_eq!(terrain.height_at(6.0, 10.0), terrain.heightmap.get(3, 5));
+
+        // Between them it lies on the mesh's triangles
+        let vertices = terrain.chunk_vertices(0, 0);
+        let indices = terrain.level_indices(0);
+        for &(x, z) in &[(3.3, 7.9), (5.9, 1.2), (20.5, 20.5)] {
+            let p = Vector3::new(x, 0.0, z);
+            let on_triangle = indices.chunks(3).find_map(|t| {
+                let v = |k: usize| vertices[t[k] as usize].position;
+                let (a, b, c) = (v(0), v(1), v(2));
+                let normal = (b - a).cross(c - a);
+                let inside = [(a, b), (b, c), (c, a)]
+                    .iter()
+                    .all(|&(e0, e1)| (e1 - e0).cross(p - e0).y >= -1e-4);
+                if inside {
+                    Some(a.y - (normal.x * (x - a.x) + normal.z * (z - a.z)) / normal.y)
+                } else {
+                    None
+                }
+            });
+            let expected = on_triangle.unwrap();
+            assert!((terrain.height_at(x, z) - expected).abs() < 1e-4);
+        }
+        assert!(terrain.normal_at(10.0, 10.0).y > 0.0);
+    }
+
+    #[test]
+    fn morph_targets_lie_on_the_next_level() {
+        let terrain = hills();
+        let fine = terrain.chunk_vertices(5, 0);
+        let coarse = terrain.chunk_vertices(5, 1);
+        let fine_n = terrain.settings.chunk_size as usize + 1;
+        let coarse_n = fine_n / 2 + 1;
+        for (j, row) in fine.chunks(fine_n).enumerate() {
+            for (i, v) in row.iter().enumerate() {
+                if i % 2 == 0 && j % 2 == 0 {
+                    let c = &coarse[(j / 2) * coarse_n + i / 2];
+                    assert_eq!(c.position, v.position);
+                    assert_eq!(v.morph_height, v.position.y);
+                }
+            }
+        }
+        // A fully morphed odd vertex sits on the coarse surface
+        let odd = &fine[fine_n + 1];
+        let a = &coarse[0];
+        let c = &coarse[coarse_n + 1];
+        assert_eq!(odd.morph_height, (a.position.y + c.position.y) * 0.5);
+        // The last level has nothing to morph to
+        let last = terrain.chunk_vertices(5, 2);
+        assert!(last.iter().all(|v| v.morph_height == v.position.y));
+    }
+
+    #[test]
+    fn levels_by_distance() {
+        let terrain = hills();
+        assert_eq!(terrain.select_level(0.0), 0);
+        assert_eq!(terrain.select_level(79.0), 0);
+        assert_eq!(terrain.select_level(81.0), 1);
+        assert_eq!(terrain.select_level(200.0), 2);
+        assert_eq!(terrain.select_level(10000.0), 2);
+
+        let ranges = terrain.morph_ranges();
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+        assert!(close(ranges[0][0], 56.0) && close(ranges[0][1], 80.0));
+        assert!(close(ranges[1][0], 136.0) && close(ranges[1][1], 160.0));
+        assert_eq!(ranges[2][0], f32::MAX);
+    }
+
+    #[test]
+    fn chunks_are_culled_and_counted() {
+        let terrain = hills();
+        let camera = Point3::new(-10.0, 40.0, 64.0);
+        // Looking down +x at the whole terrain
+        let view = Matrix4::look_at_dir(camera, Vector3::unit_x(), Vector3::unit_y());
+        let proj = crate::OPENGL_TO_WGPU_MATRIX * perspective(Deg(120.0), 1.0, 0.1, 1000.0);
+        let (draws, stats) = terrain.select(camera, &Frustum::from_matrix(proj * view));
+        assert_eq!(draws.len(), 16);
+        assert_eq!(stats.culled, 0);
+        assert_eq!(stats.chunks_per_level.iter().sum::<u32>(), 16);
+        assert!(stats.chunks_per_level[0] > 0 && stats.chunks_per_level[1] > 0);
+
+        // Turned around, there's nothing to draw
+        let view = Matrix4::look_at_dir(camera, -Vector3::unit_x(), Vector3::unit_y());
+        let (draws, stats) = terrain.select(camera, &Frustum::from_matrix(proj * view));
+        assert!(draws.is_empty());
+        assert_eq!(stats.culled, 16);
+        assert_eq!(stats.triangles, 0);
+    }
+
+    #[test]
+    fn splat_weights_add_up() {
+        let terrain = hills();
+        let splat = terrain.splat_map();
+        assert_eq!(splat.dimensions(), (65, 65));
+        for p in splat.pixels() {
+            let sum: u32 = p.0.iter().map(|&c| c as u32).sum();
+            assert!((253..=257).contains(&sum), "{:?}", p);
+        }
+    }
+}
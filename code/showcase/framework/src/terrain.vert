@@ -0,0 +1,43 @@
+#version 450
+
+// framework::TerrainVertex
+layout(location=0) in vec3 a_position;
+layout(location=1) in vec3 a_normal;
+layout(location=2) in vec3 a_tangent;
+layout(location=3) in float a_morph_height;
+
+layout(location=0) out vec3 v_position;
+layout(location=1) out vec3 v_normal;
+layout(location=2) out vec2 v_splat_coords;
+layout(location=3) out vec2 v_layer_coords;
+
+// Must match framework::MAX_TERRAIN_LEVELS
+const uint MAX_LEVELS = 8;
+
+layout(set=0, binding=0)
+uniform TerrainUniforms {
+    vec4 u_size; // world width, world depth, layer tiling, unused
+    vec4 u_morph[MAX_LEVELS]; // start and end distance per level
+};
+
+layout(set=1, binding=0)
+uniform Uniforms {
+    vec4 u_view_position;
+    mat4 u_view_proj;
+};
+
+void main() {
+    // TerrainRenderer::draw passes the chunk's level as the instance
+    vec2 morph = u_morph[gl_InstanceIndex].xy;
+    // Morphing by each vertex's own distance, rather than the chunk's,
+    // means vertices shared by two chunks always agree
+    float view_distance = distance(u_view_position.xyz, a_position);
+    float t = clamp((view_distance - morph.x) / (morph.y - morph.x), 0.0, 1.0);
+    vec3 position = vec3(a_position.x, mix(a_position.y, a_morph_height, t), a_position.z);
+
+    v_position = position;
+    v_normal = a_normal;
+    v_splat_coords = position.xz / u_size.xy;
+    v_layer_coords = position.xz * u_size.z;
+    gl_Position = u_view_proj * vec4(position, 1.0);
+}
@@ -0,0 +1,255 @@
+use anyhow::*;
+use cgmath::*;
+use futures::task::SpawnExt;
+use wgpu_glyph::{ab_glyph, GlyphBrush, GlyphBrushBuilder, Section, Text};
+
+use crate::{Display, Frame};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TextAlign {
+    Left,
+    Center,
+    Right,
+}
+
+/**
+ * How queued text looks. `size` is in pixels and `color` is linear
+ * RGBA. Text with `bounds` wraps at the bounds' width and is cut off
+ * at their height, otherwise it's laid out on one line.
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TextStyle {
+    pub size: f32,
+    pub color: [f32; 4],
+    pub align: TextAlign,
+    pub bounds: Option<[f32; 2]>,
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        Self {
+            size: 20.0,
+            color: [1.0, 1.0, 1.0, 1.0],
+            align: TextAlign::Left,
+            bounds: None,
+        }
+    }
+}
+
+/**
+ * Draws text over everything else in a frame. Queue text each frame
+ * and [Display::end_frame] draws it into the frame, or call
+ * [TextRenderer::draw] yourself if the demo doesn't go through
+ * [Display::begin_frame].
+ */
+pub struct TextRenderer {
+    brush: GlyphBrush<()>,
+    staging_belt: wgpu::util::StagingBelt,
+    local_pool: futures::executor::LocalPool,
+    width: u32,
+    height: u32,
+    queued: usize,
+}
+
+impl TextRenderer {
+    pub fn new(display: &Display, font: Vec<u8>) -> Result<Self> {
+        let font = ab_glyph::FontArc::try_from_vec(font)
+            .map_err(|e| anyhow!("Couldn't load font: {}", e))?;
+        let brush =
+            GlyphBrushBuilder::using_font(font).build(&display.device, display.sc_desc.format);
+        Ok(Self {
+            brush,
+            staging_belt: wgpu::util::StagingBelt::new(1024),
+            local_pool: futures::executor::LocalPool::new(),
+            width: display.sc_desc.width,
+            height: display.sc_desc.height,
+            queued: 0,
+        })
+    }
+
+    /// Keeps world space text lined up with the window
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+    }
+
+    /// How many pieces of text are waiting to be drawn
+    pub fn queued(&self) -> usize {
+        self.queued
+    }
+
+    /// Queues `text` at `position`, in pixels from the top left of the
+    /// window. `position` is the point [TextStyle::align] aligns to.
+    pub fn queue(&mut self, text: &str, position: [f32; 2], style: &TextStyle) {
+        let h_align = match style.align {
+            TextAlign::Left => wgpu_glyph::HorizontalAlign::Left,
+            TextAlign::Center => wgpu_glyph::HorizontalAlign::Center,
+            TextAlign::Right => wgpu_glyph::HorizontalAlign::Right,
+        };
+        let (layout, bounds) = match style.bounds {
+            Some(bounds) => (
+                wgpu_glyph::Layout::default_wrap().h_align(h_align),
+                (bounds[0], bounds[1]),
+            ),
+            None => (
+                wgpu_glyph::Layout::default_single_line().h_align(h_align),
+                (f32::INFINITY, f32::INFINITY),
+            ),
+        };
+        self.brush.queue(Section {
+            screen_position: (position[0], position[1]),
+            bounds,
+            layout,
+            text: vec![Text::new(text)
+                .with_color(style.color)
+                .with_scale(style.size)],
+        });
+        self.queued += 1;
+    }
+
+    /// Queues `text` as a label at `position` in the world. The label
+    /// always faces the camera and stays `style.size` pixels tall no
+    /// matter how far away it is. Returns false if `position` is
+    /// behind the camera or outside the view.
+    pub fn queue_world(
+        &mut self,
+        text: &str,
+        position: Point3<f32>,
+        view_proj: Matrix4<f32>,
+        style: &TextStyle,
+    ) -> bool {
+        match project_to_screen(position, view_proj, self.width, self.height) {
+            Some(screen) => {
+                self.queue(text, screen, style);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Draws and clears everything queued into `target`, which should
+    /// be the size of the window. Call [TextRenderer::recall] once
+    /// `encoder` has been submitted.
+    pub fn draw(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::TextureView,
+    ) -> Result<()> {
+        // Draw in window coordinates so supersampled screenshots get
+        // the same layout, just at a higher resolution
+        let transform = wgpu_glyph::orthographic_projection(self.width, self.height);
+        self.brush
+            .draw_queued_with_transform(device, &mut self.staging_belt, encoder, target, transform)
+            .map_err(|e| anyhow!("Couldn't draw text: {}", e))?;
+        self.staging_belt.finish();
+        self.queued = 0;
+        Ok(())
+    }
+
+    /// Lets the buffers used by [TextRenderer::draw] be reused
+    pub fn recall(&mut self) {
+        let spawner = self.local_pool.spawner();
+        if let Err(e) = spawner.spawn(self.staging_belt.recall()) {
+            log::error!("Couldn't recall text buffers: {}", e);
+        }
+        self.local_pool.run_until_stalled();
+    }
+}
+
+/// Where `position` ends up on a `width` by `height` screen, in pixels
+/// from the top left. Points behind the camera, beyond the far plane
+/// or off screen give `None`.
+pub fn project_to_screen(
+    position: Point3<f32>,
+    view_proj: Matrix4<f32>,
+    width: u32,
+    height: u32,
+) -> Option<[f32; 2]> {
+    let clip = view_proj * position.to_homogeneous();
+    if clip.w <= 0.0 {
+        return None;
+    }
+    let ndc = clip.truncate() / clip.w;
+    if ndc.x.abs() > 1.0 || ndc.y.abs() > 1.0 || ndc.z < 0.0 || ndc.z > 1.0 {
+        return None;
+    }
+    Some([
+        (ndc.x * 0.5 + 0.5) * width as f32,
+        (0.5 - ndc.y * 0.5) * height as f32,
+    ])
+}
+
+impl Display {
+    /// Turns on text drawing with `font`, a TTF or OTF file. Queue text
+    /// through [Display::text].
+    pub fn enable_text(&mut self, font: Vec<u8>) -> Result<()> {
+        self.text = Some(TextRenderer::new(self, font)?);
+        Ok(())
+    }
+
+    /// The last pass of every frame, so text ends up over everything
+    /// else, including in screenshots and recordings
+    pub(crate) fn draw_text(&mut self, frame: &Frame) -> Result<()> {
+        let text = match &mut self.text {
+            Some(text) if text.queued() > 0 => text,
+            _ => return Ok(()),
+        };
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Display::draw_text"),
+            });
+        text.draw(&self.device, &mut encoder, frame.view())?;
+        self.queue.submit(std::iter::once(encoder.finish()));
+        text.recall();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::OPENGL_TO_WGPU_MATRIX;
+
+    fn view_proj() -> Matrix4<f32> {
+        let view = Matrix4::look_at(
+            Point3::new(0.0, 0.0, 5.0),
+            Point3::new(0.0, 0.0, 0.0),
+            Vector3::unit_y(),
+        );
+        OPENGL_TO_WGPU_MATRIX * perspective(Deg(90.0), 2.0, 0.1, 100.0) * view
+    }
+
+    #[test]
+    fn projection() {
+        let center = project_to_screen(Point3::new(0.0, 0.0, 0.0), view_proj(), 200, 100).unwrap();
+        assert!((center[0] - 100.0).abs() < 1e-3);
+        assert!((center[1] - 50.0).abs() < 1e-3);
+
+        // Up in the world is up on screen, which is smaller y
+        let above = project_to_screen(Point3::new(0.0, 1.0, 0.0), view_proj(), 200, 100).unwrap();
+        assert!(above[1] < center[1]);
+        let right = project_to_screen(Point3::new(1.0, 0.0, 0.0), view_proj(), 200, 100).unwrap();
+        assert!(right[0] > center[0]);
+    }
+
+    #[test]
+    fn hidden_points() {
+        // Behind the camera
+        assert_eq!(
+            project_to_screen(Point3::new(0.0, 0.0, 10.0), view_proj(), 200, 100),
+            None
+        );
+        // Off to the side
+        assert_eq!(
+            project_to_screen(Point3::new(50.0, 0.0, 0.0), view_proj(), 200, 100),
+            None
+        );
+        // Past the far plane
+        assert_eq!(
+            project_to_screen(Point3::new(0.0, 0.0, -200.0), view_proj(), 200, 100),
+            None
+        );
+    }
+}
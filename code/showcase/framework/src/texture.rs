@@ -4,6 +4,7 @@ use std::mem;
 use std::path::Path;
 
 use crate::buffer;
+use crate::compressed::{self, ContainerImage};
 
 pub struct Texture<'a> {
     pub texture: wgpu::Texture,
@@ -23,6 +24,18 @@ impl<'a> Texture<'a> {
     ) -> Result<Self> {
         let path_copy = path.as_ref().to_path_buf();
         let label = path_copy.to_str().unwrap();
+
+        // KTX2 and DDS files know their own color space, so
+        // is_normal_map doesn't apply to them.
+        match path_copy.extension().and_then(|ext| ext.to_str()) {
+            Some("ktx2") | Some("dds") => {
+                let bytes = std::fs::read(&path_copy)?;
+                let image = ContainerImage::from_bytes(&bytes)?;
+                return Self::from_container(device, queue, &image, Some(label));
+            }
+            _ => {}
+        }
+
         let img = image::open(path)?;
         Self::from_image(device, queue, &img, Some(label), is_normal_map)
     }
@@ -130,9 +143,166 @@ impl<'a> Texture<'a> {
         })
     }
 
+    /// Creates a 1x1 texture of a single color. Useful as a stand in
+    /// for maps a material doesn't have.
+    pub fn from_color(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color: [u8; 4],
+        is_normal_map: bool,
+    ) -> Result<Self> {
+        let img =
+            image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba(color)));
+        Self::from_image(device, queue, &img, None, is_normal_map)
+    }
+
+    /// Combines separate metallic and roughness images into the glTF
+    /// layout (roughness in green, metallic in blue). A missing image
+    /// is treated as fully white so its factor is used as is.
+    pub fn pack_metallic_roughness<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        metallic: Option<P>,
+        roughness: Option<P>,
+    ) -> Result<Self> {
+        let metallic = metallic
+            .map(image::open)
+            .transpose()?
+            .map(|i| i.to_luma8());
+        let roughness = roughness
+            .map(image::open)
+            .transpose()?
+            .map(|i| i.to_luma8());
+
+        let (width, height) = match (&metallic, &roughness) {
+            (Some(m), Some(r)) => {
+                if m.dimensions() != r.dimensions() {
+                    bail!("Metallic and roughness maps must be the same size");
+                }
+                m.dimensions()
+            }
+            (Some(m), None) => m.dimensions(),
+            (None, Some(r)) => r.dimensions(),
+            (None, None) => (1, 1),
+        };
+
+        let packed = image::RgbaImage::from_fn(width, height, |x, y| {
+            let m = metallic.as_ref().map_or(255, |m| m.get_pixel(x, y)[0]);
+            let r = roughness.as_ref().map_or(255, |r| r.get_pixel(x, y)[0]);
+            image::Rgba([0, r, m, 255])
+        });
+        let img = image::DynamicImage::ImageRgba8(packed);
+        Self::from_image(device, queue, &img, Some("metallic_roughness"), true)
+    }
+
+    /// Uploads a KTX2/DDS image with all of its mip levels and array
+    /// layers. BCn data is passed through as is when the device has
+    /// `Features::TEXTURE_COMPRESSION_BC`, otherwise it gets decoded
+    /// on the CPU first.
+    pub fn from_container(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        image: &ContainerImage,
+        _label: Option<&str>,
+    ) -> Result<Self> {
+        let supports_bc = device
+            .features()
+            .contains(wgpu::Features::TEXTURE_COMPRESSION_BC);
+        let decompressed;
+        let mut image = image;
+        if image.is_compressed() {
+            // wgpu can only copy whole blocks, so we can't upload
+            // textures whose base level isn't a multiple of the
+            // block size.
+            let (bw, bh) = compressed::block_dimensions(image.format);
+            if !supports_bc || !image.width.is_multiple_of(bw) || !image.height.is_multiple_of(bh) {
+                decompressed = image.decompress()?;
+                image = &decompressed;
+            }
+        }
+
+        // For the same reason, we drop any compressed mip levels
+        // that are smaller than a block.
+        let (bw, bh) = compressed::block_dimensions(image.format);
+        let mip_level_count = (0..image.levels.len() as u32)
+            .take_while(|level| {
+                let (width, height) = image.level_extent(*level);
+                width.is_multiple_of(bw) && height.is_multiple_of(bh)
+            })
+            .count() as u32;
+
+        let desc = wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: image.width,
+                height: image.height,
+                depth: image.layers,
+            },
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: image.format,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+            label: None,
+        };
+        let texture = device.create_texture(&desc);
+
+        for level in 0..mip_level_count {
+            let (width, height) = image.level_extent(level);
+            queue.write_texture(
+                wgpu::TextureCopyView {
+                    texture: &texture,
+                    mip_level: level,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                &image.levels[level as usize],
+                wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: image.bytes_per_row(level),
+                    rows_per_image: height,
+                },
+                wgpu::Extent3d {
+                    width,
+                    height,
+                    depth: image.layers,
+                },
+            );
+        }
+
+        let view = texture.create_view(&Default::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: Some(wgpu::CompareFunction::Always),
+            ..Default::default()
+        });
+
+        Ok(Self {
+            texture,
+            view,
+            sampler,
+            desc,
+        })
+    }
+
     pub fn create_depth_texture(
         device: &wgpu::Device,
         sc_desc: &wgpu::SwapChainDescriptor,
+    ) -> Self {
+        Self::create_depth_texture_msaa(device, sc_desc, 1)
+    }
+
+    /// A depth texture for use with pipelines that have a
+    /// `sample_count` greater than 1
+    pub fn create_depth_texture_msaa(
+        device: &wgpu::Device,
+        sc_desc: &wgpu::SwapChainDescriptor,
+        sample_count: u32,
     ) -> Self {
         let desc = wgpu::TextureDescriptor {
             label: None,
@@ -142,7 +312,7 @@ impl<'a> Texture<'a> {
                 depth: 1,
             },
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
             format: Self::DEPTH_FORMAT,
             usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
@@ -150,6 +320,48 @@ impl<'a> Texture<'a> {
         Self::from_descriptor(device, desc)
     }
 
+    /// Creates a texture that can be rendered to and then sampled
+    /// with linear filtering, such as an HDR scene target.
+    pub fn create_render_target(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+        label: Option<&'a str>,
+    ) -> Self {
+        let desc = wgpu::TextureDescriptor {
+            label,
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        };
+        let texture = device.create_texture(&desc);
+        let view = texture.create_view(&Default::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Self {
+            texture,
+            view,
+            sampler,
+            desc,
+        }
+    }
+
     pub fn prepare_buffer_rgba(&self, device: &wgpu::Device) -> buffer::RawBuffer<[f32; 4]> {
         let num_pixels = self.desc.size.width * self.desc.size.height * self.desc.size.depth;
 
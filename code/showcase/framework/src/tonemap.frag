@@ -0,0 +1,44 @@
+#version 450
+
+layout(location=0) in vec2 v_ndc;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_input;
+layout(set=0, binding=1) uniform sampler s_input;
+layout(set=0, binding=2)
+uniform Params {
+    float u_exposure;
+    // Matches framework::TonemapOperator
+    uint u_operator;
+};
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve
+vec3 aces(vec3 x) {
+    const float a = 2.51;
+    const float b = 0.03;
+    const float c = 2.43;
+    const float d = 0.59;
+    const float e = 0.14;
+    return clamp((x * (a * x + b)) / (x * (c * x + d) + e), 0.0, 1.0);
+}
+
+vec3 reinhard(vec3 x) {
+    return x / (1.0 + x);
+}
+
+void main() {
+    vec2 uv = vec2(v_ndc.x, -v_ndc.y) * 0.5 + 0.5;
+    vec4 hdr = texture(sampler2D(t_input, s_input), uv);
+    vec3 color = hdr.rgb * u_exposure;
+
+    if (u_operator == 0u) {
+        color = aces(color);
+    } else if (u_operator == 1u) {
+        color = reinhard(color);
+    } else {
+        color = clamp(color, 0.0, 1.0);
+    }
+
+    f_color = vec4(color, hdr.a);
+}
@@ -0,0 +1,506 @@
+use anyhow::*;
+use cgmath::*;
+use std::cmp::Ordering;
+use std::mem;
+use std::ops::Range;
+
+use crate::buffer::ToRaw;
+use crate::instance::{Instance, InstanceRaw};
+use crate::model::{Material, Mesh, Model};
+use crate::pipeline::{BlendMode, RenderPipelineBuilder};
+use crate::texture::Texture;
+
+/// How transparent surfaces are composited over the opaque scene
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransparencyMode {
+    /// Alpha blended back to front. Exact for separate objects, but
+    /// can't untangle surfaces that overlap within one draw.
+    Sorted,
+    /// [WeightedBlendedOit]. Doesn't need sorting and handles
+    /// intersecting surfaces, at the cost of approximating the result.
+    WeightedBlended,
+}
+
+/// How far in front of the camera `position` is, given the camera's
+/// `view` matrix. Negative if it's behind the camera.
+pub fn view_depth(view: Matrix4<f32>, position: Vector3<f32>) -> f32 {
+    // cgmath's view space looks down -z
+    -(view * position.extend(1.0)).z
+}
+
+/**
+ * Orders `instances` from furthest to nearest along the view
+ * direction, so alpha blending composites them correctly. Instances
+ * at the same depth keep their order, which stops them flickering
+ * from frame to frame.
+ */
+pub fn sort_back_to_front(instances: &mut [Instance], view: Matrix4<f32>) {
+    instances.sort_by(|a, b| {
+        view_depth(view, b.position)
+            .partial_cmp(&view_depth(view, a.position))
+            .unwrap_or(Ordering::Equal)
+    });
+}
+
+/**
+ * An instance buffer that's re-sorted back to front every frame. Both
+ * passes of [DrawTransparent] can draw from it, as the order doesn't
+ * matter to opaque meshes.
+ */
+pub struct SortedInstances {
+    pub buffer: wgpu::Buffer,
+    capacity: usize,
+    count: u32,
+}
+
+impl SortedInstances {
+    pub fn new(device: &wgpu::Device, capacity: usize) -> Self {
+        Self {
+            buffer: Self::create_buffer(device, capacity),
+            capacity,
+            count: 0,
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("SortedInstances::buffer"),
+            size: (capacity.max(1) * mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Sorts `instances` for a camera with `view` and uploads them.
+    /// The buffer grows if it needs to.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        instances: &[Instance],
+        view: Matrix4<f32>,
+    ) {
+        let mut sorted = instances.to_vec();
+        sort_back_to_front(&mut sorted, view);
+        let raw = sorted.iter().map(ToRaw::to_raw).collect::<Vec<_>>();
+        if raw.len() > self.capacity {
+            self.capacity = raw.len().next_power_of_two();
+            self.buffer = Self::create_buffer(device, self.capacity);
+        }
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&raw));
+        self.count = raw.len() as u32;
+    }
+
+    /// Every instance from the last [SortedInstances::update]
+    pub fn range(&self) -> Range<u32> {
+        0..self.count
+    }
+}
+
+/// The meshes of `model` that are drawn in the opaque or transparent
+/// pass
+pub fn meshes_by_transparency<'m>(
+    model: &'m Model<'m>,
+    transparent: bool,
+) -> impl Iterator<Item = (&'m Mesh, &'m Material<'m>)> {
+    model
+        .meshes
+        .iter()
+        .map(move |mesh| (mesh, &model.materials[mesh.material]))
+        .filter(move |(_, material)| material.factors.transparent == transparent)
+}
+
+/**
+ * Splits drawing a model between an opaque and a transparent pass.
+ * Draw every model's opaque meshes first with depth writes on, then
+ * every model's transparent meshes with a pipeline that blends and
+ * doesn't write depth, such as [crate::PbrPipeline::transparent].
+ * Uses the same bind groups as [crate::DrawModel]; anything past set 2
+ * has to be set beforehand.
+ *
+ * Transparent meshes are drawn one at a time for every instance, so
+ * sorting instances with [SortedInstances] orders them within a mesh
+ * but not between the meshes of one model.
+ */
+pub trait DrawTransparent<'a, 'b>
+where
+    'b: 'a,
+{
+    fn draw_model_opaque(
+        &mut self,
+        model: &'b Model,
+        instances: Range<u32>,
+        uniforms: &'b wgpu::BindGroup,
+        light: &'b wgpu::BindGroup,
+    );
+    fn draw_model_transparent(
+        &mut self,
+        model: &'b Model,
+        instances: Range<u32>,
+        uniforms: &'b wgpu::BindGroup,
+        light: &'b wgpu::BindGroup,
+    );
+}
+
+impl<'a, 'b> DrawTransparent<'a, 'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_model_opaque(
+        &mut self,
+        model: &'b Model,
+        instances: Range<u32>,
+        uniforms: &'b wgpu::BindGroup,
+        light: &'b wgpu::BindGroup,
+    ) {
+        draw_meshes(self, model, false, instances, uniforms, light);
+    }
+
+    fn draw_model_transparent(
+        &mut self,
+        model: &'b Model,
+        instances: Range<u32>,
+        uniforms: &'b wgpu::BindGroup,
+        light: &'b wgpu::BindGroup,
+    ) {
+        draw_meshes(self, model, true, instances, uniforms, light);
+    }
+}
+
+fn draw_meshes<'a, 'b>(
+    pass: &mut wgpu::RenderPass<'a>,
+    model: &'b Model<'b>,
+    transparent: bool,
+    instances: Range<u32>,
+    uniforms: &'b wgpu::BindGroup,
+    light: &'b wgpu::BindGroup,
+) where
+    'b: 'a,
+{
+    for (mesh, material) in meshes_by_transparency(model, transparent) {
+        pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        pass.set_index_buffer(mesh.index_buffer.slice(..));
+        pass.set_bind_group(0, &material.bind_group, &[]);
+        pass.set_bind_group(1, uniforms, &[]);
+        pass.set_bind_group(2, light, &[]);
+        pass.draw_indexed(0..mesh.num_elements, 0, instances.clone());
+    }
+}
+
+/**
+ * Weighted blended order-independent transparency, from McGuire and
+ * Bavoil's 2013 paper. Transparent surfaces are drawn in any order
+ * into an accumulation target, which sums their weighted colors, and
+ * a revealage target, which multiplies together how much of the
+ * background each lets through. [WeightedBlendedOit::composite] then
+ * blends the average color over the opaque scene.
+ *
+ * Draw the opaque scene first, then draw transparent meshes in the
+ * pass from [WeightedBlendedOit::begin_accumulate_pass] with a
+ * pipeline that has [WeightedBlendedOit::color_states], such as
+ * [crate::PbrPipeline::oit_pipeline].
+ */
+pub struct WeightedBlendedOit<'a> {
+    pub accum: Texture<'a>,
+    pub revealage: Texture<'a>,
+    /// Multisampled targets that resolve into `accum` and `revealage`
+    msaa: Option<(Texture<'a>, Texture<'a>)>,
+    sample_count: u32,
+    layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    composite_pipeline: wgpu::RenderPipeline,
+}
+
+impl<'a> WeightedBlendedOit<'a> {
+    pub const ACCUM_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    pub const REVEALAGE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+    /// The blending the accumulation pass needs for its two targets
+    pub fn color_states() -> [wgpu::ColorStateDescriptor; 2] {
+        let blend = |src_factor, dst_factor| wgpu::BlendDescriptor {
+            src_factor,
+            dst_factor,
+            operation: wgpu::BlendOperation::Add,
+        };
+        [
+            wgpu::ColorStateDescriptor {
+                format: Self::ACCUM_FORMAT,
+                color_blend: blend(wgpu::BlendFactor::One, wgpu::BlendFactor::One),
+                alpha_blend: blend(wgpu::BlendFactor::One, wgpu::BlendFactor::One),
+                write_mask: wgpu::ColorWrite::ALL,
+            },
+            wgpu::ColorStateDescriptor {
+                format: Self::REVEALAGE_FORMAT,
+                color_blend: blend(wgpu::BlendFactor::Zero, wgpu::BlendFactor::OneMinusSrcColor),
+                alpha_blend: blend(wgpu::BlendFactor::Zero, wgpu::BlendFactor::OneMinusSrcAlpha),
+                write_mask: wgpu::ColorWrite::ALL,
+            },
+        ]
+    }
+
+    /// `sample_count` has to match the depth texture the accumulation
+    /// pass tests against and the target it's composited into
+    pub fn new(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        sample_count: u32,
+        output_format: wgpu::TextureFormat,
+    ) -> Result<Self> {
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::SampledTexture {
+                dimension: wgpu::TextureViewDimension::D2,
+                component_type: wgpu::TextureComponentType::Float,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("WeightedBlendedOit::layout"),
+            entries: &[
+                texture_entry(0),
+                texture_entry(1),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                    count: None,
+                },
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("WeightedBlendedOit::pipeline_layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let composite_pipeline = RenderPipelineBuilder::new()
+            .layout(&pipeline_layout)
+            .vertex_shader(wgpu::include_spirv!("fullscreen.vert.spv"))
+            .fragment_shader(wgpu::include_spirv!("oit_composite.frag.spv"))
+            .color_blend(output_format, BlendMode::Alpha)
+            .sample_count(sample_count)
+            .build(device)?;
+
+        let (accum, revealage, msaa) = Self::create_targets(device, width, height, sample_count);
+        let bind_group = Self::create_bind_group(device, &layout, &accum, &revealage);
+        Ok(Self {
+            accum,
+            revealage,
+            msaa,
+            sample_count,
+            layout,
+            bind_group,
+            composite_pipeline,
+        })
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        let (accum, revealage, msaa) =
+            Self::create_targets(device, width, height, self.sample_count);
+        self.bind_group = Self::create_bind_group(device, &self.layout, &accum, &revealage);
+        self.accum = accum;
+        self.revealage = revealage;
+        self.msaa = msaa;
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn create_targets(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        sample_count: u32,
+    ) -> (Texture<'a>, Texture<'a>, Option<(Texture<'a>, Texture<'a>)>) {
+        let create = |format, label| {
+            Texture::create_render_target(device, width, height, format, Some(label))
+        };
+        let accum = create(Self::ACCUM_FORMAT, "WeightedBlendedOit::accum");
+        let revealage = create(Self::REVEALAGE_FORMAT, "WeightedBlendedOit::revealage");
+        let msaa = if sample_count > 1 {
+            let create_msaa = |format, label| {
+                Texture::from_descriptor(
+                    device,
+                    wgpu::TextureDescriptor {
+                        label: Some(label),
+                        size: wgpu::Extent3d {
+                            width,
+                            height,
+                            depth: 1,
+                        },
+                        mip_level_count: 1,
+                        sample_count,
+                        dimension: wgpu::TextureDimension::D2,
+                        format,
+                        usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+                    },
+                )
+            };
+            Some((
+                create_msaa(Self::ACCUM_FORMAT, "WeightedBlendedOit::msaa_accum"),
+                create_msaa(Self::REVEALAGE_FORMAT, "WeightedBlendedOit::msaa_revealage"),
+            ))
+        } else {
+            None
+        };
+        (accum, revealage, msaa)
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        accum: &Texture,
+        revealage: &Texture,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("WeightedBlendedOit::bind_group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&accum.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&revealage.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&accum.sampler),
+                },
+            ],
+        })
+    }
+
+    /// Clears the accumulation target to zero and the revealage target
+    /// to one, meaning nothing is covered yet
+    pub fn color_attachments(&self) -> [wgpu::RenderPassColorAttachmentDescriptor<'_>; 2] {
+        fn attachment<'t>(
+            texture: &'t Texture,
+            msaa: Option<&'t Texture>,
+            clear: wgpu::Color,
+        ) -> wgpu::RenderPassColorAttachmentDescriptor<'t> {
+            let ops = wgpu::Operations {
+                load: wgpu::LoadOp::Clear(clear),
+                store: true,
+            };
+            match msaa {
+                Some(msaa) => wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: &msaa.view,
+                    resolve_target: Some(&texture.view),
+                    ops,
+                },
+                None => wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: &texture.view,
+                    resolve_target: None,
+                    ops,
+                },
+            }
+        }
+        let (msaa_accum, msaa_revealage) = match &self.msaa {
+            Some((accum, revealage)) => (Some(accum), Some(revealage)),
+            None => (None, None),
+        };
+        [
+            attachment(&self.accum, msaa_accum, wgpu::Color::TRANSPARENT),
+            attachment(&self.revealage, msaa_revealage, wgpu::Color::WHITE),
+        ]
+    }
+
+    /**
+     * Starts the pass transparent surfaces are drawn in. `depth` is
+     * the opaque scene's depth, which hides transparent surfaces behind
+     * opaque ones. It's only read, so it's left as it was for later
+     * passes.
+     */
+    pub fn begin_accumulate_pass<'b>(
+        &'b self,
+        encoder: &'b mut wgpu::CommandEncoder,
+        depth: &'b wgpu::TextureView,
+    ) -> wgpu::RenderPass<'b> {
+        let color_attachments = self.color_attachments();
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &color_attachments,
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        })
+    }
+
+    /// Blends the transparent surfaces over `output`, which should
+    /// load what's already there, e.g.
+    /// `display.color_attachment(&frame.view, wgpu::LoadOp::Load)`
+    pub fn composite(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        output: wgpu::RenderPassColorAttachmentDescriptor,
+    ) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[output],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.composite_pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+
+    #[test]
+    fn depth_sorting() {
+        // A yaw of zero looks down +x
+        let view = Camera::new((0.0, 0.0, 0.0), Deg(0.0), Deg(0.0)).calc_matrix();
+        assert!((view_depth(view, Vector3::new(5.0, 3.0, -2.0)) - 5.0).abs() < 1e-5);
+        assert!(view_depth(view, Vector3::new(-1.0, 0.0, 0.0)) < 0.0);
+
+        let instance = |x: f32, z: f32, tag: f32| {
+            let mut instance = Instance::new(Vector3::new(x, 0.0, z), Quaternion::one());
+            instance.scale.y = tag;
+            instance
+        };
+        let mut instances = vec![
+            instance(2.0, 0.0, 1.0),
+            instance(8.0, 1.0, 2.0),
+            // Sideways distance doesn't count, so this ties with the
+            // first one and has to stay after it
+            instance(2.0, 5.0, 3.0),
+            instance(-4.0, 0.0, 4.0),
+            instance(5.0, -1.0, 5.0),
+        ];
+        sort_back_to_front(&mut instances, view);
+        let tags = instances.iter().map(|i| i.scale.y).collect::<Vec<_>>();
+        assert_eq!(tags, vec![2.0, 5.0, 1.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn blend_presets() {
+        let format = wgpu::TextureFormat::Bgra8UnormSrgb;
+        let replace = BlendMode::Replace.color_state(format);
+        assert_eq!(replace.color_blend, wgpu::BlendDescriptor::REPLACE);
+
+        let alpha = BlendMode::Alpha.color_state(format);
+        assert_eq!(alpha.color_blend.src_factor, wgpu::BlendFactor::SrcAlpha);
+        assert_eq!(
+            alpha.color_blend.dst_factor,
+            wgpu::BlendFactor::OneMinusSrcAlpha
+        );
+
+        // Premultiplied colors already have alpha applied
+        let premultiplied = BlendMode::Premultiplied.color_state(format);
+        assert_eq!(premultiplied.color_blend.src_factor, wgpu::BlendFactor::One);
+
+        // Additive never darkens what's behind it
+        let additive = BlendMode::Additive.color_state(format);
+        assert_eq!(additive.color_blend.dst_factor, wgpu::BlendFactor::One);
+        assert_eq!(additive.alpha_blend.dst_factor, wgpu::BlendFactor::One);
+    }
+}
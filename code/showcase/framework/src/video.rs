@@ -0,0 +1,376 @@
+use anyhow::*;
+use cgmath::*;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::camera::{Camera, Projection};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VideoFormat {
+    /// `frame-000000.png`, `frame-000001.png`, ...
+    PngSequence,
+    /// One uncompressed 4:4:4 YUV4MPEG2 stream
+    Y4m,
+}
+
+/// Which Y'CbCr matrix Y4M frames are converted with. Y4M can't say
+/// which one it used, so tell the player, e.g. with ffmpeg's
+/// `-colorspace bt709`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum YuvMatrix {
+    Bt601,
+    Bt709,
+}
+
+impl YuvMatrix {
+    /// The red and blue luma weights
+    fn kr_kb(self) -> (f32, f32) {
+        match self {
+            YuvMatrix::Bt601 => (0.299, 0.114),
+            YuvMatrix::Bt709 => (0.2126, 0.0722),
+        }
+    }
+}
+
+/// The camera as it was when a frame was recorded
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CameraState {
+    pub position: Point3<f32>,
+    pub view: Matrix4<f32>,
+    pub projection: Matrix4<f32>,
+}
+
+impl CameraState {
+    pub fn new(camera: &Camera, projection: &Projection) -> Self {
+        Self {
+            position: camera.position,
+            view: camera.calc_matrix(),
+            projection: projection.calc_matrix(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FrameTiming {
+    pub frame: u64,
+    /// Simulated seconds since the recording started
+    pub time: f64,
+    /// How long the frame took to update and render in real time
+    pub render_time: Duration,
+    pub camera: Option<CameraState>,
+}
+
+struct Session {
+    directory: PathBuf,
+    width: u32,
+    height: u32,
+    y4m: Option<BufWriter<File>>,
+    timings: Vec<FrameTiming>,
+}
+
+/**
+ * Records every frame [crate::run] draws while it's active. Each
+ * recording gets its own `{directory}/{prefix}-{timestamp}` folder
+ * holding the frames and a `timings.json` sidecar with the time and
+ * camera of every frame. While recording, [crate::run] steps the demo
+ * by exactly `1 / frame_rate` seconds per frame.
+ */
+pub struct VideoCapture {
+    pub directory: PathBuf,
+    pub prefix: String,
+    pub format: VideoFormat,
+    pub frame_rate: u32,
+    pub matrix: YuvMatrix,
+    session: Option<Session>,
+}
+
+impl Default for VideoCapture {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("recordings"),
+            prefix: "recording".to_string(),
+            format: VideoFormat::PngSequence,
+            frame_rate: 60,
+            matrix: YuvMatrix::Bt709,
+            session: None,
+        }
+    }
+}
+
+impl VideoCapture {
+    pub fn is_recording(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// The simulated time between frames
+    pub fn timestep(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.frame_rate.max(1) as f64)
+    }
+
+    /// Starts a new recording of `width` by `height` frames. Returns
+    /// the folder it will be saved to.
+    pub fn start(&mut self, width: u32, height: u32) -> Result<PathBuf> {
+        self.stop()?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let directory = self.directory.join(format!("{}-{}", self.prefix, millis));
+        std::fs::create_dir_all(&directory)?;
+
+        let y4m = match self.format {
+            VideoFormat::PngSequence => None,
+            VideoFormat::Y4m => {
+                let mut file = BufWriter::new(File::create(directory.join("video.y4m"))?);
+                file.write_all(y4m_header(width, height, self.frame_rate.max(1)).as_bytes())?;
+                Some(file)
+            }
+        };
+        log::info!("Recording to {:?}", directory);
+        self.session = Some(Session {
+            directory: directory.clone(),
+            width,
+            height,
+            y4m,
+            timings: Vec::new(),
+        });
+        Ok(directory)
+    }
+
+    /// Finishes the current recording, if there is one, and writes its
+    /// `timings.json`
+    pub fn stop(&mut self) -> Result<()> {
+        let mut session = match self.session.take() {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        if let Some(y4m) = &mut session.y4m {
+            y4m.flush()?;
+        }
+        let json = timings_json(
+            self.format,
+            self.frame_rate.max(1),
+            session.width,
+            session.height,
+            &session.timings,
+        );
+        std::fs::write(session.directory.join("timings.json"), json)?;
+        log::info!(
+            "Saved {} frames to {:?}",
+            session.timings.len(),
+            session.directory
+        );
+        Ok(())
+    }
+
+    /// Adds a frame captured with [crate::Display::capture_next_frame_in_memory]
+    /// to the recording. Frames have to stay the size the recording
+    /// started at.
+    pub fn write_frame(
+        &mut self,
+        image: &image::RgbaImage,
+        render_time: Duration,
+        camera: Option<CameraState>,
+    ) -> Result<()> {
+        let timestep = self.timestep();
+        let matrix = self.matrix;
+        let session = match &mut self.session {
+            Some(session) => session,
+            None => bail!("Not recording"),
+        };
+        if image.dimensions() != (session.width, session.height) {
+            bail!(
+                "Frame is {:?} but the recording is {}x{}",
+                image.dimensions(),
+                session.width,
+                session.height
+            );
+        }
+
+        let frame = session.timings.len() as u64;
+        match &mut session.y4m {
+            Some(y4m) => {
+                y4m.write_all(b"FRAME\n")?;
+                y4m.write_all(&rgba_to_yuv444(image.as_raw(), matrix))?;
+            }
+            None => {
+                image.save(session.directory.join(format!("frame-{:06}.png", frame)))?;
+            }
+        }
+        session.timings.push(FrameTiming {
+            frame,
+            time: timestep.as_secs_f64() * frame as f64,
+            render_time,
+            camera,
+        });
+        Ok(())
+    }
+
+    /// Where the current recording is being saved to
+    pub fn directory(&self) -> Option<&Path> {
+        self.session.as_ref().map(|s| s.directory.as_path())
+    }
+}
+
+fn y4m_header(width: u32, height: u32, frame_rate: u32) -> String {
+    format!(
+        "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444 XCOLORRANGE=LIMITED\n",
+        width, height, frame_rate
+    )
+}
+
+/// Converts sRGB encoded RGBA into limited range planar Y'CbCr. The
+/// transfer function is left in, which is what video expects.
+fn rgba_to_yuv444(pixels: &[u8], matrix: YuvMatrix) -> Vec<u8> {
+    let (kr, kb) = matrix.kr_kb();
+    let kg = 1.0 - kr - kb;
+    let count = pixels.len() / 4;
+    let mut planes = vec![0u8; count * 3];
+    let (y_plane, chroma) = planes.split_at_mut(count);
+    let (u_plane, v_plane) = chroma.split_at_mut(count);
+    for (i, pixel) in pixels.chunks_exact(4).enumerate() {
+        let r = pixel[0] as f32 / 255.0;
+        let g = pixel[1] as f32 / 255.0;
+        let b = pixel[2] as f32 / 255.0;
+        let y = kr * r + kg * g + kb * b;
+        let u = (b - y) / (2.0 * (1.0 - kb));
+        let v = (r - y) / (2.0 * (1.0 - kr));
+        y_plane[i] = to_byte(16.0 + 219.0 * y);
+        u_plane[i] = to_byte(128.0 + 224.0 * u);
+        v_plane[i] = to_byte(128.0 + 224.0 * v);
+    }
+    planes
+}
+
+fn to_byte(value: f32) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+fn timings_json(
+    format: VideoFormat,
+    frame_rate: u32,
+    width: u32,
+    height: u32,
+    timings: &[FrameTiming],
+) -> String {
+    let mut json = String::new();
+    let format = match format {
+        VideoFormat::PngSequence => "png",
+        VideoFormat::Y4m => "y4m",
+    };
+    // Writing to a String can't fail
+    let _ = write!(
+        json,
+        "{{\n  \"format\": \"{}\",\n  \"frame_rate\": {},\n  \"width\": {},\n  \"height\": {},\n  \"frames\": [",
+        format, frame_rate, width, height
+    );
+    for (i, timing) in timings.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(
+            json,
+            "\n    {{\"frame\": {}, \"time\": {}, \"render_ms\": {}, \"camera\": ",
+            timing.frame,
+            timing.time,
+            timing.render_time.as_secs_f64() * 1000.0
+        );
+        match &timing.camera {
+            Some(camera) => {
+                let position: &[f32; 3] = camera.position.as_ref();
+                let view: &[f32; 16] = camera.view.as_ref();
+                let projection: &[f32; 16] = camera.projection.as_ref();
+                let _ = write!(
+                    json,
+                    "{{\"position\": {}, \"view\": {}, \"projection\": {}}}}}",
+                    json_array(position),
+                    json_array(view),
+                    json_array(projection)
+                );
+            }
+            None => json.push_str("null}"),
+        }
+    }
+    json.push_str("\n  ]\n}\n");
+    json
+}
+
+fn json_array(values: &[f32]) -> String {
+    let values = values
+        .iter()
+        .map(|v| {
+            if v.is_finite() {
+                v.to_string()
+            } else {
+                "null".to_string()
+            }
+        })
+        .collect::<Vec<_>>();
+    format!("[{}]", values.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yuv_conversion() {
+        let pixels = [
+            0, 0, 0, 255, //
+            255, 255, 255, 255, //
+            255, 0, 0, 255, //
+            0, 0, 255, 255,
+        ];
+        let planes = rgba_to_yuv444(&pixels, YuvMatrix::Bt709);
+        let (y, u, v) = (&planes[0..4], &planes[4..8], &planes[8..12]);
+        // Black and white hit the ends of the limited range and have
+        // no chroma
+        assert_eq!((y[0], u[0], v[0]), (16, 128, 128));
+        assert_eq!((y[1], u[1], v[1]), (235, 128, 128));
+        // Pure red and blue max out their own chroma channel
+        assert_eq!((y[2], v[2]), (63, 240));
+        assert_eq!((y[3], u[3]), (32, 240));
+
+        let planes = rgba_to_yuv444(&pixels[8..12], YuvMatrix::Bt601);
+        assert_eq!((planes[0], planes[2]), (81, 240));
+    }
+
+    #[test]
+    fn header() {
+        assert_eq!(
+            y4m_header(640, 480, 60),
+            "YUV4MPEG2 W640 H480 F60:1 Ip A1:1 C444 XCOLORRANGE=LIMITED\n"
+        );
+    }
+
+    #[test]
+    fn sidecar() {
+        let timings = [
+            FrameTiming {
+                frame: 0,
+                time: 0.0,
+                render_time: Duration::from_millis(2),
+                camera: None,
+            },
+            FrameTiming {
+                frame: 1,
+                time: 0.5,
+                render_time: Duration::from_millis(3),
+                camera: Some(CameraState {
+                    position: Point3::new(1.0, 2.0, 3.0),
+                    view: Matrix4::identity(),
+                    projection: Matrix4::identity(),
+                }),
+            },
+        ];
+        let json = timings_json(VideoFormat::Y4m, 2, 4, 4, &timings);
+        assert!(json.contains("\"format\": \"y4m\""));
+        assert!(json.contains("{\"frame\": 0, \"time\": 0, \"render_ms\": 2, \"camera\": null},"));
+        assert!(json.contains("\"position\": [1, 2, 3]"));
+        assert!(json.contains("\"view\": [1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1]"));
+        assert!(json.trim_end().ends_with("]\n}"));
+    }
+}
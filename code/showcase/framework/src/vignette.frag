@@ -0,0 +1,25 @@
+#version 450
+
+layout(location=0) in vec2 v_ndc;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=0, binding=0) uniform texture2D t_input;
+layout(set=0, binding=1) uniform sampler s_input;
+layout(set=0, binding=2)
+uniform Params {
+    float u_strength;
+    float u_radius;
+    float u_softness;
+};
+
+void main() {
+    vec2 uv = vec2(v_ndc.x, -v_ndc.y) * 0.5 + 0.5;
+    vec4 color = texture(sampler2D(t_input, s_input), uv);
+
+    // v_ndc is -1 to 1 so the corners are at sqrt(2)
+    float dist = length(v_ndc) / sqrt(2.0);
+    float vignette = smoothstep(u_radius, u_radius - u_softness, dist);
+
+    f_color = vec4(color.rgb * mix(1.0, vignette, u_strength), color.a);
+}
@@ -0,0 +1,23 @@
+#version 450
+
+layout(location=0) in vec3 v_barycentric;
+
+layout(location=0) out vec4 f_color;
+
+layout(set=1, binding=0)
+uniform WireframeParams {
+    vec4 u_color;
+    float u_thickness;
+};
+
+void main() {
+    // How far we are from the nearest edge, in pixels. fwidth keeps
+    // the lines the same width no matter how big the triangle is.
+    vec3 d = fwidth(v_barycentric);
+    vec3 a = smoothstep(vec3(0.0), d * u_thickness, v_barycentric);
+    float edge = 1.0 - min(min(a.x, a.y), a.z);
+    if (edge < 0.01) {
+        discard;
+    }
+    f_color = vec4(u_color.rgb, u_color.a * edge);
+}
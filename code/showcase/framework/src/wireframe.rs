@@ -0,0 +1,330 @@
+use anyhow::*;
+use cgmath::*;
+use std::ops::Range;
+use wgpu::util::DeviceExt;
+
+use crate::instance::InstanceRaw;
+use crate::model::{DrawModel, Model, ModelVertex, Vertex};
+use crate::pipeline::RenderPipelineBuilder;
+use crate::texture::Texture;
+
+/// How [Wireframe::draw_model_instanced] draws a model
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RenderMode {
+    Solid,
+    Wireframe,
+    /// The solid model with its edges drawn on top
+    SolidWireframe,
+}
+
+impl RenderMode {
+    /// Solid, then wireframe, then both, then back to solid
+    pub fn next(self) -> Self {
+        match self {
+            RenderMode::Solid => RenderMode::Wireframe,
+            RenderMode::Wireframe => RenderMode::SolidWireframe,
+            RenderMode::SolidWireframe => RenderMode::Solid,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WireframeVertex {
+    pub position: Vector3<f32>,
+    /// One of (1, 0, 0), (0, 1, 0) or (0, 0, 1) depending on which
+    /// corner of the triangle this is
+    pub barycentric: Vector3<f32>,
+}
+
+unsafe impl bytemuck::Pod for WireframeVertex {}
+unsafe impl bytemuck::Zeroable for WireframeVertex {}
+
+impl Vertex for WireframeVertex {
+    fn desc<'a>() -> wgpu::VertexBufferDescriptor<'a> {
+        use std::mem;
+        wgpu::VertexBufferDescriptor {
+            stride: mem::size_of::<WireframeVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttributeDescriptor {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float3,
+                },
+                wgpu::VertexAttributeDescriptor {
+                    offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float3,
+                },
+            ],
+        }
+    }
+}
+
+/// Unrolls an indexed triangle list so that every triangle gets its
+/// own three vertices, each tagged with its barycentric coordinate.
+/// Vertices can't be shared as a corner can only have one coordinate.
+pub fn barycentric_vertices(vertices: &[ModelVertex], indices: &[u32]) -> Vec<WireframeVertex> {
+    let corners = [
+        Vector3::new(1.0, 0.0, 0.0),
+        Vector3::new(0.0, 1.0, 0.0),
+        Vector3::new(0.0, 0.0, 1.0),
+    ];
+    indices
+        .chunks_exact(3)
+        .flat_map(|triangle| {
+            triangle
+                .iter()
+                .zip(corners.iter())
+                .map(|(&i, &barycentric)| WireframeVertex {
+                    position: vertices[i as usize].position,
+                    barycentric,
+                })
+        })
+        .collect()
+}
+
+pub struct WireframeMesh {
+    pub vertex_buffer: wgpu::Buffer,
+    pub num_vertices: u32,
+}
+
+/**
+ * The wireframe versions of a [Model]'s meshes. These are built from
+ * the CPU side copies in [crate::Mesh::vertices] and
+ * [crate::Mesh::indices], so they need to be rebuilt if those change.
+ */
+pub struct WireframeModel {
+    pub meshes: Vec<WireframeMesh>,
+}
+
+impl WireframeModel {
+    pub fn new(device: &wgpu::Device, model: &Model) -> Self {
+        let meshes = model
+            .meshes
+            .iter()
+            .map(|mesh| {
+                let vertices = barycentric_vertices(&mesh.vertices, &mesh.indices);
+                let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(&format!("{:?} Wireframe Vertex Buffer", mesh.name)),
+                    contents: bytemuck::cast_slice(&vertices),
+                    usage: wgpu::BufferUsage::VERTEX,
+                });
+                WireframeMesh {
+                    vertex_buffer,
+                    num_vertices: vertices.len() as u32,
+                }
+            })
+            .collect();
+        Self { meshes }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct WireframeParams {
+    color: Vector4<f32>,
+    thickness: f32,
+    _padding: [f32; 3],
+}
+
+unsafe impl bytemuck::Pod for WireframeParams {}
+unsafe impl bytemuck::Zeroable for WireframeParams {}
+
+/**
+ * Draws models as wireframes, or with their wireframe on top, and
+ * lets you switch between that and normal drawing at runtime with
+ * [Wireframe::mode].
+ *
+ * wgpu 0.6 doesn't expose a line polygon mode, so edges are always
+ * found in the fragment shader using barycentric coordinates. This
+ * means every model needs a [WireframeModel] made for it.
+ */
+pub struct Wireframe {
+    pub mode: RenderMode,
+    pub color: Vector4<f32>,
+    /// Roughly how wide the lines are in pixels
+    pub thickness: f32,
+    wire_pipeline: wgpu::RenderPipeline,
+    overlay_pipeline: wgpu::RenderPipeline,
+    params_buffer: wgpu::Buffer,
+    params_bind_group: wgpu::BindGroup,
+}
+
+impl Wireframe {
+    pub fn new(
+        device: &wgpu::Device,
+        color_format: wgpu::TextureFormat,
+        sample_count: u32,
+        uniform_layout: &wgpu::BindGroupLayout,
+    ) -> Result<Self> {
+        let params_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Wireframe::params_layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::UniformBuffer {
+                    dynamic: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Wireframe::layout"),
+            bind_group_layouts: &[uniform_layout, &params_layout],
+            push_constant_ranges: &[],
+        });
+        let build = |depth_write_enabled, depth_compare, depth_bias| {
+            RenderPipelineBuilder::new()
+                .layout(&layout)
+                .vertex_shader(wgpu::include_spirv!("wireframe.vert.spv"))
+                .fragment_shader(wgpu::include_spirv!("wireframe.frag.spv"))
+                .color_state(wgpu::ColorStateDescriptor {
+                    format: color_format,
+                    color_blend: wgpu::BlendDescriptor {
+                        src_factor: wgpu::BlendFactor::SrcAlpha,
+                        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                    write_mask: wgpu::ColorWrite::ALL,
+                })
+                .depth_no_stencil(Texture::DEPTH_FORMAT, depth_write_enabled, depth_compare)
+                .depth_bias(depth_bias)
+                .depth_bias_slope_scale(depth_bias as f32)
+                .sample_count(sample_count)
+                .vertex_buffer::<WireframeVertex>()
+                .vertex_buffer::<InstanceRaw>()
+                .build(device)
+        };
+        let wire_pipeline = build(true, wgpu::CompareFunction::Less, 0)?;
+        // The overlay is pulled towards the camera slightly so that it
+        // doesn't z-fight with the solid model underneath it.
+        let overlay_pipeline = build(false, wgpu::CompareFunction::LessEqual, -1)?;
+
+        let color = Vector4::new(1.0, 1.0, 1.0, 1.0);
+        let thickness = 1.0;
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Wireframe::params_buffer"),
+            contents: bytemuck::cast_slice(&[WireframeParams {
+                color,
+                thickness,
+                _padding: [0.0; 3],
+            }]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+        let params_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Wireframe::params_bind_group"),
+            layout: &params_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(params_buffer.slice(..)),
+            }],
+        });
+
+        Ok(Self {
+            mode: RenderMode::Solid,
+            color,
+            thickness,
+            wire_pipeline,
+            overlay_pipeline,
+            params_buffer,
+            params_bind_group,
+        })
+    }
+
+    /// Uploads [Wireframe::color] and [Wireframe::thickness]
+    pub fn update(&self, queue: &wgpu::Queue) {
+        queue.write_buffer(
+            &self.params_buffer,
+            0,
+            bytemuck::cast_slice(&[WireframeParams {
+                color: self.color,
+                thickness: self.thickness,
+                _padding: [0.0; 3],
+            }]),
+        );
+    }
+
+    /// Draws `model` according to [Wireframe::mode]. The solid parts
+    /// are drawn with `solid_pipeline` through [DrawModel], so it needs
+    /// the same bind groups as that does. The instance buffer has to
+    /// already be bound to vertex slot 1.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_model_instanced<'a, 'b>(
+        &'b self,
+        pass: &mut wgpu::RenderPass<'a>,
+        solid_pipeline: &'b wgpu::RenderPipeline,
+        model: &'b Model,
+        wireframe: &'b WireframeModel,
+        instances: Range<u32>,
+        uniforms: &'b wgpu::BindGroup,
+        light: &'b wgpu::BindGroup,
+    ) where
+        'b: 'a,
+    {
+        if self.mode != RenderMode::Wireframe {
+            pass.set_pipeline(solid_pipeline);
+            pass.draw_model_instanced(model, instances.clone(), uniforms, light);
+        }
+        if self.mode == RenderMode::Solid {
+            return;
+        }
+        let pipeline = match self.mode {
+            RenderMode::SolidWireframe => &self.overlay_pipeline,
+            _ => &self.wire_pipeline,
+        };
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, uniforms, &[]);
+        pass.set_bind_group(1, &self.params_bind_group, &[]);
+        for mesh in &wireframe.meshes {
+            pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+            pass.draw(0..mesh.num_vertices, instances.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(x: f32) -> ModelVertex {
+        let zero = Vector3::new(0.0, 0.0, 0.0);
+        ModelVertex {
+            position: Vector3::new(x, 0.0, 0.0),
+            tex_coords: Vector2::new(0.0, 0.0),
+            normal: zero,
+            tangent: zero,
+            bitangent: zero,
+        }
+    }
+
+    #[test]
+    fn shared_vertices_are_unrolled() {
+        // Two triangles sharing an edge
+        let vertices: Vec<_> = (0..4).map(|i| vertex(i as f32)).collect();
+        let indices = [0, 1, 2, 2, 1, 3];
+        let unrolled = barycentric_vertices(&vertices, &indices);
+        assert_eq!(unrolled.len(), indices.len());
+        for (v, &i) in unrolled.iter().zip(indices.iter()) {
+            assert_eq!(v.position, vertices[i as usize].position);
+        }
+        for triangle in unrolled.chunks(3) {
+            let sum = triangle
+                .iter()
+                .fold(Vector3::zero(), |sum, v| sum + v.barycentric);
+            assert_eq!(sum, Vector3::new(1.0, 1.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn modes_cycle() {
+        let mut mode = RenderMode::Solid;
+        for _ in 0..3 {
+            mode = mode.next();
+        }
+        assert_eq!(mode, RenderMode::Solid);
+    }
+}
@@ -0,0 +1,24 @@
+#version 450
+
+layout(location=0) in vec3 a_position;
+layout(location=1) in vec3 a_barycentric;
+
+// framework::InstanceRaw
+layout(location=5) in vec4 a_model_0;
+layout(location=6) in vec4 a_model_1;
+layout(location=7) in vec4 a_model_2;
+layout(location=8) in vec4 a_model_3;
+
+layout(location=0) out vec3 v_barycentric;
+
+layout(set=0, binding=0)
+uniform Uniforms {
+    vec4 u_view_position;
+    mat4 u_view_proj;
+};
+
+void main() {
+    mat4 model_matrix = mat4(a_model_0, a_model_1, a_model_2, a_model_3);
+    v_barycentric = a_barycentric;
+    gl_Position = u_view_proj * model_matrix * vec4(a_position, 1.0);
+}
@@ -0,0 +1,96 @@
+use cgmath::*;
+use framework::{
+    cull_instances, indirect_args, Camera, Frustum, Headless, IndirectInstances, Instance,
+    InstanceCuller, InstanceRaw, Model, Projection, Shape, ToRaw,
+};
+use futures::executor::block_on;
+
+/// A grid of `count` instances around the camera, so some are in view
+/// and plenty aren't
+fn instances(count: u32) -> Vec<InstanceRaw> {
+    let side = (count as f32).sqrt().ceil() as u32;
+    (0..count)
+        .map(|i| {
+            let x = (i % side) as f32 - side as f32 * 0.5;
+            let z = (i / side) as f32 - side as f32 * 0.5;
+            let rotation = Quaternion::from_angle_y(Deg(i as f32 * 7.0));
+            let mut instance = Instance::new(Vector3::new(x * 3.0, 0.0, z * 3.0), rotation);
+            instance.scale = Vector3::new(1.0, 1.0, 1.0) * (0.5 + (i % 3) as f32 * 0.5);
+            instance.to_raw()
+        })
+        .collect()
+}
+
+/// Checks GPU culling against [cull_instances] for `count` instances
+fn check(count: u32) {
+    let headless = block_on(Headless::any_adapter()).unwrap();
+    let (device, queue) = (&headless.device, &headless.queue);
+    let model = Model {
+        meshes: vec![
+            Shape::Cube { size: 1.0 }.mesh(device, 0),
+            Shape::Icosphere {
+                radius: 0.5,
+                subdivisions: 1,
+            }
+            .mesh(device, 0),
+        ],
+        materials: Vec::new(),
+    };
+    let instances = instances(count);
+    let camera = Camera::new((0.0, 2.0, 0.0), Deg(30.0), Deg(-10.0));
+    let projection = Projection::new(16, 9, Deg(60.0), 0.1, 60.0);
+    let frustum = Frustum::from_matrix(projection.calc_matrix() * camera.calc_matrix());
+
+    let culler = InstanceCuller::new(device).unwrap();
+    let indirect =
+        IndirectInstances::new(device, queue, &culler, &model, count, &instances).unwrap();
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("indirect test"),
+    });
+    culler.cull(queue, &mut encoder, &indirect, &frustum);
+    queue.submit(std::iter::once(encoder.finish()));
+    let (culled, args) = block_on(indirect.read_back(device, queue)).unwrap();
+
+    let expected = cull_instances(&instances, model.bounding_radius(), &frustum);
+    let index_counts = model
+        .meshes
+        .iter()
+        .map(|m| m.num_elements)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        args,
+        indirect_args(&index_counts, expected.len() as u32),
+        "on {}",
+        headless.info.name
+    );
+    let matrices = |instances: &[InstanceRaw]| {
+        instances
+            .iter()
+            .map(|i| i.model)
+            .collect::<Vec<Matrix4<f32>>>()
+    };
+    assert_eq!(
+        matrices(&culled),
+        matrices(&expected),
+        "on {}",
+        headless.info.name
+    );
+}
+
+#[test]
+#[ignore = "needs a GPU or a software adapter"]
+fn no_instances() {
+    check(0);
+}
+
+#[test]
+#[ignore = "needs a GPU or a software adapter"]
+fn partial_workgroup() {
+    check(50);
+}
+
+#[test]
+#[ignore = "needs a GPU or a software adapter"]
+fn many_workgroups() {
+    check(20_000);
+}
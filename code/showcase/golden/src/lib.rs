@@ -0,0 +1,99 @@
+//! Golden image tests for the tutorials and showcases. Each of them
+//! has a `tests/golden.rs` that `include!`s its own `main.rs`, sets up
+//! its `State` or [framework::Demo] against the hidden window [check]
+//! opens and draws one frame of it into a [GoldenTarget] instead of the
+//! swap chain. The frame is compared to the PNG of the same name in
+//! `references/`.
+//!
+//! The tests only pass on lavapipe, so they're ignored by a plain
+//! `cargo test`. Run `cargo test -- --ignored` with `VK_ICD_FILENAMES`
+//! pointing at lavapipe's ICD to check them, and `bless-golden.sh` to
+//! update the references.
+
+use anyhow::*;
+use framework::GoldenImages;
+use winit::event_loop::EventLoop;
+use winit::window::{Window, WindowBuilder};
+
+pub use framework::GoldenTarget;
+
+/// Every scene is rendered at this size
+pub const WIDTH: u32 = 320;
+pub const HEIGHT: u32 = 240;
+
+/**
+ * Opens a hidden `WIDTH` by `HEIGHT` window, lets `render` draw the
+ * scene called `name` for it and compares the result to
+ * `references/{name}.png`. Set `GOLDEN_BLESS=1` to update the
+ * reference instead.
+ */
+pub fn check<F>(name: &str, render: F)
+where
+    F: FnOnce(&Window) -> Result<image::RgbaImage>,
+{
+    if let Err(e) = try_check(name, render) {
+        panic!("{:?}", e);
+    }
+}
+
+fn try_check<F>(name: &str, render: F) -> Result<()>
+where
+    F: FnOnce(&Window) -> Result<image::RgbaImage>,
+{
+    let adapter = framework::software_adapter()?;
+    let event_loop = event_loop();
+    let window = WindowBuilder::new()
+        .with_title(name)
+        .with_inner_size(winit::dpi::PhysicalSize::new(WIDTH, HEIGHT))
+        .with_resizable(false)
+        .with_visible(false)
+        .build(&event_loop)?;
+    let size = window.inner_size();
+    if (size.width, size.height) != (WIDTH, HEIGHT) {
+        bail!(
+            "Asked for a {}x{} window but got {}x{}",
+            WIDTH,
+            HEIGHT,
+            size.width,
+            size.height
+        );
+    }
+
+    let image = render(&window)?;
+    let golden = GoldenImages::new(concat!(env!("CARGO_MANIFEST_DIR"), "/references"));
+    golden
+        .check(name, &image)
+        .with_context(|| format!("Rendered on {}", adapter.name))
+}
+
+/// Tests don't run on the main thread, which winit wants its event
+/// loop on unless told otherwise
+#[cfg(any(
+    target_os = "linux",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+fn event_loop() -> EventLoop<()> {
+    use winit::platform::unix::EventLoopExtUnix;
+    EventLoop::new_any_thread()
+}
+
+#[cfg(target_os = "windows")]
+fn event_loop() -> EventLoop<()> {
+    use winit::platform::windows::EventLoopExtWindows;
+    EventLoop::new_any_thread()
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "windows"
+)))]
+fn event_loop() -> EventLoop<()> {
+    EventLoop::new()
+}
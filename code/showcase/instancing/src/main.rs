@@ -0,0 +1,304 @@
+use anyhow::*;
+use cgmath::prelude::*;
+use cgmath::{Deg, Quaternion, Vector3};
+use framework::prelude::*;
+use framework::{
+    Camera, CameraController, Display, Frame, Instance, InstanceRaw, Light, LightBinding, Model,
+    ModelVertex, Projection, RawBuffer, RenderPipelineBuilder, Texture, UniformBinding, Uniforms,
+};
+use std::time::Duration;
+
+const NUM_INSTANCES_PER_ROW: u32 = 10;
+const SPACE_BETWEEN: f32 = 3.0;
+/// How fast the cubes spin in degrees per second
+const SPIN_SPEED: f32 = 30.0;
+
+struct Instancing {
+    camera: Camera,
+    controller: CameraController,
+    projection: Projection,
+    uniforms: Uniforms,
+    uniform_binding: UniformBinding,
+    light: Light,
+    light_binding: LightBinding,
+    model: Model<'static>,
+    instances: Vec<Instance>,
+    instance_buffer: RawBuffer<InstanceRaw>,
+    pipeline: wgpu::RenderPipeline,
+    light_pipeline: wgpu::RenderPipeline,
+    /// The demo's own rather than [Display::depth_texture], so it's
+    /// resized to [Frame::size] when Shift+F12 takes a supersampled
+    /// screenshot
+    depth_texture: Texture<'static>,
+}
+
+fn create_instances() -> Vec<Instance> {
+    (0..NUM_INSTANCES_PER_ROW)
+        .flat_map(|z| {
+            (0..NUM_INSTANCES_PER_ROW).map(move |x| {
+                let x = SPACE_BETWEEN * (x as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
+                let z = SPACE_BETWEEN * (z as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
+                let position = Vector3::new(x, 0.0, z);
+                let rotation = if position.is_zero() {
+                    Quaternion::from_axis_angle(Vector3::unit_z(), Deg(0.0))
+                } else {
+                    Quaternion::from_axis_angle(position.normalize(), Deg(45.0))
+                };
+                Instance::new(position, rotation)
+            })
+        })
+        .collect()
+}
+
+fn material_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    let texture = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStage::FRAGMENT,
+        ty: wgpu::BindingType::SampledTexture {
+            multisampled: false,
+            dimension: wgpu::TextureViewDimension::D2,
+            component_type: wgpu::TextureComponentType::Float,
+        },
+        count: None,
+    };
+    let sampler = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStage::FRAGMENT,
+        ty: wgpu::BindingType::Sampler { comparison: false },
+        count: None,
+    };
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("Instancing::material_layout"),
+        entries: &[texture(0), sampler(1), texture(2), sampler(3)],
+    })
+}
+
+/// The pipelines for the cubes and the light. The golden image tests
+/// build them with a `sample_count` of 1 as they have no [Display].
+fn create_pipelines(
+    device: &wgpu::Device,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
+    material_layout: &wgpu::BindGroupLayout,
+    uniform_binding: &UniformBinding,
+    light_binding: &LightBinding,
+) -> Result<(wgpu::RenderPipeline, wgpu::RenderPipeline)> {
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Instancing::layout"),
+        bind_group_layouts: &[
+            material_layout,
+            &uniform_binding.layout,
+            &light_binding.layout,
+        ],
+        push_constant_ranges: &[],
+    });
+    let pipeline = RenderPipelineBuilder::new()
+        .layout(&layout)
+        .vertex_shader(wgpu::include_spirv!("shader.vert.spv"))
+        .fragment_shader(wgpu::include_spirv!("shader.frag.spv"))
+        .cull_mode(wgpu::CullMode::Back)
+        .color_solid(format)
+        .depth_format(Texture::DEPTH_FORMAT)
+        .vertex_buffer::<ModelVertex>()
+        .vertex_buffer::<InstanceRaw>()
+        .sample_count(sample_count)
+        .build(device)?;
+
+    let light_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Instancing::light_layout"),
+        bind_group_layouts: &[&uniform_binding.layout, &light_binding.layout],
+        push_constant_ranges: &[],
+    });
+    let light_pipeline = RenderPipelineBuilder::new()
+        .layout(&light_layout)
+        .vertex_shader(wgpu::include_spirv!("light.vert.spv"))
+        .fragment_shader(wgpu::include_spirv!("light.frag.spv"))
+        .cull_mode(wgpu::CullMode::Back)
+        .color_solid(format)
+        .depth_format(Texture::DEPTH_FORMAT)
+        .vertex_buffer::<ModelVertex>()
+        .sample_count(sample_count)
+        .build(device)?;
+
+    Ok((pipeline, light_pipeline))
+}
+
+impl Instancing {
+    /// Makes sure the depth buffer matches `frame`, which is bigger
+    /// than the window while a supersampled screenshot is taken
+    fn fit_depth_texture(&mut self, display: &Display, frame: &Frame) {
+        let (width, height) = frame.size();
+        let size = self.depth_texture.desc.size;
+        if size.width != width || size.height != height {
+            let mut desc = display.sc_desc.clone();
+            desc.width = width;
+            desc.height = height;
+            self.depth_texture = Texture::create_depth_texture(&display.device, &desc);
+        }
+    }
+
+    fn draw(&mut self, display: &mut Display) -> Result<()> {
+        let frame = display.begin_frame()?;
+        self.fit_depth_texture(display, &frame);
+
+        let mut encoder = display
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Instancing::render"),
+            });
+        self.uniforms.update_buffer(&display.device, &mut encoder);
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[display.color_attachment(
+                    frame.view(),
+                    wgpu::LoadOp::Clear(wgpu::Color {
+                        r: 0.1,
+                        g: 0.2,
+                        b: 0.3,
+                        a: 1.0,
+                    }),
+                )],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                    attachment: &self.depth_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            pass.set_vertex_buffer(1, self.instance_buffer.buffer.slice(..));
+            pass.set_pipeline(&self.pipeline);
+            pass.draw_model_instanced(
+                &self.model,
+                0..self.instances.len() as u32,
+                &self.uniform_binding.bind_group,
+                &self.light_binding.bind_group,
+            );
+            pass.set_pipeline(&self.light_pipeline);
+            pass.draw_light_model(
+                &self.model,
+                &self.uniform_binding.bind_group,
+                &self.light_binding.bind_group,
+            );
+        }
+        display.queue.submit(std::iter::once(encoder.finish()));
+        display.end_frame(frame)?;
+        Ok(())
+    }
+}
+
+impl framework::Demo for Instancing {
+    const SUPERSAMPLE_SCREENSHOTS: bool = true;
+
+    fn init(display: &Display) -> Result<Self> {
+        let device = &display.device;
+        let (camera, projection, controller) = framework::camera_setup(
+            (0.0, 10.0, -20.0),
+            Deg(90.0),
+            Deg(-25.0),
+            display.sc_desc.width,
+            display.sc_desc.height,
+        );
+        let mut uniforms = Uniforms::new(device);
+        uniforms.update_view_proj(&camera, &projection);
+        let uniform_binding = UniformBinding::new(device, &uniforms);
+        let light = Light::new(
+            device,
+            Vector3::new(2.0, 4.0, 2.0),
+            Vector3::new(1.0, 1.0, 1.0),
+        );
+        let light_binding = LightBinding::new(device, &light);
+
+        let material_layout = material_layout(device);
+        let res_dir = std::path::Path::new(env!("OUT_DIR")).join("res");
+        let model = Model::load(
+            device,
+            &display.queue,
+            &material_layout,
+            res_dir.join("cube.obj"),
+        )?;
+
+        let instances = create_instances();
+        let instance_buffer = RawBuffer::from_slice(
+            device,
+            &instances,
+            wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+        );
+
+        let (pipeline, light_pipeline) = create_pipelines(
+            device,
+            display.color_format(),
+            display.sample_count(),
+            &material_layout,
+            &uniform_binding,
+            &light_binding,
+        )?;
+
+        let depth_texture = Texture::create_depth_texture(device, &display.sc_desc);
+
+        Ok(Self {
+            camera,
+            controller,
+            projection,
+            uniforms,
+            uniform_binding,
+            light,
+            light_binding,
+            model,
+            instances,
+            instance_buffer,
+            pipeline,
+            light_pipeline,
+            depth_texture,
+        })
+    }
+
+    fn process_mouse(&mut self, dx: f64, dy: f64) {
+        self.controller.process_mouse(dx, dy);
+    }
+
+    fn resize(&mut self, display: &Display) {
+        self.projection
+            .resize(display.sc_desc.width, display.sc_desc.height);
+        self.depth_texture = Texture::create_depth_texture(&display.device, &display.sc_desc);
+    }
+
+    fn update(&mut self, display: &Display, dt: Duration) {
+        self.controller.update_camera(&mut self.camera, dt);
+        self.uniforms
+            .update_view_proj(&self.camera, &self.projection);
+
+        let spin = Quaternion::from_angle_y(Deg(SPIN_SPEED * dt.as_secs_f32()));
+        for (instance, raw) in self
+            .instances
+            .iter_mut()
+            .zip(self.instance_buffer.data.iter_mut())
+        {
+            instance.rotation = spin * instance.rotation;
+            raw.model = instance.calc_matrix();
+        }
+        display.queue.write_buffer(
+            &self.instance_buffer.buffer,
+            0,
+            bytemuck::cast_slice(&self.instance_buffer.data),
+        );
+
+        // Circle the light around the grid
+        let position = Quaternion::from_angle_y(Deg(60.0 * dt.as_secs_f32()))
+            .rotate_vector(self.light.position());
+        let color = self.light.color();
+        self.light.set(&display.queue, position, color);
+    }
+
+    fn render(&mut self, display: &mut Display) {
+        if let Err(e) = self.draw(display) {
+            log::error!("{}", e);
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    futures::executor::block_on(framework::run::<Instancing>())
+}
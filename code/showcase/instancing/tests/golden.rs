@@ -0,0 +1,22 @@
+//! Draws the first frame of the demo and compares it to its reference
+//! image. See the `golden` crate for how to run and bless it.
+
+#![allow(dead_code, clippy::all)]
+
+include!("../src/main.rs");
+
+use framework::Demo;
+
+#[test]
+#[ignore = "needs lavapipe, see the golden crate"]
+fn renders_like_the_reference() {
+    golden::check(env!("CARGO_PKG_NAME"), |window| {
+        let mut display = futures::executor::block_on(Display::new(window))?;
+        let mut demo = Instancing::init(&display)?;
+        display.capture_next_frame_in_memory();
+        demo.render(&mut display);
+        display
+            .take_captured_frame()
+            .context("The demo didn't draw a frame")
+    });
+}